@@ -0,0 +1,296 @@
+use crate::{Clock, FibersClock, PollRecv, PollSend, Result, Transport};
+use futures::{Async, Future};
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// [`AggregatingTransporter`] builder.
+///
+/// [`AggregatingTransporter`]: ./struct.AggregatingTransporter.html
+#[derive(Debug, Clone)]
+pub struct AggregatingTransporterBuilder<C = FibersClock> {
+    max_delay: Duration,
+    max_items: usize,
+    clock: C,
+}
+impl AggregatingTransporterBuilder {
+    /// Makes a new `AggregatingTransporterBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl<C: Clock> AggregatingTransporterBuilder<C> {
+    /// Sets the maximum time for which an outgoing item is kept in a batch.
+    ///
+    /// The delay starts when the first item of a batch is enqueued by `start_send`.
+    ///
+    /// The default value is `1ms`.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Sets the maximum number of items in a batch.
+    ///
+    /// A batch reaching this size is handed to the inner transporter immediately.
+    /// If `0` is specified, `1` is used instead.
+    ///
+    /// The default value is `64`.
+    pub fn max_items(mut self, n: usize) -> Self {
+        self.max_items = n;
+        self
+    }
+
+    /// Sets the clock used for timing the delays.
+    ///
+    /// The default value is `FibersClock`.
+    pub fn clock<C2: Clock>(self, clock: C2) -> AggregatingTransporterBuilder<C2> {
+        AggregatingTransporterBuilder {
+            max_delay: self.max_delay,
+            max_items: self.max_items,
+            clock,
+        }
+    }
+
+    /// Makes a new `AggregatingTransporter` instance that wraps the given transporter.
+    pub fn finish<T, I>(self, inner: T) -> AggregatingTransporter<T, C>
+    where
+        T: Transport<SendItem = Vec<I>>,
+        T::RecvItem: IntoIterator,
+    {
+        AggregatingTransporter {
+            inner,
+            max_delay: self.max_delay,
+            max_items: std::cmp::max(self.max_items, 1),
+            clock: self.clock,
+            pending: Vec::new(),
+            incoming: VecDeque::new(),
+        }
+    }
+}
+impl Default for AggregatingTransporterBuilder {
+    fn default() -> Self {
+        AggregatingTransporterBuilder {
+            max_delay: Duration::from_millis(1),
+            max_items: 64,
+            clock: FibersClock,
+        }
+    }
+}
+
+/// An implementation of [`Transport`] that aggregates outgoing items into batches.
+///
+/// Items passed to `start_send` are buffered per peer,
+/// and each batch is handed to the inner transporter as a single item (i.e., `Vec<I>`)
+/// once `max_delay` has elapsed since its first item was enqueued or it reaches `max_items`.
+/// This reduces the per-message overhead of bursty small messages (i.e., Nagle's algorithm at application level).
+///
+/// Received batches are split into the individual items, so the peer is expected to be an `AggregatingTransporter` too.
+/// Note that the inner transporter is responsible for encoding and decoding the batches as single messages.
+///
+/// [`Transport`]: ./trait.Transport.html
+pub struct AggregatingTransporter<T, C = FibersClock>
+where
+    T: Transport,
+    T::RecvItem: IntoIterator,
+    C: Clock,
+{
+    inner: T,
+    max_delay: Duration,
+    max_items: usize,
+    clock: C,
+    pending: Vec<Batch<T::PeerAddr, T::SendItem, C::Delay>>,
+    incoming: VecDeque<(T::PeerAddr, <T::RecvItem as IntoIterator>::Item)>,
+}
+impl<T, I> AggregatingTransporter<T>
+where
+    T: Transport<SendItem = Vec<I>>,
+    T::RecvItem: IntoIterator,
+{
+    /// Makes a new `AggregatingTransporter` instance with the default settings.
+    ///
+    /// This is equivalent to `AggregatingTransporterBuilder::new().finish(inner)`.
+    pub fn new(inner: T) -> Self {
+        AggregatingTransporterBuilder::new().finish(inner)
+    }
+}
+impl<T, I, C> AggregatingTransporter<T, C>
+where
+    T: Transport<SendItem = Vec<I>>,
+    T::RecvItem: IntoIterator,
+    C: Clock,
+{
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the number of items buffered in the instance (i.e., not handed to the inner transporter yet).
+    pub fn pending_items(&self) -> usize {
+        self.pending.iter().map(|b| b.items.len()).sum()
+    }
+
+    /// Hands all of the buffered batches to the inner transporter without waiting for their delays.
+    pub fn flush(&mut self) -> Result<()> {
+        for batch in self.pending.drain(..) {
+            track!(self.inner.start_send(batch.peer, batch.items))?;
+        }
+        Ok(())
+    }
+}
+impl<T, I, C> Transport for AggregatingTransporter<T, C>
+where
+    T: Transport<SendItem = Vec<I>>,
+    T::RecvItem: IntoIterator,
+    C: Clock,
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = I;
+    type RecvItem = <T::RecvItem as IntoIterator>::Item;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        let i = if let Some(i) = self.pending.iter().position(|b| b.peer == peer) {
+            i
+        } else {
+            let delay = self.clock.delay(self.max_delay);
+            self.pending.push(Batch {
+                peer,
+                items: Vec::new(),
+                delay,
+            });
+            self.pending.len() - 1
+        };
+        self.pending[i].items.push(item);
+        if self.pending[i].items.len() >= self.max_items {
+            let batch = self.pending.remove(i);
+            track!(self.inner.start_send(batch.peer, batch.items))?;
+        }
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        let mut i = 0;
+        while i < self.pending.len() {
+            if track!(self.pending[i].delay.poll())?.is_ready() {
+                let batch = self.pending.remove(i);
+                track!(self.inner.start_send(batch.peer, batch.items))?;
+            } else {
+                i += 1;
+            }
+        }
+        let polled = track!(self.inner.poll_send())?;
+        if polled.is_ready() && self.pending.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        loop {
+            if let Some(item) = self.incoming.pop_front() {
+                return Ok(Async::Ready(Some(item)));
+            }
+            match track!(self.inner.poll_recv())? {
+                Async::Ready(Some((peer, items))) => {
+                    let items = items.into_iter().map(|item| (peer.clone(), item));
+                    self.incoming.extend(items);
+                }
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+impl<T, C> fmt::Debug for AggregatingTransporter<T, C>
+where
+    T: Transport + fmt::Debug,
+    T::RecvItem: IntoIterator,
+    C: Clock,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "AggregatingTransporter {{ inner: {:?}, max_delay: {:?}, max_items: {}, .. }}",
+            self.inner, self.max_delay, self.max_items
+        )
+    }
+}
+
+struct Batch<P, S, D> {
+    peer: P,
+    items: S,
+    delay: D,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockClock, MockTransporter, PeerName};
+
+    type Mock = MockTransporter<PeerName, Vec<u8>, Vec<u8>>;
+
+    #[test]
+    fn aggregating_transporter_works() -> Result<()> {
+        let clock = MockClock::new();
+        let mut transporter = AggregatingTransporterBuilder::new()
+            .max_delay(Duration::from_millis(10))
+            .max_items(3)
+            .clock(clock.clone())
+            .finish(Mock::new());
+
+        // Rapid sends are flushed together after the delay.
+        transporter.start_send(PeerName("foo"), 1)?;
+        transporter.start_send(PeerName("foo"), 2)?;
+        assert_eq!(transporter.poll_send()?, Async::NotReady);
+        assert_eq!(transporter.pending_items(), 2);
+        assert!(transporter.inner_ref().sent.is_empty());
+
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(transporter.poll_send()?, Async::Ready(()));
+        assert_eq!(
+            transporter.inner_ref().sent,
+            [(PeerName("foo"), vec![1, 2])]
+        );
+
+        // A batch reaching `max_items` is flushed immediately.
+        for i in 0..3 {
+            transporter.start_send(PeerName("bar"), i)?;
+        }
+        assert_eq!(transporter.poll_send()?, Async::Ready(()));
+        assert_eq!(
+            transporter.inner_ref().sent[1],
+            (PeerName("bar"), vec![0, 1, 2])
+        );
+
+        // Received batches are split.
+        transporter
+            .inner_mut()
+            .incoming
+            .push_back((PeerName("baz"), vec![7, 8]));
+        assert_eq!(
+            transporter.poll_recv()?,
+            Async::Ready(Some((PeerName("baz"), 7)))
+        );
+        assert_eq!(
+            transporter.poll_recv()?,
+            Async::Ready(Some((PeerName("baz"), 8)))
+        );
+        assert_eq!(transporter.poll_recv()?, Async::NotReady);
+        Ok(())
+    }
+}
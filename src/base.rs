@@ -1,5 +1,6 @@
 use crate::{Error, ErrorKind, PeerAddr, PollRecv, PollSend, Result};
 use futures::{Async, Future, Poll};
+use std::net::SocketAddr;
 
 /// This trait allows for sending and receiving items between peers.
 pub trait Transport {
@@ -15,6 +16,20 @@ pub trait Transport {
     /// Starts sending the given item to the destination peer.
     fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()>;
 
+    /// Starts sending the given items to the destination peer in order.
+    ///
+    /// Shared transporters (e.g., `RcTransporter`) guarantee that the items are enqueued contiguously,
+    /// without being interleaved with the items sent via the other handles.
+    ///
+    /// The default implementation calls `start_send` for each item.
+    /// If it fails, the remaining items are discarded.
+    fn start_send_batch(&mut self, peer: Self::PeerAddr, items: Vec<Self::SendItem>) -> Result<()> {
+        for item in items {
+            track!(self.start_send(peer.clone(), item))?;
+        }
+        Ok(())
+    }
+
     /// Polls the transmission of the all outstanding items in the transporter have been completed.
     ///
     /// If it has been completed, this will return `Ok(Async::Ready(()))`.
@@ -24,6 +39,33 @@ pub trait Transport {
     ///
     /// If the transporter has terminated, this will return `Ok(Async::Ready(None))`.
     fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)>;
+
+    /// Pauses (if `paused` is `true`) or resumes (if `false`) the reception of items.
+    ///
+    /// While paused, `poll_recv` returns `Ok(Async::NotReady)` without reading from the underlying socket,
+    /// so that the flow control of the OS (e.g., the TCP receive window) applies to the peers.
+    /// Note that no notification is issued on resuming, so the caller should poll again after that.
+    ///
+    /// The default implementation returns an `ErrorKind::Other` error,
+    /// which means that the transporter does not support pausing.
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track_panic!(
+            ErrorKind::Other,
+            "This transporter does not support pausing reception: paused={}",
+            paused
+        );
+    }
+
+    /// Returns the local socket address to which the transporter is bound, if any.
+    ///
+    /// This is intended to be used by code that is generic over `Transport`.
+    /// The marker traits (i.e., `TcpTransport` and `UdpTransport`) provide
+    /// the infallible `local_addr` method for socket-backed transporters.
+    ///
+    /// The default implementation returns `None`.
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        None
+    }
 }
 
 /// Returns a future that waits the transmission of the all outstanding items in
@@ -39,6 +81,36 @@ pub fn wait_recv<T: Transport>(
     WaitRecv(Some(transporter))
 }
 
+/// Returns a future that waits until the given transporter receives an item from a peer or terminates.
+///
+/// Unlike [`wait_recv`], this resolves to `(transporter, None)` instead of failing
+/// if the transporter has terminated (e.g., the TCP connection has been closed by the peer).
+///
+/// [`wait_recv`]: ./fn.wait_recv.html
+pub fn try_recv<T: Transport>(
+    transporter: T,
+) -> impl Future<Item = (T, Option<Received<T>>), Error = Error> {
+    TryRecv(Some(transporter))
+}
+
+/// Returns a future that repeatedly calls `f` with the given transporter until it returns a value.
+///
+/// This is a generalization of [`wait_send`] and [`wait_recv`],
+/// and can be used to drive an arbitrary poll-based method of a transporter to completion.
+/// The resulting future will return the transporter along with the value returned by `f`.
+///
+/// [`wait_send`]: ./fn.wait_send.html
+/// [`wait_recv`]: ./fn.wait_recv.html
+pub fn drive<T, F, I>(transporter: T, f: F) -> impl Future<Item = (T, I), Error = Error>
+where
+    F: FnMut(&mut T) -> Poll<I, Error>,
+{
+    Drive {
+        transporter: Some(transporter),
+        f,
+    }
+}
+
 #[derive(Debug)]
 struct WaitSend<T>(Option<T>);
 impl<T: Transport> Future for WaitSend<T> {
@@ -78,3 +150,96 @@ impl<T: Transport> Future for WaitRecv<T> {
         Ok(Async::Ready((transporter, peer, item)))
     }
 }
+
+type Received<T> = (<T as Transport>::PeerAddr, <T as Transport>::RecvItem);
+
+#[derive(Debug)]
+struct TryRecv<T>(Option<T>);
+impl<T: Transport> Future for TryRecv<T> {
+    type Item = (T, Option<Received<T>>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let item = {
+            let t = self.0.as_mut().expect("Cannot poll TryRecv twice");
+            if let Async::Ready(item) = track!(t.poll_recv())? {
+                item
+            } else {
+                return Ok(Async::NotReady);
+            }
+        };
+
+        let transporter = self.0.take().expect("never fails");
+        Ok(Async::Ready((transporter, item)))
+    }
+}
+
+#[derive(Debug)]
+struct Drive<T, F> {
+    transporter: Option<T>,
+    f: F,
+}
+impl<T, F, I> Future for Drive<T, F>
+where
+    F: FnMut(&mut T) -> Poll<I, Error>,
+{
+    type Item = (T, I);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let item = {
+            let t = self.transporter.as_mut().expect("Cannot poll Drive twice");
+            if let Async::Ready(item) = track!((self.f)(t))? {
+                item
+            } else {
+                return Ok(Async::NotReady);
+            }
+        };
+
+        let transporter = self.transporter.take().expect("never fails");
+        Ok(Async::Ready((transporter, item)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransporter;
+    use crate::{TcpListener, TcpTransporter};
+    use bytecodec::fixnum::{U8Decoder, U8Encoder};
+    use factory::DefaultFactory;
+    use futures::Stream;
+
+    #[test]
+    fn try_recv_works() -> Result<()> {
+        type TcpServer = TcpListener<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>;
+        type TcpClient = TcpTransporter<U8Encoder, U8Decoder>;
+
+        let server = fibers_global::execute(TcpServer::listen("127.0.0.1:0".parse().unwrap()))?;
+        let mut client = fibers_global::execute(TcpClient::connect(server.local_addr()))?;
+        client.start_send((), 1)?;
+        let client = fibers_global::execute(wait_send(client))?;
+
+        let (server, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let server = server.unwrap();
+        let (server, item) = fibers_global::execute(try_recv(server))?;
+        assert_eq!(item, Some(((), 1)));
+
+        drop(client);
+        let (_, item) = fibers_global::execute(try_recv(server))?;
+        assert_eq!(item, None);
+        Ok(())
+    }
+
+    #[test]
+    fn drive_works() -> Result<()> {
+        let mut transporter = MockTransporter::<(), usize, ()>::new();
+        transporter.start_send((), 1)?;
+        transporter.start_send((), 2)?;
+
+        let (transporter, ()) = fibers_global::execute(drive(transporter, |t| t.poll_send()))?;
+        assert_eq!(transporter.sent, [((), 1), ((), 2)]);
+        assert!(transporter.pending.is_empty());
+        Ok(())
+    }
+}
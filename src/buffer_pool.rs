@@ -0,0 +1,130 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// This trait allows for recycling the receive buffers of [`UdpTransporter`].
+///
+/// Pools are shared by the transporters built from the same builder (and its clones),
+/// so the methods take `&self` and implementations need interior mutability.
+///
+/// [`UdpTransporter`]: ./struct.UdpTransporter.html
+pub trait BufferPool: Send + Sync {
+    /// Returns a buffer of which length is `size`.
+    ///
+    /// The content of the buffer is unspecified.
+    fn acquire(&self, size: usize) -> Vec<u8>;
+
+    /// Returns the given buffer, which is no longer used, to the pool.
+    fn release(&self, buf: Vec<u8>);
+}
+impl<T: BufferPool + ?Sized> BufferPool for Arc<T> {
+    fn acquire(&self, size: usize) -> Vec<u8> {
+        (**self).acquire(size)
+    }
+
+    fn release(&self, buf: Vec<u8>) {
+        (**self).release(buf)
+    }
+}
+
+/// The default [`BufferPool`] implementation that keeps released buffers in a free list.
+///
+/// [`BufferPool`]: ./trait.BufferPool.html
+#[derive(Debug)]
+pub struct FreeListBufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+}
+impl FreeListBufferPool {
+    /// Makes a new `FreeListBufferPool` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of buffers kept in the free list.
+    ///
+    /// The buffers released while the free list is full are deallocated.
+    ///
+    /// The default value is `64`.
+    pub fn max_buffers(mut self, n: usize) -> Self {
+        self.max_buffers = n;
+        self
+    }
+
+    /// Returns the number of buffers in the free list.
+    pub fn free_buffers(&self) -> usize {
+        self.free.lock().expect("never fails").len()
+    }
+}
+impl Default for FreeListBufferPool {
+    fn default() -> Self {
+        FreeListBufferPool {
+            free: Mutex::new(Vec::new()),
+            max_buffers: 64,
+        }
+    }
+}
+impl BufferPool for FreeListBufferPool {
+    fn acquire(&self, size: usize) -> Vec<u8> {
+        let buf = self.free.lock().expect("never fails").pop();
+        if let Some(mut buf) = buf {
+            buf.resize(size, 0);
+            buf
+        } else {
+            vec![0; size]
+        }
+    }
+
+    fn release(&self, buf: Vec<u8>) {
+        let mut free = self.free.lock().expect("never fails");
+        if free.len() < self.max_buffers {
+            free.push(buf);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedBufferPool(Arc<dyn BufferPool>);
+impl SharedBufferPool {
+    pub fn new<P: BufferPool + 'static>(pool: P) -> Self {
+        SharedBufferPool(Arc::new(pool))
+    }
+
+    pub fn acquire(&self, size: usize) -> Vec<u8> {
+        self.0.acquire(size)
+    }
+
+    pub fn release(&self, buf: Vec<u8>) {
+        self.0.release(buf)
+    }
+}
+impl Default for SharedBufferPool {
+    fn default() -> Self {
+        Self::new(FreeListBufferPool::new())
+    }
+}
+impl fmt::Debug for SharedBufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedBufferPool(_)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_list_buffer_pool_works() {
+        let pool = FreeListBufferPool::new().max_buffers(1);
+        let buf = pool.acquire(10);
+        assert_eq!(buf.len(), 10);
+        let ptr = buf.as_ptr();
+        pool.release(buf);
+        pool.release(vec![0; 3]);
+        assert_eq!(pool.free_buffers(), 1);
+
+        let buf = pool.acquire(5);
+        assert_eq!(buf.len(), 5);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(pool.free_buffers(), 0);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{ErrorKind, PollRecv, PollSend, Result, Transport};
+use fibers::sync::mpsc;
+use futures::{Async, Stream};
+use std::collections::VecDeque;
+
+/// An implementation of [`Transport`] that moves typed items through in-process channels.
+///
+/// Items are passed as they are (i.e., no encoding or decoding is involved),
+/// so this is useful for wiring components together using the same interface as the network transporters.
+/// A connected pair of transporters is created by [`ChannelTransport::pair`].
+///
+/// Each direction of the pair has a capacity.
+/// If the number of the items that have been sent but not yet received by the peer reaches the capacity,
+/// the subsequent items are kept in the outgoing queue and `poll_send` returns `Ok(Async::NotReady)`
+/// until the peer receives some of the items.
+///
+/// If the peer transporter is dropped, `poll_recv` will return `Ok(Async::Ready(None))`
+/// once all of the items in the channel have been received,
+/// and `poll_send` will return an `ErrorKind::Closed` error.
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`ChannelTransport::pair`]: ./struct.ChannelTransport.html#method.pair
+#[derive(Debug)]
+pub struct ChannelTransport<S, R> {
+    tx: mpsc::Sender<S>,
+    rx: mpsc::Receiver<R>,
+    ack_tx: mpsc::Sender<()>,
+    ack_rx: mpsc::Receiver<()>,
+    outgoing_queue: VecDeque<S>,
+    in_flight: usize,
+    capacity: usize,
+}
+impl<S, R> ChannelTransport<S, R> {
+    /// Makes a connected pair of `ChannelTransport` instances.
+    ///
+    /// `capacity` is the maximum number of in-flight items in each direction.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is `0`, this will panic.
+    pub fn pair(capacity: usize) -> (ChannelTransport<S, R>, ChannelTransport<R, S>) {
+        assert_ne!(capacity, 0, "The capacity must be a positive number");
+        let (tx0, rx0) = mpsc::channel();
+        let (tx1, rx1) = mpsc::channel();
+        let (ack_tx0, ack_rx0) = mpsc::channel();
+        let (ack_tx1, ack_rx1) = mpsc::channel();
+        let a = ChannelTransport {
+            tx: tx0,
+            rx: rx1,
+            ack_tx: ack_tx1,
+            ack_rx: ack_rx0,
+            outgoing_queue: VecDeque::new(),
+            in_flight: 0,
+            capacity,
+        };
+        let b = ChannelTransport {
+            tx: tx1,
+            rx: rx0,
+            ack_tx: ack_tx0,
+            ack_rx: ack_rx1,
+            outgoing_queue: VecDeque::new(),
+            in_flight: 0,
+            capacity,
+        };
+        (a, b)
+    }
+
+    /// Returns the number of unsent items in the queue of the instance.
+    pub fn message_queue_len(&self) -> usize {
+        self.outgoing_queue.len()
+    }
+
+    /// Returns the number of the items that have been sent but not yet received by the peer.
+    pub fn in_flight_len(&self) -> usize {
+        self.in_flight
+    }
+}
+impl<S, R> Transport for ChannelTransport<S, R> {
+    type PeerAddr = ();
+    type SendItem = S;
+    type RecvItem = R;
+
+    fn start_send(&mut self, (): Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        self.outgoing_queue.push_back(item);
+        track!(self.poll_send())?;
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        while let Ok(Async::Ready(Some(()))) = self.ack_rx.poll() {
+            self.in_flight -= 1;
+        }
+        while self.in_flight < self.capacity {
+            if let Some(item) = self.outgoing_queue.pop_front() {
+                track_assert!(
+                    self.tx.send(item).is_ok(),
+                    ErrorKind::Closed,
+                    "The peer has been dropped"
+                );
+                self.in_flight += 1;
+            } else {
+                break;
+            }
+        }
+        if self.outgoing_queue.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        match self.rx.poll() {
+            Ok(Async::Ready(Some(item))) => {
+                let _ = self.ack_tx.send(());
+                Ok(Async::Ready(Some(((), item))))
+            }
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) | Err(()) => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{try_recv, wait_recv, wait_send};
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Request {
+        Add(u32, u32),
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Response {
+        Sum(u32),
+    }
+
+    #[test]
+    fn channel_transport_works() -> Result<()> {
+        let (mut client, server) = ChannelTransport::<Request, Response>::pair(1);
+
+        client.start_send((), Request::Add(1, 2))?;
+        client.start_send((), Request::Add(3, 4))?;
+        assert_eq!(client.in_flight_len(), 1);
+        assert_eq!(client.message_queue_len(), 1);
+
+        let (server, (), request) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(request, Request::Add(1, 2));
+        let client = fibers_global::execute(wait_send(client))?;
+        assert_eq!(client.message_queue_len(), 0);
+
+        let (mut server, (), request) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(request, Request::Add(3, 4));
+        server.start_send((), Response::Sum(7))?;
+        let server = fibers_global::execute(wait_send(server))?;
+        let (client, (), response) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(response, Response::Sum(7));
+
+        std::mem::drop(server);
+        let (_, item) = fibers_global::execute(try_recv(client))?;
+        assert!(item.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{Error, ErrorKind};
+use fibers::time::timer::{self, Timeout};
+use futures::{Future, Poll};
+use std::time::{Duration, Instant};
+use trackable::error::ErrorKindExt;
+
+/// This trait allows for abstracting the source of time used by the timeout-aware transporters.
+///
+/// The default implementation is [`FibersClock`] which is based on the timers of [`fibers`].
+/// Tests can use a custom implementation to advance time manually.
+///
+/// [`FibersClock`]: ./struct.FibersClock.html
+/// [`fibers`]: https://crates.io/crates/fibers
+pub trait Clock {
+    /// Future that expires after a delay.
+    type Delay: Future<Item = (), Error = Error>;
+
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Returns a future that expires after the given duration.
+    fn delay(&self, duration: Duration) -> Self::Delay;
+}
+
+/// The default [`Clock`] implementation that uses the timers of [`fibers`].
+///
+/// [`Clock`]: ./trait.Clock.html
+/// [`fibers`]: https://crates.io/crates/fibers
+#[derive(Debug, Default, Clone)]
+pub struct FibersClock;
+impl FibersClock {
+    /// Makes a new `FibersClock` instance.
+    pub fn new() -> Self {
+        FibersClock
+    }
+}
+impl Clock for FibersClock {
+    type Delay = FibersDelay;
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay(&self, duration: Duration) -> Self::Delay {
+        FibersDelay(timer::timeout(duration))
+    }
+}
+
+/// The future returned by `FibersClock::delay`.
+#[derive(Debug)]
+pub struct FibersDelay(Timeout);
+impl Future for FibersDelay {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0
+            .poll()
+            .map_err(|e| track!(Error::from(ErrorKind::Other.cause(e))))
+    }
+}
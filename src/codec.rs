@@ -0,0 +1,37 @@
+use crate::{Error, Result};
+use bytecodec::{Decode, DecodeExt, Encode, EncodeExt};
+
+/// Encodes the given item to bytes without any transporter.
+///
+/// Encoding errors are mapped to this crate's `Error` in the same way as the transporters do
+/// (i.e., `ErrorKind::CodecError` unless caused by an I/O error).
+/// This is useful for testing and for pre-serializing messages.
+pub fn encode_item<E: Encode>(encoder: &mut E, item: E::Item) -> Result<Vec<u8>> {
+    track!(encoder.encode_into_bytes(item).map_err(Error::from))
+}
+
+/// Decodes an item from the given bytes without any transporter.
+///
+/// `bytes` must contain exactly one item.
+/// Decoding errors are mapped to this crate's `Error` in the same way as the transporters do.
+pub fn decode_item<D: Decode>(decoder: &mut D, bytes: &[u8]) -> Result<D::Item> {
+    track!(decoder.decode_from_bytes(bytes).map_err(Error::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorKind;
+    use bytecodec::fixnum::{U16beDecoder, U16beEncoder};
+
+    #[test]
+    fn encode_and_decode_item_works() -> Result<()> {
+        let bytes = encode_item(&mut U16beEncoder::new(), 0x1234)?;
+        assert_eq!(bytes, [0x12, 0x34]);
+        assert_eq!(decode_item(&mut U16beDecoder::new(), &bytes)?, 0x1234);
+
+        let result = decode_item(&mut U16beDecoder::new(), &bytes[..1]);
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::CodecError));
+        Ok(())
+    }
+}
@@ -0,0 +1,406 @@
+use crate::error::timeout_error;
+use crate::{Clock, Error, ErrorKind, FibersClock, PollRecv, PollSend, Result, Transport};
+use fibers::sync::oneshot;
+use futures::{Async, Future, Poll};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use trackable::error::ErrorKindExt;
+
+/// This trait allows for accessing the correlation identifier of a request or response item.
+///
+/// It is used by [`CorrelatedTransporter`] to match responses to the outstanding requests.
+///
+/// [`CorrelatedTransporter`]: ./struct.CorrelatedTransporter.html
+pub trait Correlate {
+    /// Returns the correlation identifier of the item.
+    fn correlation_id(&self) -> u64;
+
+    /// Sets the correlation identifier of the item.
+    fn set_correlation_id(&mut self, id: u64);
+}
+
+/// [`CorrelatedTransporter`] builder.
+///
+/// [`CorrelatedTransporter`]: ./struct.CorrelatedTransporter.html
+#[derive(Debug, Clone)]
+pub struct CorrelatedTransporterBuilder<C = FibersClock> {
+    timeout: Duration,
+    clock: C,
+}
+impl CorrelatedTransporterBuilder {
+    /// Makes a new `CorrelatedTransporterBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl<C: Clock> CorrelatedTransporterBuilder<C> {
+    /// Sets the duration after which a call that has received no response fails.
+    ///
+    /// The default value is `10s`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the clock used for timing the call timeout.
+    ///
+    /// The default value is `FibersClock`.
+    pub fn clock<C2: Clock>(self, clock: C2) -> CorrelatedTransporterBuilder<C2> {
+        CorrelatedTransporterBuilder {
+            timeout: self.timeout,
+            clock,
+        }
+    }
+
+    /// Makes a new `CorrelatedTransporter` instance that wraps the given transporter.
+    pub fn finish<T>(self, inner: T) -> CorrelatedTransporter<T, C>
+    where
+        T: Transport,
+        T::SendItem: Correlate,
+        T::RecvItem: Correlate,
+    {
+        CorrelatedTransporter {
+            inner,
+            timeout: self.timeout,
+            clock: self.clock,
+            next_id: 0,
+            pending: HashMap::new(),
+        }
+    }
+}
+impl Default for CorrelatedTransporterBuilder {
+    fn default() -> Self {
+        CorrelatedTransporterBuilder {
+            timeout: Duration::from_secs(10),
+            clock: FibersClock,
+        }
+    }
+}
+
+/// An implementation of [`Transport`] that correlates requests and responses by identifiers.
+///
+/// [`call`] assigns an auto-incrementing identifier to a request and returns a future
+/// that resolves to the response having the same identifier.
+/// Responses are dispatched to the pending calls while `poll_recv` is being polled,
+/// and the items not correlated with any pending calls are yielded by `poll_recv` as usual.
+/// A response is only correlated with the calls made to the peer from which it has been received,
+/// because the identifiers are not unique across peers.
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`call`]: ./struct.CorrelatedTransporter.html#method.call
+#[derive(Debug)]
+pub struct CorrelatedTransporter<T: Transport, C: Clock = FibersClock> {
+    inner: T,
+    timeout: Duration,
+    clock: C,
+    next_id: u64,
+    pending: HashMap<(T::PeerAddr, u64), PendingCall<T::RecvItem>>,
+}
+impl<T> CorrelatedTransporter<T>
+where
+    T: Transport,
+    T::SendItem: Correlate,
+    T::RecvItem: Correlate,
+{
+    /// Makes a new `CorrelatedTransporter` instance with the default settings.
+    ///
+    /// This is equivalent to `CorrelatedTransporterBuilder::new().finish(inner)`.
+    pub fn new(inner: T) -> Self {
+        CorrelatedTransporterBuilder::new().finish(inner)
+    }
+}
+impl<T, C> CorrelatedTransporter<T, C>
+where
+    T: Transport,
+    T::SendItem: Correlate,
+    T::RecvItem: Correlate,
+    C: Clock,
+{
+    /// Sends the given request to the peer, and returns a future that resolves to the correlated response.
+    ///
+    /// The correlation identifier of `item` is overwritten by the transporter.
+    /// If no response is received within the configured timeout, the future fails,
+    /// and the message of the error includes the timeout and the elapsed time.
+    pub fn call(
+        &mut self,
+        peer: T::PeerAddr,
+        mut item: T::SendItem,
+    ) -> impl Future<Item = T::RecvItem, Error = Error>
+    where
+        C: Clone,
+    {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        item.set_correlation_id(id);
+
+        if let Err(e) = track!(self.inner.start_send(peer.clone(), item)) {
+            return Call::Failed(Some(e));
+        }
+        let (tx, rx) = oneshot::channel();
+        let started_at = self.clock.now();
+        self.pending.insert(
+            (peer, id),
+            PendingCall {
+                tx,
+                deadline: started_at + self.timeout,
+            },
+        );
+        Call::Waiting {
+            rx,
+            delay: self.clock.delay(self.timeout),
+            clock: self.clock.clone(),
+            timeout: self.timeout,
+            started_at,
+        }
+    }
+
+    /// Returns the number of the calls waiting for their responses.
+    pub fn pending_call_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    fn remove_expired_calls(&mut self) {
+        let now = self.clock.now();
+        self.pending.retain(|_, call| call.deadline > now);
+    }
+}
+impl<T, C> Transport for CorrelatedTransporter<T, C>
+where
+    T: Transport,
+    T::SendItem: Correlate,
+    T::RecvItem: Correlate,
+    C: Clock,
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        track!(self.inner.start_send(peer, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        self.remove_expired_calls();
+        loop {
+            match track!(self.inner.poll_recv())? {
+                Async::Ready(Some((peer, item))) => {
+                    let key = (peer, item.correlation_id());
+                    if let Some(call) = self.pending.remove(&key) {
+                        let _ = call.tx.send(item);
+                    } else {
+                        return Ok(Async::Ready(Some((key.0, item))));
+                    }
+                }
+                polled => return Ok(polled),
+            }
+        }
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+
+#[derive(Debug)]
+struct PendingCall<T> {
+    tx: oneshot::Sender<T>,
+    deadline: Instant,
+}
+
+enum Call<T, C: Clock> {
+    Failed(Option<Error>),
+    Waiting {
+        rx: oneshot::Receiver<T>,
+        delay: C::Delay,
+        clock: C,
+        timeout: Duration,
+        started_at: Instant,
+    },
+}
+impl<T, C: Clock> Future for Call<T, C> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self {
+            Call::Failed(e) => Err(e.take().expect("Cannot poll Call twice")),
+            Call::Waiting {
+                rx,
+                delay,
+                clock,
+                timeout,
+                started_at,
+            } => {
+                match rx.poll() {
+                    Err(e) => {
+                        // The sender of an expired call is dropped by `poll_recv`.
+                        let elapsed = clock.now() - *started_at;
+                        if elapsed >= *timeout {
+                            return Err(track!(timeout_error(*timeout, elapsed), "Call timed out"));
+                        }
+                        let e = ErrorKind::Other.cause(format!("Transporter dropped: {}", e));
+                        return Err(track!(Error::from(e)));
+                    }
+                    Ok(Async::Ready(item)) => return Ok(Async::Ready(item)),
+                    Ok(Async::NotReady) => {}
+                }
+                if track!(delay.poll())?.is_ready() {
+                    let elapsed = clock.now() - *started_at;
+                    return Err(track!(timeout_error(*timeout, elapsed), "Call timed out"));
+                }
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockClock, MockTransporter};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Message {
+        id: u64,
+        body: &'static str,
+    }
+    impl Message {
+        fn new(body: &'static str) -> Self {
+            Message { id: 0, body }
+        }
+    }
+    impl Correlate for Message {
+        fn correlation_id(&self) -> u64 {
+            self.id
+        }
+
+        fn set_correlation_id(&mut self, id: u64) {
+            self.id = id;
+        }
+    }
+
+    #[test]
+    fn concurrent_calls_work() -> Result<()> {
+        let mock = MockTransporter::<(), Message, Message>::new();
+        let mut transporter = CorrelatedTransporter::new(mock);
+
+        let call0 = transporter.call((), Message::new("foo"));
+        let call1 = transporter.call((), Message::new("bar"));
+        assert_eq!(transporter.pending_call_count(), 2);
+        let ids = transporter
+            .inner_ref()
+            .pending
+            .iter()
+            .map(|(_, m)| m.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [0, 1]);
+
+        let mock = transporter.inner_mut();
+        mock.incoming
+            .push_back(((), Message { id: 1, body: "BAR" }));
+        mock.incoming.push_back((
+            (),
+            Message {
+                id: 9,
+                body: "ping",
+            },
+        ));
+        mock.incoming
+            .push_back(((), Message { id: 0, body: "FOO" }));
+        let polled = transporter.poll_recv()?;
+        assert_eq!(
+            polled,
+            Async::Ready(Some((
+                (),
+                Message {
+                    id: 9,
+                    body: "ping"
+                }
+            )))
+        );
+        transporter.poll_recv()?;
+        assert_eq!(transporter.pending_call_count(), 0);
+
+        let (response0, response1) = fibers_global::execute(call0.join(call1))?;
+        assert_eq!(response0, Message { id: 0, body: "FOO" });
+        assert_eq!(response1, Message { id: 1, body: "BAR" });
+        Ok(())
+    }
+
+    #[test]
+    fn call_timeout_works() -> Result<()> {
+        let clock = MockClock::new();
+        let mock = MockTransporter::<(), Message, Message>::new();
+        let mut transporter = CorrelatedTransporterBuilder::new()
+            .timeout(Duration::from_secs(5))
+            .clock(clock.clone())
+            .finish(mock);
+
+        let mut call = transporter.call((), Message::new("foo"));
+        clock.advance(Duration::from_secs(6));
+        let e = fibers_global::execute(futures::lazy(move || call.poll()))
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("deadline=5s, elapsed=6s"));
+
+        transporter.poll_recv()?;
+        assert_eq!(transporter.pending_call_count(), 0);
+
+        // The expired call is removed by `poll_recv` before the call itself is polled.
+        let mut call = transporter.call((), Message::new("bar"));
+        clock.advance(Duration::from_secs(6));
+        transporter.poll_recv()?;
+        assert_eq!(transporter.pending_call_count(), 0);
+        let e = fibers_global::execute(futures::lazy(move || call.poll()))
+            .err()
+            .unwrap();
+        assert!(!e.to_string().contains("Transporter dropped"));
+        assert!(e.to_string().contains("deadline=5s, elapsed=6s"));
+        Ok(())
+    }
+
+    #[test]
+    fn responses_from_other_peers_are_not_correlated() -> Result<()> {
+        let a: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let mock = MockTransporter::<SocketAddr, Message, Message>::new();
+        let mut transporter = CorrelatedTransporter::new(mock);
+
+        let call = transporter.call(a, Message::new("foo"));
+        let mock = transporter.inner_mut();
+        mock.incoming.push_back((b, Message { id: 0, body: "BAR" }));
+        mock.incoming.push_back((a, Message { id: 0, body: "FOO" }));
+
+        // The response from `b` carries the identifier of the call to `a`, but it is not correlated.
+        assert_eq!(
+            transporter.poll_recv()?,
+            Async::Ready(Some((b, Message { id: 0, body: "BAR" })))
+        );
+        assert_eq!(transporter.pending_call_count(), 1);
+
+        assert_eq!(transporter.poll_recv()?, Async::NotReady);
+        assert_eq!(transporter.pending_call_count(), 0);
+        let response = fibers_global::execute(call)?;
+        assert_eq!(response, Message { id: 0, body: "FOO" });
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+use crate::{PollRecv, PollSend, Result, Transport, UdpTransport};
+use futures::Async;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+
+/// An implementation of [`Transport`] that suppresses duplicate items received from the same peer.
+///
+/// Each received item is converted to a key (by default, the hash value of the item),
+/// and the item is discarded if the key is found in the last `window` keys received from the peer.
+/// This is useful for idempotent protocols over UDP, where a peer may retransmit the same datagram.
+///
+/// The keys are remembered for each peer until [`forget_peer`] is called.
+/// To bound the memory usage, at most [`max_peers`] peers are tracked at once (`1024` by default),
+/// and the least recently active peer is forgotten when an item is received from a new peer.
+/// Duplicates from a forgotten peer are no longer suppressed.
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`forget_peer`]: ./struct.DedupTransporter.html#method.forget_peer
+/// [`max_peers`]: ./struct.DedupTransporter.html#method.max_peers
+pub struct DedupTransporter<T: Transport, F = fn(&<T as Transport>::RecvItem) -> u64> {
+    inner: T,
+    key: F,
+    window: usize,
+    max_peers: usize,
+    recent_keys: HashMap<T::PeerAddr, RecentKeys>,
+    last_used: u64,
+    suppressed: u64,
+}
+impl<T> DedupTransporter<T>
+where
+    T: Transport,
+    T::RecvItem: Hash,
+{
+    /// Makes a new `DedupTransporter` instance that identifies items by their hash values.
+    ///
+    /// `window` is the number of the recent items remembered for each peer.
+    /// If `0` is specified, no items are suppressed.
+    pub fn new(inner: T, window: usize) -> Self {
+        Self::with_key(inner, window, hash_item)
+    }
+}
+impl<T, F> DedupTransporter<T, F>
+where
+    T: Transport,
+    F: FnMut(&T::RecvItem) -> u64,
+{
+    /// Makes a new `DedupTransporter` instance that identifies items by the keys returned by `key`.
+    pub fn with_key(inner: T, window: usize, key: F) -> Self {
+        DedupTransporter {
+            inner,
+            key,
+            window,
+            max_peers: 1024,
+            recent_keys: HashMap::new(),
+            last_used: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Sets the maximum number of the peers whose recent keys are remembered.
+    ///
+    /// The default value is `1024`. If `0` is specified, no items are suppressed.
+    pub fn max_peers(mut self, n: usize) -> Self {
+        self.max_peers = n;
+        self
+    }
+
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Takes ownership of the instance, and returns the inner transporter.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Returns the number of the items that have been suppressed as duplicates.
+    pub fn suppressed_count(&self) -> u64 {
+        self.suppressed
+    }
+
+    /// Returns the number of the peers whose recent keys are remembered.
+    pub fn peer_count(&self) -> usize {
+        self.recent_keys.len()
+    }
+
+    /// Forgets the keys of the items received from the given peer.
+    pub fn forget_peer(&mut self, peer: &T::PeerAddr) {
+        self.recent_keys.remove(peer);
+    }
+
+    /// Returns `true` if the item having the given key has been received from `peer` recently.
+    fn check_duplicate(&mut self, peer: &T::PeerAddr, key: u64) -> bool {
+        if self.window == 0 || self.max_peers == 0 {
+            return false;
+        }
+        if !self.recent_keys.contains_key(peer) && self.recent_keys.len() >= self.max_peers {
+            self.evict_least_recently_used_peer();
+        }
+        self.last_used += 1;
+        let recent = self.recent_keys.entry(peer.clone()).or_default();
+        recent.last_used = self.last_used;
+        let keys = &mut recent.keys;
+        if keys.contains(&key) {
+            return true;
+        }
+        if keys.len() == self.window {
+            keys.pop_front();
+        }
+        keys.push_back(key);
+        false
+    }
+
+    fn evict_least_recently_used_peer(&mut self) {
+        let lru = self
+            .recent_keys
+            .iter()
+            .min_by_key(|(_, recent)| recent.last_used)
+            .map(|(peer, _)| peer.clone());
+        if let Some(peer) = lru {
+            self.recent_keys.remove(&peer);
+        }
+    }
+}
+impl<T, F> Transport for DedupTransporter<T, F>
+where
+    T: Transport,
+    F: FnMut(&T::RecvItem) -> u64,
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        track!(self.inner.start_send(peer, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        loop {
+            match track!(self.inner.poll_recv())? {
+                Async::Ready(Some((peer, item))) => {
+                    let key = (self.key)(&item);
+                    if self.check_duplicate(&peer, key) {
+                        self.suppressed += 1;
+                        continue;
+                    }
+                    return Ok(Async::Ready(Some((peer, item))));
+                }
+                polled => return Ok(polled),
+            }
+        }
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+impl<T, F> UdpTransport for DedupTransporter<T, F>
+where
+    T: UdpTransport,
+    F: FnMut(&T::RecvItem) -> u64,
+{
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+impl<T: Transport + fmt::Debug, F> fmt::Debug for DedupTransporter<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DedupTransporter {{ inner: {:?}, window: {}, suppressed: {}, .. }}",
+            self.inner, self.window, self.suppressed
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecentKeys {
+    keys: VecDeque<u64>,
+    last_used: u64,
+}
+
+fn hash_item<T: Hash>(item: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{bind, MockTransporter, PeerName};
+    use crate::{wait_recv, wait_send};
+
+    #[test]
+    fn dedup_transporter_works() -> Result<()> {
+        let server = DedupTransporter::new(bind()?, 4);
+        let mut client = bind()?;
+        let client_addr = client.local_addr();
+        client.start_send(server.local_addr(), "foo".to_owned())?;
+        client.start_send(server.local_addr(), "foo".to_owned())?;
+        client.start_send(server.local_addr(), "bar".to_owned())?;
+        fibers_global::execute(wait_send(client))?;
+
+        let (server, peer, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!((peer, item.as_str()), (client_addr, "foo"));
+        let (server, peer, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!((peer, item.as_str()), (client_addr, "bar"));
+        assert_eq!(server.suppressed_count(), 1);
+        Ok(())
+    }
+    #[test]
+    fn max_peers_works() -> Result<()> {
+        let (a, b, c) = (PeerName("a"), PeerName("b"), PeerName("c"));
+        let mut mock = MockTransporter::<PeerName, (), u8>::new();
+        for peer in [&a, &b, &a, &c, &b, &c].iter() {
+            mock.incoming.push_back(((*peer).clone(), 1));
+        }
+        let mut transporter = DedupTransporter::new(mock, 4).max_peers(2);
+
+        let mut received = Vec::new();
+        while let Async::Ready(Some((peer, _))) = transporter.poll_recv()? {
+            received.push(peer);
+        }
+        // Receiving from `c` evicts `b` (the least recently active peer),
+        // then receiving from `b` again evicts `a`.
+        assert_eq!(received, [a, b.clone(), c, b]);
+        assert_eq!(transporter.suppressed_count(), 2);
+        assert_eq!(transporter.peer_count(), 2);
+        Ok(())
+    }
+}
@@ -1,3 +1,7 @@
+use fibers::sync::oneshot::MonitorError;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
 use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt, TrackableError};
 
 /// This crate specific [`Error`] type.
@@ -5,22 +9,170 @@ use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt, TrackableE
 /// [`Error`]: https://doc.rust-lang.org/std/error/trait.Error.html
 #[derive(Debug, Clone, TrackableError)]
 pub struct Error(TrackableError<ErrorKind>);
+impl Error {
+    /// Returns `true` if the error is considered transient and
+    /// the failed operation may succeed if it is retried, otherwise `false`.
+    ///
+    /// Currently, I/O errors caused by an interrupted system call, a would-block condition,
+    /// a timeout or a refused connection are regarded as retryable.
+    pub fn is_retryable(&self) -> bool {
+        if *self.kind() != ErrorKind::IoError {
+            return false;
+        }
+        if let Some(e) = self.concrete_cause::<std::io::Error>() {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::ConnectionRefused
+            )
+        } else {
+            false
+        }
+    }
+}
 impl From<std::io::Error> for Error {
     fn from(f: std::io::Error) -> Self {
-        ErrorKind::IoError.cause(f).into()
+        io_error_kind(&f).cause(f).into()
     }
 }
 impl From<bytecodec::Error> for Error {
     fn from(f: bytecodec::Error) -> Self {
         let original_error_kind = *f.kind();
-        if f.concrete_cause::<std::io::Error>().is_some() {
-            track!(ErrorKind::IoError.takes_over(f); original_error_kind).into()
+        if let Some(kind) = f.concrete_cause::<std::io::Error>().map(io_error_kind) {
+            track!(kind.takes_over(f); original_error_kind).into()
         } else {
             track!(ErrorKind::CodecError.takes_over(f); original_error_kind).into()
         }
     }
 }
 
+/// Makes an error indicating that an operation has not been completed within the given deadline.
+///
+/// The deadline and the measured elapsed time are recorded in the history of the error,
+/// so that they are included in its message.
+pub(crate) fn timeout_error(deadline: Duration, elapsed: Duration) -> Error {
+    let e = Error::from(ErrorKind::Other.cause("Timed out"));
+    track!(e, "deadline={:?}, elapsed={:?}", deadline, elapsed)
+}
+
+/// A function that converts the errors of encoders and decoders into `Error`.
+///
+/// By default, `Error::from` is used.
+#[derive(Clone)]
+pub(crate) struct CodecErrorMapper(Arc<dyn Fn(bytecodec::Error) -> Error + Send + Sync>);
+impl CodecErrorMapper {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(bytecodec::Error) -> Error + Send + Sync + 'static,
+    {
+        CodecErrorMapper(Arc::new(f))
+    }
+
+    pub fn map(&self, e: bytecodec::Error) -> Error {
+        (self.0)(e)
+    }
+}
+impl Default for CodecErrorMapper {
+    fn default() -> Self {
+        Self::new(Error::from)
+    }
+}
+impl fmt::Debug for CodecErrorMapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CodecErrorMapper(_)")
+    }
+}
+
+/// An error returned by `try_start_send` methods.
+///
+/// This contains the item that could not be accepted by the transporter,
+/// so that the caller can recover it (e.g., to retry later or send it via another transporter).
+#[derive(Debug, Clone)]
+pub struct SendError<T> {
+    error: Error,
+    item: T,
+}
+impl<T> SendError<T> {
+    /// Makes a new `SendError` instance.
+    pub fn new(error: Error, item: T) -> Self {
+        SendError { error, item }
+    }
+
+    /// Returns a reference to the cause of the failure.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    /// Returns a reference to the rejected item.
+    pub fn item(&self) -> &T {
+        &self.item
+    }
+
+    /// Takes ownership of the instance, and returns the rejected item.
+    pub fn into_item(self) -> T {
+        self.item
+    }
+
+    /// Takes ownership of the instance, and returns the cause and the rejected item.
+    pub fn into_parts(self) -> (Error, T) {
+        (self.error, self.item)
+    }
+}
+impl<T> std::fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+impl<T: std::fmt::Debug> std::error::Error for SendError<T> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+impl<T> From<SendError<T>> for Error {
+    fn from(f: SendError<T>) -> Self {
+        f.error
+    }
+}
+
+fn io_error_kind(e: &std::io::Error) -> ErrorKind {
+    if is_connection_reset(e) {
+        ErrorKind::ConnectionReset
+    } else if is_executor_stopped(e) {
+        ErrorKind::ExecutorStopped
+    } else {
+        ErrorKind::IoError
+    }
+}
+
+fn is_connection_reset(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::BrokenPipe
+    )
+}
+
+// `fibers` reports the disappearance of the poller (i.e., the executor) via
+// `ErrorKind::Other` errors that wrap one of the following causes.
+fn is_executor_stopped(e: &std::io::Error) -> bool {
+    if e.kind() != std::io::ErrorKind::Other {
+        return false;
+    }
+    let inner = match e.get_ref() {
+        Some(inner) => inner,
+        None => return false,
+    };
+    // NOTE: `fibers` boxes the causes before passing them to `io::Error::new`.
+    if inner.is::<Box<std::sync::mpsc::RecvError>>() {
+        return true;
+    }
+    if let Some(e) = inner.downcast_ref::<Box<MonitorError<std::io::Error>>>() {
+        return matches!(**e, MonitorError::Aborted);
+    }
+    inner.to_string() == "Monitor channel disconnected"
+}
+
 /// Possible error kinds.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ErrorKind {
@@ -33,6 +185,26 @@ pub enum ErrorKind {
     /// Input is invalid.
     InvalidInput,
 
+    /// The transporter (or the underlying connection) has been closed.
+    Closed,
+
+    /// The connection has been reset by the peer.
+    ConnectionReset,
+
+    /// An outgoing message is too large to be sent (e.g., it exceeds the path MTU).
+    MessageTooLarge,
+
+    /// The `fibers` executor that drives the transporter has been stopped.
+    ///
+    /// This is distinguished from `IoError` so that callers can tell a shutdown from real network failures.
+    ExecutorStopped,
+
+    /// A proxy server (e.g., SOCKS5) has refused the request or violated the protocol.
+    ProxyError,
+
+    /// A quota (e.g., the maximum number of bytes to be received) has been exceeded.
+    QuotaExceeded,
+
     /// Other error.
     Other,
 }
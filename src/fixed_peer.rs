@@ -1,12 +1,33 @@
 use crate::{
-    ErrorKind, PeerAddr, PollRecv, PollSend, Result, TcpTransport, Transport, UdpTransport,
+    wait_send, Error, ErrorKind, PeerAddr, PollRecv, PollSend, Result, TcpTransport, Transport,
+    UdpTransport,
 };
-use futures::Async;
+use futures::{Async, Future};
 use std::net::SocketAddr;
 
+/// A transporter that presents `PeerAddr = SocketAddr` for a TCP transporter (whose `PeerAddr` is `()`).
+///
+/// This is useful for writing protocol code generic over both TCP and UDP transporters.
+/// Instances can be made by [`FixedPeerTransporter::with_peer_addr`].
+///
+/// [`FixedPeerTransporter::with_peer_addr`]: ./struct.FixedPeerTransporter.html#method.with_peer_addr
+pub type WithPeerAddr<T> = FixedPeerTransporter<T, SocketAddr>;
+
+/// A transporter that presents `PeerAddr = ()` for a UDP transporter communicating with a single peer.
+///
+/// Instances can be made by [`FixedPeerTransporter::ignore_peer_addr`].
+///
+/// [`FixedPeerTransporter::ignore_peer_addr`]: ./struct.FixedPeerTransporter.html#method.ignore_peer_addr
+pub type IgnorePeerAddr<T> = FixedPeerTransporter<T, ()>;
+
 /// An implementation of [`Transport`] used for communicating with a fixed peer.
 ///
+/// This can also be used for bridging the peer address types of transporters
+/// (see [`WithPeerAddr`] and [`IgnorePeerAddr`]).
+///
 /// [`Transport`]: ./trait.Transport.html
+/// [`WithPeerAddr`]: ./type.WithPeerAddr.html
+/// [`IgnorePeerAddr`]: ./type.IgnorePeerAddr.html
 #[derive(Debug)]
 pub struct FixedPeerTransporter<T: Transport, P = <T as Transport>::PeerAddr> {
     exterior_peer: P,
@@ -42,6 +63,37 @@ impl<T: Transport, P> FixedPeerTransporter<T, P> {
     pub fn interior_peer(&self) -> &T::PeerAddr {
         &self.interior_peer
     }
+
+    /// Ends the communication with the fixed peer, and returns the inner transporter for reuse.
+    ///
+    /// If `final_item` is given, it is sent to the peer (e.g., a "bye" message)
+    /// and the resulting future completes after all of the queued items have been sent.
+    pub fn close(
+        mut self,
+        final_item: Option<T::SendItem>,
+    ) -> impl Future<Item = T, Error = Error> {
+        let result = if let Some(item) = final_item {
+            track!(self.inner.start_send(self.interior_peer.clone(), item))
+        } else {
+            Ok(())
+        };
+        futures::done(result).and_then(move |()| wait_send(self.inner))
+    }
+}
+impl<T: TcpTransport> FixedPeerTransporter<T, SocketAddr> {
+    /// Makes a new `WithPeerAddr` instance that presents the address of the connected peer
+    /// (i.e., `inner.peer_addr()`) as its peer address.
+    pub fn with_peer_addr(inner: T) -> Self {
+        FixedPeerTransporter::new(inner.peer_addr(), (), inner)
+    }
+}
+impl<T: UdpTransport> FixedPeerTransporter<T, ()> {
+    /// Makes a new `IgnorePeerAddr` instance that communicates with only the given peer.
+    ///
+    /// Items received from the other peers are discarded.
+    pub fn ignore_peer_addr(inner: T, peer: SocketAddr) -> Self {
+        FixedPeerTransporter::new((), peer, inner)
+    }
 }
 impl<T: Transport, P: PeerAddr> Transport for FixedPeerTransporter<T, P> {
     type PeerAddr = P;
@@ -75,6 +127,14 @@ impl<T: Transport, P: PeerAddr> Transport for FixedPeerTransporter<T, P> {
             }
         }
     }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
 }
 impl<T: UdpTransport> UdpTransport for FixedPeerTransporter<T, SocketAddr> {
     fn local_addr(&self) -> SocketAddr {
@@ -83,6 +143,80 @@ impl<T: UdpTransport> UdpTransport for FixedPeerTransporter<T, SocketAddr> {
 }
 impl<T: TcpTransport> From<T> for FixedPeerTransporter<T, SocketAddr> {
     fn from(f: T) -> Self {
-        FixedPeerTransporter::new(f.peer_addr(), (), f)
+        FixedPeerTransporter::with_peer_addr(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::bind;
+    use crate::{wait_recv, TcpListener, TcpTransporter};
+    use bytecodec::fixnum::{U8Decoder, U8Encoder};
+    use factory::DefaultFactory;
+    use futures::Stream;
+
+    type Tcp = TcpTransporter<U8Encoder, U8Decoder>;
+    type Server = TcpListener<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>;
+
+    #[test]
+    fn close_works() -> Result<()> {
+        let server = bind()?;
+        let client = bind()?;
+        let client_addr = client.local_addr();
+        let mut client = FixedPeerTransporter::new((), server.local_addr(), client);
+
+        client.start_send((), "hello".to_owned())?;
+        let client = fibers_global::execute(client.close(Some("bye".to_owned())))?;
+        assert_eq!(client.local_addr(), client_addr);
+        assert_eq!(client.message_queue_len(), 0);
+
+        let (server, peer, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!((peer, item.as_str()), (client_addr, "hello"));
+        let (_, peer, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!((peer, item.as_str()), (client_addr, "bye"));
+        Ok(())
+    }
+
+    #[test]
+    fn with_peer_addr_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let server_addr = server.local_addr();
+        let client = fibers_global::execute(Tcp::connect(server_addr))?;
+        let client_addr = client.local_addr();
+        let mut client = WithPeerAddr::with_peer_addr(client);
+        let (accepted, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let server = WithPeerAddr::with_peer_addr(accepted.expect("never fails"));
+
+        client.start_send(server_addr, 1)?;
+        let client = fibers_global::execute(wait_send(client))?;
+        let (mut server, peer, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!((peer, item), (client_addr, 1));
+
+        server.start_send(peer, 2)?;
+        let _server = fibers_global::execute(wait_send(server))?;
+        let (_, peer, item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!((peer, item), (server_addr, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_peer_addr_works() -> Result<()> {
+        let server = bind()?;
+        let client = bind()?;
+        let (server_addr, client_addr) = (server.local_addr(), client.local_addr());
+        let mut client = IgnorePeerAddr::ignore_peer_addr(client, server_addr);
+        let server = IgnorePeerAddr::ignore_peer_addr(server, client_addr);
+
+        client.start_send((), "ping".to_owned())?;
+        let client = fibers_global::execute(wait_send(client))?;
+        let (mut server, (), item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, "ping");
+
+        server.start_send((), "pong".to_owned())?;
+        let _server = fibers_global::execute(wait_send(server))?;
+        let (_, (), item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(item, "pong");
+        Ok(())
     }
 }
@@ -0,0 +1,212 @@
+use bytecodec::bytes::BytesEncoder;
+use bytecodec::fixnum::U32beDecoder;
+use bytecodec::io::IoEncodeExt;
+use bytecodec::{ByteCount, Decode, Encode, Eos, ErrorKind, Result};
+use std::cmp;
+
+/// An encoder that optionally prefixes each item encoded by `E` with its length (32-bit big-endian).
+///
+/// If framing is disabled, this simply delegates to the inner encoder.
+#[derive(Debug)]
+pub(crate) struct FramedEncoder<E> {
+    inner: E,
+    enabled: bool,
+    frame: BytesEncoder<Vec<u8>>,
+}
+impl<E: Encode> FramedEncoder<E> {
+    pub fn new(inner: E, enabled: bool) -> Self {
+        FramedEncoder {
+            inner,
+            enabled,
+            frame: BytesEncoder::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn inner_ref(&self) -> &E {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+impl<E: Encode> Encode for FramedEncoder<E> {
+    type Item = E::Item;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> Result<usize> {
+        if self.enabled {
+            track!(self.frame.encode(buf, eos))
+        } else {
+            track!(self.inner.encode(buf, eos))
+        }
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> Result<()> {
+        if !self.enabled {
+            return track!(self.inner.start_encoding(item));
+        }
+
+        // The whole item is encoded in advance, because the length of it has to be written first.
+        let mut frame = vec![0; 4];
+        track!(self.inner.start_encoding(item))?;
+        track!(self.inner.encode_all(&mut frame))?;
+        let len = frame.len() - 4;
+        track_assert!(
+            len <= u32::MAX as usize,
+            ErrorKind::InvalidInput,
+            "Too large frame: {} bytes",
+            len
+        );
+        frame[..4].copy_from_slice(&(len as u32).to_be_bytes());
+        track!(self.frame.start_encoding(frame))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if self.enabled {
+            self.frame.requiring_bytes()
+        } else {
+            self.inner.requiring_bytes()
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        if self.enabled {
+            self.frame.is_idle()
+        } else {
+            self.inner.is_idle()
+        }
+    }
+}
+
+/// A decoder that decodes the frames made by `FramedEncoder`, and then decodes their payloads by `D`.
+///
+/// Each payload must be decoded to exactly one item.
+/// If framing is disabled, this simply delegates to the inner decoder.
+#[derive(Debug)]
+pub(crate) struct FramedDecoder<D> {
+    inner: D,
+    enabled: bool,
+    header: U32beDecoder,
+    remaining: Option<usize>,
+}
+impl<D: Decode> FramedDecoder<D> {
+    pub fn new(inner: D, enabled: bool) -> Self {
+        FramedDecoder {
+            inner,
+            enabled,
+            header: U32beDecoder::new(),
+            remaining: None,
+        }
+    }
+
+    pub fn inner_ref(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    fn decode_payload(&mut self, buf: &[u8], remaining: usize) -> Result<usize> {
+        let len = cmp::min(buf.len(), remaining);
+        let size = track!(self.inner.decode(&buf[..len], Eos::new(len == remaining)))?;
+        let remaining = remaining - size;
+        if self.inner.is_idle() {
+            track_assert_eq!(
+                remaining,
+                0,
+                ErrorKind::InvalidInput,
+                "The frame has trailing bytes"
+            );
+            self.remaining = None;
+        } else {
+            track_assert_ne!(
+                remaining,
+                0,
+                ErrorKind::InvalidInput,
+                "The frame has been consumed without decoding an item"
+            );
+            self.remaining = Some(remaining);
+        }
+        Ok(size)
+    }
+}
+impl<D: Decode> Decode for FramedDecoder<D> {
+    type Item = D::Item;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> Result<usize> {
+        if !self.enabled {
+            return track!(self.inner.decode(buf, eos));
+        }
+        if self.inner.is_idle() {
+            return Ok(0);
+        }
+
+        let mut offset = 0;
+        if self.remaining.is_none() {
+            offset = track!(self.header.decode(buf, eos))?;
+            if !self.header.is_idle() {
+                return Ok(offset);
+            }
+            self.remaining = Some(track!(self.header.finish_decoding())? as usize);
+        }
+        let remaining = self.remaining.expect("never fails");
+        offset += track!(self.decode_payload(&buf[offset..], remaining))?;
+        Ok(offset)
+    }
+
+    fn finish_decoding(&mut self) -> Result<Self::Item> {
+        track!(self.inner.finish_decoding())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if !self.enabled {
+            return self.inner.requiring_bytes();
+        }
+        match self.remaining {
+            _ if self.inner.is_idle() => ByteCount::Finite(0),
+            None => self.header.requiring_bytes(),
+            Some(remaining) => ByteCount::Finite(remaining as u64),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+    use bytecodec::io::IoDecodeExt;
+
+    #[test]
+    fn framing_works() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut encoder = FramedEncoder::new(BytesEncoder::new(), true);
+        encoder.start_encoding(b"foo".to_vec())?;
+        encoder.encode_all(&mut buf)?;
+        encoder.start_encoding(Vec::new())?;
+        encoder.encode_all(&mut buf)?;
+        assert_eq!(buf, b"\x00\x00\x00\x03foo\x00\x00\x00\x00");
+
+        let mut decoder = FramedDecoder::new(RemainingBytesDecoder::new(), true);
+        let mut input = &buf[..];
+        assert_eq!(decoder.decode_exact(&mut input)?, b"foo");
+        assert_eq!(decoder.decode_exact(&mut input)?, b"");
+        Ok(())
+    }
+}
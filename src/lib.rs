@@ -83,26 +83,103 @@ extern crate fibers;
 #[cfg(test)]
 extern crate fibers_global;
 extern crate futures;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(unix)]
+extern crate mio;
 #[macro_use]
 extern crate trackable;
 
-pub use base::{wait_recv, wait_send, Transport};
-pub use error::{Error, ErrorKind};
-pub use fixed_peer::FixedPeerTransporter;
+pub use aggregate::{AggregatingTransporter, AggregatingTransporterBuilder};
+pub use base::{drive, try_recv, wait_recv, wait_send, Transport};
+pub use buffer_pool::{BufferPool, FreeListBufferPool};
+pub use channel::ChannelTransport;
+pub use clock::{Clock, FibersClock, FibersDelay};
+pub use codec::{decode_item, encode_item};
+pub use correlate::{Correlate, CorrelatedTransporter, CorrelatedTransporterBuilder};
+pub use dedup::DedupTransporter;
+pub use error::{Error, ErrorKind, SendError};
+pub use fixed_peer::{FixedPeerTransporter, IgnorePeerAddr, WithPeerAddr};
+pub use line::{LineDecoder, LineEncoder};
+pub use load_balance::{BalancePolicy, LoadBalancedUdpTransporter};
+pub use merge::{merge_transports, MergedTransports};
+pub use metrics::TransportMetrics;
+pub use multi_bind::MultiBindUdpTransporter;
+pub use mux::{UdpMux, UdpMuxBuilder, UdpMuxSession};
 pub use peer_addr::PeerAddr;
+pub use peer_addr_map::PeerAddrMap;
+pub use pool::{TcpConnectionPool, TcpConnectionPoolBuilder};
+pub use port_range::FamilyPreference;
+pub use priority::Priority;
+pub use quota::{QuotaTransporter, QuotaTransporterBuilder};
+pub use recv_loop::{spawn_recv_loop, RecvLoopHandle};
+pub use recv_result::RecvResultTransporter;
+pub use relay::{RelayDecoder, RelayEncoder, RelayTcpTransporter};
+pub use retry::{RetryTransporter, RetryTransporterBuilder};
+pub use scripted::{ScriptStep, ScriptedTransport};
 pub use share::RcTransporter;
-pub use tcp::{TcpTransport, TcpTransporter, TcpTransporterBuilder};
-pub use tcp_listener::{TcpListener, TcpListenerBuilder};
-pub use udp::{UdpTransport, UdpTransporter, UdpTransporterBuilder};
+pub use slow_log::{SlowLogTransporter, SlowLogTransporterBuilder, SlowOp};
+#[cfg(unix)]
+pub use stdio::{StdioTransporter, StdioTransporterBuilder};
+pub use striped::StripedTcpTransporter;
+pub use tcp::{
+    ConnectHandle, ConnectionState, StreamTransporter, TcpTransport, TcpTransporter,
+    TcpTransporterBuilder,
+};
+pub use tcp_listener::{
+    CodecDecoderFactory, CodecEncoderFactory, CodecFactory, RawTcpListener, TcpListener,
+    TcpListenerBuilder,
+};
+pub use tee::TeeTransporter;
+pub use udp::{
+    ChecksumKind, DropOrBlock, RecvMetadata, UdpTransport, UdpTransporter, UdpTransporterBuilder,
+};
+pub use udp_pool::UdpTransporterPool;
+pub use window::WindowedTransporter;
 
+mod aggregate;
 mod base;
+mod buffer_pool;
+mod channel;
+mod clock;
+mod codec;
+mod correlate;
+mod dedup;
 mod error;
 mod fixed_peer;
+mod framing;
+mod line;
+mod load_balance;
+mod merge;
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod multi_bind;
+mod mux;
 mod peer_addr;
+mod peer_addr_map;
+mod pool;
+mod port_range;
+mod priority;
+mod quota;
+mod recv_loop;
+mod recv_result;
+mod relay;
+mod retry;
+mod scripted;
 mod share;
+mod slow_log;
+mod socks5;
+#[cfg(unix)]
+mod stdio;
+mod striped;
+mod sys;
 mod tcp;
 mod tcp_listener;
+mod tee;
 mod udp;
+mod udp_pool;
+mod window;
 
 /// This crate specific [`Result`] type.
 ///
@@ -126,7 +203,6 @@ mod tests {
     use factory::DefaultFactory;
     use futures::Stream;
     use std::result::Result;
-    use trackable;
 
     use super::*;
 
@@ -173,4 +249,29 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn local_socket_addr_works() -> Result<(), trackable::error::MainError> {
+        type Udp = UdpTransporter<Utf8Encoder, Utf8Decoder>;
+        type TcpServer = TcpListener<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>;
+        type TcpClient = TcpTransporter<U8Encoder, U8Decoder>;
+
+        fn local_socket_addr<T: Transport>(t: &T) -> Option<std::net::SocketAddr> {
+            t.local_socket_addr()
+        }
+
+        let udp = fibers_global::execute(Udp::bind("127.0.0.1:0".parse().unwrap()))?;
+        assert_eq!(local_socket_addr(&udp), Some(udp.local_addr()));
+
+        let server = fibers_global::execute(TcpServer::listen("127.0.0.1:0".parse().unwrap()))?;
+        let client = fibers_global::execute(TcpClient::connect(server.local_addr()))?;
+        assert_eq!(local_socket_addr(&client), Some(client.local_addr()));
+
+        let shared = RcTransporter::new(udp);
+        assert_eq!(
+            local_socket_addr(&shared),
+            Some(shared.with_inner_ref(|t| t.local_addr()))
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,240 @@
+use bytecodec::bytes::BytesEncoder;
+use bytecodec::{ByteCount, Decode, Encode, Eos, ErrorKind, Result};
+use std::mem;
+use trackable::error::ErrorKindExt;
+
+/// The default maximum length of a line in byte.
+const DEFAULT_MAX_LINE_LEN: usize = 64 * 1024;
+
+/// An encoder that frames each UTF-8 message by a newline (`\n`).
+///
+/// This is useful for implementing line based text protocols
+/// (e.g., `TcpTransporter<LineEncoder, LineDecoder>`).
+#[derive(Debug, Default)]
+pub struct LineEncoder {
+    bytes: BytesEncoder<Vec<u8>>,
+    crlf: bool,
+}
+impl LineEncoder {
+    /// Makes a new `LineEncoder` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether to terminate each line by `\r\n` instead of `\n`.
+    ///
+    /// The default value is `false`.
+    pub fn crlf(mut self, enabled: bool) -> Self {
+        self.crlf = enabled;
+        self
+    }
+}
+impl Encode for LineEncoder {
+    type Item = String;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> Result<usize> {
+        track!(self.bytes.encode(buf, eos))
+    }
+
+    /// # Errors
+    ///
+    /// If the given line contains a newline, this will return an `ErrorKind::InvalidInput` error.
+    fn start_encoding(&mut self, item: Self::Item) -> Result<()> {
+        track_assert!(
+            !item.contains('\n'),
+            ErrorKind::InvalidInput,
+            "A line must not contain any newlines"
+        );
+        let mut bytes = item.into_bytes();
+        if self.crlf {
+            bytes.push(b'\r');
+        }
+        bytes.push(b'\n');
+        track!(self.bytes.start_encoding(bytes))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.bytes.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.bytes.is_idle()
+    }
+}
+
+/// A decoder that decodes UTF-8 messages delimited by a newline (`\n`).
+///
+/// The trailing newline is not included in the decoded messages.
+#[derive(Debug)]
+pub struct LineDecoder {
+    buf: Vec<u8>,
+    line: Option<String>,
+    max_line_len: usize,
+    crlf: bool,
+}
+impl LineDecoder {
+    /// Makes a new `LineDecoder` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum length of a line in byte (excluding the line terminator).
+    ///
+    /// If a longer line is received, `decode` will return an `ErrorKind::InvalidInput` error.
+    ///
+    /// The default value is `65536`.
+    pub fn max_line_len(mut self, len: usize) -> Self {
+        self.max_line_len = len;
+        self
+    }
+
+    /// Sets whether to accept `\r\n` as the line terminator.
+    ///
+    /// If `true`, a `\r` preceding `\n` is removed from the decoded messages.
+    ///
+    /// The default value is `false`.
+    pub fn crlf(mut self, enabled: bool) -> Self {
+        self.crlf = enabled;
+        self
+    }
+
+    fn check_line_len(&self, terminated: bool) -> Result<()> {
+        let mut len = self.buf.len();
+        if self.crlf && self.buf.last() == Some(&b'\r') {
+            // The `\r` may be (or is) a part of the line terminator.
+            len -= 1;
+        }
+        track_assert!(
+            len <= self.max_line_len,
+            ErrorKind::InvalidInput,
+            "Too long line: max_line_len={}, terminated={}",
+            self.max_line_len,
+            terminated
+        );
+        Ok(())
+    }
+}
+impl Default for LineDecoder {
+    fn default() -> Self {
+        LineDecoder {
+            buf: Vec::new(),
+            line: None,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            crlf: false,
+        }
+    }
+}
+impl Decode for LineDecoder {
+    type Item = String;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> Result<usize> {
+        if self.line.is_some() {
+            return Ok(0);
+        }
+        let (size, terminated) = match buf.iter().position(|&b| b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (buf.len(), false),
+        };
+        let content_len = if terminated { size - 1 } else { size };
+        self.buf.extend_from_slice(&buf[..content_len]);
+        if let Err(e) = track!(self.check_line_len(terminated)) {
+            self.buf.clear();
+            return Err(e);
+        }
+        if terminated {
+            if self.crlf && self.buf.last() == Some(&b'\r') {
+                self.buf.pop();
+            }
+            let bytes = mem::take(&mut self.buf);
+            let line =
+                track!(String::from_utf8(bytes).map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+            self.line = Some(line);
+        } else {
+            track_assert!(
+                !eos.is_reached() || self.buf.is_empty(),
+                ErrorKind::UnexpectedEos
+            );
+        }
+        Ok(size)
+    }
+
+    fn finish_decoding(&mut self) -> Result<Self::Item> {
+        let line = track_assert_some!(self.line.take(), ErrorKind::IncompleteDecoding);
+        Ok(line)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if self.line.is_some() {
+            ByteCount::Finite(0)
+        } else {
+            ByteCount::Unknown
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.line.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecodec::io::IoEncodeExt;
+
+    fn decode_all(decoder: &mut LineDecoder, mut input: &[u8]) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        while !input.is_empty() {
+            let size = track!(decoder.decode(input, Eos::new(false)))?;
+            input = &input[size..];
+            if decoder.is_idle() {
+                lines.push(track!(decoder.finish_decoding())?);
+            }
+        }
+        Ok(lines)
+    }
+
+    #[test]
+    fn line_encoder_works() -> Result<()> {
+        let mut buf = Vec::new();
+        let mut encoder = LineEncoder::new();
+        encoder.start_encoding("foo".to_owned())?;
+        encoder.encode_all(&mut buf)?;
+        let mut encoder = LineEncoder::new().crlf(true);
+        encoder.start_encoding("bar".to_owned())?;
+        encoder.encode_all(&mut buf)?;
+        assert_eq!(buf, b"foo\nbar\r\n");
+
+        assert_eq!(
+            LineEncoder::new()
+                .start_encoding("a\nb".to_owned())
+                .map_err(|e| *e.kind()),
+            Err(ErrorKind::InvalidInput)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn line_decoder_works() -> Result<()> {
+        // CRLF
+        let mut decoder = LineDecoder::new().crlf(true);
+        let lines = decode_all(&mut decoder, b"foo\r\nbar\n\r\n")?;
+        assert_eq!(lines, ["foo", "bar", ""]);
+
+        // A line split across reads
+        let mut decoder = LineDecoder::new();
+        assert!(decode_all(&mut decoder, b"hel")?.is_empty());
+        assert!(decode_all(&mut decoder, b"lo, wor")?.is_empty());
+        let lines = decode_all(&mut decoder, b"ld\nfoo\n")?;
+        assert_eq!(lines, ["hello, world", "foo"]);
+
+        // An over-length line
+        let mut decoder = LineDecoder::new().max_line_len(3);
+        assert_eq!(decode_all(&mut decoder, b"abc\n")?, ["abc"]);
+        assert!(decode_all(&mut decoder, b"ab")?.is_empty());
+        assert_eq!(
+            decode_all(&mut decoder, b"cd\n").map_err(|e| *e.kind()),
+            Err(ErrorKind::InvalidInput)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{ErrorKind, PollRecv, PollSend, Result, Transport, UdpTransport};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+
+type KeyFn<T> = Box<dyn Fn(&T) -> u64 + Send>;
+
+/// The policy used by [`LoadBalancedUdpTransporter`] to choose the destination of each item.
+///
+/// [`LoadBalancedUdpTransporter`]: ./struct.LoadBalancedUdpTransporter.html
+pub enum BalancePolicy<T> {
+    /// The destinations are chosen in round-robin order.
+    RoundRobin,
+
+    /// The destinations are chosen at random.
+    Random,
+
+    /// The destination is chosen by the key derived from each item (i.e., `destinations[key % len]`).
+    ///
+    /// Items having the same key are always sent to the same destination
+    /// as long as the set of the destinations is unchanged.
+    HashByKey(KeyFn<T>),
+}
+impl<T> BalancePolicy<T> {
+    /// Makes a `BalancePolicy::HashByKey` instance with the given key function.
+    pub fn hash_by_key<F>(f: F) -> Self
+    where
+        F: Fn(&T) -> u64 + Send + 'static,
+    {
+        BalancePolicy::HashByKey(Box::new(f))
+    }
+}
+impl<T> fmt::Debug for BalancePolicy<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BalancePolicy::RoundRobin => write!(f, "RoundRobin"),
+            BalancePolicy::Random => write!(f, "Random"),
+            BalancePolicy::HashByKey(_) => write!(f, "HashByKey(_)"),
+        }
+    }
+}
+
+/// An implementation of [`Transport`] that distributes outgoing items among multiple destinations.
+///
+/// This is useful for UDP clients talking to a replicated service.
+/// `start_send((), item)` sends `item` to one of the destinations chosen by the [`BalancePolicy`].
+///
+/// Items received by `poll_recv` are reported with `()` as the peer address.
+/// Use [`poll_recv_from`] to obtain the actual source of each item.
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`BalancePolicy`]: ./enum.BalancePolicy.html
+/// [`poll_recv_from`]: ./struct.LoadBalancedUdpTransporter.html#method.poll_recv_from
+pub struct LoadBalancedUdpTransporter<T: Transport> {
+    inner: T,
+    destinations: Vec<SocketAddr>,
+    policy: BalancePolicy<T::SendItem>,
+    next_destination: usize,
+    random_state: RandomState,
+    random_seq: u64,
+}
+impl<T: UdpTransport> LoadBalancedUdpTransporter<T> {
+    /// Makes a new `LoadBalancedUdpTransporter` instance.
+    ///
+    /// # Errors
+    ///
+    /// If `destinations` is empty, this will return an `ErrorKind::InvalidInput` error.
+    pub fn new(
+        inner: T,
+        destinations: Vec<SocketAddr>,
+        policy: BalancePolicy<T::SendItem>,
+    ) -> Result<Self> {
+        track_assert!(
+            !destinations.is_empty(),
+            ErrorKind::InvalidInput,
+            "No destinations are given"
+        );
+        Ok(LoadBalancedUdpTransporter {
+            inner,
+            destinations,
+            policy,
+            next_destination: 0,
+            random_state: RandomState::new(),
+            random_seq: 0,
+        })
+    }
+
+    /// Returns the destination addresses among which items are distributed.
+    pub fn destinations(&self) -> &[SocketAddr] {
+        &self.destinations
+    }
+
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Polls reception of an item along with the address of the peer that sent it.
+    pub fn poll_recv_from(&mut self) -> PollRecv<(SocketAddr, T::RecvItem)> {
+        track!(self.inner.poll_recv())
+    }
+
+    fn choose_destination(&mut self, item: &T::SendItem) -> SocketAddr {
+        let n = self.destinations.len();
+        let i = match self.policy {
+            BalancePolicy::RoundRobin => {
+                let i = self.next_destination;
+                self.next_destination = (i + 1) % n;
+                i
+            }
+            BalancePolicy::Random => {
+                let hash = self.random_state.hash_one(self.random_seq);
+                self.random_seq += 1;
+                (hash % n as u64) as usize
+            }
+            BalancePolicy::HashByKey(ref f) => (f(item) % n as u64) as usize,
+        };
+        self.destinations[i]
+    }
+}
+impl<T: UdpTransport> Transport for LoadBalancedUdpTransporter<T> {
+    type PeerAddr = ();
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, (): Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        let destination = self.choose_destination(&item);
+        track!(self.inner.start_send(destination, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        let polled = track!(self.poll_recv_from())?;
+        Ok(polled.map(|item| item.map(|(_, item)| ((), item))))
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        Some(self.inner.local_addr())
+    }
+}
+impl<T: Transport + fmt::Debug> fmt::Debug for LoadBalancedUdpTransporter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "LoadBalancedUdpTransporter {{ inner: {:?}, destinations: {:?}, policy: {:?}, .. }}",
+            self.inner, self.destinations, self.policy
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::bind;
+    use crate::{wait_recv, wait_send};
+
+    #[test]
+    fn round_robin_works() -> Result<()> {
+        let mut servers = vec![bind()?, bind()?, bind()?];
+        let destinations = servers.iter().map(|s| s.local_addr()).collect();
+        let mut client =
+            LoadBalancedUdpTransporter::new(bind()?, destinations, BalancePolicy::RoundRobin)?;
+        let client_addr = client.inner_ref().local_addr();
+
+        for i in 0..6 {
+            client.start_send((), i.to_string())?;
+        }
+        let mut client = fibers_global::execute(wait_send(client))?;
+
+        for (i, server) in servers.drain(..).enumerate() {
+            let (server, peer, item) = fibers_global::execute(wait_recv(server))?;
+            assert_eq!(peer, client_addr);
+            assert_eq!(item, i.to_string());
+            let (mut server, _, item) = fibers_global::execute(wait_recv(server))?;
+            assert_eq!(item, (i + 3).to_string());
+
+            server.start_send(peer, format!("reply{}", i))?;
+            fibers_global::execute(wait_send(server))?;
+            let (c, (), item) = fibers_global::execute(wait_recv(client))?;
+            assert_eq!(item, format!("reply{}", i));
+            client = c;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn hash_by_key_works() -> Result<()> {
+        let destinations = vec![
+            "127.0.0.1:1000".parse().unwrap(),
+            "127.0.0.1:2000".parse().unwrap(),
+        ];
+        let policy = BalancePolicy::hash_by_key(|item: &String| item.len() as u64);
+        let mut client = LoadBalancedUdpTransporter::new(bind()?, destinations, policy)?;
+        assert_eq!(client.choose_destination(&"ab".to_owned()).port(), 1000);
+        assert_eq!(client.choose_destination(&"abc".to_owned()).port(), 2000);
+        assert_eq!(client.choose_destination(&"abcd".to_owned()).port(), 1000);
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{Error, Transport};
+use futures::future::Either;
+use futures::{Async, Poll, Stream};
+
+/// Returns a stream that merges the items received by the given two transporters.
+///
+/// The items received by `a` are yielded as `Either::A((peer, item))`
+/// and the ones received by `b` are yielded as `Either::B((peer, item))`.
+/// The transporters are polled alternately so that a busy transporter does not starve the other.
+/// The stream terminates once both of the transporters have terminated.
+///
+/// Note that the stream only receives items.
+/// Outgoing items should be sent via [`MergedTransports::inner_mut`].
+///
+/// [`MergedTransports::inner_mut`]: ./struct.MergedTransports.html#method.inner_mut
+pub fn merge_transports<A, B>(a: A, b: B) -> MergedTransports<A, B>
+where
+    A: Transport,
+    B: Transport,
+{
+    MergedTransports {
+        a,
+        b,
+        a_eos: false,
+        b_eos: false,
+        a_first: true,
+    }
+}
+
+/// A stream returned by [`merge_transports`].
+///
+/// [`merge_transports`]: ./fn.merge_transports.html
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct MergedTransports<A, B> {
+    a: A,
+    b: B,
+    a_eos: bool,
+    b_eos: bool,
+    a_first: bool,
+}
+impl<A, B> MergedTransports<A, B>
+where
+    A: Transport,
+    B: Transport,
+{
+    /// Returns references to the merged transporters.
+    pub fn inner_ref(&self) -> (&A, &B) {
+        (&self.a, &self.b)
+    }
+
+    /// Returns mutable references to the merged transporters.
+    pub fn inner_mut(&mut self) -> (&mut A, &mut B) {
+        (&mut self.a, &mut self.b)
+    }
+
+    /// Takes ownership of the instance, and returns the merged transporters.
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+
+    fn poll_a(&mut self) -> Poll<Option<<Self as Stream>::Item>, Error> {
+        if self.a_eos {
+            return Ok(Async::NotReady);
+        }
+        match track!(self.a.poll_recv())? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => {
+                self.a_eos = true;
+                Ok(Async::NotReady)
+            }
+            Async::Ready(Some(x)) => Ok(Async::Ready(Some(Either::A(x)))),
+        }
+    }
+
+    fn poll_b(&mut self) -> Poll<Option<<Self as Stream>::Item>, Error> {
+        if self.b_eos {
+            return Ok(Async::NotReady);
+        }
+        match track!(self.b.poll_recv())? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => {
+                self.b_eos = true;
+                Ok(Async::NotReady)
+            }
+            Async::Ready(Some(x)) => Ok(Async::Ready(Some(Either::B(x)))),
+        }
+    }
+}
+impl<A, B> Stream for MergedTransports<A, B>
+where
+    A: Transport,
+    B: Transport,
+{
+    type Item = Either<(A::PeerAddr, A::RecvItem), (B::PeerAddr, B::RecvItem)>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let a_first = self.a_first;
+        self.a_first = !a_first;
+        let polled = if a_first {
+            match track!(self.poll_a())? {
+                Async::NotReady => track!(self.poll_b())?,
+                polled => polled,
+            }
+        } else {
+            match track!(self.poll_b())? {
+                Async::NotReady => track!(self.poll_a())?,
+                polled => polled,
+            }
+        };
+        if polled.is_not_ready() && self.a_eos && self.b_eos {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(polled)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransporter;
+    use crate::{wait_send, Result, UdpTransport, UdpTransporter};
+    use bytecodec::bytes::{Utf8Decoder, Utf8Encoder};
+
+    type Udp = UdpTransporter<Utf8Encoder, Utf8Decoder>;
+
+    #[test]
+    fn merge_transports_works() -> Result<()> {
+        let udp = fibers_global::execute(Udp::bind("127.0.0.1:0".parse().unwrap()))?;
+        let mut sender = fibers_global::execute(Udp::bind("127.0.0.1:0".parse().unwrap()))?;
+        sender.start_send(udp.local_addr(), "foo".to_owned())?;
+        let sender = fibers_global::execute(wait_send(sender))?;
+
+        let mut mock = MockTransporter::<(), (), u32>::new();
+        mock.incoming.push_back(((), 1));
+        mock.incoming.push_back(((), 2));
+        mock.eos = true;
+
+        let merged = merge_transports(udp, mock);
+        let items = fibers_global::execute(merged.take(3).collect())?;
+        let mut from_udp = Vec::new();
+        let mut from_mock = Vec::new();
+        for item in items {
+            match item {
+                Either::A(x) => from_udp.push(x),
+                Either::B(x) => from_mock.push(x),
+            }
+        }
+        assert_eq!(from_udp, [(sender.local_addr(), "foo".to_owned())]);
+        assert_eq!(from_mock, [((), 1), ((), 2)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+/// Metrics collected by a transporter.
+///
+/// See [`TcpTransporter::metrics`] for details.
+///
+/// [`TcpTransporter::metrics`]: ./struct.TcpTransporter.html#method.metrics
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TransportMetrics {
+    /// The number of the items that have been taken from the outgoing queue to be sent.
+    pub sent_messages: u64,
+
+    /// The number of the items that have been received.
+    pub received_messages: u64,
+
+    /// The number of the items of which queue latency has been measured.
+    pub queue_latency_samples: u64,
+
+    /// The sum of the measured queue latencies.
+    ///
+    /// The queue latency of an item is the time from its enqueue (e.g., by `start_send`)
+    /// to its dequeue for being written to the underlying stream.
+    pub total_queue_latency: Duration,
+}
+impl TransportMetrics {
+    /// Makes a new `TransportMetrics` instance of which counters are all zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the average queue latency of the measured items.
+    ///
+    /// If no item has been measured, this will return `None`.
+    pub fn avg_queue_latency(&self) -> Option<Duration> {
+        if self.queue_latency_samples == 0 {
+            None
+        } else {
+            let nanos =
+                self.total_queue_latency.as_nanos() / u128::from(self.queue_latency_samples);
+            Some(Duration::from_nanos(nanos as u64))
+        }
+    }
+
+    pub(crate) fn record_queue_latency(&mut self, latency: Duration) {
+        self.queue_latency_samples += 1;
+        self.total_queue_latency += latency;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn avg_queue_latency_works() {
+        let mut metrics = TransportMetrics::new();
+        assert_eq!(metrics.avg_queue_latency(), None);
+
+        metrics.record_queue_latency(Duration::from_millis(10));
+        metrics.record_queue_latency(Duration::from_millis(30));
+        assert_eq!(metrics.avg_queue_latency(), Some(Duration::from_millis(20)));
+    }
+}
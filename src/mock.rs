@@ -0,0 +1,141 @@
+//! Test-only transport and clock implementations.
+use crate::{Clock, Error, PeerAddr, PollRecv, PollSend, Result, Transport, UdpTransporter};
+use bytecodec::bytes::{Utf8Decoder, Utf8Encoder};
+use futures::{Async, Future, Poll};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// An in-memory implementation of [`Transport`] whose behavior can be controlled from tests.
+///
+/// [`Transport`]: ./trait.Transport.html
+#[derive(Debug)]
+pub struct MockTransporter<P, S, R> {
+    /// Items that have been completely sent.
+    pub sent: Vec<(P, S)>,
+
+    /// Items that have been enqueued but not yet sent.
+    pub pending: VecDeque<(P, S)>,
+
+    /// Errors to be returned by the subsequent `poll_send` calls.
+    ///
+    /// When an error is returned, the oldest pending item is discarded.
+    pub send_errors: VecDeque<Error>,
+
+    /// Items to be returned by the subsequent `poll_recv` calls.
+    pub incoming: VecDeque<(P, R)>,
+
+    /// Errors to be returned by the subsequent `poll_recv` calls.
+    pub recv_errors: VecDeque<Error>,
+
+    /// If `true`, `poll_recv` returns `Ok(Async::Ready(None))` once `incoming` is drained.
+    pub eos: bool,
+}
+impl<P, S, R> MockTransporter<P, S, R> {
+    /// Makes a new `MockTransporter` instance.
+    pub fn new() -> Self {
+        MockTransporter {
+            sent: Vec::new(),
+            pending: VecDeque::new(),
+            send_errors: VecDeque::new(),
+            incoming: VecDeque::new(),
+            recv_errors: VecDeque::new(),
+            eos: false,
+        }
+    }
+}
+impl<P: PeerAddr, S, R> Transport for MockTransporter<P, S, R> {
+    type PeerAddr = P;
+    type SendItem = S;
+    type RecvItem = R;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        self.pending.push_back((peer, item));
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        if let Some(e) = self.send_errors.pop_front() {
+            self.pending.pop_front();
+            return Err(track!(e));
+        }
+        self.sent.extend(self.pending.drain(..));
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        if let Some(e) = self.recv_errors.pop_front() {
+            return Err(track!(e));
+        }
+        if let Some(item) = self.incoming.pop_front() {
+            Ok(Async::Ready(Some(item)))
+        } else if self.eos {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// A [`Clock`] implementation of which time is advanced manually.
+///
+/// [`Clock`]: ./trait.Clock.html
+#[derive(Debug, Clone)]
+pub struct MockClock(Arc<Mutex<Instant>>);
+impl MockClock {
+    /// Makes a new `MockClock` instance.
+    pub fn new() -> Self {
+        MockClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// Advances the time of the clock (and its clones) by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().expect("never fails") += duration;
+    }
+}
+impl Clock for MockClock {
+    type Delay = MockDelay;
+
+    fn now(&self) -> Instant {
+        *self.0.lock().expect("never fails")
+    }
+
+    fn delay(&self, duration: Duration) -> Self::Delay {
+        MockDelay {
+            clock: self.clone(),
+            deadline: self.now() + duration,
+        }
+    }
+}
+
+/// The future returned by `MockClock::delay`.
+#[derive(Debug)]
+pub struct MockDelay {
+    clock: MockClock,
+    deadline: Instant,
+}
+impl Future for MockDelay {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.clock.now() >= self.deadline {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// A peer address identified by a name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PeerName(pub &'static str);
+impl PeerAddr for PeerName {}
+
+/// A UDP transporter that sends and receives UTF-8 strings.
+pub type Udp = UdpTransporter<Utf8Encoder, Utf8Decoder>;
+
+/// Binds a `Udp` transporter to an ephemeral port on the loopback address.
+pub fn bind() -> Result<Udp> {
+    fibers_global::execute(Udp::bind("127.0.0.1:0".parse().unwrap()))
+}
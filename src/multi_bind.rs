@@ -0,0 +1,188 @@
+use crate::{ErrorKind, PollRecv, PollSend, Result, Transport, UdpTransport};
+use futures::Async;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// An implementation of [`Transport`] that unifies multiple UDP transporters bound to different local addresses.
+///
+/// This is useful on multi-homed hosts.
+/// `poll_recv` receives items from all of the transporters in round-robin order,
+/// and [`poll_recv_tagged`] additionally reports the local address on which each item was received.
+/// The transporter used for sending an item is chosen by the routing function given to [`new`].
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`new`]: ./struct.MultiBindUdpTransporter.html#method.new
+/// [`poll_recv_tagged`]: ./struct.MultiBindUdpTransporter.html#method.poll_recv_tagged
+pub struct MultiBindUdpTransporter<T, F> {
+    transporters: Vec<T>,
+    local_addrs: Vec<SocketAddr>,
+    router: F,
+    next_recv: usize,
+}
+impl<T, F> MultiBindUdpTransporter<T, F>
+where
+    T: UdpTransport,
+    F: FnMut(&SocketAddr, &[SocketAddr]) -> usize,
+{
+    /// Makes a new `MultiBindUdpTransporter` instance.
+    ///
+    /// `router` is invoked with the destination peer and the local addresses of `transporters`,
+    /// and returns the index of the transporter used for sending items to the peer.
+    ///
+    /// # Errors
+    ///
+    /// If `transporters` is empty, this will return an `ErrorKind::InvalidInput` error.
+    pub fn new(transporters: Vec<T>, router: F) -> Result<Self> {
+        track_assert!(
+            !transporters.is_empty(),
+            ErrorKind::InvalidInput,
+            "No transporters are given"
+        );
+        let local_addrs = transporters.iter().map(|t| t.local_addr()).collect();
+        Ok(MultiBindUdpTransporter {
+            transporters,
+            local_addrs,
+            router,
+            next_recv: 0,
+        })
+    }
+
+    /// Returns the local addresses to which the inner transporters are bound.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Returns a reference to the inner transporters.
+    pub fn transporters_ref(&self) -> &[T] {
+        &self.transporters
+    }
+
+    /// Returns a mutable reference to the inner transporters.
+    pub fn transporters_mut(&mut self) -> &mut [T] {
+        &mut self.transporters
+    }
+
+    /// Polls reception of an item from a peer.
+    ///
+    /// Unlike `poll_recv`, the resulting tuple also contains the local address on which the item was received
+    /// (i.e., `(local_addr, peer, item)`).
+    pub fn poll_recv_tagged(&mut self) -> PollRecv<(SocketAddr, SocketAddr, T::RecvItem)> {
+        let n = self.transporters.len();
+        let mut terminated = 0;
+        for _ in 0..n {
+            let i = self.next_recv;
+            self.next_recv = (self.next_recv + 1) % n;
+            match track!(self.transporters[i].poll_recv())? {
+                Async::NotReady => {}
+                Async::Ready(None) => terminated += 1,
+                Async::Ready(Some((peer, item))) => {
+                    return Ok(Async::Ready(Some((self.local_addrs[i], peer, item))));
+                }
+            }
+        }
+        if terminated == n {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+impl<T, F> Transport for MultiBindUdpTransporter<T, F>
+where
+    T: UdpTransport,
+    F: FnMut(&SocketAddr, &[SocketAddr]) -> usize,
+{
+    type PeerAddr = SocketAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        let i = (self.router)(&peer, &self.local_addrs);
+        track_assert!(
+            i < self.transporters.len(),
+            ErrorKind::InvalidInput,
+            "Routing function returned an out of range index: {}",
+            i
+        );
+        track!(self.transporters[i].start_send(peer, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        let mut ready = true;
+        for t in &mut self.transporters {
+            ready &= track!(t.poll_send())?.is_ready();
+        }
+        if ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        let polled = track!(self.poll_recv_tagged())?;
+        Ok(polled.map(|item| item.map(|(_, peer, item)| (peer, item))))
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        for t in &mut self.transporters {
+            track!(t.set_recv_paused(paused))?;
+        }
+        Ok(())
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.first().cloned()
+    }
+}
+impl<T, F> fmt::Debug for MultiBindUdpTransporter<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MultiBindUdpTransporter {{ local_addrs: {:?}, .. }}",
+            self.local_addrs
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{drive, wait_recv, wait_send, UdpTransporter};
+    use bytecodec::bytes::{Utf8Decoder, Utf8Encoder};
+
+    type Udp = UdpTransporter<Utf8Encoder, Utf8Decoder>;
+
+    fn bind(addr: &str) -> Result<Udp> {
+        fibers_global::execute(Udp::bind(addr.parse().unwrap()))
+    }
+
+    #[test]
+    fn multi_bind_works() -> Result<()> {
+        let transporters = vec![bind("127.0.0.1:0")?, bind("127.0.0.2:0")?];
+        let multi = MultiBindUdpTransporter::new(transporters, |peer, locals| {
+            locals
+                .iter()
+                .position(|local| local.ip() == peer.ip())
+                .unwrap_or(0)
+        })?;
+        let local_addrs = multi.local_addrs().to_owned();
+
+        let mut client = bind("127.0.0.2:0")?;
+        client.start_send(local_addrs[1], "foo".to_owned())?;
+        let client = fibers_global::execute(wait_send(client))?;
+
+        let (mut multi, received) = fibers_global::execute(drive(multi, |t| t.poll_recv_tagged()))?;
+        let (local, peer, item) = received.expect("never fails");
+        assert_eq!(local, local_addrs[1]);
+        assert_eq!(peer, client.local_addr());
+        assert_eq!(item, "foo");
+
+        multi.start_send(peer, "bar".to_owned())?;
+        let _ = fibers_global::execute(wait_send(multi))?;
+        let (_, from, item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(from, local_addrs[1]);
+        assert_eq!(item, "bar");
+        Ok(())
+    }
+}
@@ -0,0 +1,441 @@
+use crate::{
+    Clock, Error, FibersClock, FixedPeerTransporter, PollRecv, PollSend, RcTransporter, Result,
+    Transport, UdpTransport,
+};
+use fibers::fiber;
+use futures::{Async, Future, Poll, Stream};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// [`UdpMux`] builder.
+///
+/// [`UdpMux`]: ./struct.UdpMux.html
+#[derive(Debug, Clone)]
+pub struct UdpMuxBuilder<C = FibersClock> {
+    session_timeout: Duration,
+    clock: C,
+}
+impl UdpMuxBuilder {
+    /// Makes a new `UdpMuxBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl<C: Clock> UdpMuxBuilder<C> {
+    /// Sets the duration after which a session that has received no datagrams is torn down.
+    ///
+    /// The default value is `60s`.
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        self.session_timeout = timeout;
+        self
+    }
+
+    /// Sets the clock used for timing the session timeout.
+    ///
+    /// The default value is `FibersClock`.
+    pub fn clock<C2: Clock>(self, clock: C2) -> UdpMuxBuilder<C2> {
+        UdpMuxBuilder {
+            session_timeout: self.session_timeout,
+            clock,
+        }
+    }
+
+    /// Makes a new `UdpMux` instance that multiplexes the given transporter.
+    pub fn finish<T: UdpTransport>(self, transporter: T) -> UdpMux<T, C> {
+        UdpMux {
+            transporter: RcTransporter::new(transporter),
+            session_timeout: self.session_timeout,
+            clock: self.clock,
+            sessions: HashMap::new(),
+        }
+    }
+}
+impl Default for UdpMuxBuilder {
+    fn default() -> Self {
+        UdpMuxBuilder {
+            session_timeout: Duration::from_secs(60),
+            clock: FibersClock,
+        }
+    }
+}
+
+/// A multiplexer that serves many logical sessions over a single UDP transporter
+/// (e.g., [`UdpTransporter`]).
+///
+/// A session is created when a datagram is received from an unknown peer for the first time,
+/// and is yielded by the `Stream` implementation of `UdpMux`.
+/// The subsequent datagrams from the same peer are dispatched to the session.
+///
+/// A session is torn down when no datagrams have been received from the peer
+/// for the configured timeout, or when the [`UdpMuxSession`] is dropped.
+///
+/// Note that datagrams are only dispatched while the `UdpMux` stream is being polled,
+/// and that the multiplexer and its sessions have to be polled by the same future
+/// because they share the transporter via `RcTransporter`.
+///
+/// Because they are not `Send`, they cannot be spawned on executors (e.g., by `fibers::Spawn::spawn`
+/// or `fibers_global::execute`).
+/// Instead, run a future that drives the multiplexer and all of its sessions on the current thread
+/// by `fibers::Executor::run_future` (e.g., of an `InPlaceExecutor`).
+///
+/// [`UdpTransporter`]: ./struct.UdpTransporter.html
+/// [`UdpMuxSession`]: ./struct.UdpMuxSession.html
+pub struct UdpMux<T: UdpTransport, C: Clock = FibersClock> {
+    transporter: RcTransporter<T>,
+    session_timeout: Duration,
+    clock: C,
+    sessions: HashMap<SocketAddr, SessionEntry<T::RecvItem, C::Delay>>,
+}
+impl<T: UdpTransport> UdpMux<T> {
+    /// Makes a new `UdpMux` instance with the default settings.
+    ///
+    /// This is equivalent to `UdpMuxBuilder::new().finish(transporter)`.
+    pub fn new(transporter: T) -> Self {
+        UdpMuxBuilder::new().finish(transporter)
+    }
+}
+impl<T: UdpTransport, C: Clock> UdpMux<T, C> {
+    /// Returns the addresses of the peers with which the multiplexer has sessions.
+    pub fn sessions(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.sessions.keys()
+    }
+
+    /// Returns a reference to the shared transporter.
+    pub fn transporter_ref(&self) -> &RcTransporter<T> {
+        &self.transporter
+    }
+
+    /// Returns a mutable reference to the shared transporter.
+    pub fn transporter_mut(&mut self) -> &mut RcTransporter<T> {
+        &mut self.transporter
+    }
+
+    fn new_session(&mut self, peer: SocketAddr, item: T::RecvItem) -> UdpMuxSession<T> {
+        let mut incoming = VecDeque::new();
+        incoming.push_back(item);
+        let state = Rc::new(RefCell::new(SessionState {
+            incoming,
+            closed: false,
+            recv_paused: false,
+            waiting: false,
+        }));
+        self.sessions.insert(
+            peer,
+            SessionEntry {
+                state: state.clone(),
+                idle_timer: None,
+            },
+        );
+        UdpMuxSession {
+            transporter: FixedPeerTransporter::new(peer, peer, self.transporter.clone()),
+            state,
+        }
+    }
+
+    fn poll_sessions(&mut self) -> Result<()> {
+        let timeout = self.session_timeout;
+        let clock = &self.clock;
+        let mut closed = Vec::new();
+        for (&peer, session) in &mut self.sessions {
+            if Rc::strong_count(&session.state) == 1 {
+                closed.push(peer);
+                continue;
+            }
+            let timer = session
+                .idle_timer
+                .get_or_insert_with(|| clock.delay(timeout));
+            let expired = track!(timer.poll())?;
+            if expired.is_ready() {
+                closed.push(peer);
+            }
+        }
+        for peer in closed {
+            if let Some(session) = self.sessions.remove(&peer) {
+                session.close();
+            }
+        }
+        Ok(())
+    }
+}
+impl<T: UdpTransport, C: Clock> Stream for UdpMux<T, C> {
+    type Item = UdpMuxSession<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match track!(self.transporter.poll_recv())? {
+                Async::NotReady => break,
+                Async::Ready(None) => {
+                    for (_, session) in self.sessions.drain() {
+                        session.close();
+                    }
+                    return Ok(Async::Ready(None));
+                }
+                Async::Ready(Some((peer, item))) => {
+                    if let Some(session) = self.sessions.get_mut(&peer) {
+                        session.idle_timer = None;
+                        session.push(item);
+                        continue;
+                    }
+                    let session = self.new_session(peer, item);
+                    return Ok(Async::Ready(Some(session)));
+                }
+            }
+        }
+        track!(self.poll_sessions())?;
+        Ok(Async::NotReady)
+    }
+}
+impl<T: UdpTransport, C: Clock> fmt::Debug for UdpMux<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "UdpMux {{ sessions: {:?}, session_timeout: {:?}, .. }}",
+            self.sessions.keys().collect::<Vec<_>>(),
+            self.session_timeout
+        )
+    }
+}
+
+/// A logical session with a peer, created by [`UdpMux`].
+///
+/// Outgoing items are sent to the peer via the socket shared with the multiplexer.
+/// After the session has been torn down by the multiplexer,
+/// `poll_recv` returns `Ok(Async::Ready(None))` once the pending items have been drained.
+///
+/// Pausing the reception of a session (by `set_recv_paused`) only holds back the items of the session.
+/// The multiplexer keeps reading the shared socket for the other sessions,
+/// and the items from the peer of the paused session are buffered until it is resumed.
+///
+/// [`UdpMux`]: ./struct.UdpMux.html
+pub struct UdpMuxSession<T: UdpTransport> {
+    transporter: FixedPeerTransporter<RcTransporter<T>>,
+    state: Rc<RefCell<SessionState<T::RecvItem>>>,
+}
+impl<T: UdpTransport> UdpMuxSession<T> {
+    /// Returns the address of the peer of the session.
+    pub fn peer_addr(&self) -> SocketAddr {
+        *self.transporter.exterior_peer()
+    }
+
+    /// Returns `true` if the session has been torn down by the multiplexer, otherwise `false`.
+    pub fn is_closed(&self) -> bool {
+        self.state.borrow().closed
+    }
+}
+impl<T: UdpTransport> Transport for UdpMuxSession<T> {
+    type PeerAddr = SocketAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        track!(self.transporter.start_send(peer, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.transporter.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        let mut state = self.state.borrow_mut();
+        if state.recv_paused {
+            Ok(Async::NotReady)
+        } else if let Some(item) = state.incoming.pop_front() {
+            Ok(Async::Ready(Some((self.peer_addr(), item))))
+        } else if state.closed {
+            Ok(Async::Ready(None))
+        } else {
+            state.waiting = true;
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        state.recv_paused = paused;
+        if !paused && (state.closed || !state.incoming.is_empty()) {
+            // The items that arrived while paused may have no one to wake up the session.
+            state.waiting = true;
+            state.wake();
+        }
+        Ok(())
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.transporter.local_socket_addr()
+    }
+}
+impl<T: UdpTransport> UdpTransport for UdpMuxSession<T> {
+    fn local_addr(&self) -> SocketAddr {
+        self.transporter.local_addr()
+    }
+}
+impl<T: UdpTransport> fmt::Debug for UdpMuxSession<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "UdpMuxSession {{ peer_addr: {}, closed: {}, .. }}",
+            self.peer_addr(),
+            self.is_closed()
+        )
+    }
+}
+
+struct SessionEntry<T, D> {
+    state: Rc<RefCell<SessionState<T>>>,
+    idle_timer: Option<D>,
+}
+impl<T, D> SessionEntry<T, D> {
+    fn push(&self, item: T) {
+        let mut state = self.state.borrow_mut();
+        state.incoming.push_back(item);
+        state.wake();
+    }
+
+    fn close(self) {
+        let mut state = self.state.borrow_mut();
+        state.closed = true;
+        state.wake();
+    }
+}
+
+struct SessionState<T> {
+    incoming: VecDeque<T>,
+    closed: bool,
+    recv_paused: bool,
+    waiting: bool,
+}
+impl<T> SessionState<T> {
+    fn wake(&mut self) {
+        // The multiplexer and its sessions share the same fiber (they are `!Send`),
+        // so rescheduling the current fiber is enough to let the waiting session be polled again.
+        if self.waiting {
+            self.waiting = false;
+            let _: Poll<(), ()> = fiber::yield_poll();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::bind;
+    use crate::{wait_recv, wait_send};
+    use fibers::{Executor, InPlaceExecutor};
+
+    /// Runs the given future, which does not have to be `Send`, on the current thread.
+    fn run<F: Future>(future: F) -> std::result::Result<F::Item, F::Error> {
+        let mut executor = InPlaceExecutor::new().expect("never fails");
+        executor.run_future(future).expect("never fails")
+    }
+
+    #[test]
+    fn udp_mux_works() -> Result<()> {
+        let server = bind()?;
+        let server_addr = server.local_addr();
+
+        let mut client0 = bind()?;
+        let mut client1 = bind()?;
+        client0.start_send(server_addr, "foo".to_owned())?;
+        let client0 = fibers_global::execute(wait_send(client0))?;
+        client1.start_send(server_addr, "bar".to_owned())?;
+        let client1 = fibers_global::execute(wait_send(client1))?;
+
+        let future = futures::lazy(move || {
+            let mux = UdpMux::new(server);
+            let first = mux.into_future().map_err(|(e, _)| e);
+            first
+                .and_then(|(session0, mux)| {
+                    let second = mux.into_future().map_err(|(e, _)| e);
+                    second.map(move |(session1, mux)| (session0.unwrap(), session1.unwrap(), mux))
+                })
+                .and_then(|(session0, session1, mux)| {
+                    let session_count = mux.sessions().count();
+                    wait_recv(session0).join(wait_recv(session1)).and_then(
+                        move |((mut session0, peer0, item0), (_, peer1, item1))| {
+                            track!(session0.start_send(peer0, "baz".to_owned()))?;
+                            Ok((
+                                session0,
+                                vec![(peer0, item0), (peer1, item1)],
+                                session_count,
+                            ))
+                        },
+                    )
+                })
+                .and_then(|(session0, received, session_count)| {
+                    wait_send(session0).map(move |_| (received, session_count))
+                })
+        });
+        let (received, session_count) = run(future)?;
+        assert_eq!(session_count, 2);
+        assert_eq!(
+            received,
+            [
+                (client0.local_addr(), "foo".to_owned()),
+                (client1.local_addr(), "bar".to_owned())
+            ]
+        );
+
+        let (_, peer, item) = fibers_global::execute(wait_recv(client0))?;
+        assert_eq!(peer, server_addr);
+        assert_eq!(item, "baz");
+        Ok(())
+    }
+    #[test]
+    fn session_recv_pause_works() -> Result<()> {
+        let server = bind()?;
+        let server_addr = server.local_addr();
+
+        let mut client0 = bind()?;
+        let mut client1 = bind()?;
+        client0.start_send(server_addr, "foo".to_owned())?;
+        let client0 = fibers_global::execute(wait_send(client0))?;
+        client1.start_send(server_addr, "bar".to_owned())?;
+        let mut client1 = fibers_global::execute(wait_send(client1))?;
+
+        let mut mux = UdpMux::new(server);
+        let mut sessions = Vec::new();
+        run(futures::future::poll_fn(|| -> Poll<(), Error> {
+            while let Async::Ready(session) = track!(mux.poll())? {
+                sessions.push(session.expect("never fails"));
+                if sessions.len() == 2 {
+                    return Ok(Async::Ready(()));
+                }
+            }
+            Ok(Async::NotReady)
+        }))?;
+        sessions.sort_by_key(|s| s.peer_addr() == client1.local_addr());
+        let mut session1 = sessions.pop().expect("never fails");
+        let mut session0 = sessions.pop().expect("never fails");
+
+        // Pausing a session does not stop the other sessions from receiving.
+        session0.set_recv_paused(true)?;
+        client1.start_send(server_addr, "baz".to_owned())?;
+        let _client1 = fibers_global::execute(wait_send(client1))?;
+        let mut received = Vec::new();
+        run(futures::future::poll_fn(|| -> Poll<(), Error> {
+            track!(mux.poll())?;
+            assert_eq!(track!(session0.poll_recv())?, Async::NotReady);
+            while let Async::Ready(Some((_, item))) = track!(session1.poll_recv())? {
+                received.push(item);
+            }
+            if received.len() == 2 {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }))?;
+        assert_eq!(received, ["bar", "baz"]);
+
+        session0.set_recv_paused(false)?;
+        assert_eq!(
+            session0.poll_recv()?,
+            Async::Ready(Some((client0.local_addr(), "foo".to_owned())))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use crate::{ErrorKind, PeerAddr, PollRecv, PollSend, Result, Transport};
+use futures::Async;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// An implementation of [`Transport`] that maps the peer addresses of the inner transporter to arbitrary keys.
+///
+/// This generalizes [`FixedPeerTransporter`] to dynamic mappings.
+/// The mappings are kept in a bidirectional table that can be modified by [`insert`] and [`remove`].
+///
+/// When an item is received from an inner peer that is not in the table,
+/// the resolver `F` is invoked to obtain the corresponding key.
+/// If it returns `Some(key)`, the mapping is inserted into the table,
+/// otherwise the item is discarded.
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`FixedPeerTransporter`]: ./struct.FixedPeerTransporter.html
+/// [`insert`]: ./struct.PeerAddrMap.html#method.insert
+/// [`remove`]: ./struct.PeerAddrMap.html#method.remove
+pub struct PeerAddrMap<T: Transport, K, F> {
+    inner: T,
+    exterior_to_interior: HashMap<K, T::PeerAddr>,
+    interior_to_exterior: HashMap<T::PeerAddr, K>,
+    resolver: F,
+}
+impl<T, K, F> PeerAddrMap<T, K, F>
+where
+    T: Transport,
+    K: PeerAddr,
+    F: FnMut(&T::PeerAddr) -> Option<K>,
+{
+    /// Makes a new `PeerAddrMap` instance with an empty table.
+    pub fn new(inner: T, resolver: F) -> Self {
+        PeerAddrMap {
+            inner,
+            exterior_to_interior: HashMap::new(),
+            interior_to_exterior: HashMap::new(),
+            resolver,
+        }
+    }
+
+    /// Inserts the mapping between `key` and `peer` into the table.
+    ///
+    /// The existing mappings for `key` and `peer` are replaced.
+    pub fn insert(&mut self, key: K, peer: T::PeerAddr) {
+        self.remove(&key);
+        if let Some(old_key) = self.interior_to_exterior.remove(&peer) {
+            self.exterior_to_interior.remove(&old_key);
+        }
+        self.exterior_to_interior.insert(key.clone(), peer.clone());
+        self.interior_to_exterior.insert(peer, key);
+    }
+
+    /// Removes the mapping for `key` from the table, and returns the inner peer address mapped to it.
+    pub fn remove(&mut self, key: &K) -> Option<T::PeerAddr> {
+        let peer = self.exterior_to_interior.remove(key)?;
+        self.interior_to_exterior.remove(&peer);
+        Some(peer)
+    }
+
+    /// Returns the inner peer address mapped to `key`.
+    pub fn get(&self, key: &K) -> Option<&T::PeerAddr> {
+        self.exterior_to_interior.get(key)
+    }
+
+    /// Returns the number of the mappings in the table.
+    pub fn len(&self) -> usize {
+        self.exterior_to_interior.len()
+    }
+
+    /// Returns `true` if the table has no mappings, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.exterior_to_interior.is_empty()
+    }
+
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+impl<T, K, F> Transport for PeerAddrMap<T, K, F>
+where
+    T: Transport,
+    K: PeerAddr,
+    F: FnMut(&T::PeerAddr) -> Option<K>,
+{
+    type PeerAddr = K;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        let interior_peer = track_assert_some!(
+            self.exterior_to_interior.get(&peer).cloned(),
+            ErrorKind::InvalidInput,
+            "Unknown peer: {:?}",
+            peer
+        );
+        track!(self.inner.start_send(interior_peer, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        loop {
+            match track!(self.inner.poll_recv())? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::Ready(Some((peer, item))) => {
+                    if let Some(key) = self.interior_to_exterior.get(&peer) {
+                        return Ok(Async::Ready(Some((key.clone(), item))));
+                    }
+                    if let Some(key) = (self.resolver)(&peer) {
+                        self.insert(key.clone(), peer);
+                        return Ok(Async::Ready(Some((key, item))));
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+impl<T, K, F> fmt::Debug for PeerAddrMap<T, K, F>
+where
+    T: Transport + fmt::Debug,
+    K: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PeerAddrMap {{ inner: {:?}, table: {:?}, .. }}",
+            self.inner, self.exterior_to_interior
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{bind, PeerName};
+    use crate::{wait_recv, wait_send, UdpTransport};
+
+    #[test]
+    fn peer_addr_map_works() -> Result<()> {
+        let mut alice = bind()?;
+        let alice_addr = alice.local_addr();
+        let bob = bind()?;
+        let mut carol = bind()?;
+        let carol_addr = carol.local_addr();
+
+        let mut map = PeerAddrMap::new(bob, move |peer: &SocketAddr| {
+            if *peer == carol_addr {
+                Some(PeerName("carol"))
+            } else {
+                None
+            }
+        });
+        map.insert(PeerName("alice"), alice_addr);
+
+        alice.start_send(map.inner_ref().local_addr(), "foo".to_owned())?;
+        fibers_global::execute(wait_send(alice))?;
+        let (map, peer, item) = fibers_global::execute(wait_recv(map))?;
+        assert_eq!(peer, PeerName("alice"));
+        assert_eq!(item, "foo");
+
+        carol.start_send(map.inner_ref().local_addr(), "bar".to_owned())?;
+        let carol = fibers_global::execute(wait_send(carol))?;
+        let (mut map, peer, item) = fibers_global::execute(wait_recv(map))?;
+        assert_eq!(peer, PeerName("carol"));
+        assert_eq!(item, "bar");
+        assert_eq!(map.len(), 2);
+
+        map.start_send(PeerName("carol"), "baz".to_owned())?;
+        let mut map = fibers_global::execute(wait_send(map))?;
+        let (_, _, item) = fibers_global::execute(wait_recv(carol))?;
+        assert_eq!(item, "baz");
+
+        assert_eq!(map.remove(&PeerName("alice")), Some(alice_addr));
+        let e = map
+            .start_send(PeerName("alice"), "qux".to_owned())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+}
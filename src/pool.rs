@@ -0,0 +1,616 @@
+use crate::{
+    Clock, Error, FibersClock, PollRecv, PollSend, Result, TcpTransporter, TcpTransporterBuilder,
+    Transport,
+};
+use bytecodec::{Decode, Encode};
+use factory::Factory;
+use fibers::net::futures::Connect;
+use fibers::net::TcpStream;
+use futures::{Async, Future};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// [`TcpConnectionPool`] builder.
+///
+/// [`TcpConnectionPool`]: ./struct.TcpConnectionPool.html
+#[derive(Debug)]
+pub struct TcpConnectionPoolBuilder<E, D, C = FibersClock> {
+    buf_size: usize,
+    idle_timeout: Option<Duration>,
+    encoder_factory: E,
+    decoder_factory: D,
+    clock: C,
+}
+impl<E, D> TcpConnectionPoolBuilder<E, D>
+where
+    E: Factory + Default,
+    D: Factory + Default,
+    E::Item: Encode,
+    D::Item: Decode,
+{
+    /// Makes a new `TcpConnectionPoolBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::with_codec(E::default(), D::default())
+    }
+}
+impl<E, D> TcpConnectionPoolBuilder<E, D>
+where
+    E: Factory,
+    D: Factory,
+    E::Item: Encode,
+    D::Item: Decode,
+{
+    /// Makes a new `TcpConnectionPoolBuilder` instance with the given encoder and decoder factories.
+    pub fn with_codec(encoder_factory: E, decoder_factory: D) -> Self {
+        TcpConnectionPoolBuilder {
+            buf_size: 8192,
+            idle_timeout: None,
+            encoder_factory,
+            decoder_factory,
+            clock: FibersClock,
+        }
+    }
+}
+impl<E, D, C> TcpConnectionPoolBuilder<E, D, C>
+where
+    E: Factory,
+    D: Factory,
+    E::Item: Encode,
+    D::Item: Decode,
+    C: Clock,
+{
+    /// Sets the application level read/write buffer size of the pooled connections in byte.
+    ///
+    /// The default value is `8192`.
+    pub fn buf_size(mut self, size: usize) -> Self {
+        self.buf_size = size;
+        self
+    }
+
+    /// Sets the duration after which a connection having no activity is closed and evicted from the pool.
+    ///
+    /// By default, idle connections are never evicted.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the clock used for timing the idle timeout.
+    ///
+    /// The default value is `FibersClock`.
+    pub fn clock<C2: Clock>(self, clock: C2) -> TcpConnectionPoolBuilder<E, D, C2> {
+        TcpConnectionPoolBuilder {
+            buf_size: self.buf_size,
+            idle_timeout: self.idle_timeout,
+            encoder_factory: self.encoder_factory,
+            decoder_factory: self.decoder_factory,
+            clock,
+        }
+    }
+
+    /// Builds a new `TcpConnectionPool` instance.
+    pub fn finish(self) -> TcpConnectionPool<E, D, C> {
+        TcpConnectionPool {
+            buf_size: self.buf_size,
+            idle_timeout: self.idle_timeout,
+            encoder_factory: self.encoder_factory,
+            decoder_factory: self.decoder_factory,
+            clock: self.clock,
+            connections: HashMap::new(),
+            connect_count: 0,
+            recv_paused: false,
+        }
+    }
+}
+impl<E, D> Default for TcpConnectionPoolBuilder<E, D>
+where
+    E: Factory + Default,
+    D: Factory + Default,
+    E::Item: Encode,
+    D::Item: Decode,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A pool of TCP connections keyed by peer addresses.
+///
+/// Connections are established lazily when an item is sent to a peer for the first time,
+/// and reused by the subsequent sends to the same peer.
+/// A connection is removed from the pool when it is closed by the peer, when it fails,
+/// or when it has been idle longer than the configured idle timeout.
+///
+/// Items received from any of the pooled connections are yielded by `poll_recv`
+/// together with the address of the peer.
+///
+/// # Limitations
+///
+/// The pool holds at most one connection per peer,
+/// so all of the items sent to a peer are serialized over its single connection.
+/// Accordingly, there is no max-idle-per-peer setting:
+/// the number of idle connections is bounded by the number of peers,
+/// and [`TcpConnectionPoolBuilder::idle_timeout`] is the way to close them.
+///
+/// [`TcpConnectionPoolBuilder::idle_timeout`]: ./struct.TcpConnectionPoolBuilder.html#method.idle_timeout
+pub struct TcpConnectionPool<E, D, C = FibersClock>
+where
+    E: Factory,
+    D: Factory,
+    E::Item: Encode,
+    D::Item: Decode,
+    C: Clock,
+{
+    buf_size: usize,
+    idle_timeout: Option<Duration>,
+    encoder_factory: E,
+    decoder_factory: D,
+    clock: C,
+    connections: HashMap<SocketAddr, Connection<E::Item, D::Item, C>>,
+    connect_count: u64,
+    recv_paused: bool,
+}
+impl<E, D> TcpConnectionPool<E, D>
+where
+    E: Factory + Default,
+    D: Factory + Default,
+    E::Item: Encode,
+    D::Item: Decode,
+{
+    /// Makes a new `TcpConnectionPool` instance with the default settings.
+    ///
+    /// This is equivalent to `TcpConnectionPoolBuilder::new().finish()`.
+    pub fn new() -> Self {
+        TcpConnectionPoolBuilder::new().finish()
+    }
+}
+impl<E, D> Default for TcpConnectionPool<E, D>
+where
+    E: Factory + Default,
+    D: Factory + Default,
+    E::Item: Encode,
+    D::Item: Decode,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<E, D, C> TcpConnectionPool<E, D, C>
+where
+    E: Factory,
+    D: Factory,
+    E::Item: Encode,
+    D::Item: Decode,
+    C: Clock,
+{
+    /// Returns the number of the connections (including the ones being established) in the pool.
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Returns the total number of connect attempts made by the pool.
+    pub fn connect_count(&self) -> u64 {
+        self.connect_count
+    }
+
+    /// Returns the addresses of the peers to which the pool has connections.
+    pub fn peers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.connections.keys()
+    }
+
+    /// Returns a reference to the established connection to the given peer.
+    pub fn connection_ref(&self, peer: SocketAddr) -> Option<&TcpTransporter<E::Item, D::Item>> {
+        if let Some(Connection::Connected { transporter, .. }) = self.connections.get(&peer) {
+            Some(transporter)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the established connection to the given peer.
+    pub fn connection_mut(
+        &mut self,
+        peer: SocketAddr,
+    ) -> Option<&mut TcpTransporter<E::Item, D::Item>> {
+        if let Some(Connection::Connected { transporter, .. }) = self.connections.get_mut(&peer) {
+            Some(transporter)
+        } else {
+            None
+        }
+    }
+
+    /// Closes the connection to the given peer.
+    ///
+    /// Returns `true` if the pool had a connection to the peer, otherwise `false`.
+    pub fn disconnect(&mut self, peer: SocketAddr) -> bool {
+        self.connections.remove(&peer).is_some()
+    }
+
+    fn connect(&mut self, peer: SocketAddr) -> Connection<E::Item, D::Item, C> {
+        self.connect_count += 1;
+        let encoder = self.encoder_factory.create();
+        let decoder = self.decoder_factory.create();
+        Connection::Connecting {
+            future: TcpStream::connect(peer),
+            codec: Some((encoder, decoder)),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn poll_connections<F, T>(&mut self, mut f: F) -> Result<Option<T>>
+    where
+        F: FnMut(SocketAddr, &mut TcpTransporter<E::Item, D::Item>) -> Result<Step<T>>,
+    {
+        let buf_size = self.buf_size;
+        let idle_timeout = self.idle_timeout;
+        let clock = &self.clock;
+        let recv_paused = self.recv_paused;
+        let mut closed = Vec::new();
+        let mut result = Ok(None);
+        for (&peer, connection) in &mut self.connections {
+            let step = match track!(connection.poll(buf_size, idle_timeout, clock, recv_paused); peer)
+            {
+                Err(e) => Err(e),
+                Ok(Polled::Connecting) => Ok(Step::Continue),
+                Ok(Polled::Idle) => Ok(Step::Close),
+                Ok(Polled::Connected(transporter)) => track!(f(peer, transporter); peer),
+            };
+            match step {
+                Err(e) => {
+                    closed.push(peer);
+                    result = Err(e);
+                    break;
+                }
+                Ok(Step::Continue) => {}
+                Ok(Step::Close) => closed.push(peer),
+                Ok(Step::Yield(x)) => {
+                    result = Ok(Some(x));
+                    break;
+                }
+            }
+        }
+        for peer in closed {
+            self.connections.remove(&peer);
+        }
+        result
+    }
+}
+impl<E, D, C> Transport for TcpConnectionPool<E, D, C>
+where
+    E: Factory,
+    D: Factory,
+    E::Item: Encode,
+    D::Item: Decode,
+    C: Clock,
+{
+    type PeerAddr = SocketAddr;
+    type SendItem = <E::Item as Encode>::Item;
+    type RecvItem = <D::Item as Decode>::Item;
+
+    /// Acquires (or creates) the connection to the given peer, and starts sending the item over it.
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        if !self.connections.contains_key(&peer) {
+            let connection = self.connect(peer);
+            self.connections.insert(peer, connection);
+        }
+        let result = match self.connections.get_mut(&peer).expect("never fails") {
+            Connection::Connecting { pending, .. } => {
+                pending.push_back(item);
+                Ok(())
+            }
+            Connection::Connected {
+                transporter,
+                idle_timer,
+            } => {
+                *idle_timer = None;
+                track!(transporter.start_send((), item); peer)
+            }
+        };
+        if result.is_err() {
+            self.connections.remove(&peer);
+        }
+        result
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        let mut ready = true;
+        track!(self.poll_connections(|_, transporter| {
+            ready &= track!(transporter.poll_send())?.is_ready();
+            Ok(Step::<()>::Continue)
+        }))?;
+        if ready && !self.connections.values().any(Connection::is_connecting) {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        let item = track!(self.poll_connections(|peer, transporter| {
+            match track!(transporter.poll_recv())? {
+                Async::NotReady => Ok(Step::Continue),
+                Async::Ready(None) => Ok(Step::Close),
+                Async::Ready(Some(((), item))) => Ok(Step::Yield((peer, item))),
+            }
+        }))?;
+        if let Some((peer, item)) = item {
+            if let Some(Connection::Connected { idle_timer, .. }) = self.connections.get_mut(&peer)
+            {
+                *idle_timer = None;
+            }
+            Ok(Async::Ready(Some((peer, item))))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Pauses or resumes the reception of all the connections, including the ones established later.
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        self.recv_paused = paused;
+        for connection in self.connections.values_mut() {
+            if let Connection::Connected { transporter, .. } = connection {
+                track!(transporter.set_recv_paused(paused))?;
+            }
+        }
+        Ok(())
+    }
+}
+impl<E, D, C> fmt::Debug for TcpConnectionPool<E, D, C>
+where
+    E: Factory,
+    D: Factory,
+    E::Item: Encode,
+    D::Item: Decode,
+    C: Clock,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "TcpConnectionPool {{ peers: {:?}, connect_count: {}, .. }}",
+            self.connections.keys().collect::<Vec<_>>(),
+            self.connect_count
+        )
+    }
+}
+
+enum Connection<E: Encode, D: Decode, C: Clock> {
+    Connecting {
+        future: Connect,
+        codec: Option<(E, D)>,
+        pending: VecDeque<E::Item>,
+    },
+    Connected {
+        transporter: Box<TcpTransporter<E, D>>,
+        idle_timer: Option<C::Delay>,
+    },
+}
+impl<E: Encode, D: Decode, C: Clock> Connection<E, D, C> {
+    fn is_connecting(&self) -> bool {
+        matches!(self, Connection::Connecting { .. })
+    }
+
+    fn poll(
+        &mut self,
+        buf_size: usize,
+        idle_timeout: Option<Duration>,
+        clock: &C,
+        recv_paused: bool,
+    ) -> Result<Polled<'_, E, D>> {
+        if let Connection::Connecting {
+            future,
+            codec,
+            pending,
+        } = self
+        {
+            if let Async::Ready(stream) = track!(future.poll().map_err(Error::from))? {
+                let (encoder, decoder) = codec.take().expect("never fails");
+                let mut transporter = track!(TcpTransporterBuilder::with_codec(encoder, decoder)
+                    .buf_size(buf_size)
+                    .finish(stream))?;
+                track!(transporter.set_recv_paused(recv_paused))?;
+                for item in pending.drain(..) {
+                    track!(transporter.start_send((), item))?;
+                }
+                *self = Connection::Connected {
+                    transporter: Box::new(transporter),
+                    idle_timer: None,
+                };
+            } else {
+                return Ok(Polled::Connecting);
+            }
+        }
+
+        if let Connection::Connected {
+            transporter,
+            idle_timer,
+        } = self
+        {
+            if let Some(timeout) = idle_timeout {
+                if transporter.message_queue_len() == 0 {
+                    let timer = idle_timer.get_or_insert_with(|| clock.delay(timeout));
+                    if track!(timer.poll())?.is_ready() {
+                        return Ok(Polled::Idle);
+                    }
+                } else {
+                    *idle_timer = None;
+                }
+            }
+            Ok(Polled::Connected(transporter))
+        } else {
+            unreachable!()
+        }
+    }
+}
+
+enum Polled<'a, E: Encode, D: Decode> {
+    Connecting,
+    Connected(&'a mut TcpTransporter<E, D>),
+    Idle,
+}
+
+enum Step<T> {
+    Continue,
+    Yield(T),
+    Close,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockClock;
+    use crate::{wait_recv, wait_send, ErrorKind, TcpListener};
+    use bytecodec::fixnum::{U8Decoder, U8Encoder};
+    use factory::DefaultFactory;
+    use futures::{Poll, Stream};
+    use trackable::error::ErrorKindExt;
+
+    type Pool = TcpConnectionPool<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>;
+    type Server = TcpListener<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>;
+
+    #[test]
+    fn connection_reuse_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let server_addr = server.local_addr();
+
+        let mut pool = Pool::new();
+        pool.start_send(server_addr, 1)?;
+        let mut pool = fibers_global::execute(wait_send(pool))?;
+        pool.start_send(server_addr, 2)?;
+        let pool = fibers_global::execute(wait_send(pool))?;
+        assert_eq!(pool.connection_count(), 1);
+        assert_eq!(pool.connect_count(), 1);
+
+        let (client, server) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let client = client.unwrap();
+        let (client, _, item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(item, 1);
+        let (mut client, _, item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(item, 2);
+
+        client.start_send((), 3)?;
+        let _client = fibers_global::execute(wait_send(client))?;
+        let (_, peer, item) = fibers_global::execute(wait_recv(pool))?;
+        assert_eq!(peer, server_addr);
+        assert_eq!(item, 3);
+        drop(server);
+        Ok(())
+    }
+
+    #[test]
+    fn idle_eviction_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let server_addr = server.local_addr();
+
+        let mut pool = TcpConnectionPoolBuilder::new()
+            .idle_timeout(Duration::from_millis(10))
+            .finish();
+        Transport::start_send(&mut pool, server_addr, 1)?;
+        let pool: Pool = fibers_global::execute(wait_send(pool))?;
+        assert_eq!(pool.connection_count(), 1);
+
+        let pool = fibers_global::execute(futures::future::poll_fn({
+            let mut pool = Some(pool);
+            move || {
+                let _ = track!(pool.as_mut().unwrap().poll_recv())?;
+                if pool.as_ref().unwrap().connection_count() == 0 {
+                    Ok(Async::Ready(pool.take().unwrap()))
+                } else {
+                    Ok::<_, Error>(Async::NotReady)
+                }
+            }
+        }))?;
+        assert_eq!(pool.connect_count(), 1);
+        drop(server);
+        Ok(())
+    }
+
+    #[test]
+    fn idle_eviction_with_mock_clock_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let server_addr = server.local_addr();
+
+        let clock = MockClock::new();
+        let mut pool = TcpConnectionPoolBuilder::<DefaultFactory<U8Encoder>, _>::new()
+            .idle_timeout(Duration::from_secs(60))
+            .clock(clock.clone())
+            .finish();
+        let poll_once = |mut pool: TcpConnectionPool<_, DefaultFactory<U8Decoder>, _>| {
+            fibers_global::execute(futures::lazy(move || {
+                track!(pool.poll_recv())?;
+                Ok::<_, Error>(pool)
+            }))
+        };
+
+        pool.start_send(server_addr, 1)?;
+        let pool = fibers_global::execute(wait_send(pool))?;
+        let pool = poll_once(pool)?;
+        assert_eq!(pool.connection_count(), 1);
+
+        clock.advance(Duration::from_secs(59));
+        let pool = poll_once(pool)?;
+        assert_eq!(pool.connection_count(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        let pool = poll_once(pool)?;
+        assert_eq!(pool.connection_count(), 0);
+        drop(server);
+        Ok(())
+    }
+
+    #[test]
+    fn set_recv_paused_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let server_addr = server.local_addr();
+
+        let mut pool = Pool::new();
+        pool.set_recv_paused(true)?;
+        pool.start_send(server_addr, 1)?;
+        let mut pool = fibers_global::execute(wait_send(pool))?;
+        assert!(pool.connection_ref(server_addr).unwrap().is_recv_paused());
+
+        pool.set_recv_paused(false)?;
+        assert!(!pool.connection_ref(server_addr).unwrap().is_recv_paused());
+        drop(server);
+        Ok(())
+    }
+    #[test]
+    fn idle_timer_error_works() -> Result<()> {
+        struct FailingClock;
+        impl Clock for FailingClock {
+            type Delay = futures::future::FutureResult<(), Error>;
+
+            fn now(&self) -> std::time::Instant {
+                std::time::Instant::now()
+            }
+
+            fn delay(&self, _duration: Duration) -> Self::Delay {
+                futures::future::err(ErrorKind::Other.cause("timer failed").into())
+            }
+        }
+
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let server_addr = server.local_addr();
+
+        let mut pool =
+            TcpConnectionPoolBuilder::<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>::new()
+                .idle_timeout(Duration::from_secs(60))
+                .clock(FailingClock)
+                .finish();
+        pool.start_send(server_addr, 1)?;
+        let (pool, e) = fibers_global::execute(futures::future::poll_fn({
+            let mut pool = Some(pool);
+            move || -> Poll<_, Error> {
+                match pool.as_mut().unwrap().poll_recv() {
+                    Err(e) => Ok(Async::Ready((pool.take().unwrap(), e))),
+                    Ok(_) => Ok(Async::NotReady),
+                }
+            }
+        }))?;
+        assert_eq!(*e.kind(), ErrorKind::Other);
+        assert_eq!(pool.connection_count(), 0);
+        drop(server);
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{Error, ErrorKind};
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use trackable::error::ErrorKindExt;
+
+/// The preference of the address family used when binding to a host name.
+///
+/// See [`UdpTransporterBuilder::bind_host`] and [`TcpListenerBuilder::listen_host`] for more details.
+///
+/// [`UdpTransporterBuilder::bind_host`]: ./struct.UdpTransporterBuilder.html#method.bind_host
+/// [`TcpListenerBuilder::listen_host`]: ./struct.TcpListenerBuilder.html#method.listen_host
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FamilyPreference {
+    /// IPv4 addresses are tried before IPv6 addresses.
+    #[default]
+    PreferIpv4,
+
+    /// IPv6 addresses are tried before IPv4 addresses.
+    PreferIpv6,
+
+    /// The addresses are tried in the order returned by the resolver.
+    ResolverOrder,
+}
+impl FamilyPreference {
+    fn sort(self, addrs: &mut [SocketAddr]) {
+        match self {
+            FamilyPreference::PreferIpv4 => addrs.sort_by_key(|a| a.is_ipv6()),
+            FamilyPreference::PreferIpv6 => addrs.sort_by_key(|a| a.is_ipv4()),
+            FamilyPreference::ResolverOrder => {}
+        }
+    }
+}
+
+/// Resolves the given host, and tries binding to the resolved addresses in the order
+/// specified by `preference` until one is bound successfully.
+///
+/// If no address is bound, the resulting future fails with the error of the last attempt.
+pub(crate) fn bind_host<F, B>(
+    host: &str,
+    port: u16,
+    preference: FamilyPreference,
+    mut bind: F,
+) -> impl Future<Item = B::Item, Error = Error>
+where
+    F: FnMut(SocketAddr) -> B,
+    B: Future<Error = io::Error>,
+{
+    let host = host.to_owned();
+    let addrs = (host.as_str(), port)
+        .to_socket_addrs()
+        .map(|addrs| {
+            let mut addrs = addrs.collect::<Vec<_>>();
+            preference.sort(&mut addrs);
+            addrs
+        })
+        .map_err(|e| track!(Error::from(e); host, port));
+    future::result(addrs).and_then(move |addrs| {
+        future::loop_fn(
+            (addrs.into_iter(), None),
+            move |(mut addrs, last_error)| match addrs.next() {
+                None => {
+                    let e = last_error.unwrap_or_else(|| {
+                        let e = ErrorKind::InvalidInput
+                            .cause(format!("No address is resolved: host={:?}", host));
+                        track!(Error::from(e))
+                    });
+                    Either::A(future::err(e))
+                }
+                Some(addr) => Either::B(bind(addr).then(move |result| match result {
+                    Ok(bound) => Ok(Loop::Break(bound)),
+                    Err(e) => Ok(Loop::Continue((addrs, Some(track!(Error::from(e); addr))))),
+                })),
+            },
+        )
+    })
+}
+
+/// Tries binding to the ports in the given range in order until one is bound successfully.
+///
+/// Ports already in use are skipped.
+/// If all of the ports are in use, the resulting future fails with an `ErrorKind::InvalidInput` error.
+pub(crate) fn bind_in_range<F, B>(
+    ip: IpAddr,
+    ports: RangeInclusive<u16>,
+    mut bind: F,
+) -> impl Future<Item = B::Item, Error = Error>
+where
+    F: FnMut(SocketAddr) -> B,
+    B: Future<Error = io::Error>,
+{
+    let range = format!("{}:{:?}", ip, ports);
+    future::loop_fn(ports, move |mut ports| match ports.next() {
+        None => {
+            let e = ErrorKind::InvalidInput.cause(format!("No available port: range={}", range));
+            Either::A(future::err(track!(Error::from(e))))
+        }
+        Some(port) => Either::B(
+            bind(SocketAddr::new(ip, port)).then(move |result| match result {
+                Ok(bound) => Ok(Loop::Break(bound)),
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => Ok(Loop::Continue(ports)),
+                Err(e) => Err(track!(Error::from(e); port)),
+            }),
+        ),
+    })
+}
@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Instant;
+
+/// Priority of an outgoing item.
+///
+/// Items having higher priority are sent ahead of the ones having lower priority,
+/// while items having the same priority are sent in FIFO order.
+/// Note that an item is never interrupted once it has started being sent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// High priority (e.g., heartbeats and acknowledgements).
+    High,
+
+    /// Normal priority.
+    ///
+    /// This is the priority of the items sent by `Transport::start_send`.
+    #[default]
+    Normal,
+
+    /// Low priority (e.g., bulk data).
+    Low,
+}
+impl Priority {
+    pub(crate) const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A callback invoked once an item has been sent.
+pub(crate) type SendCallback = Box<dyn FnOnce() + Send>;
+
+/// Additional information attached to an item in `PriorityQueue`.
+#[derive(Default)]
+pub(crate) struct ItemTag {
+    /// The time at which the item was enqueued.
+    pub enqueued_at: Option<Instant>,
+
+    /// The callback to be invoked once the item has been sent.
+    pub on_sent: Option<SendCallback>,
+}
+impl fmt::Debug for ItemTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ItemTag {{ enqueued_at: {:?}, on_sent: {} }}",
+            self.enqueued_at,
+            if self.on_sent.is_some() {
+                "Some(_)"
+            } else {
+                "None"
+            }
+        )
+    }
+}
+
+/// A FIFO queue that is drained in the order of priority.
+///
+/// Each item can be tagged with the time at which it was enqueued and a callback.
+#[derive(Debug)]
+pub(crate) struct PriorityQueue<T> {
+    queues: [VecDeque<(T, ItemTag)>; 3],
+}
+impl<T> PriorityQueue<T> {
+    /// Makes a new `PriorityQueue` instance.
+    ///
+    /// `capacity` is applied to the queue of each priority.
+    pub fn with_capacity(capacity: usize) -> Self {
+        PriorityQueue {
+            queues: [
+                VecDeque::with_capacity(capacity),
+                VecDeque::with_capacity(capacity),
+                VecDeque::with_capacity(capacity),
+            ],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|q| q.is_empty())
+    }
+
+    pub fn push(&mut self, item: T, priority: Priority, enqueued_at: Option<Instant>) {
+        let tag = ItemTag {
+            enqueued_at,
+            on_sent: None,
+        };
+        self.push_tagged(item, priority, tag);
+    }
+
+    pub fn push_tagged(&mut self, item: T, priority: Priority, tag: ItemTag) {
+        self.queues[priority.index()].push_back((item, tag));
+    }
+
+    pub fn pop_front(&mut self) -> Option<(T, ItemTag)> {
+        self.queues.iter_mut().find_map(|q| q.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_queue_works() {
+        let mut queue = PriorityQueue::with_capacity(0);
+        queue.push(0, Priority::Low, None);
+        queue.push(1, Priority::Normal, None);
+        queue.push(2, Priority::High, None);
+        queue.push(3, Priority::Low, None);
+        queue.push(4, Priority::High, None);
+        assert_eq!(queue.len(), 5);
+
+        let order =
+            std::iter::from_fn(|| queue.pop_front().map(|(item, _)| item)).collect::<Vec<_>>();
+        assert_eq!(order, [2, 4, 1, 0, 3]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn priority_queue_capacity_works() {
+        let mut queue = PriorityQueue::with_capacity(100);
+        let capacities = queue
+            .queues
+            .iter()
+            .map(|q| q.capacity())
+            .collect::<Vec<_>>();
+        assert!(capacities.iter().all(|&c| c >= 100));
+
+        for i in 0..100 {
+            for &priority in &Priority::ALL {
+                queue.push(i, priority, None);
+            }
+        }
+        assert_eq!(queue.len(), 300);
+        let new_capacities = queue
+            .queues
+            .iter()
+            .map(|q| q.capacity())
+            .collect::<Vec<_>>();
+        assert_eq!(new_capacities, capacities);
+    }
+}
@@ -0,0 +1,291 @@
+use crate::{
+    ErrorKind, PollRecv, PollSend, Result, TcpTransport, Transport, TransportMetrics, UdpTransport,
+};
+use futures::Async;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// [`QuotaTransporter`] builder.
+///
+/// [`QuotaTransporter`]: ./struct.QuotaTransporter.html
+#[derive(Debug, Default, Clone)]
+pub struct QuotaTransporterBuilder {
+    max_recv_bytes: Option<u64>,
+    max_recv_messages: Option<u64>,
+    max_send_bytes: Option<u64>,
+}
+impl QuotaTransporterBuilder {
+    /// Makes a new `QuotaTransporterBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of bytes to be received over the lifetime of the transporter.
+    ///
+    /// By default, the number of bytes is unlimited.
+    pub fn max_recv_bytes(mut self, bytes: u64) -> Self {
+        self.max_recv_bytes = Some(bytes);
+        self
+    }
+
+    /// Sets the maximum number of messages to be received over the lifetime of the transporter.
+    ///
+    /// By default, the number of messages is unlimited.
+    pub fn max_recv_messages(mut self, messages: u64) -> Self {
+        self.max_recv_messages = Some(messages);
+        self
+    }
+
+    /// Sets the maximum number of bytes to be sent over the lifetime of the transporter.
+    ///
+    /// By default, the number of bytes is unlimited.
+    pub fn max_send_bytes(mut self, bytes: u64) -> Self {
+        self.max_send_bytes = Some(bytes);
+        self
+    }
+
+    /// Makes a new `QuotaTransporter` instance that wraps the given transporter.
+    ///
+    /// `send_size` and `recv_size` return the number of bytes of an outgoing item and an incoming item respectively.
+    pub fn finish<T, S, R>(self, inner: T, send_size: S, recv_size: R) -> QuotaTransporter<T, S, R>
+    where
+        T: Transport,
+        S: FnMut(&T::SendItem) -> usize,
+        R: FnMut(&T::RecvItem) -> usize,
+    {
+        QuotaTransporter {
+            inner,
+            send_size,
+            recv_size,
+            max_recv_bytes: self.max_recv_bytes,
+            max_recv_messages: self.max_recv_messages,
+            max_send_bytes: self.max_send_bytes,
+            metrics: TransportMetrics::new(),
+            sent_bytes: 0,
+            received_bytes: 0,
+            recv_exceeded: false,
+        }
+    }
+}
+
+/// An implementation of [`Transport`] that enforces quotas on the total traffic of the inner transporter.
+///
+/// If an outgoing item would exceed the send quota, `start_send` fails with an `ErrorKind::QuotaExceeded` error
+/// and the item is discarded.
+/// If an incoming item exceeds one of the receive quotas, `poll_recv` fails with an `ErrorKind::QuotaExceeded` error,
+/// and so do all of the subsequent `poll_recv` calls.
+///
+/// This is useful for preventing a single (possibly malicious) peer from consuming too many resources.
+///
+/// [`Transport`]: ./trait.Transport.html
+pub struct QuotaTransporter<T, S, R> {
+    inner: T,
+    send_size: S,
+    recv_size: R,
+    max_recv_bytes: Option<u64>,
+    max_recv_messages: Option<u64>,
+    max_send_bytes: Option<u64>,
+    metrics: TransportMetrics,
+    sent_bytes: u64,
+    received_bytes: u64,
+    recv_exceeded: bool,
+}
+impl<T, S, R> QuotaTransporter<T, S, R>
+where
+    T: Transport,
+    S: FnMut(&T::SendItem) -> usize,
+    R: FnMut(&T::RecvItem) -> usize,
+{
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Takes ownership of the instance, and returns the inner transporter.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Returns the numbers of the messages that have been sent and received via the instance.
+    pub fn metrics(&self) -> &TransportMetrics {
+        &self.metrics
+    }
+
+    /// Returns the number of bytes that have been sent via the instance.
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_bytes
+    }
+
+    /// Returns the number of bytes that have been received via the instance.
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes
+    }
+
+    fn check_recv_quota(&self) -> Result<()> {
+        if let Some(max) = self.max_recv_messages {
+            track_assert!(
+                self.metrics.received_messages <= max,
+                ErrorKind::QuotaExceeded,
+                "Too many messages have been received: max={}",
+                max
+            );
+        }
+        if let Some(max) = self.max_recv_bytes {
+            track_assert!(
+                self.received_bytes <= max,
+                ErrorKind::QuotaExceeded,
+                "Too many bytes have been received: received={}, max={}",
+                self.received_bytes,
+                max
+            );
+        }
+        Ok(())
+    }
+}
+impl<T, S, R> Transport for QuotaTransporter<T, S, R>
+where
+    T: Transport,
+    S: FnMut(&T::SendItem) -> usize,
+    R: FnMut(&T::RecvItem) -> usize,
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        let sent_bytes = self.sent_bytes + (self.send_size)(&item) as u64;
+        if let Some(max) = self.max_send_bytes {
+            track_assert!(
+                sent_bytes <= max,
+                ErrorKind::QuotaExceeded,
+                "Too many bytes to be sent: sent={}, max={}",
+                sent_bytes,
+                max
+            );
+        }
+        track!(self.inner.start_send(peer, item))?;
+        self.sent_bytes = sent_bytes;
+        self.metrics.sent_messages += 1;
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        track_assert!(
+            !self.recv_exceeded,
+            ErrorKind::QuotaExceeded,
+            "The receive quota has been exceeded"
+        );
+        let polled = track!(self.inner.poll_recv())?;
+        if let Async::Ready(Some((_, ref item))) = polled {
+            self.metrics.received_messages += 1;
+            self.received_bytes += (self.recv_size)(item) as u64;
+            if let Err(e) = self.check_recv_quota() {
+                self.recv_exceeded = true;
+                return Err(track!(e));
+            }
+        }
+        Ok(polled)
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+impl<T, S, R> UdpTransport for QuotaTransporter<T, S, R>
+where
+    T: UdpTransport,
+    S: FnMut(&T::SendItem) -> usize,
+    R: FnMut(&T::RecvItem) -> usize,
+{
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+impl<T, S, R> TcpTransport for QuotaTransporter<T, S, R>
+where
+    T: TcpTransport,
+    S: FnMut(&T::SendItem) -> usize,
+    R: FnMut(&T::RecvItem) -> usize,
+{
+    fn peer_addr(&self) -> SocketAddr {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+impl<T: fmt::Debug, S, R> fmt::Debug for QuotaTransporter<T, S, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "QuotaTransporter {{ inner: {:?}, max_recv_bytes: {:?}, max_recv_messages: {:?}, \
+             max_send_bytes: {:?}, .. }}",
+            self.inner, self.max_recv_bytes, self.max_recv_messages, self.max_send_bytes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockTransporter, PeerName};
+
+    #[test]
+    fn quota_transporter_works() -> Result<()> {
+        let mut transporter = QuotaTransporterBuilder::new()
+            .max_recv_messages(2)
+            .max_send_bytes(5)
+            .finish(
+                MockTransporter::<PeerName, Vec<u8>, Vec<u8>>::new(),
+                |item| item.len(),
+                |item| item.len(),
+            );
+
+        // Send quota
+        transporter.start_send(PeerName("foo"), vec![0; 3])?;
+        let result = transporter.start_send(PeerName("foo"), vec![0; 3]);
+        assert_eq!(
+            result.err().map(|e| *e.kind()),
+            Some(ErrorKind::QuotaExceeded)
+        );
+        assert_eq!(transporter.sent_bytes(), 3);
+        assert_eq!(transporter.inner_ref().pending.len(), 1);
+
+        // Receive quota
+        for i in 0..3 {
+            transporter
+                .inner_mut()
+                .incoming
+                .push_back((PeerName("bar"), vec![i]));
+        }
+        assert!(transporter.poll_recv()?.is_ready());
+        assert!(transporter.poll_recv()?.is_ready());
+        let result = transporter.poll_recv();
+        assert_eq!(
+            result.err().map(|e| *e.kind()),
+            Some(ErrorKind::QuotaExceeded)
+        );
+        assert_eq!(transporter.metrics().received_messages, 3);
+
+        let result = transporter.poll_recv();
+        assert_eq!(
+            result.err().map(|e| *e.kind()),
+            Some(ErrorKind::QuotaExceeded)
+        );
+        Ok(())
+    }
+}
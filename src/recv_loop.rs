@@ -0,0 +1,169 @@
+use crate::{Error, ErrorKind, Transport};
+use fibers::sync::oneshot::{self, Monitor, MonitorError};
+use fibers::Spawn;
+use futures::{Async, Future, Poll};
+use std::fmt;
+use trackable::error::ErrorKindExt;
+
+/// Spawns a fiber that runs the receive loop of the given transporter.
+///
+/// `handler` is invoked with each received item, and the reply returned by it (if any) is sent via the transporter.
+/// The loop terminates when the transporter reaches the end of the stream
+/// (after the queued replies have been sent), when it fails, or when [`RecvLoopHandle::stop`] is called.
+///
+/// [`RecvLoopHandle::stop`]: ./struct.RecvLoopHandle.html#method.stop
+pub fn spawn_recv_loop<S, T, F>(spawner: &S, transporter: T, handler: F) -> RecvLoopHandle<T>
+where
+    S: Spawn,
+    T: Transport + Send + 'static,
+    F: FnMut(T::PeerAddr, T::RecvItem) -> Option<(T::PeerAddr, T::SendItem)> + Send + 'static,
+{
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let future = RecvLoop {
+        transporter: Some(transporter),
+        handler,
+        stop_rx: Some(stop_rx),
+        eos: false,
+    };
+    RecvLoopHandle {
+        stop_tx: Some(stop_tx),
+        monitor: spawner.spawn_monitor(future),
+    }
+}
+
+/// A handle of the receive loop spawned by [`spawn_recv_loop`].
+///
+/// This is a future that resolves to the transporter when the loop has terminated,
+/// or fails with the error that terminated the loop.
+/// Dropping the handle does not stop the loop.
+///
+/// [`spawn_recv_loop`]: ./fn.spawn_recv_loop.html
+#[must_use = "futures do nothing unless polled"]
+pub struct RecvLoopHandle<T> {
+    stop_tx: Option<oneshot::Sender<()>>,
+    monitor: Monitor<T, Error>,
+}
+impl<T> RecvLoopHandle<T> {
+    /// Stops the loop.
+    ///
+    /// The replies that have not been sent yet are kept in the resulting transporter.
+    /// This has no effect if the loop has already been terminated.
+    pub fn stop(&mut self) {
+        if let Some(tx) = self.stop_tx.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+impl<T> Future for RecvLoopHandle<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.monitor.poll().map_err(|e| match e {
+            MonitorError::Aborted => {
+                track!(Error::from(
+                    ErrorKind::Other.cause("The recv loop fiber has aborted")
+                ))
+            }
+            MonitorError::Failed(e) => track!(e),
+        })
+    }
+}
+impl<T> fmt::Debug for RecvLoopHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RecvLoopHandle {{ .. }}")
+    }
+}
+
+struct RecvLoop<T, F> {
+    transporter: Option<T>,
+    handler: F,
+    stop_rx: Option<oneshot::Receiver<()>>,
+    eos: bool,
+}
+impl<T, F> RecvLoop<T, F>
+where
+    T: Transport,
+    F: FnMut(T::PeerAddr, T::RecvItem) -> Option<(T::PeerAddr, T::SendItem)>,
+{
+    fn is_stopped(&mut self) -> bool {
+        match self.stop_rx.as_mut().map(|rx| rx.poll()) {
+            None | Some(Ok(Async::NotReady)) => false,
+            Some(Ok(Async::Ready(()))) => true,
+            Some(Err(_)) => {
+                // The handle has been dropped without stopping.
+                self.stop_rx = None;
+                false
+            }
+        }
+    }
+
+    fn poll_loop(&mut self) -> Poll<(), Error> {
+        let t = self
+            .transporter
+            .as_mut()
+            .expect("Cannot poll RecvLoop twice");
+        loop {
+            let flushed = track!(t.poll_send())?.is_ready();
+            if self.eos {
+                return Ok(if flushed {
+                    Async::Ready(())
+                } else {
+                    Async::NotReady
+                });
+            }
+            match track!(t.poll_recv())? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => self.eos = true,
+                Async::Ready(Some((peer, item))) => {
+                    if let Some((peer, reply)) = (self.handler)(peer, item) {
+                        track!(t.start_send(peer, reply))?;
+                    }
+                }
+            }
+        }
+    }
+}
+impl<T, F> Future for RecvLoop<T, F>
+where
+    T: Transport,
+    F: FnMut(T::PeerAddr, T::RecvItem) -> Option<(T::PeerAddr, T::SendItem)>,
+{
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !self.is_stopped() && track!(self.poll_loop())?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        Ok(Async::Ready(self.transporter.take().expect("never fails")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::bind;
+    use crate::{wait_recv, wait_send, Result, UdpTransport};
+
+    #[test]
+    fn spawn_recv_loop_works() -> Result<()> {
+        let server = bind()?;
+        let server_addr = server.local_addr();
+        let mut handle = spawn_recv_loop(&fibers_global::handle(), server, |peer, item| {
+            Some((peer, item))
+        });
+
+        let mut client = bind()?;
+        client.start_send(server_addr, "hello".to_owned())?;
+        let client = fibers_global::execute(wait_send(client))?;
+        let (_, peer, item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(peer, server_addr);
+        assert_eq!(item, "hello");
+
+        handle.stop();
+        let server = fibers_global::execute(handle)?;
+        assert_eq!(server.local_addr(), server_addr);
+        Ok(())
+    }
+}
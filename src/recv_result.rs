@@ -0,0 +1,142 @@
+use crate::{Error, ErrorKind, PollRecv, PollSend, Result, Transport};
+use futures::Async;
+use std::net::SocketAddr;
+
+/// An implementation of [`Transport`] that reports decoding errors as received items.
+///
+/// If the inner transporter fails to decode an item (i.e., `ErrorKind::CodecError`),
+/// `poll_recv` yields `Err(..)` as the item instead of failing,
+/// so that the callers (e.g., supervisors) can keep using the transporter.
+/// The errors are reported along with `T::PeerAddr::default()` as the peer address.
+/// The other errors (e.g., I/O errors) are propagated as usual.
+///
+/// Because of this, the peer address type of the inner transporter must implement `Default`.
+/// This is intended for connection oriented transporters such as [`TcpTransporter`] (whose peer address is `()`),
+/// and cannot wrap transporters addressed by `SocketAddr` (e.g., [`UdpTransporter`]).
+///
+/// Note that whether the inner transporter can continue decoding after an error depends on its decoder.
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`TcpTransporter`]: ./struct.TcpTransporter.html
+/// [`UdpTransporter`]: ./struct.UdpTransporter.html
+#[derive(Debug)]
+pub struct RecvResultTransporter<T> {
+    inner: T,
+}
+impl<T: Transport> RecvResultTransporter<T> {
+    /// Makes a new `RecvResultTransporter` instance.
+    pub fn new(inner: T) -> Self {
+        RecvResultTransporter { inner }
+    }
+
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Takes ownership of the instance, and returns the inner transporter.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+impl<T> Transport for RecvResultTransporter<T>
+where
+    T: Transport,
+    T::PeerAddr: Default,
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = std::result::Result<T::RecvItem, Error>;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        track!(self.inner.start_send(peer, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        match self.inner.poll_recv() {
+            Err(e) => {
+                if *e.kind() == ErrorKind::CodecError {
+                    Ok(Async::Ready(Some((T::PeerAddr::default(), Err(e)))))
+                } else {
+                    Err(track!(e))
+                }
+            }
+            Ok(polled) => Ok(polled.map(|item| item.map(|(peer, item)| (peer, Ok(item))))),
+        }
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransporter;
+    use crate::{wait_recv, wait_send, TcpListener, TcpTransporterBuilder};
+    use bytecodec::fixnum::{U8Decoder, U8Encoder};
+    use bytecodec::DecodeExt;
+    use factory::DefaultFactory;
+    use futures::Stream;
+
+    fn reject_0xff(b: u8) -> bytecodec::Result<u8> {
+        track_assert_ne!(b, 0xFF, bytecodec::ErrorKind::InvalidInput);
+        Ok(b)
+    }
+
+    #[test]
+    fn recv_result_transporter_works() -> Result<()> {
+        type Server = TcpListener<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>;
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let builder = TcpTransporterBuilder::with_codec(
+            U8Encoder::new(),
+            U8Decoder::new().try_map(reject_0xff),
+        );
+        let client = fibers_global::execute(builder.connect(server.local_addr()))?;
+        let (server, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let mut server = server.expect("never fails");
+
+        server.start_send((), 1)?;
+        server.start_send((), 0xFF)?;
+        server.start_send((), 2)?;
+        let _server = fibers_global::execute(wait_send(server))?;
+
+        let client = RecvResultTransporter::new(client);
+        let (client, (), item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(item.ok(), Some(1));
+        let (client, (), item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(item.err().map(|e| *e.kind()), Some(ErrorKind::CodecError));
+        let (_, (), item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(item.ok(), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn non_codec_errors_are_propagated() {
+        let mut mock = MockTransporter::<(), (), u8>::new();
+        mock.recv_errors.push_back(ErrorKind::CodecError.into());
+        mock.recv_errors.push_back(ErrorKind::IoError.into());
+        let mut transporter = RecvResultTransporter::new(mock);
+
+        let polled = transporter
+            .poll_recv()
+            .map(|p| p.map(|x| x.map(|(_, r)| r.is_err())));
+        assert_eq!(polled.ok(), Some(Async::Ready(Some(true))));
+        let polled = transporter.poll_recv();
+        assert_eq!(polled.err().map(|e| *e.kind()), Some(ErrorKind::IoError));
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{Error, PollRecv, PollSend, Result, TcpTransport, TcpTransporter, Transport};
+use bytecodec::bytes::BytesEncoder;
+use bytecodec::{self, ByteCount, Decode, Encode, Eos};
+use futures::{Async, Future};
+use std::cmp;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const FAMILY_IPV4: u8 = 4;
+const FAMILY_IPV6: u8 = 6;
+
+/// An implementation of [`Transport`] that relays messages to/from many logical peers over a single TCP connection.
+///
+/// Each message is prefixed by the address of its logical peer
+/// (i.e., the destination of an outgoing message, or the origin of an incoming one),
+/// so `PeerAddr` of this transporter is `SocketAddr` unlike [`TcpTransporter`].
+/// The underlying transporter has to be built with [`RelayEncoder`] and [`RelayDecoder`]
+/// (e.g., `TcpTransporterBuilder::with_codec(RelayEncoder::new(encoder), RelayDecoder::new(decoder))`).
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`TcpTransporter`]: ./struct.TcpTransporter.html
+/// [`RelayEncoder`]: ./struct.RelayEncoder.html
+/// [`RelayDecoder`]: ./struct.RelayDecoder.html
+#[derive(Debug)]
+pub struct RelayTcpTransporter<E: Encode, D: Decode> {
+    inner: TcpTransporter<RelayEncoder<E>, RelayDecoder<D>>,
+}
+impl<E, D> RelayTcpTransporter<E, D>
+where
+    E: Encode + Default,
+    D: Decode + Default,
+{
+    /// Starts connecting to the given relay, and
+    /// will return a new `RelayTcpTransporter` instance if the connect operation is succeeded.
+    pub fn connect(relay: SocketAddr) -> impl Future<Item = Self, Error = Error> {
+        TcpTransporter::connect(relay).map(Self::new)
+    }
+}
+impl<E: Encode, D: Decode> RelayTcpTransporter<E, D> {
+    /// Makes a new `RelayTcpTransporter` instance that wraps the given transporter.
+    pub fn new(inner: TcpTransporter<RelayEncoder<E>, RelayDecoder<D>>) -> Self {
+        RelayTcpTransporter { inner }
+    }
+
+    /// Returns the address of the relay (i.e., the peer of the TCP connection).
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.inner.peer_addr()
+    }
+
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &TcpTransporter<RelayEncoder<E>, RelayDecoder<D>> {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut TcpTransporter<RelayEncoder<E>, RelayDecoder<D>> {
+        &mut self.inner
+    }
+
+    /// Takes ownership of the instance, and returns the inner transporter.
+    pub fn into_inner(self) -> TcpTransporter<RelayEncoder<E>, RelayDecoder<D>> {
+        self.inner
+    }
+}
+impl<E: Encode, D: Decode> Transport for RelayTcpTransporter<E, D> {
+    type PeerAddr = SocketAddr;
+    type SendItem = E::Item;
+    type RecvItem = D::Item;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        track!(self.inner.start_send((), (peer, item)); peer)
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        match track!(self.inner.poll_recv())? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::Ready(Some(((), (peer, item)))) => Ok(Async::Ready(Some((peer, item)))),
+        }
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+impl<E: Encode, D: Decode> From<TcpTransporter<RelayEncoder<E>, RelayDecoder<D>>>
+    for RelayTcpTransporter<E, D>
+{
+    fn from(f: TcpTransporter<RelayEncoder<E>, RelayDecoder<D>>) -> Self {
+        RelayTcpTransporter::new(f)
+    }
+}
+
+/// An encoder that prefixes each item encoded by `E` with the address of its logical peer.
+///
+/// The address is encoded as the family (`4` or `6`), the IP address and the port (16-bit big-endian).
+#[derive(Debug)]
+pub struct RelayEncoder<E> {
+    inner: E,
+    header: BytesEncoder<Vec<u8>>,
+}
+impl<E: Encode> RelayEncoder<E> {
+    /// Makes a new `RelayEncoder` instance that encodes the items by `inner`.
+    pub fn new(inner: E) -> Self {
+        RelayEncoder {
+            inner,
+            header: BytesEncoder::new(),
+        }
+    }
+
+    /// Returns a reference to the inner encoder.
+    pub fn inner_ref(&self) -> &E {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner encoder.
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    /// Takes ownership of the instance, and returns the inner encoder.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+impl<E: Encode> Encode for RelayEncoder<E> {
+    type Item = (SocketAddr, E::Item);
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        let mut offset = 0;
+        if !self.header.is_idle() {
+            offset = track!(self.header.encode(buf, eos))?;
+            if !self.header.is_idle() {
+                return Ok(offset);
+            }
+        }
+        offset += track!(self.inner.encode(&mut buf[offset..], eos))?;
+        Ok(offset)
+    }
+
+    fn start_encoding(&mut self, (peer, item): Self::Item) -> bytecodec::Result<()> {
+        let mut header = Vec::with_capacity(19);
+        match peer.ip() {
+            IpAddr::V4(ip) => {
+                header.push(FAMILY_IPV4);
+                header.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                header.push(FAMILY_IPV6);
+                header.extend_from_slice(&ip.octets());
+            }
+        }
+        header.extend_from_slice(&peer.port().to_be_bytes());
+        track!(self.header.start_encoding(header))?;
+        track!(self.inner.start_encoding(item))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.header
+            .requiring_bytes()
+            .add_for_encoding(self.inner.requiring_bytes())
+    }
+
+    fn is_idle(&self) -> bool {
+        self.header.is_idle() && self.inner.is_idle()
+    }
+}
+impl<E: Encode + Default> Default for RelayEncoder<E> {
+    fn default() -> Self {
+        RelayEncoder::new(E::default())
+    }
+}
+
+/// A decoder that decodes the items made by [`RelayEncoder`].
+///
+/// [`RelayEncoder`]: ./struct.RelayEncoder.html
+#[derive(Debug)]
+pub struct RelayDecoder<D> {
+    inner: D,
+    header: Vec<u8>,
+    peer: Option<SocketAddr>,
+}
+impl<D: Decode> RelayDecoder<D> {
+    /// Makes a new `RelayDecoder` instance that decodes the items by `inner`.
+    pub fn new(inner: D) -> Self {
+        RelayDecoder {
+            inner,
+            header: Vec::with_capacity(19),
+            peer: None,
+        }
+    }
+
+    /// Returns a reference to the inner decoder.
+    pub fn inner_ref(&self) -> &D {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner decoder.
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    /// Takes ownership of the instance, and returns the inner decoder.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    fn header_len(&self) -> bytecodec::Result<usize> {
+        match self.header.first() {
+            None => Ok(1),
+            Some(&FAMILY_IPV4) => Ok(1 + 4 + 2),
+            Some(&FAMILY_IPV6) => Ok(1 + 16 + 2),
+            Some(family) => track_panic!(
+                bytecodec::ErrorKind::InvalidInput,
+                "Unknown address family: {}",
+                family
+            ),
+        }
+    }
+
+    fn decode_header(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        let mut offset = 0;
+        loop {
+            let header_len = track!(self.header_len())?;
+            let size = cmp::min(header_len - self.header.len(), buf.len() - offset);
+            self.header.extend_from_slice(&buf[offset..][..size]);
+            offset += size;
+            if self.header.len() < header_len {
+                if offset == buf.len() {
+                    track_assert!(
+                        !eos.is_reached() || self.header.is_empty(),
+                        bytecodec::ErrorKind::UnexpectedEos
+                    );
+                    return Ok(offset);
+                }
+                continue;
+            }
+            if header_len == 1 {
+                continue;
+            }
+
+            let h = &self.header;
+            let ip = if h[0] == FAMILY_IPV4 {
+                let mut octets = [0; 4];
+                octets.copy_from_slice(&h[1..5]);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            } else {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&h[1..17]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let port = u16::from_be_bytes([h[header_len - 2], h[header_len - 1]]);
+            self.peer = Some(SocketAddr::new(ip, port));
+            return Ok(offset);
+        }
+    }
+}
+impl<D: Decode> Decode for RelayDecoder<D> {
+    type Item = (SocketAddr, D::Item);
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        let mut offset = 0;
+        if self.peer.is_none() {
+            offset = track!(self.decode_header(buf, eos))?;
+            if self.peer.is_none() {
+                return Ok(offset);
+            }
+        }
+        if !self.inner.is_idle() {
+            offset += track!(self.inner.decode(&buf[offset..], eos))?;
+        }
+        Ok(offset)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        let peer = track_assert_some!(self.peer.take(), bytecodec::ErrorKind::IncompleteDecoding);
+        self.header.clear();
+        let item = track!(self.inner.finish_decoding())?;
+        Ok((peer, item))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if self.peer.is_some() {
+            self.inner.requiring_bytes()
+        } else {
+            // The exact length of the header is unknown until the family is decoded.
+            let header_len = self.header_len().unwrap_or(1);
+            ByteCount::Finite((header_len - cmp::min(header_len, self.header.len())) as u64)
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.peer.is_some() && self.inner.is_idle()
+    }
+}
+impl<D: Decode + Default> Default for RelayDecoder<D> {
+    fn default() -> Self {
+        RelayDecoder::new(D::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{wait_recv, wait_send, TcpListener};
+    use bytecodec::fixnum::{U8Decoder, U8Encoder};
+    use factory::DefaultFactory;
+    use futures::Stream;
+
+    type Relay = RelayTcpTransporter<U8Encoder, U8Decoder>;
+    type Server = TcpListener<
+        DefaultFactory<RelayEncoder<U8Encoder>>,
+        DefaultFactory<RelayDecoder<U8Decoder>>,
+    >;
+
+    #[test]
+    fn relay_tcp_transporter_works() -> Result<()> {
+        let peer0: SocketAddr = "192.0.2.1:1000".parse().unwrap();
+        let peer1: SocketAddr = "[2001:db8::1]:2000".parse().unwrap();
+
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let mut client = fibers_global::execute(Relay::connect(server.local_addr()))?;
+        let (accepted, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let relay = Relay::from(accepted.expect("never fails"));
+
+        client.start_send(peer0, 1)?;
+        client.start_send(peer1, 2)?;
+        let client = fibers_global::execute(wait_send(client))?;
+
+        let (relay, peer, item) = fibers_global::execute(wait_recv(relay))?;
+        assert_eq!((peer, item), (peer0, 1));
+        let (mut relay, peer, item) = fibers_global::execute(wait_recv(relay))?;
+        assert_eq!((peer, item), (peer1, 2));
+
+        relay.start_send(peer1, 20)?;
+        relay.start_send(peer0, 10)?;
+        let _relay = fibers_global::execute(wait_send(relay))?;
+
+        let (client, peer, item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!((peer, item), (peer1, 20));
+        let (_, peer, item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!((peer, item), (peer0, 10));
+        Ok(())
+    }
+}
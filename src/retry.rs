@@ -0,0 +1,292 @@
+use crate::{Clock, Error, FibersClock, PollRecv, PollSend, Result, Transport};
+use futures::{Async, Future};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// [`RetryTransporter`] builder.
+///
+/// [`RetryTransporter`]: ./struct.RetryTransporter.html
+#[derive(Debug, Clone)]
+pub struct RetryTransporterBuilder<C = FibersClock> {
+    max_retries: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    clock: C,
+}
+impl RetryTransporterBuilder {
+    /// Makes a new `RetryTransporterBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl<C: Clock> RetryTransporterBuilder<C> {
+    /// Sets the maximum number of retries for each item.
+    ///
+    /// The default value is `3`.
+    pub fn max_retries(mut self, n: usize) -> Self {
+        self.max_retries = n;
+        self
+    }
+
+    /// Sets the backoff interval before the first retry.
+    ///
+    /// The interval is doubled for each subsequent retry of the same item.
+    ///
+    /// The default value is `100ms`.
+    pub fn initial_backoff(mut self, interval: Duration) -> Self {
+        self.initial_backoff = interval;
+        self
+    }
+
+    /// Sets the upper bound of the backoff interval.
+    ///
+    /// The default value is `10s`.
+    pub fn max_backoff(mut self, interval: Duration) -> Self {
+        self.max_backoff = interval;
+        self
+    }
+
+    /// Sets the clock used for timing the backoff intervals.
+    ///
+    /// The default value is `FibersClock`.
+    pub fn clock<C2: Clock>(self, clock: C2) -> RetryTransporterBuilder<C2> {
+        RetryTransporterBuilder {
+            max_retries: self.max_retries,
+            initial_backoff: self.initial_backoff,
+            max_backoff: self.max_backoff,
+            clock,
+        }
+    }
+
+    /// Makes a new `RetryTransporter` instance that wraps the given transporter.
+    pub fn finish<T>(self, inner: T) -> RetryTransporter<T, C>
+    where
+        T: Transport,
+        T::SendItem: Clone,
+    {
+        RetryTransporter {
+            inner,
+            policy: self,
+            outgoing_queue: VecDeque::new(),
+            in_flight: None,
+            retries: 0,
+            backoff: None,
+        }
+    }
+
+    fn backoff(&self, retries: usize) -> Duration {
+        let mut interval = self.initial_backoff;
+        for _ in 1..retries {
+            if interval >= self.max_backoff {
+                break;
+            }
+            interval *= 2;
+        }
+        std::cmp::min(interval, self.max_backoff)
+    }
+}
+impl Default for RetryTransporterBuilder {
+    fn default() -> Self {
+        RetryTransporterBuilder {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            clock: FibersClock,
+        }
+    }
+}
+
+/// An implementation of [`Transport`] that retries failed sends with exponential backoff.
+///
+/// If sending an item fails with a retryable error (see [`Error::is_retryable`]),
+/// the item is re-sent to the inner transporter after a backoff interval.
+/// Non-retryable errors and errors exceeding the retry limit are propagated immediately.
+///
+/// Note that items are handed to the inner transporter one by one,
+/// because the failed item has to be identified for retrying.
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`Error::is_retryable`]: ./struct.Error.html#method.is_retryable
+#[derive(Debug)]
+pub struct RetryTransporter<T: Transport, C: Clock = FibersClock> {
+    inner: T,
+    policy: RetryTransporterBuilder<C>,
+    outgoing_queue: VecDeque<(T::PeerAddr, T::SendItem)>,
+    in_flight: Option<(T::PeerAddr, T::SendItem)>,
+    retries: usize,
+    backoff: Option<C::Delay>,
+}
+impl<T> RetryTransporter<T>
+where
+    T: Transport,
+    T::SendItem: Clone,
+{
+    /// Makes a new `RetryTransporter` instance with the default settings.
+    ///
+    /// This is equivalent to `RetryTransporterBuilder::new().finish(inner)`.
+    pub fn new(inner: T) -> Self {
+        RetryTransporterBuilder::new().finish(inner)
+    }
+}
+impl<T, C> RetryTransporter<T, C>
+where
+    T: Transport,
+    T::SendItem: Clone,
+    C: Clock,
+{
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the number of items waiting to be (re)sent.
+    pub fn message_queue_len(&self) -> usize {
+        self.outgoing_queue.len() + if self.in_flight.is_some() { 1 } else { 0 }
+    }
+
+    fn send_in_flight_item(&mut self) -> Result<()> {
+        let (peer, item) = self.in_flight.clone().expect("never fails");
+        track!(self.inner.start_send(peer, item))
+    }
+
+    fn handle_send_error(&mut self, e: Error) -> Result<()> {
+        if e.is_retryable() && self.retries < self.policy.max_retries {
+            self.retries += 1;
+            let interval = self.policy.backoff(self.retries);
+            self.backoff = Some(self.policy.clock.delay(interval));
+            Ok(())
+        } else {
+            self.in_flight = None;
+            self.retries = 0;
+            Err(track!(e))
+        }
+    }
+}
+impl<T, C> Transport for RetryTransporter<T, C>
+where
+    T: Transport,
+    T::SendItem: Clone,
+    C: Clock,
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        self.outgoing_queue.push_back((peer, item));
+        track!(self.poll_send())?;
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        loop {
+            if let Some(mut backoff) = self.backoff.take() {
+                let expired = track!(backoff.poll())?;
+                if expired.is_not_ready() {
+                    self.backoff = Some(backoff);
+                    return Ok(Async::NotReady);
+                }
+                if let Err(e) = self.send_in_flight_item() {
+                    track!(self.handle_send_error(e))?;
+                    continue;
+                }
+            } else if self.in_flight.is_none() {
+                if let Some(x) = self.outgoing_queue.pop_front() {
+                    self.in_flight = Some(x);
+                    if let Err(e) = self.send_in_flight_item() {
+                        track!(self.handle_send_error(e))?;
+                        continue;
+                    }
+                }
+            }
+
+            match self.inner.poll_send() {
+                Err(e) => track!(self.handle_send_error(e))?,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => {
+                    if self.in_flight.take().is_none() {
+                        return Ok(Async::Ready(()));
+                    }
+                    self.retries = 0;
+                }
+            }
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        track!(self.inner.poll_recv())
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransporter;
+    use crate::{wait_send, ErrorKind};
+    use std::io;
+    use trackable::error::ErrorKindExt;
+
+    fn transient_error() -> Error {
+        Error::from(io::Error::new(io::ErrorKind::Interrupted, "transient"))
+    }
+
+    #[test]
+    fn retry_works() -> Result<()> {
+        let mut mock = MockTransporter::<(), usize, ()>::new();
+        mock.send_errors.push_back(transient_error());
+        mock.send_errors.push_back(transient_error());
+
+        let mut transporter = RetryTransporterBuilder::new()
+            .initial_backoff(Duration::from_millis(1))
+            .finish(mock);
+        transporter.start_send((), 1)?;
+        transporter.start_send((), 2)?;
+
+        let transporter = fibers_global::execute(wait_send(transporter))?;
+        assert_eq!(transporter.inner_ref().sent, [((), 1), ((), 2)]);
+        assert_eq!(transporter.message_queue_len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn retry_limit_works() -> Result<()> {
+        let mut mock = MockTransporter::<(), usize, ()>::new();
+        for _ in 0..3 {
+            mock.send_errors.push_back(transient_error());
+        }
+
+        let mut transporter = RetryTransporterBuilder::new()
+            .max_retries(2)
+            .initial_backoff(Duration::from_millis(1))
+            .finish(mock);
+        transporter.start_send((), 1)?;
+        assert!(fibers_global::execute(wait_send(transporter)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn non_retryable_error_works() -> Result<()> {
+        let mut mock = MockTransporter::<(), usize, ()>::new();
+        mock.send_errors
+            .push_back(ErrorKind::InvalidInput.cause("invalid").into());
+
+        let mut transporter = RetryTransporter::new(mock);
+        assert!(transporter.start_send((), 1).is_err());
+        assert!(transporter.inner_ref().sent.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{ErrorKind, PeerAddr, PollRecv, PollSend, Result, Transport};
+use futures::Async;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+
+/// A step of the script played by [`ScriptedTransport`].
+///
+/// [`ScriptedTransport`]: ./struct.ScriptedTransport.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptStep<P, S, R> {
+    /// The transporter expects that the given item is sent to the given peer.
+    Expect(P, S),
+
+    /// The transporter delivers the given item as if it was received from the given peer.
+    Deliver(P, R),
+}
+
+/// An implementation of [`Transport`] that replays a scripted session for testing.
+///
+/// The steps of the script are consumed in order:
+/// `start_send` checks the item against the next `Expect` step, and
+/// `poll_recv` yields the item of the next `Deliver` step.
+///
+/// `poll_recv` returns `Ok(Async::NotReady)` while the next step is `Expect`,
+/// and `Ok(Async::Ready(None))` once all of the steps have been consumed.
+/// Note that no task is notified when an `Expect` step is consumed,
+/// so this is intended to be polled by the test code (e.g., via `drive`) after sending.
+///
+/// [`Transport`]: ./trait.Transport.html
+#[derive(Debug)]
+pub struct ScriptedTransport<P, S, R> {
+    steps: VecDeque<ScriptStep<P, S, R>>,
+}
+impl<P, S, R> ScriptedTransport<P, S, R> {
+    /// Makes a new `ScriptedTransport` instance that plays the given steps.
+    pub fn new<I>(steps: I) -> Self
+    where
+        I: IntoIterator<Item = ScriptStep<P, S, R>>,
+    {
+        ScriptedTransport {
+            steps: steps.into_iter().collect(),
+        }
+    }
+
+    /// Returns the steps that have not been played yet.
+    pub fn remaining_steps(&self) -> &VecDeque<ScriptStep<P, S, R>> {
+        &self.steps
+    }
+
+    /// Returns `true` if all of the steps have been played, otherwise `false`.
+    pub fn is_finished(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+impl<P, S, R> Transport for ScriptedTransport<P, S, R>
+where
+    P: PeerAddr,
+    S: PartialEq + Debug,
+{
+    type PeerAddr = P;
+    type SendItem = S;
+    type RecvItem = R;
+
+    /// # Errors
+    ///
+    /// If the given item does not match the next `Expect` step (or the next step is not `Expect`),
+    /// this will return an `ErrorKind::InvalidInput` error.
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        match self.steps.front() {
+            Some(ScriptStep::Expect(p, s)) => {
+                track_assert!(
+                    *p == peer && *s == item,
+                    ErrorKind::InvalidInput,
+                    "Unexpected item: expected=({:?}, {:?}), actual=({:?}, {:?})",
+                    p,
+                    s,
+                    peer,
+                    item
+                );
+            }
+            Some(ScriptStep::Deliver(p, _)) => track_panic!(
+                ErrorKind::InvalidInput,
+                "Unexpected item: expected a delivery from {:?}, actual=({:?}, {:?})",
+                p,
+                peer,
+                item
+            ),
+            None => track_panic!(
+                ErrorKind::InvalidInput,
+                "Unexpected item: the script has been finished, actual=({:?}, {:?})",
+                peer,
+                item
+            ),
+        }
+        self.steps.pop_front();
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        match self.steps.front() {
+            None => Ok(Async::Ready(None)),
+            Some(ScriptStep::Expect(..)) => Ok(Async::NotReady),
+            Some(ScriptStep::Deliver(..)) => match self.steps.pop_front() {
+                Some(ScriptStep::Deliver(peer, item)) => Ok(Async::Ready(Some((peer, item)))),
+                _ => unreachable!(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{drive, Transport};
+
+    /// A simple protocol that replies "pong" to each "ping".
+    fn pong<T>(transporter: &mut T) -> PollRecv<usize>
+    where
+        T: Transport<PeerAddr = (), SendItem = &'static str, RecvItem = &'static str>,
+    {
+        let mut count = 0;
+        loop {
+            match track!(transporter.poll_recv())? {
+                Async::Ready(Some(((), "ping"))) => {
+                    track!(transporter.start_send((), "pong"))?;
+                    count += 1;
+                }
+                Async::Ready(Some(((), item))) => {
+                    track_panic!(ErrorKind::InvalidInput, "Unknown item: {:?}", item)
+                }
+                Async::Ready(None) => return Ok(Async::Ready(Some(count))),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+
+    #[test]
+    fn scripted_transport_works() -> Result<()> {
+        use ScriptStep::{Deliver, Expect};
+
+        let script = vec![
+            Deliver((), "ping"),
+            Expect((), "pong"),
+            Deliver((), "ping"),
+            Expect((), "pong"),
+        ];
+        let transporter = ScriptedTransport::new(script);
+        let (transporter, count) = fibers_global::execute(drive(transporter, pong))?;
+        assert_eq!(count, Some(2));
+        assert!(transporter.is_finished());
+
+        // Mismatched item
+        let script = vec![Deliver((), "ping"), Expect((), "PONG")];
+        let mut transporter = ScriptedTransport::new(script);
+        let result = pong(&mut transporter);
+        assert_eq!(
+            result.err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        Ok(())
+    }
+}
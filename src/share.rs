@@ -1,12 +1,26 @@
 use crate::base::Transport;
 use crate::{PollRecv, PollSend, Result, TcpTransport, UdpTransport};
+use futures::task::{self, Task};
 use futures::Async;
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::fmt;
 use std::net::SocketAddr;
 use std::rc::Rc;
 
 /// Shareable transporter.
+///
+/// # Deferred sending
+///
+/// By default, `start_send` hands the item to the inner transporter immediately,
+/// which may perform I/O (e.g., if its eager flush is enabled).
+/// When many producers share an instance, it is more efficient to only enqueue items in the producers
+/// (via [`enqueue_only`] or by enabling [`set_deferred_send`]), and to let a single driver call `poll_send`,
+/// which hands the enqueued items to the inner transporter in order.
+/// The task that last called `poll_send` is notified whenever an item is enqueued in this way.
+///
+/// [`enqueue_only`]: ./struct.RcTransporter.html#method.enqueue_only
+/// [`set_deferred_send`]: ./struct.RcTransporter.html#method.set_deferred_send
 #[derive(Debug)]
 pub struct RcTransporter<T: Transport>(Rc<RefCell<Inner<T>>>);
 impl<T: Transport> RcTransporter<T> {
@@ -15,10 +29,36 @@ impl<T: Transport> RcTransporter<T> {
         let inner = Inner {
             transporter: inner,
             peek_recv: None,
+            deferred: VecDeque::new(),
+            deferred_send: false,
+            driver: None,
         };
         RcTransporter(Rc::new(RefCell::new(inner)))
     }
 
+    /// Enqueues the given item without handing it to the inner transporter.
+    ///
+    /// The enqueued items are handed to the inner transporter by the next `poll_send` call
+    /// (or before the item of the next `start_send` call if the deferred sending is disabled).
+    pub fn enqueue_only(&mut self, peer: T::PeerAddr, item: T::SendItem) {
+        self.0.borrow_mut().defer(peer, item);
+    }
+
+    /// Enables or disables the deferred sending.
+    ///
+    /// While enabled, `start_send` (and `start_send_batch`) behaves like `enqueue_only`.
+    /// This setting is shared by all of the clones of this instance.
+    ///
+    /// The default value is `false`.
+    pub fn set_deferred_send(&mut self, enabled: bool) {
+        self.0.borrow_mut().deferred_send = enabled;
+    }
+
+    /// Returns the number of items enqueued but not yet handed to the inner transporter.
+    pub fn deferred_len(&self) -> usize {
+        self.0.borrow().deferred.len()
+    }
+
     /// Executes the given function with a reference to the inner transporter.
     pub fn with_inner_ref<F, U>(&self, f: F) -> U
     where
@@ -35,6 +75,32 @@ impl<T: Transport> RcTransporter<T> {
         f(&mut self.0.borrow_mut().transporter)
     }
 
+    /// Tries to execute the given function with a reference to the inner transporter.
+    ///
+    /// If the inner transporter is currently mutably borrowed
+    /// (e.g., within the callback of `with_peek_recv` invoked on a clone of this instance),
+    /// this will return `None` without executing `f` instead of panicking.
+    pub fn try_with_inner_ref<F, U>(&self, f: F) -> Option<U>
+    where
+        F: FnOnce(&T) -> U,
+    {
+        self.0.try_borrow().ok().map(|inner| f(&inner.transporter))
+    }
+
+    /// Tries to execute the given function with a mutable reference to the inner transporter.
+    ///
+    /// If the inner transporter is currently borrowed, this will return `None` without executing `f`
+    /// instead of panicking.
+    pub fn try_with_inner_mut<F, U>(&mut self, f: F) -> Option<U>
+    where
+        F: FnOnce(&mut T) -> U,
+    {
+        self.0
+            .try_borrow_mut()
+            .ok()
+            .map(|mut inner| f(&mut inner.transporter))
+    }
+
     /// Executes the given function with the next incoming item if it is available.
     ///
     /// If there is no such item, this will return `Ok(None)` without executing `f`.
@@ -52,6 +118,34 @@ impl<T: Transport> RcTransporter<T> {
             Ok(None)
         }
     }
+
+    /// Executes the given function with a mutable reference to the inner transporter and the next incoming item
+    /// if it is available.
+    ///
+    /// `f` can use the reference to send items (e.g., a reply to the request) via the same transporter.
+    /// Note that the inner transporter is mutably borrowed while `f` is executed,
+    /// so the clones of this instance cannot be used within `f`.
+    ///
+    /// This returns `Ok(true)` if an item has been processed, otherwise `Ok(false)`.
+    pub fn process_next<F>(&mut self, f: F) -> Result<bool>
+    where
+        F: FnOnce(&mut T, T::PeerAddr, T::RecvItem),
+    {
+        let mut inner = self.0.borrow_mut();
+        let next = if let Some(next) = inner.peek_recv.take() {
+            Some(next)
+        } else if let Async::Ready(next) = track!(inner.transporter.poll_recv())? {
+            next
+        } else {
+            None
+        };
+        if let Some((peer, item)) = next {
+            f(&mut inner.transporter, peer, item);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
 }
 impl<T: Transport> Clone for RcTransporter<T> {
     fn clone(&self) -> Self {
@@ -64,11 +158,34 @@ impl<T: Transport> Transport for RcTransporter<T> {
     type RecvItem = T::RecvItem;
 
     fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
-        track!(self.0.borrow_mut().transporter.start_send(peer, item))
+        let mut inner = self.0.borrow_mut();
+        if inner.deferred_send {
+            inner.defer(peer, item);
+            return Ok(());
+        }
+        track!(inner.flush_deferred())?;
+        track!(inner.transporter.start_send(peer, item))
+    }
+
+    fn start_send_batch(&mut self, peer: Self::PeerAddr, items: Vec<Self::SendItem>) -> Result<()> {
+        let mut inner = self.0.borrow_mut();
+        if inner.deferred_send {
+            for item in items {
+                inner.defer(peer.clone(), item);
+            }
+            return Ok(());
+        }
+        track!(inner.flush_deferred())?;
+        track!(inner.transporter.start_send_batch(peer, items))
     }
 
     fn poll_send(&mut self) -> PollSend {
-        track!(self.0.borrow_mut().transporter.poll_send())
+        let mut inner = self.0.borrow_mut();
+        if task::is_in_task() {
+            inner.driver = Some(task::current());
+        }
+        track!(inner.flush_deferred())?;
+        track!(inner.transporter.poll_send())
     }
 
     fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
@@ -79,6 +196,14 @@ impl<T: Transport> Transport for RcTransporter<T> {
             track!(inner.transporter.poll_recv())
         }
     }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.0.borrow_mut().transporter.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.0.borrow().transporter.local_socket_addr()
+    }
 }
 impl<T: TcpTransport> TcpTransport for RcTransporter<T> {
     fn peer_addr(&self) -> SocketAddr {
@@ -98,6 +223,41 @@ impl<T: UdpTransport> UdpTransport for RcTransporter<T> {
 struct Inner<T: Transport> {
     transporter: T,
     peek_recv: Option<(T::PeerAddr, T::RecvItem)>,
+    deferred: VecDeque<(T::PeerAddr, T::SendItem)>,
+    deferred_send: bool,
+    driver: Option<Task>,
+}
+impl<T: Transport> Inner<T> {
+    fn defer(&mut self, peer: T::PeerAddr, item: T::SendItem) {
+        self.deferred.push_back((peer, item));
+        if let Some(driver) = self.driver.take() {
+            driver.notify();
+        }
+    }
+
+    /// Hands the deferred items to the inner transporter.
+    ///
+    /// Consecutive items destined for the same peer are handed as a batch,
+    /// so that the inner transporter can enqueue all of them before performing I/O.
+    fn flush_deferred(&mut self) -> Result<()> {
+        let mut index = 0;
+        while let Some((peer, item)) = self.deferred.pop_front() {
+            let mut items = vec![item];
+            while self.deferred.front().is_some_and(|(p, _)| *p == peer) {
+                items.push(self.deferred.pop_front().expect("never fails").1);
+            }
+            let len = items.len();
+            track!(
+                self.transporter.start_send_batch(peer.clone(), items),
+                "Failed to hand the deferred items to the inner transporter: peer={:?}, items={}..{}",
+                peer,
+                index,
+                index + len
+            )?;
+            index += len;
+        }
+        Ok(())
+    }
 }
 impl<T> fmt::Debug for Inner<T>
 where
@@ -107,3 +267,122 @@ where
         write!(f, "{:?}", self.transporter)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockTransporter, PeerName};
+    use crate::Error;
+    use futures::executor::{self, Notify, NotifyHandle};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn start_send_batch_works() -> Result<()> {
+        let mut transporter = RcTransporter::new(MockTransporter::<(), usize, ()>::new());
+        let mut cloned = transporter.clone();
+
+        cloned.start_send((), 0)?;
+        transporter.start_send_batch((), vec![1, 2, 3])?;
+        cloned.start_send((), 4)?;
+
+        let items = transporter
+            .with_inner_ref(|t| t.pending.iter().map(|(_, item)| *item).collect::<Vec<_>>());
+        assert_eq!(items, [0, 1, 2, 3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn try_with_inner_works() -> Result<()> {
+        let mut mock = MockTransporter::<(), (), usize>::new();
+        mock.incoming.push_back(((), 10));
+
+        let mut transporter = RcTransporter::new(mock);
+        let mut cloned = transporter.clone();
+        assert_eq!(cloned.try_with_inner_ref(|t| t.incoming.len()), Some(1));
+
+        let result = transporter.with_peek_recv(|_, item| {
+            (
+                *item,
+                cloned.try_with_inner_ref(|_| ()),
+                cloned.try_with_inner_mut(|_| ()),
+            )
+        })?;
+        assert_eq!(result, Some((10, None, None)));
+
+        assert_eq!(cloned.try_with_inner_mut(|t| t.incoming.len()), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn process_next_works() -> Result<()> {
+        let mut mock = MockTransporter::<PeerName, usize, usize>::new();
+        mock.incoming.push_back((PeerName("foo"), 1));
+        mock.incoming.push_back((PeerName("bar"), 2));
+        let mut transporter = RcTransporter::new(mock);
+
+        let reply = |t: &mut MockTransporter<_, _, _>, peer, item| {
+            t.start_send(peer, item * 10).unwrap();
+        };
+        assert!(transporter.process_next(reply)?);
+        assert!(transporter.process_next(reply)?);
+        assert!(!transporter.process_next(reply)?);
+
+        let replies = transporter.with_inner_ref(|t| t.pending.iter().cloned().collect::<Vec<_>>());
+        assert_eq!(replies, [(PeerName("foo"), 10), (PeerName("bar"), 20)]);
+        Ok(())
+    }
+
+    #[test]
+    fn enqueue_only_works() -> Result<()> {
+        let mut driver = RcTransporter::new(MockTransporter::<(), usize, ()>::new());
+        let mut producers = [driver.clone(), driver.clone()];
+
+        producers[0].enqueue_only((), 0);
+        producers[1].enqueue_only((), 1);
+        driver.set_deferred_send(true);
+        producers[0].start_send((), 2)?;
+        assert_eq!(driver.deferred_len(), 3);
+        assert!(driver.with_inner_ref(|t| t.pending.is_empty()));
+
+        assert_eq!(driver.poll_send()?, Async::Ready(()));
+        assert_eq!(driver.deferred_len(), 0);
+        let sent =
+            driver.with_inner_ref(|t| t.sent.iter().map(|(_, item)| *item).collect::<Vec<_>>());
+        assert_eq!(sent, [0, 1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn driver_notification_works() -> Result<()> {
+        struct CountingNotify(AtomicUsize);
+        impl Notify for CountingNotify {
+            fn notify(&self, _id: usize) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let mut driver = RcTransporter::new(MockTransporter::<(), usize, ()>::new());
+        let mut producer = driver.clone();
+        let mut task = executor::spawn(futures::future::poll_fn(move || {
+            track!(driver.poll_send())?;
+            if driver.with_inner_ref(|t| t.sent.is_empty()) {
+                Ok::<_, Error>(Async::NotReady)
+            } else {
+                Ok(Async::Ready(driver.with_inner_ref(|t| t.sent.clone())))
+            }
+        }));
+        let notify = Arc::new(CountingNotify(AtomicUsize::new(0)));
+        let handle = NotifyHandle::from(notify.clone());
+        assert_eq!(task.poll_future_notify(&handle, 0)?, Async::NotReady);
+
+        producer.enqueue_only((), 1);
+        producer.enqueue_only((), 2);
+        assert_eq!(notify.0.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            task.poll_future_notify(&handle, 0)?,
+            Async::Ready(vec![((), 1), ((), 2)])
+        );
+        Ok(())
+    }
+}
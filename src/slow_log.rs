@@ -0,0 +1,284 @@
+use crate::{
+    Clock, FibersClock, PollRecv, PollSend, Result, TcpTransport, Transport, UdpTransport,
+};
+use futures::Async;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Operation reported by [`SlowLogTransporter`].
+///
+/// [`SlowLogTransporter`]: ./struct.SlowLogTransporter.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SlowOp {
+    /// A send cycle, i.e., from the `start_send` call enqueuing the first item
+    /// until `poll_send` returns `Ok(Async::Ready(()))`.
+    Send,
+
+    /// A receive cycle, i.e., from the first `poll_recv` call after the previous item
+    /// until `poll_recv` returns the next item.
+    Recv,
+}
+
+/// [`SlowLogTransporter`] builder.
+///
+/// [`SlowLogTransporter`]: ./struct.SlowLogTransporter.html
+#[derive(Debug, Clone)]
+pub struct SlowLogTransporterBuilder<C = FibersClock> {
+    threshold: Duration,
+    clock: C,
+}
+impl SlowLogTransporterBuilder {
+    /// Makes a new `SlowLogTransporterBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl<C: Clock> SlowLogTransporterBuilder<C> {
+    /// Sets the duration above which an operation is regarded as slow.
+    ///
+    /// The default value is `100ms`.
+    pub fn threshold(mut self, threshold: Duration) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Sets the clock used for timing the operations.
+    ///
+    /// The default value is `FibersClock`.
+    pub fn clock<C2: Clock>(self, clock: C2) -> SlowLogTransporterBuilder<C2> {
+        SlowLogTransporterBuilder {
+            threshold: self.threshold,
+            clock,
+        }
+    }
+
+    /// Makes a new `SlowLogTransporter` instance that wraps the given transporter.
+    pub fn finish<T, F>(self, inner: T, on_slow: F) -> SlowLogTransporter<T, F, C>
+    where
+        T: Transport,
+        F: FnMut(SlowOp, &T::PeerAddr, Duration),
+    {
+        SlowLogTransporter {
+            inner,
+            on_slow,
+            threshold: self.threshold,
+            clock: self.clock,
+            send_started: None,
+            recv_started: None,
+        }
+    }
+}
+impl Default for SlowLogTransporterBuilder {
+    fn default() -> Self {
+        SlowLogTransporterBuilder {
+            threshold: Duration::from_millis(100),
+            clock: FibersClock,
+        }
+    }
+}
+
+/// An implementation of [`Transport`] that reports slow operations to a callback.
+///
+/// The callback `F` is invoked with the operation, the peer address and the elapsed time
+/// when a send or receive cycle (see [`SlowOp`]) takes longer than the threshold.
+/// The peer address of a send cycle is the one of the item that started the cycle.
+/// Note that a receive cycle includes the time spent waiting for the peers to send items.
+///
+/// The overhead is only a couple of clock readings per cycle.
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`SlowOp`]: ./enum.SlowOp.html
+pub struct SlowLogTransporter<T: Transport, F, C: Clock = FibersClock> {
+    inner: T,
+    on_slow: F,
+    threshold: Duration,
+    clock: C,
+    send_started: Option<(T::PeerAddr, Instant)>,
+    recv_started: Option<Instant>,
+}
+impl<T, F> SlowLogTransporter<T, F>
+where
+    T: Transport,
+    F: FnMut(SlowOp, &T::PeerAddr, Duration),
+{
+    /// Makes a new `SlowLogTransporter` instance with the default settings.
+    ///
+    /// This is equivalent to `SlowLogTransporterBuilder::new().finish(inner, on_slow)`.
+    pub fn new(inner: T, on_slow: F) -> Self {
+        SlowLogTransporterBuilder::new().finish(inner, on_slow)
+    }
+}
+impl<T, F, C> SlowLogTransporter<T, F, C>
+where
+    T: Transport,
+    F: FnMut(SlowOp, &T::PeerAddr, Duration),
+    C: Clock,
+{
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Takes ownership of the instance, and returns the inner transporter.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn check(&mut self, op: SlowOp, peer: &T::PeerAddr, started_at: Instant) {
+        let elapsed = self.clock.now().saturating_duration_since(started_at);
+        if elapsed > self.threshold {
+            (self.on_slow)(op, peer, elapsed);
+        }
+    }
+}
+impl<T, F, C> Transport for SlowLogTransporter<T, F, C>
+where
+    T: Transport,
+    F: FnMut(SlowOp, &T::PeerAddr, Duration),
+    C: Clock,
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        if self.send_started.is_none() {
+            self.send_started = Some((peer.clone(), self.clock.now()));
+        }
+        track!(self.inner.start_send(peer, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        let polled = self.inner.poll_send();
+        if let Ok(Async::NotReady) = polled {
+            return Ok(Async::NotReady);
+        }
+        if let Some((peer, started_at)) = self.send_started.take() {
+            if polled.is_ok() {
+                self.check(SlowOp::Send, &peer, started_at);
+            }
+        }
+        track!(polled)
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        let clock = &self.clock;
+        let started_at = *self.recv_started.get_or_insert_with(|| clock.now());
+        let polled = track!(self.inner.poll_recv());
+        match polled {
+            Ok(Async::NotReady) => {}
+            Ok(Async::Ready(Some((ref peer, _)))) => {
+                self.recv_started = None;
+                self.check(SlowOp::Recv, peer, started_at);
+            }
+            _ => {
+                self.recv_started = None;
+            }
+        }
+        polled
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+impl<T, F, C> UdpTransport for SlowLogTransporter<T, F, C>
+where
+    T: UdpTransport,
+    F: FnMut(SlowOp, &SocketAddr, Duration),
+    C: Clock,
+{
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+impl<T, F, C> TcpTransport for SlowLogTransporter<T, F, C>
+where
+    T: TcpTransport,
+    F: FnMut(SlowOp, &(), Duration),
+    C: Clock,
+{
+    fn peer_addr(&self) -> SocketAddr {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+impl<T: Transport + fmt::Debug, F, C: Clock> fmt::Debug for SlowLogTransporter<T, F, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SlowLogTransporter {{ inner: {:?}, threshold: {:?}, .. }}",
+            self.inner, self.threshold
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockClock, MockTransporter, PeerName};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn slow_log_transporter_works() -> Result<()> {
+        let clock = MockClock::new();
+        let logs = Rc::new(RefCell::new(Vec::new()));
+        let logs_clone = logs.clone();
+        let mut transporter = SlowLogTransporterBuilder::new()
+            .threshold(Duration::from_millis(100))
+            .clock(clock.clone())
+            .finish(
+                MockTransporter::<PeerName, (), u8>::new(),
+                move |op, peer: &PeerName, elapsed| {
+                    logs_clone.borrow_mut().push((op, peer.clone(), elapsed))
+                },
+            );
+
+        // A fast receive
+        transporter
+            .inner_mut()
+            .incoming
+            .push_back((PeerName("foo"), 1));
+        assert_eq!(
+            transporter.poll_recv()?,
+            Async::Ready(Some((PeerName("foo"), 1)))
+        );
+        assert!(logs.borrow().is_empty());
+
+        // A stalled receive
+        assert_eq!(transporter.poll_recv()?, Async::NotReady);
+        clock.advance(Duration::from_millis(150));
+        transporter
+            .inner_mut()
+            .incoming
+            .push_back((PeerName("bar"), 2));
+        assert_eq!(
+            transporter.poll_recv()?,
+            Async::Ready(Some((PeerName("bar"), 2)))
+        );
+        assert_eq!(
+            *logs.borrow(),
+            [(SlowOp::Recv, PeerName("bar"), Duration::from_millis(150))]
+        );
+
+        // A fast send
+        transporter.start_send(PeerName("baz"), ())?;
+        assert_eq!(transporter.poll_send()?, Async::Ready(()));
+        assert_eq!(logs.borrow().len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+use crate::{Error, ErrorKind, Result};
+use fibers::net::TcpStream;
+use futures::{Async, Future, Poll};
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+const AUTH_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Settings of a SOCKS5 proxy (RFC 1928).
+///
+/// If `auth` is specified, the username/password authentication (RFC 1929) is used.
+#[derive(Clone)]
+pub(crate) struct Socks5Proxy {
+    pub addr: SocketAddr,
+    pub auth: Option<(String, String)>,
+}
+impl fmt::Debug for Socks5Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The password is intentionally omitted.
+        write!(
+            f,
+            "Socks5Proxy {{ addr: {:?}, username: {:?}, .. }}",
+            self.addr,
+            self.auth.as_ref().map(|(username, _)| username)
+        )
+    }
+}
+
+/// Connects to `dest` through the given proxy, and returns the stream connected to the proxy
+/// on which the SOCKS5 handshake has been completed.
+pub(crate) fn connect(
+    proxy: Socks5Proxy,
+    dest: SocketAddr,
+) -> impl Future<Item = TcpStream, Error = Error> {
+    TcpStream::connect(proxy.addr)
+        .map_err(|e| track!(Error::from(e), "while connecting to the SOCKS5 proxy"))
+        .and_then(move |stream| track!(Handshake::new(stream, proxy.auth, dest)))
+        .and_then(|handshake| handshake)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Greeting,
+    Auth,
+    Request,
+    ReplyHead,
+    ReplyTail,
+}
+
+#[derive(Debug)]
+struct Handshake {
+    stream: Option<TcpStream>,
+    auth: Option<(String, String)>,
+    dest: SocketAddr,
+    phase: Phase,
+    write_buf: Vec<u8>,
+    write_offset: usize,
+    read_buf: Vec<u8>,
+    read_size: usize,
+}
+impl Handshake {
+    fn new(stream: TcpStream, auth: Option<(String, String)>, dest: SocketAddr) -> Result<Self> {
+        let method = if let Some((ref username, ref password)) = auth {
+            track_assert!(
+                !username.is_empty() && username.len() <= 255 && password.len() <= 255,
+                ErrorKind::InvalidInput,
+                "The SOCKS5 username must be 1 to 255 bytes and the password must be at most 255 bytes"
+            );
+            METHOD_USERNAME_PASSWORD
+        } else {
+            METHOD_NO_AUTH
+        };
+        Ok(Handshake {
+            stream: Some(stream),
+            auth,
+            dest,
+            phase: Phase::Greeting,
+            write_buf: vec![VERSION, 1, method],
+            write_offset: 0,
+            read_buf: Vec::new(),
+            read_size: 2,
+        })
+    }
+
+    fn expect(&mut self, phase: Phase, write_buf: Vec<u8>, read_size: usize) {
+        self.phase = phase;
+        self.write_buf = write_buf;
+        self.write_offset = 0;
+        self.read_buf.clear();
+        self.read_size = read_size;
+    }
+
+    /// Handles the bytes read in the current phase, and returns `true` if the handshake has been completed.
+    fn handle_read_bytes(&mut self) -> Result<bool> {
+        let buf = &self.read_buf;
+        match self.phase {
+            Phase::Greeting => {
+                track_assert_eq!(buf[0], VERSION, ErrorKind::ProxyError);
+                track_assert_ne!(
+                    buf[1],
+                    METHOD_NO_ACCEPTABLE,
+                    ErrorKind::ProxyError,
+                    "The SOCKS5 proxy accepts none of the offered authentication methods"
+                );
+                if let Some(request) = track!(self.auth_request(buf[1]))? {
+                    self.expect(Phase::Auth, request, 2);
+                } else {
+                    let request = self.connect_request();
+                    self.expect(Phase::Request, request, 0);
+                }
+            }
+            Phase::Auth => {
+                track_assert_eq!(buf[0], AUTH_VERSION, ErrorKind::ProxyError);
+                track_assert_eq!(
+                    buf[1],
+                    0,
+                    ErrorKind::ProxyError,
+                    "SOCKS5 authentication failed"
+                );
+                let request = self.connect_request();
+                self.expect(Phase::Request, request, 0);
+            }
+            Phase::Request => {
+                // The head of a reply consists of VER, REP, RSV, ATYP and the first byte of BND.ADDR.
+                self.expect(Phase::ReplyHead, Vec::new(), 5);
+            }
+            Phase::ReplyHead => {
+                track_assert_eq!(buf[0], VERSION, ErrorKind::ProxyError);
+                track_assert_eq!(
+                    buf[1],
+                    0,
+                    ErrorKind::ProxyError,
+                    "The SOCKS5 proxy refused the request: {}",
+                    reply_message(buf[1])
+                );
+                let remaining = match buf[3] {
+                    ATYP_IPV4 => 4 - 1 + 2,
+                    ATYP_IPV6 => 16 - 1 + 2,
+                    ATYP_DOMAIN => buf[4] as usize + 2,
+                    atyp => track_panic!(
+                        ErrorKind::ProxyError,
+                        "Unknown SOCKS5 address type: {}",
+                        atyp
+                    ),
+                };
+                self.expect(Phase::ReplyTail, Vec::new(), remaining);
+            }
+            Phase::ReplyTail => return Ok(true),
+        }
+        Ok(false)
+    }
+
+    fn auth_request(&self, method: u8) -> Result<Option<Vec<u8>>> {
+        if let Some((ref username, ref password)) = self.auth {
+            track_assert_eq!(method, METHOD_USERNAME_PASSWORD, ErrorKind::ProxyError);
+            let mut request = vec![AUTH_VERSION, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            Ok(Some(request))
+        } else {
+            track_assert_eq!(method, METHOD_NO_AUTH, ErrorKind::ProxyError);
+            Ok(None)
+        }
+    }
+
+    fn connect_request(&self) -> Vec<u8> {
+        let mut request = vec![VERSION, CMD_CONNECT, 0];
+        match self.dest {
+            SocketAddr::V4(a) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&a.ip().octets());
+            }
+            SocketAddr::V6(a) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&a.ip().octets());
+            }
+        }
+        request.extend_from_slice(&self.dest.port().to_be_bytes());
+        request
+    }
+}
+impl Future for Handshake {
+    type Item = TcpStream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let stream = self.stream.as_mut().expect("Cannot poll Handshake twice");
+            if self.write_offset < self.write_buf.len() {
+                match stream.write(&self.write_buf[self.write_offset..]) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady)
+                    }
+                    Err(e) => return Err(track!(Error::from(e))),
+                    Ok(0) => track_panic!(
+                        ErrorKind::ProxyError,
+                        "The SOCKS5 proxy closed the connection"
+                    ),
+                    Ok(size) => self.write_offset += size,
+                }
+                continue;
+            }
+            if self.read_buf.len() < self.read_size {
+                let mut buf = [0; 256];
+                let size = cmp::min(self.read_size - self.read_buf.len(), buf.len());
+                match stream.read(&mut buf[..size]) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady)
+                    }
+                    Err(e) => return Err(track!(Error::from(e))),
+                    Ok(0) => track_panic!(
+                        ErrorKind::ProxyError,
+                        "The SOCKS5 proxy closed the connection"
+                    ),
+                    Ok(size) => self.read_buf.extend_from_slice(&buf[..size]),
+                }
+                continue;
+            }
+            if track!(self.handle_read_bytes(); self.phase)? {
+                let stream = self.stream.take().expect("never fails");
+                return Ok(Async::Ready(stream));
+            }
+        }
+    }
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        wait_recv, wait_send, ErrorKind, Result, TcpTransport, TcpTransporterBuilder, Transport,
+    };
+    use bytecodec::fixnum::{U8Decoder, U8Encoder};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    type Builder = TcpTransporterBuilder<U8Encoder, U8Decoder>;
+
+    /// Serves a single client with the given reply, and then echoes a byte incremented by one as the destination.
+    fn spawn_proxy(reply: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 3];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [5, 1, 2]);
+            stream.write_all(&[5, 2]).unwrap();
+
+            let mut buf = [0; 11];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"\x01\x04user\x04pass");
+            stream.write_all(&[1, 0]).unwrap();
+
+            let mut buf = [0; 10];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [5, 1, 0, 1, 192, 0, 2, 1, 0, 80]);
+            stream.write_all(&reply).unwrap();
+
+            let mut buf = [0; 1];
+            if stream.read_exact(&mut buf).is_ok() {
+                stream.write_all(&[buf[0] + 1]).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn ipv4_reply(code: u8) -> Vec<u8> {
+        vec![5, code, 0, 1, 127, 0, 0, 1, 0x12, 0x34]
+    }
+
+    fn auth() -> Option<(String, String)> {
+        Some(("user".to_owned(), "pass".to_owned()))
+    }
+
+    #[test]
+    fn socks5_proxy_works() -> Result<()> {
+        let proxy = spawn_proxy(ipv4_reply(0));
+        let dest = "192.0.2.1:80".parse().unwrap();
+        let builder = Builder::new().socks5_proxy(proxy, auth());
+        let mut client = fibers_global::execute(builder.connect(dest))?;
+        assert_eq!(client.peer_addr(), dest);
+
+        client.start_send((), 10)?;
+        let client = fibers_global::execute(wait_send(client))?;
+        let (_, _, item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(item, 11);
+        Ok(())
+    }
+
+    #[test]
+    fn socks5_proxy_refusal_works() {
+        let proxy = spawn_proxy(ipv4_reply(5));
+        let builder = Builder::new().socks5_proxy(proxy, auth());
+        let result = fibers_global::execute(builder.connect("192.0.2.1:80".parse().unwrap()));
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::ProxyError));
+    }
+
+    #[test]
+    fn socks5_proxy_domain_reply_works() -> Result<()> {
+        let mut reply = vec![5, 0, 0, 3, 255];
+        reply.extend_from_slice(&[b'a'; 255]);
+        reply.extend_from_slice(&[0x12, 0x34]);
+        let proxy = spawn_proxy(reply);
+        let builder = Builder::new().socks5_proxy(proxy, auth());
+        let mut client = fibers_global::execute(builder.connect("192.0.2.1:80".parse().unwrap()))?;
+
+        client.start_send((), 10)?;
+        let client = fibers_global::execute(wait_send(client))?;
+        let (_, _, item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(item, 11);
+        Ok(())
+    }
+}
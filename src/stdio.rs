@@ -0,0 +1,261 @@
+use crate::base::Transport;
+use crate::sys::FdStream;
+use crate::tcp::{StreamTransporter, TcpTransporterBuilder};
+use crate::{Error, PollRecv, PollSend, Result};
+use bytecodec::{Decode, Encode};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+
+/// [`StdioTransporter`] builder.
+///
+/// [`StdioTransporter`]: ./struct.StdioTransporter.html
+#[derive(Debug)]
+pub struct StdioTransporterBuilder<E, D> {
+    buf_size: usize,
+    encoder: E,
+    decoder: D,
+}
+impl<E, D> StdioTransporterBuilder<E, D>
+where
+    E: Encode + Default,
+    D: Decode + Default,
+{
+    /// Makes a new `StdioTransporterBuilder` with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl<E: Encode, D: Decode> StdioTransporterBuilder<E, D> {
+    /// Makes a new `StdioTransporterBuilder` with the given encoder and decoder.
+    pub fn with_codec(encoder: E, decoder: D) -> Self {
+        StdioTransporterBuilder {
+            buf_size: 8192,
+            encoder,
+            decoder,
+        }
+    }
+
+    /// Sets the application level read/write buffer size of the resulting instance in byte.
+    ///
+    /// The default value is `8192`.
+    pub fn buf_size(mut self, size: usize) -> Self {
+        self.buf_size = size;
+        self
+    }
+
+    /// Builds a `StdioTransporter` instance that reads from stdin and writes to stdout.
+    ///
+    /// Note that this puts stdin and stdout into non-blocking mode until the transporter is dropped.
+    /// See [`finish_with_fds`] for the details.
+    ///
+    /// [`finish_with_fds`]: ./struct.StdioTransporterBuilder.html#method.finish_with_fds
+    pub fn finish(self) -> Result<StdioTransporter<E, D>> {
+        self.finish_with_fds(&io::stdin(), &io::stdout())
+    }
+
+    /// Builds a `StdioTransporter` instance that reads from `reader` and writes to `writer`
+    /// (e.g., the ends of pipes).
+    ///
+    /// The transporter uses duplicates of the descriptors, so the given objects can be closed independently.
+    ///
+    /// Note that the descriptors are put into non-blocking mode until the transporter is dropped.
+    /// The mode is shared by all of the descriptors referring to the same open file,
+    /// so this also affects the given objects and any other processes sharing the files (e.g., a terminal).
+    pub fn finish_with_fds<R, W>(self, reader: &R, writer: &W) -> Result<StdioTransporter<E, D>>
+    where
+        R: AsRawFd,
+        W: AsRawFd,
+    {
+        let reader = track!(FdStream::new(reader.as_raw_fd()).map_err(Error::from))?;
+        let writer = track!(FdStream::new(writer.as_raw_fd()).map_err(Error::from))?;
+        let stream = Duplex { reader, writer };
+        let inner = TcpTransporterBuilder::with_codec(self.encoder, self.decoder)
+            .buf_size(self.buf_size)
+            .finish_with_stream(stream);
+        Ok(StdioTransporter { inner })
+    }
+}
+impl<E, D> Default for StdioTransporterBuilder<E, D>
+where
+    E: Encode + Default,
+    D: Decode + Default,
+{
+    fn default() -> Self {
+        Self::with_codec(E::default(), D::default())
+    }
+}
+
+/// An implementation of [`Transport`] that uses stdin and stdout (or arbitrary pipes) as the transport layer.
+///
+/// This is useful for CLI tools that speak a framed protocol over stdio (e.g., language servers).
+/// If the reading side reaches EOF, `poll_recv` will return `Ok(Async::Ready(None))`.
+///
+/// This is only available on Unix platforms.
+///
+/// [`Transport`]: ./trait.Transport.html
+#[derive(Debug)]
+pub struct StdioTransporter<E: Encode, D: Decode> {
+    inner: StreamTransporter<Duplex, E, D>,
+}
+impl<E, D> StdioTransporter<E, D>
+where
+    E: Encode + Default,
+    D: Decode + Default,
+{
+    /// Makes a new `StdioTransporter` instance that reads from stdin and writes to stdout.
+    ///
+    /// This is equivalent to `StdioTransporterBuilder::new().finish()`.
+    pub fn new() -> Result<Self> {
+        StdioTransporterBuilder::new().finish()
+    }
+}
+impl<E: Encode, D: Decode> StdioTransporter<E, D> {
+    /// Returns the number of unsent messages in the queue of the instance.
+    pub fn message_queue_len(&self) -> usize {
+        self.inner.message_queue_len()
+    }
+
+    /// Returns a reference to the decoder being used by the instance.
+    pub fn decoder_ref(&self) -> &D {
+        self.inner.decoder_ref()
+    }
+
+    /// Returns a mutable reference to the decoder being used by the instance.
+    pub fn decoder_mut(&mut self) -> &mut D {
+        self.inner.decoder_mut()
+    }
+
+    /// Returns a reference to the encoder being used by the instance.
+    pub fn encoder_ref(&self) -> &E {
+        self.inner.encoder_ref()
+    }
+
+    /// Returns a mutable reference to the encoder being used by the instance.
+    pub fn encoder_mut(&mut self) -> &mut E {
+        self.inner.encoder_mut()
+    }
+}
+impl<E: Encode, D: Decode> Transport for StdioTransporter<E, D> {
+    type PeerAddr = ();
+    type SendItem = E::Item;
+    type RecvItem = D::Item;
+
+    fn start_send(&mut self, (): Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        track!(self.inner.start_send((), item))
+    }
+
+    fn start_send_batch(&mut self, (): Self::PeerAddr, items: Vec<Self::SendItem>) -> Result<()> {
+        track!(self.inner.start_send_batch((), items))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        track!(self.inner.poll_recv())
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+}
+
+#[derive(Debug)]
+struct Duplex {
+    reader: FdStream,
+    writer: FdStream,
+}
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{try_recv, wait_recv, wait_send};
+    use bytecodec::bytes::{Utf8Decoder, Utf8Encoder};
+    use bytecodec::fixnum::{U8Decoder, U8Encoder};
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    type Stdio = StdioTransporter<U8Encoder, U8Decoder>;
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn pipe_round_trip_works() -> Result<()> {
+        let (r0, w0) = pipe();
+        let (r1, w1) = pipe();
+        let mut a: Stdio = StdioTransporterBuilder::new().finish_with_fds(&r0, &w1)?;
+        let b: Stdio = StdioTransporterBuilder::new().finish_with_fds(&r1, &w0)?;
+
+        a.start_send((), 12)?;
+        let a = fibers_global::execute(wait_send(a))?;
+        let (mut b, (), item) = fibers_global::execute(wait_recv(b))?;
+        assert_eq!(item, 12);
+
+        b.start_send((), 34)?;
+        let b = fibers_global::execute(wait_send(b))?;
+        let (a, (), item) = fibers_global::execute(wait_recv(a))?;
+        assert_eq!(item, 34);
+
+        // Closing all of the writing ends makes the reader observe EOF.
+        drop(a);
+        drop(w1);
+        drop(b);
+        let b: Stdio = StdioTransporterBuilder::new().finish_with_fds(&r1, &w0)?;
+        let (_, item) = fibers_global::execute(try_recv(b))?;
+        assert_eq!(item, None);
+        Ok(())
+    }
+
+    #[test]
+    fn eos_terminated_item_works() -> Result<()> {
+        type Stdio = StdioTransporter<Utf8Encoder, Utf8Decoder>;
+        let (r, mut w) = pipe();
+        let (_, w1) = pipe();
+        let stdio: Stdio = StdioTransporterBuilder::new().finish_with_fds(&r, &w1)?;
+
+        // The last item is not followed by any delimiter.
+        w.write_all(b"hello")?;
+        drop(w);
+        let (stdio, item) = fibers_global::execute(try_recv(stdio))?;
+        assert_eq!(item, Some(((), "hello".to_owned())));
+        let (_, item) = fibers_global::execute(try_recv(stdio))?;
+        assert_eq!(item, None);
+        Ok(())
+    }
+
+    #[test]
+    fn nonblocking_mode_restore_works() -> Result<()> {
+        let is_nonblocking =
+            |f: &File| unsafe { libc::fcntl(f.as_raw_fd(), libc::F_GETFL) } & libc::O_NONBLOCK != 0;
+        let (r, w) = pipe();
+        assert!(!is_nonblocking(&r));
+
+        let stdio: Stdio = StdioTransporterBuilder::new().finish_with_fds(&r, &w)?;
+        assert!(is_nonblocking(&r));
+        assert!(is_nonblocking(&w));
+
+        drop(stdio);
+        assert!(!is_nonblocking(&r));
+        assert!(!is_nonblocking(&w));
+        Ok(())
+    }
+}
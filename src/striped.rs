@@ -0,0 +1,289 @@
+use crate::{
+    Error, ErrorKind, PollRecv, PollSend, Result, TcpTransport, TcpTransporter, Transport,
+    TransportMetrics,
+};
+use bytecodec::{Decode, Encode};
+use futures::future::{self, Future};
+use futures::Async;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// An implementation of [`Transport`] that stripes outgoing items across multiple TCP connections to the same peer.
+///
+/// `start_send` distributes items among the connections (i.e., stripes) in round-robin order,
+/// and `poll_recv` merges the items received from all of them.
+/// Note that the order of items sent via different stripes is not preserved.
+///
+/// A stripe that fails (or is closed by the peer) is removed from the set, and the others continue to work.
+/// An item rejected by a closed stripe is retried on the next one,
+/// but the items already queued in the removed stripe are lost.
+/// Once all of the stripes have failed, the error of the last one is returned.
+///
+/// [`Transport`]: ./trait.Transport.html
+pub struct StripedTcpTransporter<E: Encode, D: Decode> {
+    stripes: Vec<TcpTransporter<E, D>>,
+    peer_addr: SocketAddr,
+    next_send: usize,
+    next_recv: usize,
+    errors: Vec<Error>,
+}
+impl<E, D> StripedTcpTransporter<E, D>
+where
+    E: Encode + Default,
+    D: Decode + Default,
+{
+    /// Starts connecting to the given peer `stripes` times, and
+    /// will return a new `StripedTcpTransporter` instance if all of the connect operations are succeeded.
+    pub fn connect(peer: SocketAddr, stripes: usize) -> impl Future<Item = Self, Error = Error> {
+        let futures = (0..stripes).map(move |_| TcpTransporter::connect(peer));
+        future::join_all(futures).and_then(|stripes| track!(Self::new(stripes)))
+    }
+}
+impl<E: Encode, D: Decode> StripedTcpTransporter<E, D> {
+    /// Makes a new `StripedTcpTransporter` instance that owns the given transporters.
+    ///
+    /// # Errors
+    ///
+    /// If `stripes` is empty or the transporters are connected to different peers,
+    /// this will return an `ErrorKind::InvalidInput` error.
+    pub fn new(stripes: Vec<TcpTransporter<E, D>>) -> Result<Self> {
+        let peer_addr = track_assert_some!(
+            stripes.first().map(|t| t.peer_addr()),
+            ErrorKind::InvalidInput,
+            "No stripes are given"
+        );
+        for t in &stripes {
+            track_assert_eq!(
+                t.peer_addr(),
+                peer_addr,
+                ErrorKind::InvalidInput,
+                "All of the stripes must be connected to the same peer"
+            );
+        }
+        Ok(StripedTcpTransporter {
+            stripes,
+            peer_addr,
+            next_send: 0,
+            next_recv: 0,
+            errors: Vec::new(),
+        })
+    }
+
+    /// Returns the address of the peer to which the stripes are connected.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Returns the number of the stripes that have not been removed.
+    pub fn stripe_count(&self) -> usize {
+        self.stripes.len()
+    }
+
+    /// Returns a reference to the `i`-th stripe.
+    pub fn stripe_ref(&self, i: usize) -> Option<&TcpTransporter<E, D>> {
+        self.stripes.get(i)
+    }
+
+    /// Returns the metrics collected by each stripe.
+    pub fn stripe_metrics(&self) -> impl Iterator<Item = &TransportMetrics> + '_ {
+        self.stripes.iter().map(|t| t.metrics())
+    }
+
+    /// Returns the errors of the stripes that have been removed due to failures.
+    pub fn stripe_errors(&self) -> &[Error] {
+        &self.errors
+    }
+
+    fn flush_stripe(&mut self, i: usize) -> Result<()> {
+        if self.stripes[i].is_eager_flush() {
+            if let Err(e) = self.stripes[i].poll_send() {
+                track!(self.remove_stripe(i, Some(e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_stripe(&mut self, i: usize, error: Option<Error>) -> Result<()> {
+        self.stripes.remove(i);
+        if let Some(e) = error {
+            if self.stripes.is_empty() {
+                return Err(track!(e));
+            }
+            self.errors.push(e);
+        }
+        Ok(())
+    }
+}
+impl<E: Encode, D: Decode> Transport for StripedTcpTransporter<E, D> {
+    type PeerAddr = ();
+    type SendItem = E::Item;
+    type RecvItem = D::Item;
+
+    /// # Errors
+    ///
+    /// If all of the stripes have been removed, this will return an `ErrorKind::Closed` error.
+    ///
+    /// If the queues of all of the remaining stripes are full, this will return the error of the last one.
+    fn start_send(&mut self, (): Self::PeerAddr, mut item: Self::SendItem) -> Result<()> {
+        let mut skipped = 0;
+        loop {
+            track_assert!(
+                !self.stripes.is_empty(),
+                ErrorKind::Closed,
+                "All of the stripes have been removed"
+            );
+            let i = self.next_send % self.stripes.len();
+            let (e, rejected) = match self.stripes[i].try_start_send(item) {
+                Ok(()) => {
+                    self.next_send = i + 1;
+                    return track!(self.flush_stripe(i));
+                }
+                Err(e) => e.into_parts(),
+            };
+            item = rejected;
+            if *e.kind() == ErrorKind::Closed {
+                track!(self.remove_stripe(i, Some(e)))?;
+                self.next_send = i;
+            } else {
+                skipped += 1;
+                if skipped >= self.stripes.len() {
+                    return Err(track!(e; i));
+                }
+                self.next_send = i + 1;
+            }
+        }
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        let mut ready = true;
+        let mut i = 0;
+        while i < self.stripes.len() {
+            match self.stripes[i].poll_send() {
+                Err(e) => track!(self.remove_stripe(i, Some(e)))?,
+                Ok(polled) => {
+                    ready &= polled.is_ready();
+                    i += 1;
+                }
+            }
+        }
+        if ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        for _ in 0..self.stripes.len() {
+            if self.stripes.is_empty() {
+                break;
+            }
+            let i = self.next_recv % self.stripes.len();
+            match self.stripes[i].poll_recv() {
+                Err(e) => track!(self.remove_stripe(i, Some(e)))?,
+                Ok(Async::Ready(None)) => track!(self.remove_stripe(i, None))?,
+                Ok(Async::Ready(Some(((), item)))) => {
+                    self.next_recv = i + 1;
+                    return Ok(Async::Ready(Some(((), item))));
+                }
+                Ok(Async::NotReady) => self.next_recv = i + 1,
+            }
+        }
+        if self.stripes.is_empty() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        for t in &mut self.stripes {
+            track!(t.set_recv_paused(paused))?;
+        }
+        Ok(())
+    }
+}
+impl<E: Encode, D: Decode> fmt::Debug for StripedTcpTransporter<E, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "StripedTcpTransporter {{ peer_addr: {}, stripes: {}, .. }}",
+            self.peer_addr,
+            self.stripes.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{wait_recv, wait_send, TcpListener};
+    use bytecodec::fixnum::{U8Decoder, U8Encoder};
+    use factory::DefaultFactory;
+    use futures::Stream;
+
+    type Server = TcpListener<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>;
+    type Striped = StripedTcpTransporter<U8Encoder, U8Decoder>;
+
+    #[test]
+    fn striped_tcp_transporter_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let mut client = fibers_global::execute(Striped::connect(server.local_addr(), 2))?;
+        assert_eq!(client.stripe_count(), 2);
+
+        for i in 0..4 {
+            client.start_send((), i)?;
+        }
+        let mut client = fibers_global::execute(wait_send(client))?;
+        let sent = client
+            .stripe_metrics()
+            .map(|m| m.sent_messages)
+            .collect::<Vec<_>>();
+        assert_eq!(sent, [2, 2]);
+
+        let accepted = fibers_global::execute(server.take(2).collect())?;
+        for mut server in accepted {
+            let (s, _, item0) = fibers_global::execute(wait_recv(server))?;
+            let (s, _, item1) = fibers_global::execute(wait_recv(s))?;
+            assert_eq!(item1, item0 + 2);
+            server = s;
+            server.start_send((), item0 + 10)?;
+            fibers_global::execute(wait_send(server))?;
+        }
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let (c, (), item) = fibers_global::execute(wait_recv(client))?;
+            received.push(item);
+            client = c;
+        }
+        received.sort();
+        assert_eq!(received, [10, 11]);
+        Ok(())
+    }
+
+    #[test]
+    fn stripe_failover_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let mut client = fibers_global::execute(Striped::connect(server.local_addr(), 2))?;
+        client.stripes[0].shutdown_write()?;
+
+        for i in 0..4 {
+            client.start_send((), i)?;
+        }
+        assert_eq!(client.stripe_count(), 1);
+        assert_eq!(client.stripe_errors().len(), 1);
+
+        let mut client = fibers_global::execute(wait_send(client))?;
+        let sent = client
+            .stripe_metrics()
+            .map(|m| m.sent_messages)
+            .collect::<Vec<_>>();
+        assert_eq!(sent, [4]);
+
+        client.stripes[0].shutdown_write()?;
+        assert!(client.start_send((), 4).is_err());
+        assert_eq!(client.stripe_count(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,557 @@
+//! Platform specific socket and file descriptor operations.
+#[cfg(target_os = "linux")]
+pub use self::linux::*;
+
+#[cfg(not(target_os = "linux"))]
+pub use self::fallback::*;
+
+#[cfg(unix)]
+pub use self::unix::*;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use crate::RecvMetadata;
+    use fibers::net::{TcpStream, UdpSocket};
+    use std::io;
+    use std::mem;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Sets the `TCP_CORK` option of the given stream.
+    pub fn set_tcp_cork(stream: &TcpStream, cork: bool) -> io::Result<()> {
+        let fd = stream.with_inner(|s| s.as_raw_fd());
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_CORK, cork as libc::c_int)
+    }
+
+    /// Returns the number of bytes in the send buffer of the given stream
+    /// that have not been sent or acknowledged yet (`TIOCOUTQ`).
+    pub fn tcp_unsent_bytes(stream: &TcpStream) -> io::Result<usize> {
+        let fd = stream.with_inner(|s| s.as_raw_fd());
+        let mut unsent: libc::c_int = 0;
+        if unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut unsent) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsent as usize)
+    }
+
+    /// Enables the socket options required by `recv_with_metadata`.
+    pub fn enable_recv_metadata(socket: &UdpSocket) -> io::Result<()> {
+        let fd = udp_fd(socket);
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1 as libc::c_int)?;
+        if socket.local_addr()?.is_ipv4() {
+            setsockopt(fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1 as libc::c_int)
+        } else {
+            setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_RECVPKTINFO,
+                1 as libc::c_int,
+            )
+        }
+    }
+
+    /// Sets the don't-fragment bit on the outgoing datagrams of the given socket.
+    ///
+    /// This enables path MTU discovery (`IP_PMTUDISC_DO`), so datagrams exceeding
+    /// the known path MTU are rejected with `EMSGSIZE` instead of being fragmented.
+    pub fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+        let fd = udp_fd(socket);
+        if socket.local_addr()?.is_ipv4() {
+            setsockopt(
+                fd,
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_DO,
+            )
+        } else {
+            setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_DO,
+            )
+        }
+    }
+
+    /// Sets the traffic class (`IPV6_TCLASS`) of the outgoing datagrams of the given IPv6 socket.
+    pub fn set_traffic_class(socket: &UdpSocket, traffic_class: u8) -> io::Result<()> {
+        let fd = udp_fd(socket);
+        setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            libc::c_int::from(traffic_class),
+        )
+    }
+
+    /// Makes the kernel use the flow information of destination addresses (`IPV6_FLOWINFO_SEND`).
+    pub fn enable_flowinfo_send(socket: &UdpSocket) -> io::Result<()> {
+        let fd = udp_fd(socket);
+        setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_FLOWINFO_SEND,
+            1 as libc::c_int,
+        )
+    }
+
+    /// Receives a datagram with its metadata without blocking.
+    pub fn recv_with_metadata(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, RecvMetadata)> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let size = unsafe { libc::recvmsg(udp_fd(socket), &mut msg, libc::MSG_DONTWAIT) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let peer = to_socket_addr(&addr)?;
+
+        let mut metadata = RecvMetadata::default();
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                let data = libc::CMSG_DATA(cmsg);
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                        let ts = (data as *const libc::timespec).read_unaligned();
+                        let elapsed = Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+                        metadata.timestamp = Some(UNIX_EPOCH + elapsed);
+                    }
+                    (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                        let info = (data as *const libc::in_pktinfo).read_unaligned();
+                        let addr = Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr));
+                        metadata.destination = Some(IpAddr::V4(addr));
+                        metadata.interface_index = Some(info.ipi_ifindex as u32);
+                    }
+                    (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                        let info = (data as *const libc::in6_pktinfo).read_unaligned();
+                        let addr = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+                        metadata.destination = Some(IpAddr::V6(addr));
+                        metadata.interface_index = Some(info.ipi6_ifindex as u32);
+                    }
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        Ok((size as usize, peer, metadata))
+    }
+
+    /// Receives datagrams into the given buffers by a single `recvmmsg` call without blocking.
+    ///
+    /// This returns the size and the source address of each received datagram.
+    pub fn recv_batch(
+        socket: &UdpSocket,
+        bufs: &mut [Vec<u8>],
+    ) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let mut addrs: Vec<libc::sockaddr_storage> =
+            (0..bufs.len()).map(|_| unsafe { mem::zeroed() }).collect();
+        let mut iovs = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut msgs = iovs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iov, addr)| {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect::<Vec<_>>();
+
+        let count = unsafe {
+            libc::recvmmsg(
+                udp_fd(socket),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        msgs.iter()
+            .zip(addrs.iter())
+            .take(count as usize)
+            .map(|(msg, addr)| Ok((msg.msg_len as usize, to_socket_addr(addr)?)))
+            .collect()
+    }
+
+    /// Returns the file descriptor of the given socket.
+    pub fn udp_fd(socket: &UdpSocket) -> RawFd {
+        socket.with_inner(|s| s.as_raw_fd())
+    }
+
+    fn to_socket_addr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { *(addr as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                let port = u16::from_be(addr.sin_port);
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { *(addr as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                let port = u16::from_be(addr.sin6_port);
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    port,
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported address family: {}", family),
+            )),
+        }
+    }
+
+    fn setsockopt<T>(
+        fd: libc::c_int,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: T,
+    ) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+        if result == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod fallback {
+    use fibers::net::{TcpStream, UdpSocket};
+    use std::io;
+
+    /// Sets the don't-fragment bit on the outgoing datagrams of the given socket.
+    ///
+    /// This is a no-op on non-Linux platforms.
+    pub fn set_dont_fragment(_socket: &UdpSocket) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sets the traffic class of the outgoing datagrams of the given IPv6 socket.
+    ///
+    /// This is a no-op on non-Linux platforms.
+    pub fn set_traffic_class(_socket: &UdpSocket, _traffic_class: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes the kernel use the flow information of destination addresses.
+    ///
+    /// This is a no-op on non-Linux platforms.
+    pub fn enable_flowinfo_send(_socket: &UdpSocket) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sets the `TCP_CORK` option of the given stream.
+    ///
+    /// This is a no-op on non-Linux platforms.
+    pub fn set_tcp_cork(_stream: &TcpStream, _cork: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the number of bytes in the send buffer of the given stream
+    /// that have not been sent or acknowledged yet.
+    ///
+    /// This always returns `0` on non-Linux platforms.
+    pub fn tcp_unsent_bytes(_stream: &TcpStream) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use fibers::fiber;
+    use fibers::io::poll::{EventedHandle, Interest, Register};
+    use fibers::sync::oneshot::{Monitor, MonitorError};
+    use futures::{Async, Future, Poll};
+    use mio::unix::EventedFd;
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::sync::Arc;
+
+    /// An owned file descriptor that is closed when dropped.
+    #[derive(Debug)]
+    pub struct OwnedFd(RawFd);
+    impl OwnedFd {
+        /// Makes a new `OwnedFd` instance by duplicating the given file descriptor.
+        pub fn dup(fd: RawFd) -> io::Result<Self> {
+            let fd = unsafe { libc::dup(fd) };
+            if fd < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(OwnedFd(fd))
+            }
+        }
+
+        /// Sets (if `nonblocking` is `true`) or clears `O_NONBLOCK` on the file descriptor,
+        /// and returns whether the flag was set before the call.
+        ///
+        /// Note that the flag is shared by all of the descriptors referring to the same open file.
+        pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<bool> {
+            let flags = unsafe { libc::fcntl(self.0, libc::F_GETFL) };
+            if flags < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let new_flags = if nonblocking {
+                flags | libc::O_NONBLOCK
+            } else {
+                flags & !libc::O_NONBLOCK
+            };
+            if unsafe { libc::fcntl(self.0, libc::F_SETFL, new_flags) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(flags & libc::O_NONBLOCK != 0)
+        }
+    }
+    impl AsRawFd for OwnedFd {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0
+        }
+    }
+    impl Drop for OwnedFd {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.0);
+            }
+        }
+    }
+    impl mio::Evented for OwnedFd {
+        fn register(
+            &self,
+            poll: &mio::Poll,
+            token: mio::Token,
+            interest: mio::Ready,
+            opts: mio::PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0).register(poll, token, interest, opts)
+        }
+
+        fn reregister(
+            &self,
+            poll: &mio::Poll,
+            token: mio::Token,
+            interest: mio::Ready,
+            opts: mio::PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0).reregister(poll, token, interest, opts)
+        }
+
+        fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+            EventedFd(&self.0).deregister(poll)
+        }
+    }
+
+    /// Readiness notifier for operations performed directly on a file descriptor.
+    ///
+    /// It registers a duplicate of the descriptor to the poller of the current fiber,
+    /// so that the fiber can wait for the descriptor to become readable or writable.
+    #[derive(Debug)]
+    pub struct Readiness {
+        state: ReadinessState,
+        read_monitor: Option<Monitor<(), io::Error>>,
+        write_monitor: Option<Monitor<(), io::Error>>,
+    }
+    impl Readiness {
+        pub fn new(fd: RawFd) -> io::Result<Self> {
+            let fd = OwnedFd::dup(fd)?;
+            Ok(Readiness {
+                state: ReadinessState::Unregistered(Some(fd)),
+                read_monitor: None,
+                write_monitor: None,
+            })
+        }
+
+        /// Waits until the descriptor becomes readable.
+        ///
+        /// This should be called after a read operation returned `WouldBlock`.
+        pub fn poll_readable(&mut self) -> Poll<(), io::Error> {
+            self.poll_ready(Interest::Read)
+        }
+
+        /// Waits until the descriptor becomes writable.
+        ///
+        /// This should be called after a write operation returned `WouldBlock`.
+        pub fn poll_writable(&mut self) -> Poll<(), io::Error> {
+            self.poll_ready(Interest::Write)
+        }
+
+        fn poll_ready(&mut self, interest: Interest) -> Poll<(), io::Error> {
+            let handle = if let Async::Ready(handle) = self.poll_registered()? {
+                handle
+            } else {
+                return Ok(Async::NotReady);
+            };
+            let monitor = match interest {
+                Interest::Read => &mut self.read_monitor,
+                Interest::Write => &mut self.write_monitor,
+            };
+            let mut m = monitor.take().unwrap_or_else(|| handle.monitor(interest));
+            match m.poll().map_err(from_monitor_error)? {
+                Async::NotReady => {
+                    *monitor = Some(m);
+                    Ok(Async::NotReady)
+                }
+                Async::Ready(()) => Ok(Async::Ready(())),
+            }
+        }
+
+        fn poll_registered(&mut self) -> Poll<Arc<EventedHandle<OwnedFd>>, io::Error> {
+            loop {
+                let next = match self.state {
+                    ReadinessState::Unregistered(ref mut fd) => {
+                        // Outside of fibers, there is no poller to register to (and no one to wake up).
+                        if fiber::with_current_context(|_| ()).is_none() {
+                            return Ok(Async::NotReady);
+                        }
+                        let fd = fd.take().expect("never fails");
+                        let register = fiber::with_current_context(|mut c| c.poller().register(fd));
+                        ReadinessState::Registering(register.expect("never fails"))
+                    }
+                    ReadinessState::Registering(ref mut register) => {
+                        if let Async::Ready(handle) = register.poll().map_err(io::Error::other)? {
+                            ReadinessState::Registered(handle)
+                        } else {
+                            return Ok(Async::NotReady);
+                        }
+                    }
+                    ReadinessState::Registered(ref handle) => {
+                        return Ok(Async::Ready(handle.clone()));
+                    }
+                };
+                self.state = next;
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    enum ReadinessState {
+        Unregistered(Option<OwnedFd>),
+        Registering(Register<OwnedFd>),
+        Registered(Arc<EventedHandle<OwnedFd>>),
+    }
+
+    /// Non-blocking stream over a file descriptor (e.g., a pipe or a terminal).
+    ///
+    /// Like the streams of `fibers`, read and write operations return `WouldBlock` errors
+    /// and arrange for the current fiber to be woken up once the descriptor becomes ready.
+    ///
+    /// If the descriptor was in blocking mode, it is put back into blocking mode when the stream is dropped.
+    #[derive(Debug)]
+    pub struct FdStream {
+        fd: OwnedFd,
+        readiness: Readiness,
+        restore_blocking: bool,
+    }
+    impl FdStream {
+        /// Makes a new `FdStream` instance from a duplicate of the given descriptor.
+        ///
+        /// Note that this puts the open file referred to by the descriptor into non-blocking mode.
+        pub fn new(fd: RawFd) -> io::Result<Self> {
+            let fd = OwnedFd::dup(fd)?;
+            let was_nonblocking = fd.set_nonblocking(true)?;
+            let readiness = Readiness::new(fd.as_raw_fd())?;
+            Ok(FdStream {
+                fd,
+                readiness,
+                restore_blocking: !was_nonblocking,
+            })
+        }
+
+        fn operate<F>(&mut self, interest: Interest, mut f: F) -> io::Result<usize>
+        where
+            F: FnMut(RawFd) -> libc::ssize_t,
+        {
+            loop {
+                let result = f(self.fd.as_raw_fd());
+                if result >= 0 {
+                    return Ok(result as usize);
+                }
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::Interrupted => {}
+                    io::ErrorKind::WouldBlock => {
+                        let ready = match interest {
+                            Interest::Read => self.readiness.poll_readable()?,
+                            Interest::Write => self.readiness.poll_writable()?,
+                        };
+                        if ready.is_not_ready() {
+                            return Err(e);
+                        }
+                    }
+                    _ => return Err(e),
+                }
+            }
+        }
+    }
+    impl Read for FdStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.operate(Interest::Read, |fd| unsafe {
+                libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+            })
+        }
+    }
+    impl Write for FdStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.operate(Interest::Write, |fd| unsafe {
+                libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len())
+            })
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Drop for FdStream {
+        fn drop(&mut self) {
+            if self.restore_blocking {
+                let _ = self.fd.set_nonblocking(false);
+            }
+        }
+    }
+
+    fn from_monitor_error(e: MonitorError<io::Error>) -> io::Error {
+        match e {
+            // Wrapped in the same way as `fibers` does, so that it is reported as `ErrorKind::ExecutorStopped`.
+            MonitorError::Aborted => io::Error::other(Box::new(e)),
+            MonitorError::Failed(e) => e,
+        }
+    }
+}
@@ -1,11 +1,37 @@
-use crate::base::Transport;
-use crate::{Error, PollRecv, PollSend, Result};
-use bytecodec::io::{BufferedIo, IoDecodeExt, IoEncodeExt};
-use bytecodec::{Decode, Encode};
+use crate::base::{wait_send, Transport};
+use crate::error::CodecErrorMapper;
+use crate::framing::{FramedDecoder, FramedEncoder};
+use crate::priority::{ItemTag, PriorityQueue, SendCallback};
+use crate::socks5::{self, Socks5Proxy};
+use crate::sys;
+use crate::tcp_listener::ConnectionGuard;
+use crate::{
+    Clock, Error, ErrorKind, FibersClock, FibersDelay, PollRecv, PollSend, Priority, Result,
+    SendError, TransportMetrics,
+};
+use bytecodec::io::{BufferedIo, IoDecodeExt, IoEncodeExt, ReadBuf, WriteBuf};
+use bytecodec::{ByteCount, Decode, Encode};
 use fibers::net::TcpStream;
-use futures::{Async, Future};
+use fibers::sync::{mpsc, oneshot};
+use futures::future::Either;
+use futures::{Async, Future, Poll};
 use std::collections::VecDeque;
-use std::net::SocketAddr;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+type HandshakeFn<E, D> = Box<
+    dyn FnOnce(
+            TcpTransporter<E, D>,
+        ) -> Box<dyn Future<Item = TcpTransporter<E, D>, Error = Error> + Send>
+        + Send,
+>;
+
+/// The interval at which `TcpTransporter::poll_flushed` re-checks the send buffer of the kernel.
+const FLUSH_CHECK_INTERVAL: Duration = Duration::from_millis(5);
 
 /// This trait indicates that the implementation implements TCP.
 pub trait TcpTransport: Transport<PeerAddr = ()> {
@@ -20,8 +46,24 @@ pub trait TcpTransport: Transport<PeerAddr = ()> {
 ///
 /// [`TcpTransporter`]: ./struct.TcpTransporter.html
 #[derive(Debug)]
-pub struct TcpTransporterBuilder<E, D> {
+pub struct TcpTransporterBuilder<E: Encode, D: Decode> {
     buf_size: usize,
+    cork_messages: bool,
+    write_coalesce_bytes: usize,
+    queue_capacity: usize,
+    max_queue_len: Option<usize>,
+    write_buf_threshold: Option<usize>,
+    eager_flush: bool,
+    strict_encode: bool,
+    track_queue_latency: bool,
+    read_buf_grow: bool,
+    max_read_buf_size: usize,
+    adaptive_buffer: Option<(usize, usize)>,
+    preserve_boundaries: bool,
+    socks5_proxy: Option<Socks5Proxy>,
+    handshake: Option<Handshake<E, D>>,
+    codec_error_mapper: CodecErrorMapper,
+    state_subscribers: Vec<mpsc::Sender<ConnectionState>>,
     encoder: E,
     decoder: D,
 }
@@ -40,6 +82,22 @@ impl<E: Encode, D: Decode> TcpTransporterBuilder<E, D> {
     pub fn with_codec(encoder: E, decoder: D) -> Self {
         TcpTransporterBuilder {
             buf_size: 8192,
+            cork_messages: false,
+            write_coalesce_bytes: 0,
+            queue_capacity: 0,
+            max_queue_len: None,
+            write_buf_threshold: None,
+            eager_flush: true,
+            strict_encode: cfg!(debug_assertions),
+            track_queue_latency: false,
+            read_buf_grow: false,
+            max_read_buf_size: 1024 * 1024,
+            adaptive_buffer: None,
+            preserve_boundaries: false,
+            socks5_proxy: None,
+            handshake: None,
+            codec_error_mapper: CodecErrorMapper::default(),
+            state_subscribers: Vec::new(),
             encoder,
             decoder,
         }
@@ -53,29 +111,362 @@ impl<E: Encode, D: Decode> TcpTransporterBuilder<E, D> {
         self
     }
 
+    /// Sets whether to cork the stream (i.e., set `TCP_CORK`) while outgoing messages are being written.
+    ///
+    /// If `true`, the stream is corked when the transporter starts writing a message,
+    /// and uncorked once all of the outstanding messages have been handed to the kernel.
+    /// This reduces the number of small packets sent for a burst of messages.
+    ///
+    /// This option only has an effect on Linux, and is ignored on the other platforms.
+    ///
+    /// The default value is `false`.
+    pub fn cork_messages(mut self, enabled: bool) -> Self {
+        self.cork_messages = enabled;
+        self
+    }
+
+    /// Sets the number of bytes of encoded messages accumulated in the write buffer before writing them to the stream.
+    ///
+    /// While the accumulated bytes are fewer than this value and there are more queued messages,
+    /// `poll_send` encodes the next message without issuing any I/O.
+    /// This trades a little latency for fewer write syscalls when many tiny messages are queued.
+    /// Note that the effective value is capped by the write buffer size.
+    ///
+    /// If `0` is specified, the coalescing is disabled.
+    ///
+    /// The default value is `0`.
+    pub fn write_coalesce_bytes(mut self, bytes: usize) -> Self {
+        self.write_coalesce_bytes = bytes;
+        self
+    }
+
+    /// Sets the function that converts the errors of the encoder and decoder into `Error`.
+    ///
+    /// This is applied to the codec errors that occurred in `poll_send` and `poll_recv`,
+    /// and allows for classifying them by custom rules (e.g., regarding some decode errors as `ErrorKind::InvalidInput`).
+    ///
+    /// By default, `Error::from` is used (i.e., I/O errors are classified by their kinds, and the others are `ErrorKind::CodecError`).
+    pub fn codec_error_mapper<F>(mut self, f: F) -> Self
+    where
+        F: Fn(bytecodec::Error) -> Error + Send + Sync + 'static,
+    {
+        self.codec_error_mapper = CodecErrorMapper::new(f);
+        self
+    }
+
+    /// Sets the initial capacity of the outgoing message queue of the resulting instance.
+    ///
+    /// Pre-allocating the queue avoids reallocations while a burst of messages is being enqueued.
+    /// The capacity is reserved for each priority of messages (see [`Priority`]).
+    ///
+    /// The default value is `0`.
+    ///
+    /// [`Priority`]: ./enum.Priority.html
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// Sets the maximum number of messages in the outgoing queue of the resulting instance.
+    ///
+    /// If the queue is full, `start_send` will return an `ErrorKind::Other` error,
+    /// and `try_start_send` will hand the rejected message back.
+    ///
+    /// By default, the length of the queue is unlimited.
+    pub fn max_queue_len(mut self, len: usize) -> Self {
+        self.max_queue_len = Some(len);
+        self
+    }
+
+    /// Sets the number of bytes in the write buffer above which `poll_send_ready` applies backpressure.
+    ///
+    /// While the write buffer holds more bytes than this value
+    /// (i.e., the peer does not read the sent bytes fast enough),
+    /// `poll_send_ready` returns `Ok(Async::NotReady)` so that the caller can refrain from enqueueing more messages.
+    ///
+    /// By default, no backpressure is applied.
+    pub fn write_buf_threshold(mut self, bytes: usize) -> Self {
+        self.write_buf_threshold = Some(bytes);
+        self
+    }
+
+    /// Sets whether `start_send` immediately tries to write the enqueued message.
+    ///
+    /// If `false`, `start_send` only enqueues the message,
+    /// and all of the I/O is left to the explicit calls of `poll_send` (e.g., via `wait_send`).
+    /// This is useful for batching many messages before driving the transporter once.
+    ///
+    /// The default value is `true`.
+    pub fn eager_flush(mut self, enabled: bool) -> Self {
+        self.eager_flush = enabled;
+        self
+    }
+
+    /// Sets whether to check that the encoder produces at least one byte for each message.
+    ///
+    /// If `true` and the encoder becomes idle without producing any bytes for a message
+    /// (e.g., due to a bug of the encoder), `poll_send` will return an `ErrorKind::CodecError` error
+    /// instead of silently dropping the message.
+    /// Messages for which the encoder reports `ByteCount::Finite(0)` as the requiring bytes
+    /// right after `start_encoding` (e.g., empty strings) are legitimately encoded to zero bytes and not checked.
+    ///
+    /// The default value is `true` in debug builds, and `false` in release builds.
+    pub fn strict_encode(mut self, enabled: bool) -> Self {
+        self.strict_encode = enabled;
+        self
+    }
+
+    /// Sets whether to measure the queue latency of each outgoing message.
+    ///
+    /// If `true`, each enqueued message is tagged with the time of the enqueue,
+    /// and the time until it is taken from the queue to be written is recorded to
+    /// the metrics of the resulting instance (see [`TransportMetrics::avg_queue_latency`]).
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`TransportMetrics::avg_queue_latency`]: ./struct.TransportMetrics.html#method.avg_queue_latency
+    pub fn track_queue_latency(mut self, enabled: bool) -> Self {
+        self.track_queue_latency = enabled;
+        self
+    }
+
+    /// Sets whether to grow the read buffer if it is filled with the bytes of an incomplete item.
+    ///
+    /// Some decoders do not consume any bytes until a whole frame is available in the buffer
+    /// (e.g., decoders of large length-prefixed frames).
+    /// If `true`, the read buffer is grown (by doubling its size) up to the size specified by
+    /// `max_read_buf_size` so that such a frame can be decoded.
+    /// If the maximum size is exceeded, `poll_recv` will return an `ErrorKind::InvalidInput` error.
+    ///
+    /// The default value is `false`.
+    pub fn read_buf_grow(mut self, enabled: bool) -> Self {
+        self.read_buf_grow = enabled;
+        self
+    }
+
+    /// Sets the maximum size of the read buffer in byte when `read_buf_grow` is enabled.
+    ///
+    /// The default value is `1048576` (i.e., 1MiB).
+    pub fn max_read_buf_size(mut self, size: usize) -> Self {
+        self.max_read_buf_size = size;
+        self
+    }
+
+    /// Enables the adaptive sizing of the application level read/write buffers.
+    ///
+    /// If enabled, the buffers start with `min` bytes (instead of `buf_size`).
+    /// A buffer that is found full after an I/O operation is grown (by doubling its size) up to `max` bytes,
+    /// and a buffer that remains empty for a while (i.e., during an idle period) is shrunk
+    /// (by halving its size) down to `min` bytes.
+    /// The current sizes can be retrieved by `recv_buf_capacity` and `send_buf_capacity`.
+    ///
+    /// By default, the adaptive sizing is disabled.
+    pub fn adaptive_buffer(mut self, min: usize, max: usize) -> Self {
+        self.adaptive_buffer = Some((min, std::cmp::max(min, max)));
+        self
+    }
+
+    /// Sets whether to preserve the boundaries of messages.
+    ///
+    /// If `true`, each encoded message is prefixed by its length (32-bit big-endian),
+    /// and the decoder is given exactly the bytes of one message (followed by EOS) at a time.
+    /// Thus each item passed to `start_send` maps to exactly one item returned by `poll_recv`
+    /// regardless of TCP segmentation, even if the codec does not delimit messages by itself
+    /// (e.g., `BytesEncoder` and `RemainingBytesDecoder`).
+    ///
+    /// Note that both ends of the connection must enable this option.
+    ///
+    /// The default value is `false`.
+    pub fn preserve_boundaries(mut self, enabled: bool) -> Self {
+        self.preserve_boundaries = enabled;
+        self
+    }
+
+    /// Sets the SOCKS5 proxy through which `connect` establishes connections.
+    ///
+    /// If set, `connect` connects to `proxy` and asks it to connect to the destination
+    /// (only IP addresses are supported as destinations).
+    /// If `auth` is `Some((username, password))`, the username/password authentication is performed.
+    /// The `peer_addr` of the resulting transporter reports the destination rather than the proxy.
+    ///
+    /// If the proxy refuses the request or violates the protocol,
+    /// `connect` will fail with an `ErrorKind::ProxyError` error.
+    ///
+    /// By default, no proxy is used.
+    pub fn socks5_proxy(mut self, proxy: SocketAddr, auth: Option<(String, String)>) -> Self {
+        self.socks5_proxy = Some(Socks5Proxy { addr: proxy, auth });
+        self
+    }
+
+    /// Sets the handshake performed by `connect` on the freshly connected transporter.
+    ///
+    /// The future returned by `handshake` can exchange arbitrary messages with the peer
+    /// (e.g., for authentication or version negotiation),
+    /// and the `connect` future resolves to the transporter yielded by it.
+    /// If the handshake fails, the `connect` future fails with the same error.
+    ///
+    /// Note that the handshake is only performed by `connect` (and `connect_abortable`), not by `finish`.
+    ///
+    /// By default, no handshake is performed.
+    pub fn with_handshake<H, F>(mut self, handshake: H) -> Self
+    where
+        H: FnOnce(TcpTransporter<E, D>) -> F + Send + 'static,
+        F: Future<Item = TcpTransporter<E, D>, Error = Error> + Send + 'static,
+    {
+        let f: HandshakeFn<E, D> = Box::new(move |transporter| Box::new(handshake(transporter)));
+        self.handshake = Some(Handshake(f));
+        self
+    }
+
+    /// Returns a stream that emits the state transitions of the connection of the resulting instance.
+    ///
+    /// Unlike [`TcpTransporter::state_changes`], this also observes the connecting phase:
+    /// `connect` emits `ConnectionState::Connecting` when it is called,
+    /// and then `ConnectionState::Connected` (or `ConnectionState::Errored` if it fails).
+    /// The subsequent transitions are emitted in the same way as [`TcpTransporter::state_changes`].
+    ///
+    /// [`TcpTransporter::state_changes`]: ./struct.TcpTransporter.html#method.state_changes
+    pub fn state_changes(&mut self) -> mpsc::Receiver<ConnectionState> {
+        let (tx, rx) = mpsc::channel();
+        self.state_subscribers.push(tx);
+        rx
+    }
+
     /// Builds a `TcpTransporterBuilder` instance from the given `TcpStream`.
-    pub fn finish(self, stream: TcpStream) -> Result<TcpTransporter<E, D>> {
+    ///
+    /// Note that [`fibers`] provides no way to convert a `std::net::TcpStream` into
+    /// a `fibers::net::TcpStream`, so streams created elsewhere (e.g., by `socket2`)
+    /// cannot be wrapped directly.
+    /// Socket options can instead be configured via `TcpStream::with_inner`.
+    ///
+    /// [`fibers`]: https://crates.io/crates/fibers
+    pub fn finish(mut self, stream: TcpStream) -> Result<TcpTransporter<E, D>> {
         let _ = stream.set_nodelay(true);
         let peer_addr = track!(stream.peer_addr().map_err(Error::from))?;
         let local_addr = track!(stream.local_addr().map_err(Error::from))?;
+        let cork_messages = self.cork_messages;
+        let state = StateTracker::new(std::mem::take(&mut self.state_subscribers));
         Ok(TcpTransporter {
-            stream: BufferedIo::new(stream, self.buf_size, self.buf_size),
+            inner: self.finish_with_stream(stream),
             peer_addr,
             local_addr,
-            encoder: self.encoder,
-            decoder: self.decoder,
-            outgoing_queue: VecDeque::new(),
+            cork_messages,
+            corked: false,
+            connection_guard: None,
+            flush_check: None,
+            state,
         })
     }
 
+    /// Builds a `TcpTransporter` instance from the given `TcpStream` only if `pred` accepts its peer address.
+    ///
+    /// If `pred` returns `false`, the stream is dropped (i.e., closed) and this returns `Ok(None)`.
+    /// This is useful for filtering peers in custom accept loops.
+    pub fn finish_if<F>(self, stream: TcpStream, pred: F) -> Result<Option<TcpTransporter<E, D>>>
+    where
+        F: FnOnce(SocketAddr) -> bool,
+    {
+        let peer_addr = track!(stream.peer_addr().map_err(Error::from))?;
+        if !pred(peer_addr) {
+            return Ok(None);
+        }
+        track!(self.finish(stream)).map(Some)
+    }
+
+    /// Builds a [`StreamTransporter`] instance from the given stream.
+    ///
+    /// The stream must be non-blocking (i.e., return `io::ErrorKind::WouldBlock` if it is not ready).
+    /// The TCP specific settings (i.e., `cork_messages`) are ignored.
+    ///
+    /// [`StreamTransporter`]: ./struct.StreamTransporter.html
+    pub fn finish_with_stream<S: Read + Write>(self, stream: S) -> StreamTransporter<S, E, D> {
+        let buf_size = self.adaptive_buffer.map_or(self.buf_size, |(min, _)| min);
+        StreamTransporter {
+            stream: BufferedIo::new(stream, buf_size, buf_size),
+            encoder: FramedEncoder::new(self.encoder, self.preserve_boundaries),
+            decoder: FramedDecoder::new(self.decoder, self.preserve_boundaries),
+            decoding_item: false,
+            outgoing_queue: PriorityQueue::with_capacity(self.queue_capacity),
+            max_queue_len: self.max_queue_len,
+            write_coalesce_bytes: self.write_coalesce_bytes,
+            write_buf_threshold: self.write_buf_threshold,
+            write_buf_high_water: 0,
+            eager_flush: self.eager_flush,
+            strict_encode: self.strict_encode,
+            encoding_start: None,
+            track_queue_latency: self.track_queue_latency,
+            codec_error_mapper: self.codec_error_mapper,
+            max_read_buf_size: if self.read_buf_grow {
+                Some(self.max_read_buf_size)
+            } else {
+                None
+            },
+            adaptive_buffer: self
+                .adaptive_buffer
+                .map(|(min, max)| AdaptiveBuffer::new(min, max)),
+            encoding_callback: None,
+            written_callbacks: VecDeque::new(),
+            encoded_bytes: 0,
+            written_bytes: 0,
+            read_closed: false,
+            write_closed: false,
+            recv_paused: false,
+            metrics: TransportMetrics::new(),
+        }
+    }
+
     /// Builds a `TcpTransporterBuilder` instance by connecting to the specified peer.
+    ///
+    /// If a handshake is set by `with_handshake`, the resulting future completes after the handshake.
     pub fn connect(
-        self,
+        mut self,
         peer: SocketAddr,
     ) -> impl Future<Item = TcpTransporter<E, D>, Error = Error> {
-        TcpStream::connect(peer)
-            .map_err(|e| track!(Error::from(e)))
+        let handshake = self.handshake.take();
+        let subscribers = self.state_subscribers.clone();
+        for tx in &subscribers {
+            let _ = tx.send(ConnectionState::Connecting);
+        }
+        let connected = if let Some(proxy) = self.socks5_proxy.take() {
+            Either::A(socks5::connect(proxy, peer))
+        } else {
+            Either::B(TcpStream::connect(peer).map_err(|e| track!(Error::from(e))))
+        };
+        connected
             .and_then(move |stream| track!(self.finish(stream)))
+            .map(move |mut transporter| {
+                // The stream may be connected to a proxy.
+                transporter.peer_addr = peer;
+                transporter
+            })
+            .and_then(move |transporter| match handshake {
+                None => Either::A(futures::finished(transporter)),
+                Some(Handshake(f)) => {
+                    Either::B(f(transporter).map_err(|e| track!(e, "while handshaking")))
+                }
+            })
+            .map_err(move |e| {
+                for tx in &subscribers {
+                    let _ = tx.send(ConnectionState::Errored);
+                }
+                e
+            })
+    }
+
+    /// Builds a `TcpTransporterBuilder` instance by connecting to the specified peer,
+    /// and returns a [`ConnectHandle`] that can abort the connect attempt.
+    ///
+    /// See [`ConnectHandle`] for the semantics of aborting.
+    ///
+    /// [`ConnectHandle`]: ./struct.ConnectHandle.html
+    pub fn connect_abortable(
+        self,
+        peer: SocketAddr,
+    ) -> (
+        impl Future<Item = TcpTransporter<E, D>, Error = Error>,
+        ConnectHandle,
+    ) {
+        Abortable::new(self.connect(peer))
     }
 }
 impl<E, D> Default for TcpTransporterBuilder<E, D>
@@ -88,129 +479,2379 @@ where
     }
 }
 
-/// An implementation of [`Transport`] that uses TCP as the transport layer.
+struct Handshake<E: Encode, D: Decode>(HandshakeFn<E, D>);
+impl<E: Encode, D: Decode> fmt::Debug for Handshake<E, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handshake(_)")
+    }
+}
+
+/// A handle for aborting an in-progress connect attempt.
 ///
-/// [`Transport`]: ./trait.Transport.html
+/// This is returned by [`TcpTransporter::connect_abortable`].
+///
+/// Calling `abort` wakes up the associated future, which then fails with an `ErrorKind::Other` error
+/// and closes the socket being connected.
+/// Note that the socket is released when the future is polled next (or dropped), not by `abort` itself.
+/// Dropping the handle does not abort the connect attempt.
+///
+/// [`TcpTransporter::connect_abortable`]: ./struct.TcpTransporter.html#method.connect_abortable
 #[derive(Debug)]
-pub struct TcpTransporter<E: Encode, D: Decode> {
-    stream: BufferedIo<TcpStream>,
-    peer_addr: SocketAddr,
-    local_addr: SocketAddr,
-    decoder: D,
-    encoder: E,
-    outgoing_queue: VecDeque<E::Item>,
+pub struct ConnectHandle {
+    abort_tx: oneshot::Sender<()>,
 }
-impl<E, D> TcpTransporter<E, D>
+impl ConnectHandle {
+    /// Aborts the connect attempt.
+    ///
+    /// This has no effect if the connect attempt has already been completed.
+    pub fn abort(self) {
+        let _ = self.abort_tx.send(());
+    }
+}
+
+/// A future that can be aborted via `ConnectHandle`.
+#[derive(Debug)]
+struct Abortable<F> {
+    future: Option<F>,
+    abort_rx: Option<oneshot::Receiver<()>>,
+}
+impl<F: Future<Error = Error>> Abortable<F> {
+    fn new(future: F) -> (Self, ConnectHandle) {
+        let (abort_tx, abort_rx) = oneshot::channel();
+        let this = Abortable {
+            future: Some(future),
+            abort_rx: Some(abort_rx),
+        };
+        (this, ConnectHandle { abort_tx })
+    }
+}
+impl<F: Future<Error = Error>> Future for Abortable<F> {
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let aborted = match self.abort_rx.as_mut().map(|rx| rx.poll()) {
+            None | Some(Ok(Async::NotReady)) => false,
+            Some(Ok(Async::Ready(()))) => true,
+            Some(Err(_)) => {
+                // The handle has been dropped without aborting.
+                self.abort_rx = None;
+                false
+            }
+        };
+        if aborted {
+            self.future = None;
+            self.abort_rx = None;
+        }
+        let future = track_assert_some!(
+            self.future.as_mut(),
+            ErrorKind::Other,
+            "The connect attempt has been aborted"
+        );
+        track!(future.poll())
+    }
+}
+
+/// An implementation of [`Transport`] that runs the codecs over an arbitrary byte stream
+/// (e.g., a Unix domain socket, a TLS session or an in-memory pipe).
+///
+/// The stream must be non-blocking (i.e., return `io::ErrorKind::WouldBlock` if it is not ready).
+/// [`TcpTransporter`] is built on top of this.
+///
+/// Instances having custom settings can be built by [`TcpTransporterBuilder::finish_with_stream`].
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`TcpTransporter`]: ./struct.TcpTransporter.html
+/// [`TcpTransporterBuilder::finish_with_stream`]: ./struct.TcpTransporterBuilder.html#method.finish_with_stream
+pub struct StreamTransporter<S, E: Encode, D: Decode> {
+    stream: BufferedIo<S>,
+    decoder: FramedDecoder<D>,
+    decoding_item: bool,
+    encoder: FramedEncoder<E>,
+    outgoing_queue: PriorityQueue<E::Item>,
+    max_queue_len: Option<usize>,
+    write_coalesce_bytes: usize,
+    write_buf_threshold: Option<usize>,
+    write_buf_high_water: usize,
+    eager_flush: bool,
+    strict_encode: bool,
+    encoding_start: Option<u64>,
+    track_queue_latency: bool,
+    codec_error_mapper: CodecErrorMapper,
+    max_read_buf_size: Option<usize>,
+    adaptive_buffer: Option<AdaptiveBuffer>,
+    encoding_callback: Option<SendCallback>,
+    written_callbacks: VecDeque<(u64, SendCallback)>,
+    encoded_bytes: u64,
+    written_bytes: u64,
+    read_closed: bool,
+    write_closed: bool,
+    recv_paused: bool,
+    metrics: TransportMetrics,
+}
+impl<S, E, D> StreamTransporter<S, E, D>
 where
+    S: Read + Write,
     E: Encode + Default,
     D: Decode + Default,
 {
-    /// Starts connecting to the given peer and
-    /// will return a new `TcpTransporter` instance if the connect operation is succeeded.
-    ///
-    /// This is equivalent to `TcpTransporterBuilder::new().connect(peer)`.
-    pub fn connect(peer: SocketAddr) -> impl Future<Item = Self, Error = Error> {
-        TcpTransporterBuilder::new().connect(peer)
-    }
-
-    /// Makes a new `TcpTransporter` instance from the given `TcpStream`.
+    /// Makes a new `StreamTransporter` instance from the given stream.
     ///
-    /// This is equivalent to `TcpTransporterBuilder::new().finish(stream)`.
-    pub fn from_stream(stream: TcpStream) -> Result<Self> {
-        TcpTransporterBuilder::new().finish(stream)
+    /// This is equivalent to `TcpTransporterBuilder::new().finish_with_stream(stream)`.
+    pub fn new(stream: S) -> Self {
+        TcpTransporterBuilder::new().finish_with_stream(stream)
     }
 }
-impl<E: Encode, D: Decode> TcpTransporter<E, D> {
+impl<S: Read + Write, E: Encode, D: Decode> StreamTransporter<S, E, D> {
     /// Returns the number of unsent messages in the queue of the instance.
     pub fn message_queue_len(&self) -> usize {
         self.outgoing_queue.len() + if self.encoder.is_idle() { 0 } else { 1 }
     }
 
-    /// Returns a reference to the TCP stream being used by the instance.
-    pub fn stream_ref(&self) -> &TcpStream {
+    /// Returns a reference to the stream being used by the instance.
+    pub fn stream_ref(&self) -> &S {
         self.stream.stream_ref()
     }
 
-    /// Returns a mutable reference to the TCP stream being used by the instance.
-    pub fn stream_mut(&mut self) -> &mut TcpStream {
+    /// Returns a mutable reference to the stream being used by the instance.
+    pub fn stream_mut(&mut self) -> &mut S {
         self.stream.stream_mut()
     }
 
     /// Returns a reference to the decoder being used by the instance.
     pub fn decoder_ref(&self) -> &D {
-        &self.decoder
+        self.decoder.inner_ref()
     }
 
     /// Returns a mutable reference to the decoder being used by the instance.
     pub fn decoder_mut(&mut self) -> &mut D {
-        &mut self.decoder
+        self.decoder.inner_mut()
     }
 
     /// Returns a reference to the encoder being used by the instance.
     pub fn encoder_ref(&self) -> &E {
-        &self.encoder
+        self.encoder.inner_ref()
     }
 
     /// Returns a mutable reference to the encoder being used by the instance.
     pub fn encoder_mut(&mut self) -> &mut E {
-        &mut self.encoder
+        self.encoder.inner_mut()
     }
-}
-impl<E: Encode, D: Decode> Transport for TcpTransporter<E, D> {
-    type PeerAddr = ();
-    type SendItem = E::Item;
-    type RecvItem = D::Item;
 
-    fn start_send(&mut self, (): Self::PeerAddr, item: Self::SendItem) -> Result<()> {
-        self.outgoing_queue.push_back(item);
-        track!(self.poll_send())?;
+    /// Returns `true` if both the read and write halves of the stream have been closed, otherwise `false`.
+    pub fn is_closed(&self) -> bool {
+        self.read_closed && self.write_closed
+    }
+
+    /// Returns `true` if the read half of the stream has been closed, otherwise `false`.
+    ///
+    /// The read half is regarded as closed once the end of the stream is reached.
+    /// Note that the write half may still be usable.
+    pub fn is_read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    /// Returns `true` if the write half of the stream has been closed, otherwise `false`.
+    ///
+    /// The write half is regarded as closed once the stream refuses to accept any more bytes.
+    pub fn is_write_closed(&self) -> bool {
+        self.write_closed
+    }
+
+    /// Returns `true` if the reception of items has been paused by `set_recv_paused`, otherwise `false`.
+    pub fn is_recv_paused(&self) -> bool {
+        self.recv_paused
+    }
+
+    /// Tries to enqueue the given message.
+    ///
+    /// Unlike `start_send`, this never issues any I/O (the message is written by the subsequent `poll_send` calls),
+    /// and hands the message back if it cannot be accepted
+    /// (i.e., the write half has been closed or the queue is full).
+    pub fn try_start_send(&mut self, item: E::Item) -> std::result::Result<(), SendError<E::Item>> {
+        if let Err(e) = track!(self.check_can_enqueue()) {
+            return Err(SendError::new(e, item));
+        }
+        let enqueued_at = self.enqueued_at();
+        self.outgoing_queue
+            .push(item, Priority::Normal, enqueued_at);
         Ok(())
     }
 
-    fn poll_send(&mut self) -> PollSend {
-        loop {
-            track!(self.stream.execute_io())?;
-            track!(self
-                .encoder
-                .encode_to_write_buf(self.stream.write_buf_mut()))?;
-            if self.encoder.is_idle() {
-                if let Some(item) = self.outgoing_queue.pop_front() {
-                    track!(self.encoder.start_encoding(item))?;
-                } else if self.stream.write_buf_ref().is_empty() {
-                    return Ok(Async::Ready(()));
-                }
-            }
-            if self.stream.would_block() || self.stream.is_eos() {
-                return Ok(Async::NotReady);
-            }
+    /// Enqueues the given message with the specified priority.
+    ///
+    /// Messages having higher priority are sent ahead of the queued ones having lower priority.
+    /// `start_send` is equivalent to calling this method with `Priority::Normal`.
+    pub fn start_send_prioritized(
+        &mut self,
+        (): (),
+        item: E::Item,
+        priority: Priority,
+    ) -> Result<()> {
+        track!(self.enqueue(item, priority))?;
+        if self.eager_flush {
+            track!(self.poll_send())?;
         }
+        Ok(())
     }
 
-    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
-        loop {
-            track!(self.stream.execute_io())?;
-            track!(self
-                .decoder
-                .decode_from_read_buf(self.stream.read_buf_mut()))?;
-            if self.decoder.is_idle() {
-                let item = track!(self.decoder.finish_decoding())?;
-                return Ok(Async::Ready(Some(((), item))));
-            }
-            if self.stream.is_eos() {
-                return Ok(Async::Ready(None));
-            }
-            if self.stream.would_block() {
-                return Ok(Async::NotReady);
-            }
+    /// Enqueues the given message with a callback that is invoked once the message has been written.
+    ///
+    /// The callback is invoked (usually during `poll_send`) when the last byte of the encoded message
+    /// leaves the write buffer (i.e., is handed to the underlying stream).
+    /// The callbacks of the messages are invoked in the order in which the messages are sent.
+    pub fn start_send_with_callback<F>(&mut self, (): (), item: E::Item, callback: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        track!(self.enqueue_with_callback(item, Priority::Normal, Some(Box::new(callback))))?;
+        if self.eager_flush {
+            track!(self.poll_send())?;
         }
+        Ok(())
     }
-}
-impl<E: Encode, D: Decode> TcpTransport for TcpTransporter<E, D> {
-    fn peer_addr(&self) -> SocketAddr {
-        self.peer_addr
+
+    /// Returns the metrics collected by the instance.
+    ///
+    /// The queue latency is only measured if the instance was built with
+    /// [`TcpTransporterBuilder::track_queue_latency`] enabled.
+    ///
+    /// [`TcpTransporterBuilder::track_queue_latency`]: ./struct.TcpTransporterBuilder.html#method.track_queue_latency
+    pub fn metrics(&self) -> &TransportMetrics {
+        &self.metrics
     }
 
-    fn local_addr(&self) -> SocketAddr {
-        self.local_addr
+    /// Returns the metrics collected by the instance, and resets them to zero.
+    ///
+    /// This is useful for scraping the metrics periodically without double-counting.
+    pub fn take_metrics(&mut self) -> TransportMetrics {
+        std::mem::take(&mut self.metrics)
+    }
+
+    /// Returns the maximum number of bytes that the write buffer has held so far.
+    pub fn write_buf_high_water(&self) -> usize {
+        self.write_buf_high_water
+    }
+
+    /// Returns the capacity of the application level read buffer in byte.
+    ///
+    /// This may become larger than [`TcpTransporterBuilder::buf_size`] if
+    /// [`TcpTransporterBuilder::read_buf_grow`] is enabled,
+    /// and varies over time if [`TcpTransporterBuilder::adaptive_buffer`] is enabled.
+    ///
+    /// [`TcpTransporterBuilder::buf_size`]: ./struct.TcpTransporterBuilder.html#method.buf_size
+    /// [`TcpTransporterBuilder::read_buf_grow`]: ./struct.TcpTransporterBuilder.html#method.read_buf_grow
+    /// [`TcpTransporterBuilder::adaptive_buffer`]: ./struct.TcpTransporterBuilder.html#method.adaptive_buffer
+    pub fn recv_buf_capacity(&self) -> usize {
+        self.stream.read_buf_ref().capacity()
+    }
+
+    /// Returns the capacity of the application level write buffer in byte.
+    ///
+    /// This varies over time if [`TcpTransporterBuilder::adaptive_buffer`] is enabled.
+    ///
+    /// [`TcpTransporterBuilder::adaptive_buffer`]: ./struct.TcpTransporterBuilder.html#method.adaptive_buffer
+    pub fn send_buf_capacity(&self) -> usize {
+        self.stream.write_buf_ref().capacity()
+    }
+
+    /// Drives the sending of the queued messages, and
+    /// polls whether the instance is ready to accept more messages.
+    ///
+    /// This returns `Ok(Async::NotReady)` while the write buffer holds more bytes than
+    /// the threshold specified by [`TcpTransporterBuilder::write_buf_threshold`].
+    ///
+    /// [`TcpTransporterBuilder::write_buf_threshold`]: ./struct.TcpTransporterBuilder.html#method.write_buf_threshold
+    pub fn poll_send_ready(&mut self) -> PollSend {
+        track!(self.poll_send())?;
+        Ok(self.send_readiness())
+    }
+
+    /// Replaces the underlying stream of the instance with the given one.
+    ///
+    /// The encoder, the decoder and the queued items are preserved,
+    /// so the items that have not been sent yet will be sent over the new stream.
+    /// Bytes remaining in the buffers of the old stream are discarded
+    /// (the callbacks of the messages that have not been fully written are dropped without being invoked).
+    ///
+    /// # Errors
+    ///
+    /// If the current encoder is not idle, this will return an `ErrorKind::Other` error.
+    pub fn replace_stream(&mut self, stream: S) -> Result<()> {
+        track_assert!(
+            self.encoder.is_idle(),
+            ErrorKind::Other,
+            "The encoder is in the middle of encoding an item"
+        );
+        let read_buf_size = self.stream.read_buf_ref().capacity();
+        let write_buf_size = self.stream.write_buf_ref().capacity();
+        self.stream = BufferedIo::new(stream, read_buf_size, write_buf_size);
+        self.written_callbacks.clear();
+        self.written_bytes = self.encoded_bytes;
+        self.read_closed = false;
+        self.write_closed = false;
+        Ok(())
+    }
+
+    /// Replaces the encoder and decoder of the instance with the given ones.
+    ///
+    /// The underlying stream and its buffers are preserved,
+    /// so bytes that have already been received but not decoded yet are decoded by the new decoder.
+    ///
+    /// # Errors
+    ///
+    /// If the current encoder is not idle or the outgoing queue is not empty,
+    /// this will return an `ErrorKind::Other` error.
+    pub fn swap_codec<E2, D2>(
+        self,
+        encoder: E2,
+        decoder: D2,
+    ) -> Result<StreamTransporter<S, E2, D2>>
+    where
+        E2: Encode,
+        D2: Decode,
+    {
+        track_assert!(
+            self.encoder.is_idle(),
+            ErrorKind::Other,
+            "The encoder is in the middle of encoding an item"
+        );
+        track_assert!(
+            self.outgoing_queue.is_empty(),
+            ErrorKind::Other,
+            "There are unsent items in the outgoing queue"
+        );
+        let preserve_boundaries = self.encoder.is_enabled();
+        Ok(StreamTransporter {
+            stream: self.stream,
+            encoder: FramedEncoder::new(encoder, preserve_boundaries),
+            decoder: FramedDecoder::new(decoder, preserve_boundaries),
+            decoding_item: false,
+            outgoing_queue: PriorityQueue::with_capacity(0),
+            max_queue_len: self.max_queue_len,
+            write_coalesce_bytes: self.write_coalesce_bytes,
+            write_buf_threshold: self.write_buf_threshold,
+            write_buf_high_water: self.write_buf_high_water,
+            eager_flush: self.eager_flush,
+            strict_encode: self.strict_encode,
+            encoding_start: None,
+            track_queue_latency: self.track_queue_latency,
+            codec_error_mapper: self.codec_error_mapper,
+            max_read_buf_size: self.max_read_buf_size,
+            adaptive_buffer: self.adaptive_buffer,
+            encoding_callback: None,
+            written_callbacks: self.written_callbacks,
+            encoded_bytes: self.encoded_bytes,
+            written_bytes: self.written_bytes,
+            read_closed: self.read_closed,
+            write_closed: self.write_closed,
+            recv_paused: self.recv_paused,
+            metrics: self.metrics,
+        })
+    }
+
+    /// Takes ownership of the instance, and returns the encoder and decoder being used by it.
+    ///
+    /// The underlying stream, its buffers and the outgoing queue are discarded.
+    ///
+    /// # Errors
+    ///
+    /// If the current encoder is not idle, this will return an `ErrorKind::Other` error.
+    pub fn into_codec(self) -> Result<(E, D)> {
+        track_assert!(
+            self.encoder.is_idle(),
+            ErrorKind::Other,
+            "The encoder is in the middle of encoding an item"
+        );
+        Ok((self.encoder.into_inner(), self.decoder.into_inner()))
+    }
+
+    fn send_readiness(&self) -> Async<()> {
+        match self.write_buf_threshold {
+            Some(threshold) if self.stream.write_buf_ref().len() > threshold => Async::NotReady,
+            _ => Async::Ready(()),
+        }
+    }
+
+    fn enqueue(&mut self, item: E::Item, priority: Priority) -> Result<()> {
+        track!(self.enqueue_with_callback(item, priority, None))
+    }
+
+    fn enqueue_with_callback(
+        &mut self,
+        item: E::Item,
+        priority: Priority,
+        on_sent: Option<SendCallback>,
+    ) -> Result<()> {
+        track!(self.check_can_enqueue())?;
+        let tag = ItemTag {
+            enqueued_at: self.enqueued_at(),
+            on_sent,
+        };
+        self.outgoing_queue.push_tagged(item, priority, tag);
+        Ok(())
+    }
+
+    fn enqueued_at(&self) -> Option<Instant> {
+        if self.track_queue_latency {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+
+    fn check_can_enqueue(&self) -> Result<()> {
+        track!(self.check_not_write_closed())?;
+        if let Some(max) = self.max_queue_len {
+            track_assert!(
+                self.outgoing_queue.len() < max,
+                ErrorKind::Other,
+                "The outgoing queue is full: max_queue_len={}",
+                max
+            );
+        }
+        Ok(())
+    }
+
+    fn check_not_write_closed(&self) -> Result<()> {
+        track_assert!(
+            !self.write_closed,
+            ErrorKind::Closed,
+            "The connection has been closed"
+        );
+        Ok(())
+    }
+
+    fn execute_io(&mut self) -> Result<()> {
+        let write_buf_len = self.stream.write_buf_ref().len();
+        let write_buf_empty = self.stream.write_buf_ref().is_empty();
+        let write_buf_full = self.stream.write_buf_ref().is_full();
+        if let Err(e) = self.stream.execute_io() {
+            let e = Error::from(e);
+            if *e.kind() == ErrorKind::ConnectionReset {
+                self.read_closed = true;
+                self.write_closed = true;
+            }
+            return Err(track!(e));
+        }
+        self.written_bytes += (write_buf_len - self.stream.write_buf_ref().len()) as u64;
+        while self
+            .written_callbacks
+            .front()
+            .is_some_and(|(end, _)| *end <= self.written_bytes)
+        {
+            let (_, callback) = self.written_callbacks.pop_front().expect("never fails");
+            callback();
+        }
+        if let Some(ref mut adaptive) = self.adaptive_buffer {
+            // The write buffer is examined before writing, because the write usage is determined by the encoder.
+            adaptive.adapt_write_buf(self.stream.write_buf_mut(), write_buf_empty, write_buf_full);
+            adaptive.adapt_read_buf(self.stream.read_buf_mut());
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the read buffer has been grown.
+    fn grow_read_buf_if_needed(&mut self) -> Result<bool> {
+        let max = match self.max_read_buf_size {
+            Some(max) if self.stream.read_buf_ref().is_full() && !self.decoder.is_idle() => max,
+            _ => return Ok(false),
+        };
+        let size = self.stream.read_buf_ref().capacity();
+        track_assert!(
+            size < max,
+            ErrorKind::InvalidInput,
+            "The read buffer is full, but it cannot be grown any more: max_size={}",
+            max
+        );
+        let new_size = std::cmp::min(size.saturating_mul(2), max);
+        self.stream.read_buf_mut().inner_mut().resize(new_size, 0);
+        Ok(true)
+    }
+
+    fn should_coalesce(&self) -> bool {
+        let write_buf = self.stream.write_buf_ref();
+        write_buf.len() < self.write_coalesce_bytes
+            && !write_buf.is_full()
+            && !(self.encoder.is_idle() && self.outgoing_queue.is_empty())
+    }
+
+    /// Same as `poll_send`, except that `set_cork(stream, true)` is called when a message
+    /// starts being encoded, and `set_cork(stream, false)` is called once all of them are written.
+    fn poll_send_with_cork<F>(&mut self, mut set_cork: F) -> PollSend
+    where
+        F: FnMut(&S, bool) -> Result<()>,
+    {
+        loop {
+            track!(self.check_not_write_closed())?;
+            if !self.should_coalesce() {
+                track!(self.execute_io())?;
+            }
+            let mapper = &self.codec_error_mapper;
+            let write_buf_len = self.stream.write_buf_ref().len();
+            track!(
+                self.encoder
+                    .encode_to_write_buf(self.stream.write_buf_mut())
+                    .map_err(|e| mapper.map(e)),
+                "while encoding"
+            )?;
+            self.encoded_bytes += (self.stream.write_buf_ref().len() - write_buf_len) as u64;
+            self.write_buf_high_water =
+                std::cmp::max(self.write_buf_high_water, self.stream.write_buf_ref().len());
+            if self.encoder.is_idle() {
+                if let Some(start) = self.encoding_start.take() {
+                    track_assert!(
+                        !self.strict_encode || self.encoded_bytes > start,
+                        ErrorKind::CodecError,
+                        "The encoder has produced no bytes for a message"
+                    );
+                }
+                if let Some(callback) = self.encoding_callback.take() {
+                    self.written_callbacks
+                        .push_back((self.encoded_bytes, callback));
+                }
+                if let Some((item, tag)) = self.outgoing_queue.pop_front() {
+                    self.metrics.sent_messages += 1;
+                    if let Some(enqueued_at) = tag.enqueued_at {
+                        self.metrics.record_queue_latency(enqueued_at.elapsed());
+                    }
+                    self.encoding_callback = tag.on_sent;
+                    track!(set_cork(self.stream.stream_ref(), true))?;
+                    track!(
+                        self.encoder.start_encoding(item).map_err(|e| mapper.map(e)),
+                        "while encoding"
+                    )?;
+                    self.encoding_start = if self.encoder.requiring_bytes() == ByteCount::Finite(0)
+                    {
+                        None
+                    } else {
+                        Some(self.encoded_bytes)
+                    };
+                    continue;
+                } else if self.stream.write_buf_ref().is_empty() {
+                    track!(set_cork(self.stream.stream_ref(), false))?;
+                    return Ok(Async::Ready(()));
+                }
+            }
+
+            // The read half is not taken into account here, so that items can be sent after read EOF.
+            let write_state = self.stream.write_buf_ref().stream_state();
+            if write_state.is_eos() {
+                self.write_closed = true;
+            } else if write_state.would_block() || self.stream.write_buf_ref().is_empty() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+impl<S: Read + Write, E: Encode, D: Decode> Transport for StreamTransporter<S, E, D> {
+    type PeerAddr = ();
+    type SendItem = E::Item;
+    type RecvItem = D::Item;
+
+    fn start_send(&mut self, (): Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        track!(self.start_send_prioritized((), item, Priority::Normal))
+    }
+
+    fn start_send_batch(&mut self, (): Self::PeerAddr, items: Vec<Self::SendItem>) -> Result<()> {
+        for item in items {
+            track!(self.enqueue(item, Priority::Normal))?;
+        }
+        if self.eager_flush {
+            track!(self.poll_send())?;
+        }
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.poll_send_with_cork(|_, _| Ok(())))
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        if self.recv_paused {
+            return Ok(Async::NotReady);
+        }
+        loop {
+            track!(self.execute_io())?;
+
+            // The stream ended between items, so failing to decode another one is not an error.
+            let clean_eos = self.stream.is_eos()
+                && self.stream.read_buf_ref().is_empty()
+                && !self.decoding_item;
+            let read_buf_len = self.stream.read_buf_ref().len();
+            let mapper = &self.codec_error_mapper;
+            if let Err(e) = self
+                .decoder
+                .decode_from_read_buf(self.stream.read_buf_mut())
+            {
+                if clean_eos && *e.kind() == bytecodec::ErrorKind::UnexpectedEos {
+                    self.read_closed = true;
+                    return Ok(Async::Ready(None));
+                }
+                return Err(track!(mapper.map(e), "while decoding"));
+            }
+            if self.stream.read_buf_ref().len() != read_buf_len {
+                self.decoding_item = true;
+            }
+            if self.decoder.is_idle() {
+                let item = track!(
+                    self.decoder.finish_decoding().map_err(|e| mapper.map(e)),
+                    "while decoding"
+                )?;
+                self.decoding_item = false;
+                if clean_eos {
+                    // An item made of no bytes at all is not a part of the stream.
+                    self.read_closed = true;
+                    return Ok(Async::Ready(None));
+                }
+                self.metrics.received_messages += 1;
+                return Ok(Async::Ready(Some(((), item))));
+            }
+            if track!(self.grow_read_buf_if_needed())? {
+                continue;
+            }
+            if self.stream.is_eos() {
+                self.read_closed = true;
+                return Ok(Async::Ready(None));
+            }
+            if self.stream.would_block() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        self.recv_paused = paused;
+        Ok(())
+    }
+}
+impl<S, E, D> fmt::Debug for StreamTransporter<S, E, D>
+where
+    S: fmt::Debug,
+    E: Encode + fmt::Debug,
+    D: Decode + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "StreamTransporter {{ stream: {:?}, encoder: {:?}, decoder: {:?}, outgoing_queue_len: {}, .. }}",
+            self.stream,
+            self.encoder.inner_ref(),
+            self.decoder.inner_ref(),
+            self.outgoing_queue.len()
+        )
+    }
+}
+
+/// The number of consecutive I/O rounds in which a buffer must remain empty before it is shrunk.
+const ADAPTIVE_BUF_SHRINK_ROUNDS: usize = 16;
+
+/// The state of the adaptive sizing of the buffers (see `TcpTransporterBuilder::adaptive_buffer`).
+#[derive(Debug)]
+struct AdaptiveBuffer {
+    min: usize,
+    max: usize,
+    read_idle_rounds: usize,
+    write_idle_rounds: usize,
+}
+impl AdaptiveBuffer {
+    fn new(min: usize, max: usize) -> Self {
+        AdaptiveBuffer {
+            min,
+            max,
+            read_idle_rounds: 0,
+            write_idle_rounds: 0,
+        }
+    }
+
+    fn adapt_read_buf(&mut self, buf: &mut ReadBuf<Vec<u8>>) {
+        let (full, empty) = (buf.is_full(), buf.is_empty());
+        if let Some(size) = self.adapt(buf.capacity(), full, empty, true) {
+            buf.inner_mut().resize(size, 0);
+        }
+    }
+
+    fn adapt_write_buf(&mut self, buf: &mut WriteBuf<Vec<u8>>, was_empty: bool, was_full: bool) {
+        // Shrinking is only allowed if the buffer is empty now (i.e., there are no bytes to be preserved).
+        let empty = was_empty && buf.is_empty();
+        if let Some(size) = self.adapt(buf.capacity(), was_full, empty, false) {
+            buf.inner_mut().resize(size, 0);
+        }
+    }
+
+    /// Returns the new size of the buffer if it should be changed.
+    fn adapt(&mut self, size: usize, full: bool, empty: bool, read: bool) -> Option<usize> {
+        let idle_rounds = if read {
+            &mut self.read_idle_rounds
+        } else {
+            &mut self.write_idle_rounds
+        };
+        if !empty {
+            *idle_rounds = 0;
+            if full && size < self.max {
+                return Some(std::cmp::min(size.saturating_mul(2), self.max));
+            }
+            return None;
+        }
+        *idle_rounds += 1;
+        if *idle_rounds < ADAPTIVE_BUF_SHRINK_ROUNDS || size <= self.min {
+            return None;
+        }
+        *idle_rounds = 0;
+        Some(std::cmp::max(size / 2, self.min))
+    }
+}
+
+/// An implementation of [`Transport`] that uses TCP as the transport layer.
+///
+/// [`Transport`]: ./trait.Transport.html
+#[derive(Debug)]
+pub struct TcpTransporter<E: Encode, D: Decode> {
+    inner: StreamTransporter<TcpStream, E, D>,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    cork_messages: bool,
+    corked: bool,
+    connection_guard: Option<ConnectionGuard>,
+    flush_check: Option<FibersDelay>,
+    state: StateTracker,
+}
+impl<E, D> TcpTransporter<E, D>
+where
+    E: Encode + Default,
+    D: Decode + Default,
+{
+    /// Starts connecting to the given peer and
+    /// will return a new `TcpTransporter` instance if the connect operation is succeeded.
+    ///
+    /// This is equivalent to `TcpTransporterBuilder::new().connect(peer)`.
+    pub fn connect(peer: SocketAddr) -> impl Future<Item = Self, Error = Error> {
+        TcpTransporterBuilder::new().connect(peer)
+    }
+
+    /// Starts connecting to the given peer, and returns a [`ConnectHandle`] that can abort the connect attempt.
+    ///
+    /// This is equivalent to `TcpTransporterBuilder::new().connect_abortable(peer)`.
+    ///
+    /// [`ConnectHandle`]: ./struct.ConnectHandle.html
+    pub fn connect_abortable(
+        peer: SocketAddr,
+    ) -> (impl Future<Item = Self, Error = Error>, ConnectHandle) {
+        TcpTransporterBuilder::new().connect_abortable(peer)
+    }
+
+    /// Makes a new `TcpTransporter` instance from the given `TcpStream`.
+    ///
+    /// This is equivalent to `TcpTransporterBuilder::new().finish(stream)`.
+    pub fn from_stream(stream: TcpStream) -> Result<Self> {
+        TcpTransporterBuilder::new().finish(stream)
+    }
+}
+impl<E: Encode, D: Decode> TcpTransporter<E, D> {
+    /// Returns the number of unsent messages in the queue of the instance.
+    pub fn message_queue_len(&self) -> usize {
+        self.inner.message_queue_len()
+    }
+
+    /// Returns a reference to the TCP stream being used by the instance.
+    pub fn stream_ref(&self) -> &TcpStream {
+        self.inner.stream_ref()
+    }
+
+    /// Returns a mutable reference to the TCP stream being used by the instance.
+    pub fn stream_mut(&mut self) -> &mut TcpStream {
+        self.inner.stream_mut()
+    }
+
+    /// Returns a reference to the decoder being used by the instance.
+    pub fn decoder_ref(&self) -> &D {
+        self.inner.decoder_ref()
+    }
+
+    /// Returns a mutable reference to the decoder being used by the instance.
+    pub fn decoder_mut(&mut self) -> &mut D {
+        self.inner.decoder_mut()
+    }
+
+    /// Returns a reference to the encoder being used by the instance.
+    pub fn encoder_ref(&self) -> &E {
+        self.inner.encoder_ref()
+    }
+
+    /// Returns a mutable reference to the encoder being used by the instance.
+    pub fn encoder_mut(&mut self) -> &mut E {
+        self.inner.encoder_mut()
+    }
+
+    /// Returns a clone of the decoder being used by the instance.
+    ///
+    /// This is useful for carrying the state accumulated by a stateful decoder
+    /// (e.g., the dictionary of a compression decoder) over to a new connection
+    /// via [`import_decoder_state`].
+    ///
+    /// [`import_decoder_state`]: ./struct.TcpTransporter.html#method.import_decoder_state
+    pub fn export_decoder_state(&self) -> D
+    where
+        D: Clone,
+    {
+        self.decoder_ref().clone()
+    }
+
+    /// Replaces the decoder of the instance with the given one exported by [`export_decoder_state`].
+    ///
+    /// Note that the bytes that have already been received but not decoded yet are decoded by the new decoder.
+    ///
+    /// [`export_decoder_state`]: ./struct.TcpTransporter.html#method.export_decoder_state
+    pub fn import_decoder_state(&mut self, decoder: D) {
+        *self.decoder_mut() = decoder;
+    }
+
+    /// Returns `true` if both the read and write halves of the connection have been closed, otherwise `false`.
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// Returns `true` if the read half of the connection has been closed, otherwise `false`.
+    ///
+    /// The read half is regarded as closed once the end of the stream is reached
+    /// (i.e., the peer has shut down its write half) or after `shutdown` is called.
+    /// Note that the write half may still be usable (i.e., the connection is half-open).
+    pub fn is_read_closed(&self) -> bool {
+        self.inner.is_read_closed()
+    }
+
+    /// Returns `true` if the write half of the connection has been closed, otherwise `false`.
+    ///
+    /// The write half is regarded as closed after `shutdown` or `shutdown_write` is called,
+    /// or once the stream refuses to accept any more bytes.
+    pub fn is_write_closed(&self) -> bool {
+        self.inner.is_write_closed()
+    }
+
+    /// Returns `true` if the reception of items has been paused by `set_recv_paused`, otherwise `false`.
+    pub fn is_recv_paused(&self) -> bool {
+        self.inner.is_recv_paused()
+    }
+
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state.current
+    }
+
+    /// Returns a stream that emits the subsequent state transitions of the connection.
+    ///
+    /// The transitions are detected by the methods of the instance (e.g., `poll_recv` reaching the end of the stream),
+    /// so they are emitted only while the instance is being polled.
+    /// The stream terminates when the instance is dropped.
+    pub fn state_changes(&mut self) -> mpsc::Receiver<ConnectionState> {
+        let (tx, rx) = mpsc::channel();
+        self.state.subscribers.push(tx);
+        rx
+    }
+
+    fn update_state(&mut self) {
+        let next = if self.state.errored {
+            ConnectionState::Errored
+        } else if self.inner.is_closed() {
+            ConnectionState::Closed
+        } else if self.inner.is_read_closed() || self.inner.is_write_closed() {
+            ConnectionState::Draining
+        } else {
+            ConnectionState::Connected
+        };
+        self.state.transit(next);
+    }
+
+    fn observe<T>(&mut self, result: Result<T>) -> Result<T> {
+        match result {
+            Err(ref e) if *e.kind() != ErrorKind::Closed => self.state.errored = true,
+            _ => {}
+        }
+        self.update_state();
+        result
+    }
+
+    /// Shuts down both the read and write halves of the connection.
+    ///
+    /// After this call, `start_send` and `poll_send` will return an `ErrorKind::Closed` error.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.inner.read_closed = true;
+        self.inner.write_closed = true;
+        self.update_state();
+        let result = self
+            .inner
+            .stream_mut()
+            .with_inner(|s| s.shutdown(Shutdown::Both));
+        track!(result.map_err(Error::from))
+    }
+
+    /// Shuts down the write half of the connection.
+    ///
+    /// The peer will observe the end of the stream, but the instance can continue to receive items.
+    /// After this call, `start_send` and `poll_send` will return an `ErrorKind::Closed` error.
+    pub fn shutdown_write(&mut self) -> Result<()> {
+        self.inner.write_closed = true;
+        self.update_state();
+        let result = self
+            .inner
+            .stream_mut()
+            .with_inner(|s| s.shutdown(Shutdown::Write));
+        track!(result.map_err(Error::from))
+    }
+
+    /// Sends the given item as the final message (e.g., a close or error frame),
+    /// and then shuts down the write half of the connection.
+    ///
+    /// The resulting future completes after all of the queued items and `item` have been written
+    /// to the stream, so the peer will observe `item` as the last message followed by the end of the stream.
+    pub fn close_with(mut self, item: E::Item) -> impl Future<Item = (), Error = Error> {
+        let result = track!(self.start_send((), item));
+        futures::done(result)
+            .and_then(move |()| wait_send(self))
+            .and_then(|mut transporter| track!(transporter.shutdown_write()))
+    }
+
+    /// Polls until all of the sent bytes have left the send buffer of the kernel.
+    ///
+    /// `poll_send` returns `Ok(Async::Ready(()))` once the bytes are handed to the kernel,
+    /// while this method waits further until the kernel reports
+    /// no unsent or unacknowledged bytes (`TIOCOUTQ`).
+    /// As the kernel provides no readiness notification for that,
+    /// the send buffer is re-checked at a short interval.
+    ///
+    /// This is only supported on Linux.
+    /// On the other platforms, this is equivalent to `poll_send`.
+    pub fn poll_flushed(&mut self) -> PollSend {
+        if !track!(self.poll_send())?.is_ready() {
+            return Ok(Async::NotReady);
+        }
+        loop {
+            if let Some(mut delay) = self.flush_check.take() {
+                if !track!(delay.poll())?.is_ready() {
+                    self.flush_check = Some(delay);
+                    return Ok(Async::NotReady);
+                }
+            }
+            let unsent = track!(sys::tcp_unsent_bytes(self.stream_ref()).map_err(Error::from))?;
+            if unsent == 0 {
+                return Ok(Async::Ready(()));
+            }
+            self.flush_check = Some(FibersClock.delay(FLUSH_CHECK_INTERVAL));
+        }
+    }
+
+    /// Tries to enqueue the given message.
+    ///
+    /// Unlike `start_send`, this never issues any I/O (the message is written by the subsequent `poll_send` calls),
+    /// and hands the message back if it cannot be accepted
+    /// (i.e., the write half has been closed or the queue is full).
+    pub fn try_start_send(&mut self, item: E::Item) -> std::result::Result<(), SendError<E::Item>> {
+        self.inner.try_start_send(item)
+    }
+
+    /// Enqueues the given message with the specified priority.
+    ///
+    /// Messages having higher priority are sent ahead of the queued ones having lower priority.
+    /// `start_send` is equivalent to calling this method with `Priority::Normal`.
+    pub fn start_send_prioritized(
+        &mut self,
+        (): (),
+        item: E::Item,
+        priority: Priority,
+    ) -> Result<()> {
+        track!(self.inner.enqueue(item, priority))?;
+        if self.inner.eager_flush {
+            track!(self.poll_send())?;
+        }
+        Ok(())
+    }
+
+    /// Enqueues the given message with a callback that is invoked once the message has been written.
+    ///
+    /// See [`StreamTransporter::start_send_with_callback`] for more details.
+    ///
+    /// [`StreamTransporter::start_send_with_callback`]: ./struct.StreamTransporter.html#method.start_send_with_callback
+    pub fn start_send_with_callback<F>(&mut self, (): (), item: E::Item, callback: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        track!(self
+            .inner
+            .enqueue_with_callback(item, Priority::Normal, Some(Box::new(callback))))?;
+        if self.inner.eager_flush {
+            track!(self.poll_send())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the metrics collected by the instance.
+    ///
+    /// The queue latency is only measured if the instance was built with
+    /// [`TcpTransporterBuilder::track_queue_latency`] enabled.
+    ///
+    /// [`TcpTransporterBuilder::track_queue_latency`]: ./struct.TcpTransporterBuilder.html#method.track_queue_latency
+    pub fn metrics(&self) -> &TransportMetrics {
+        self.inner.metrics()
+    }
+
+    /// Returns the metrics collected by the instance, and resets them to zero.
+    ///
+    /// This is useful for scraping the metrics periodically without double-counting.
+    pub fn take_metrics(&mut self) -> TransportMetrics {
+        self.inner.take_metrics()
+    }
+
+    /// Returns the maximum number of bytes that the write buffer has held so far.
+    pub fn write_buf_high_water(&self) -> usize {
+        self.inner.write_buf_high_water()
+    }
+
+    /// Returns the capacity of the application level read buffer in byte.
+    ///
+    /// This may become larger than [`TcpTransporterBuilder::buf_size`] if
+    /// [`TcpTransporterBuilder::read_buf_grow`] is enabled,
+    /// and varies over time if [`TcpTransporterBuilder::adaptive_buffer`] is enabled.
+    ///
+    /// [`TcpTransporterBuilder::buf_size`]: ./struct.TcpTransporterBuilder.html#method.buf_size
+    /// [`TcpTransporterBuilder::read_buf_grow`]: ./struct.TcpTransporterBuilder.html#method.read_buf_grow
+    /// [`TcpTransporterBuilder::adaptive_buffer`]: ./struct.TcpTransporterBuilder.html#method.adaptive_buffer
+    pub fn recv_buf_capacity(&self) -> usize {
+        self.inner.recv_buf_capacity()
+    }
+
+    /// Returns the capacity of the application level write buffer in byte.
+    ///
+    /// This varies over time if [`TcpTransporterBuilder::adaptive_buffer`] is enabled.
+    ///
+    /// [`TcpTransporterBuilder::adaptive_buffer`]: ./struct.TcpTransporterBuilder.html#method.adaptive_buffer
+    pub fn send_buf_capacity(&self) -> usize {
+        self.inner.send_buf_capacity()
+    }
+
+    /// Drives the sending of the queued messages, and
+    /// polls whether the instance is ready to accept more messages.
+    ///
+    /// This returns `Ok(Async::NotReady)` while the write buffer holds more bytes than
+    /// the threshold specified by [`TcpTransporterBuilder::write_buf_threshold`].
+    ///
+    /// [`TcpTransporterBuilder::write_buf_threshold`]: ./struct.TcpTransporterBuilder.html#method.write_buf_threshold
+    pub fn poll_send_ready(&mut self) -> PollSend {
+        track!(self.poll_send())?;
+        Ok(self.inner.send_readiness())
+    }
+
+    pub(crate) fn set_connection_guard(&mut self, guard: ConnectionGuard) {
+        self.connection_guard = Some(guard);
+    }
+
+    pub(crate) fn is_eager_flush(&self) -> bool {
+        self.inner.eager_flush
+    }
+
+    /// Replaces the underlying stream of the instance with the given one
+    /// (e.g., for failing over to a freshly established connection).
+    ///
+    /// The encoder, the decoder and the queued items are preserved,
+    /// so the items that have not been sent yet will be sent over the new stream.
+    /// Bytes remaining in the buffers of the old stream are discarded
+    /// (the callbacks of the messages that have not been fully written are dropped without being invoked).
+    ///
+    /// # Errors
+    ///
+    /// If the current encoder is not idle, this will return an `ErrorKind::Other` error.
+    pub fn replace_stream(&mut self, stream: TcpStream) -> Result<()> {
+        let _ = stream.set_nodelay(true);
+        let peer_addr = track!(stream.peer_addr().map_err(Error::from))?;
+        let local_addr = track!(stream.local_addr().map_err(Error::from))?;
+        track!(self.inner.replace_stream(stream))?;
+        self.peer_addr = peer_addr;
+        self.local_addr = local_addr;
+        self.corked = false;
+        self.flush_check = None;
+        self.state.errored = false;
+        self.update_state();
+        Ok(())
+    }
+
+    /// Replaces the encoder and decoder of the instance with the given ones
+    /// (e.g., for switching to a different framing after a protocol upgrade).
+    ///
+    /// The underlying stream and its buffers are preserved,
+    /// so bytes that have already been received but not decoded yet are decoded by the new decoder.
+    ///
+    /// # Errors
+    ///
+    /// If the current encoder is not idle or the outgoing queue is not empty,
+    /// this will return an `ErrorKind::Other` error.
+    pub fn swap_codec<E2, D2>(self, encoder: E2, decoder: D2) -> Result<TcpTransporter<E2, D2>>
+    where
+        E2: Encode,
+        D2: Decode,
+    {
+        let inner = track!(self.inner.swap_codec(encoder, decoder))?;
+        Ok(TcpTransporter {
+            inner,
+            peer_addr: self.peer_addr,
+            local_addr: self.local_addr,
+            cork_messages: self.cork_messages,
+            corked: self.corked,
+            connection_guard: self.connection_guard,
+            flush_check: self.flush_check,
+            state: self.state,
+        })
+    }
+
+    /// Takes ownership of the instance, and returns the encoder and decoder being used by it
+    /// (e.g., for reusing a stateful codec for a new connection).
+    ///
+    /// The underlying stream, its buffers and the outgoing queue are discarded.
+    ///
+    /// # Errors
+    ///
+    /// If the current encoder is not idle, this will return an `ErrorKind::Other` error.
+    pub fn into_codec(self) -> Result<(E, D)> {
+        track!(self.inner.into_codec())
+    }
+}
+impl<E: Encode, D: Decode> Transport for TcpTransporter<E, D> {
+    type PeerAddr = ();
+    type SendItem = E::Item;
+    type RecvItem = D::Item;
+
+    fn start_send(&mut self, (): Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        track!(self.start_send_prioritized((), item, Priority::Normal))
+    }
+
+    fn start_send_batch(&mut self, (): Self::PeerAddr, items: Vec<Self::SendItem>) -> Result<()> {
+        for item in items {
+            track!(self.inner.enqueue(item, Priority::Normal))?;
+        }
+        if self.inner.eager_flush {
+            track!(self.poll_send())?;
+        }
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        let cork_messages = self.cork_messages;
+        let corked = &mut self.corked;
+        let result = track!(self.inner.poll_send_with_cork(|stream, cork| {
+            if cork_messages && *corked != cork {
+                track!(sys::set_tcp_cork(stream, cork).map_err(Error::from))?;
+                *corked = cork;
+            }
+            Ok(())
+        }));
+        self.observe(result)
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        let result = track!(self.inner.poll_recv());
+        self.observe(result)
+    }
+
+    /// Note that bytes may still be read into the read buffer by `poll_send` while paused,
+    /// but the buffer is bounded by `TcpTransporterBuilder::buf_size`.
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        Some(self.local_addr)
+    }
+}
+impl<E: Encode, D: Decode> TcpTransport for TcpTransporter<E, D> {
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+#[cfg(unix)]
+impl<E: Encode, D: Decode> AsRawFd for TcpTransporter<E, D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream_ref().with_inner(|s| s.as_raw_fd())
+    }
+}
+
+/// The state of a TCP connection.
+///
+/// See [`TcpTransporter::state`] and [`TcpTransporter::state_changes`].
+///
+/// [`TcpTransporter::state`]: ./struct.TcpTransporter.html#method.state
+/// [`TcpTransporter::state_changes`]: ./struct.TcpTransporter.html#method.state_changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    /// The connection is being established.
+    ///
+    /// Note that `TcpTransporter` instances are made after connecting, so they never are in this state.
+    /// This state is only emitted by the stream returned by [`TcpTransporterBuilder::state_changes`].
+    ///
+    /// [`TcpTransporterBuilder::state_changes`]: ./struct.TcpTransporterBuilder.html#method.state_changes
+    Connecting,
+
+    /// The connection has been established.
+    Connected,
+
+    /// One of the halves of the connection has been closed (i.e., the connection is half-open).
+    Draining,
+
+    /// Both halves of the connection have been closed.
+    Closed,
+
+    /// The connection has failed (e.g., it has been reset by the peer).
+    Errored,
+}
+
+#[derive(Debug)]
+struct StateTracker {
+    current: ConnectionState,
+    errored: bool,
+    subscribers: Vec<mpsc::Sender<ConnectionState>>,
+}
+impl StateTracker {
+    fn new(mut subscribers: Vec<mpsc::Sender<ConnectionState>>) -> Self {
+        // The subscribers registered to the builder have observed `ConnectionState::Connecting`.
+        subscribers.retain(|tx| tx.send(ConnectionState::Connected).is_ok());
+        StateTracker {
+            current: ConnectionState::Connected,
+            errored: false,
+            subscribers,
+        }
+    }
+
+    fn transit(&mut self, next: ConnectionState) {
+        if self.current == next {
+            return;
+        }
+        self.current = next;
+        self.subscribers.retain(|tx| tx.send(next).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{drive, try_recv, wait_recv, wait_send, TcpListener};
+    use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder, Utf8Decoder, Utf8Encoder};
+    use bytecodec::fixnum::{U16beDecoder, U16beEncoder, U8Decoder, U8Encoder};
+    use bytecodec::{DecodeExt, EncodeExt};
+    use factory::DefaultFactory;
+    use futures::Stream;
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::io;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    type Server = TcpListener<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>;
+    type Client = TcpTransporter<U8Encoder, U8Decoder>;
+    type Builder = TcpTransporterBuilder<U8Encoder, U8Decoder>;
+
+    fn connect() -> Result<(Client, Client)> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let client = fibers_global::execute(Client::connect(server.local_addr()))?;
+        let (accepted, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        Ok((client, accepted.expect("never fails")))
+    }
+
+    /// One end of an in-memory duplex pipe.
+    #[derive(Debug)]
+    struct MemoryStream {
+        rx: Rc<RefCell<VecDeque<u8>>>,
+        tx: Rc<RefCell<VecDeque<u8>>>,
+        writes: Rc<Cell<usize>>,
+    }
+    impl MemoryStream {
+        fn pair() -> (Self, Self) {
+            let a = Rc::new(RefCell::new(VecDeque::new()));
+            let b = Rc::new(RefCell::new(VecDeque::new()));
+            let x = MemoryStream {
+                rx: a.clone(),
+                tx: b.clone(),
+                writes: Rc::default(),
+            };
+            let y = MemoryStream {
+                rx: b,
+                tx: a,
+                writes: Rc::default(),
+            };
+            (x, y)
+        }
+    }
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut rx = self.rx.borrow_mut();
+            if rx.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            rx.read(buf)
+        }
+    }
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.set(self.writes.get() + 1);
+            self.tx.borrow_mut().extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn preserve_boundaries_works() -> Result<()> {
+        type Builder = TcpTransporterBuilder<BytesEncoder<Vec<u8>>, RemainingBytesDecoder>;
+        let (a, b) = MemoryStream::pair();
+        let mut a = Builder::new()
+            .preserve_boundaries(true)
+            .finish_with_stream(a);
+        let mut b = Builder::new()
+            .preserve_boundaries(true)
+            .buf_size(3)
+            .finish_with_stream(b);
+
+        a.start_send((), b"foo".to_vec())?;
+        a.start_send((), b"bar".to_vec())?;
+        assert_eq!(a.poll_send()?, Async::Ready(()));
+        assert_eq!(b.poll_recv()?, Async::Ready(Some(((), b"foo".to_vec()))));
+        assert_eq!(b.poll_recv()?, Async::Ready(Some(((), b"bar".to_vec()))));
+        assert_eq!(b.poll_recv()?, Async::NotReady);
+        Ok(())
+    }
+
+    #[test]
+    fn buf_capacity_works() {
+        let (a, _b) = MemoryStream::pair();
+        let a = TcpTransporterBuilder::<U8Encoder, U8Decoder>::new()
+            .buf_size(100)
+            .finish_with_stream(a);
+        assert_eq!(a.recv_buf_capacity(), 100);
+        assert_eq!(a.send_buf_capacity(), 100);
+    }
+
+    #[test]
+    fn adaptive_buffer_works() -> Result<()> {
+        type Builder = TcpTransporterBuilder<U8Encoder, U8Decoder>;
+        let (a, b) = MemoryStream::pair();
+        let mut a = Builder::new()
+            .adaptive_buffer(16, 256)
+            .eager_flush(false)
+            .write_coalesce_bytes(1024)
+            .finish_with_stream(a);
+        let mut b = Builder::new()
+            .adaptive_buffer(16, 256)
+            .finish_with_stream(b);
+        assert_eq!(a.send_buf_capacity(), 16);
+        assert_eq!(b.recv_buf_capacity(), 16);
+
+        // High throughput
+        for i in 0..1000 {
+            a.start_send((), i as u8)?;
+        }
+        assert_eq!(a.poll_send()?, Async::Ready(()));
+        for i in 0..1000 {
+            assert_eq!(b.poll_recv()?, Async::Ready(Some(((), i as u8))));
+        }
+        assert_eq!(a.send_buf_capacity(), 256);
+        assert_eq!(b.recv_buf_capacity(), 256);
+
+        // Idle
+        for _ in 0..100 {
+            assert_eq!(a.poll_send()?, Async::Ready(()));
+            assert_eq!(b.poll_recv()?, Async::NotReady);
+        }
+        assert_eq!(a.send_buf_capacity(), 16);
+        assert_eq!(b.recv_buf_capacity(), 16);
+        Ok(())
+    }
+
+    #[test]
+    fn start_send_with_callback_works() -> Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        let (a, b) = MemoryStream::pair();
+        let mut a = TcpTransporterBuilder::<U8Encoder, U8Decoder>::new()
+            .eager_flush(false)
+            .finish_with_stream(a);
+        let mut b = StreamTransporter::<_, U8Encoder, U8Decoder>::new(b);
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..2 {
+            let sent = sent.clone();
+            a.start_send_with_callback((), i, move || sent.lock().unwrap().push(i))?;
+        }
+        assert!(sent.lock().unwrap().is_empty());
+
+        assert_eq!(a.poll_send()?, Async::Ready(()));
+        assert_eq!(*sent.lock().unwrap(), [0, 1]);
+        assert_eq!(b.poll_recv()?, Async::Ready(Some(((), 0))));
+        assert_eq!(b.poll_recv()?, Async::Ready(Some(((), 1))));
+        Ok(())
+    }
+
+    #[test]
+    fn set_recv_paused_works() -> Result<()> {
+        let (a, b) = MemoryStream::pair();
+        let mut a = StreamTransporter::<_, U8Encoder, U8Decoder>::new(a);
+        let mut b = StreamTransporter::<_, U8Encoder, U8Decoder>::new(b);
+        a.start_send((), 1)?;
+        assert_eq!(a.poll_send()?, Async::Ready(()));
+
+        b.set_recv_paused(true)?;
+        assert!(b.is_recv_paused());
+        assert_eq!(b.poll_recv()?, Async::NotReady);
+        assert_eq!(b.metrics().received_messages, 0);
+
+        b.set_recv_paused(false)?;
+        assert_eq!(b.poll_recv()?, Async::Ready(Some(((), 1))));
+        Ok(())
+    }
+
+    #[test]
+    fn stream_transporter_works() -> Result<()> {
+        let (a, b) = MemoryStream::pair();
+        let mut a = StreamTransporter::<_, U16beEncoder, U16beDecoder>::new(a);
+        let mut b = TcpTransporterBuilder::<U16beEncoder, U16beDecoder>::new()
+            .buf_size(3)
+            .finish_with_stream(b);
+
+        for i in 0..3 {
+            a.start_send((), 0x1230 + i)?;
+        }
+        assert_eq!(a.poll_send()?, Async::Ready(()));
+        for i in 0..3 {
+            assert_eq!(b.poll_recv()?, Async::Ready(Some(((), 0x1230 + i))));
+        }
+        assert_eq!(b.poll_recv()?, Async::NotReady);
+
+        b.start_send((), 0xABCD)?;
+        assert_eq!(a.poll_recv()?, Async::Ready(Some(((), 0xABCD))));
+        assert_eq!(a.metrics().sent_messages, 3);
+        assert_eq!(b.metrics().received_messages, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn abortable_works() -> Result<()> {
+        struct PendingConnect(Arc<AtomicUsize>);
+        impl Future for PendingConnect {
+            type Item = ();
+            type Error = Error;
+            fn poll(&mut self) -> Poll<(), Error> {
+                Ok(Async::NotReady)
+            }
+        }
+        impl Drop for PendingConnect {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (mut future, handle) = Abortable::new(PendingConnect(drops.clone()));
+        assert_eq!(future.poll()?, Async::NotReady);
+
+        handle.abort();
+        assert_eq!(future.poll().map_err(|e| *e.kind()), Err(ErrorKind::Other));
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+
+        // Dropping the handle does not abort the future.
+        let (mut future, handle) = Abortable::new(PendingConnect(drops.clone()));
+        std::mem::drop(handle);
+        assert_eq!(future.poll()?, Async::NotReady);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn connect_abortable_works() -> Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        // The first poll creates the socket and starts connecting.
+        let (mut future, handle) = Client::connect_abortable(addr);
+        let (future, polled) = fibers_global::execute(futures::lazy(move || {
+            let polled = track!(future.poll())?.is_ready();
+            Ok::<_, Error>((future, polled))
+        }))?;
+        assert!(!polled);
+
+        handle.abort();
+        let result = fibers_global::execute(future);
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::Other));
+
+        // The socket has been released, so the accepted connection is closed by the client side.
+        let (mut stream, _) = listener.accept()?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut buf = [0; 1];
+        match stream.read(&mut buf) {
+            Ok(size) => assert_eq!(size, 0),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+        }
+        Ok(())
+    }
+
+    fn handshake_server(version: u8) -> Result<SocketAddr> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let addr = server.local_addr();
+        let future = server
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(client, _)| wait_recv(client.expect("never fails")))
+            .and_then(move |(mut client, (), _)| {
+                track!(client.start_send((), version))?;
+                Ok(client)
+            })
+            .and_then(wait_send);
+        fibers_global::spawn(future.map(|_| ()).map_err(|_| ()));
+        Ok(addr)
+    }
+
+    #[test]
+    fn with_handshake_works() -> Result<()> {
+        fn version_exchange(mut client: Client) -> impl Future<Item = Client, Error = Error> {
+            futures::done(client.start_send((), 1))
+                .and_then(move |()| wait_send(client))
+                .and_then(wait_recv)
+                .and_then(|(client, (), version)| {
+                    track_assert_eq!(version, 1, ErrorKind::Other, "Unsupported version");
+                    Ok(client)
+                })
+        }
+
+        let peer = handshake_server(1)?;
+        let builder = TcpTransporterBuilder::new().with_handshake(version_exchange);
+        let client = fibers_global::execute(builder.connect(peer))?;
+        assert_eq!(client.peer_addr(), peer);
+
+        let peer = handshake_server(2)?;
+        let builder = TcpTransporterBuilder::new().with_handshake(version_exchange);
+        let result = fibers_global::execute(builder.connect(peer));
+        assert_eq!(result.err().map(|e| *e.kind()), Some(ErrorKind::Other));
+        Ok(())
+    }
+
+    #[test]
+    fn take_metrics_works() -> Result<()> {
+        let (mut client, server) = connect()?;
+        client.start_send((), 1)?;
+        client.start_send((), 2)?;
+        let mut client = fibers_global::execute(wait_send(client))?;
+        let (server, _, _) = fibers_global::execute(wait_recv(server))?;
+        let (mut server, _, _) = fibers_global::execute(wait_recv(server))?;
+
+        assert_eq!(client.take_metrics().sent_messages, 2);
+        assert_eq!(server.take_metrics().received_messages, 2);
+        assert_eq!(*client.metrics(), TransportMetrics::new());
+        assert_eq!(*server.metrics(), TransportMetrics::new());
+
+        client.start_send((), 3)?;
+        let client = fibers_global::execute(wait_send(client))?;
+        assert_eq!(client.metrics().sent_messages, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn finish_if_works() -> Result<()> {
+        let rejected = std::net::TcpListener::bind("127.0.0.1:0").expect("never fails");
+        let rejected_addr = rejected.local_addr().expect("never fails");
+        let accepted = std::net::TcpListener::bind("127.0.0.1:0").expect("never fails");
+        let accepted_addr = accepted.local_addr().expect("never fails");
+
+        let stream = fibers_global::execute(TcpStream::connect(rejected_addr))?;
+        let client = Builder::new().finish_if(stream, |peer| peer != rejected_addr)?;
+        assert!(client.is_none());
+
+        let stream = fibers_global::execute(TcpStream::connect(accepted_addr))?;
+        let client = Builder::new().finish_if(stream, |peer| peer != rejected_addr)?;
+        assert_eq!(client.map(|c| c.peer_addr()), Some(accepted_addr));
+        Ok(())
+    }
+
+    #[test]
+    fn swap_codec_works() -> Result<()> {
+        let (mut client, server) = connect()?;
+
+        client.start_send((), 1)?;
+        let client = fibers_global::execute(wait_send(client))?;
+        let mut client = client.swap_codec(U16beEncoder::new(), U16beDecoder::new())?;
+        client.start_send((), 0x1234)?;
+        let _client = fibers_global::execute(wait_send(client))?;
+
+        let (server, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 1);
+        let server = server.swap_codec(U16beEncoder::new(), U16beDecoder::new())?;
+        let (_, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 0x1234);
+        Ok(())
+    }
+
+    #[test]
+    fn into_codec_works() -> Result<()> {
+        let (mut client, server) = connect()?;
+        let server = server.swap_codec(U8Encoder::new(), U16beDecoder::new())?;
+
+        // The decoder of the server holds the first byte of a `u16` when the connection ends.
+        client.start_send((), 0x01)?;
+        let _client = fibers_global::execute(wait_send(client))?;
+        let (server, ()) = fibers_global::execute(drive(server, |s| {
+            track!(s.poll_recv())?;
+            Ok(if s.decoder_ref().is_idle() {
+                Async::NotReady
+            } else {
+                Async::Ready(())
+            })
+        }))?;
+        let (encoder, decoder) = server.into_codec()?;
+
+        let (mut client, server) = connect()?;
+        let server = server.swap_codec(encoder, decoder)?;
+        client.start_send((), 0x02)?;
+        let _client = fibers_global::execute(wait_send(client))?;
+        let (_, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 0x0102);
+        Ok(())
+    }
+
+    #[test]
+    fn replace_stream_works() -> Result<()> {
+        let (mut client, server0) = connect()?;
+        let server1 = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let server1_addr = server1.local_addr();
+
+        client.start_send((), 1)?;
+        let mut client = fibers_global::execute(wait_send(client))?;
+        let (_, _, item) = fibers_global::execute(wait_recv(server0))?;
+        assert_eq!(item, 1);
+
+        // Simulates items queued while the old stream was stalled.
+        client.inner.enqueue(2, Priority::Normal)?;
+        client.inner.enqueue(3, Priority::Normal)?;
+
+        let stream = fibers_global::execute(TcpStream::connect(server1_addr).map_err(Error::from))?;
+        client.replace_stream(stream)?;
+        assert_eq!(client.peer_addr(), server1_addr);
+        assert_eq!(client.message_queue_len(), 2);
+        let _client = fibers_global::execute(wait_send(client))?;
+
+        let (server1, _) = fibers_global::execute(server1.into_future()).map_err(|(e, _)| e)?;
+        let mut server1 = server1.expect("never fails");
+        for i in 2..4 {
+            let (s, _, item) = fibers_global::execute(wait_recv(server1))?;
+            assert_eq!(item, i);
+            server1 = s;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn codec_error_context_works() -> Result<()> {
+        let (client, server) = connect()?;
+
+        let encoder = U8Encoder::new().try_map_from(|_: u8| -> bytecodec::Result<u8> {
+            track_panic!(bytecodec::ErrorKind::InvalidInput)
+        });
+        let mut client = client.swap_codec(encoder, U8Decoder::new())?;
+        let e = client.start_send((), 1).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::CodecError);
+        assert!(e.to_string().contains("while encoding"));
+
+        let decoder = U8Decoder::new().try_map(|_| -> bytecodec::Result<u8> {
+            track_panic!(bytecodec::ErrorKind::InvalidInput)
+        });
+        let mut server = server.swap_codec(U8Encoder::new(), decoder)?;
+        server.start_send((), 1)?;
+        let server = fibers_global::execute(wait_send(server))?;
+        let mut client = client.swap_codec(U8Encoder::new(), U8Decoder::new())?;
+        client.start_send((), 2)?;
+        let _client = fibers_global::execute(wait_send(client))?;
+        let e = fibers_global::execute(wait_recv(server)).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::CodecError);
+        assert!(e.to_string().contains("while decoding"));
+        Ok(())
+    }
+
+    #[test]
+    fn codec_error_mapper_works() -> Result<()> {
+        use trackable::error::ErrorKindExt;
+
+        let (a, b) = MemoryStream::pair();
+        let mut a = StreamTransporter::<_, U8Encoder, U8Decoder>::new(a);
+        let decoder = U8Decoder::new().try_map(|b| -> bytecodec::Result<u8> {
+            track_assert_ne!(b, 0, bytecodec::ErrorKind::InvalidInput);
+            Ok(b)
+        });
+        let mut b = TcpTransporterBuilder::with_codec(U8Encoder::new(), decoder)
+            .codec_error_mapper(|e| {
+                if *e.kind() == bytecodec::ErrorKind::InvalidInput {
+                    ErrorKind::InvalidInput.takes_over(e).into()
+                } else {
+                    Error::from(e)
+                }
+            })
+            .finish_with_stream(b);
+
+        a.start_send((), 0)?;
+        assert_eq!(a.poll_send()?, Async::Ready(()));
+        let e = b.poll_recv().err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("while decoding"));
+        Ok(())
+    }
+
+    #[test]
+    fn send_after_close_fails() -> Result<()> {
+        let (client, server) = connect()?;
+        std::mem::drop(server);
+
+        let (mut client, item) = fibers_global::execute(try_recv(client))?;
+        assert!(item.is_none());
+        assert!(client.is_read_closed());
+
+        client.shutdown()?;
+        assert!(client.is_closed());
+        let e = client.start_send((), 1).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Closed);
+        assert_eq!(client.message_queue_len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn eos_terminated_item_works() -> Result<()> {
+        type Server = TcpListener<DefaultFactory<Utf8Encoder>, DefaultFactory<Utf8Decoder>>;
+        type Client = TcpTransporter<Utf8Encoder, Utf8Decoder>;
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let mut client = fibers_global::execute(Client::connect(server.local_addr()))?;
+        let (accepted, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let server = accepted.expect("never fails");
+
+        // The item ends where the stream ends.
+        client.start_send((), "hello".to_owned())?;
+        let client = fibers_global::execute(wait_send(client))?;
+        std::mem::drop(client);
+
+        let (server, item) = fibers_global::execute(try_recv(server))?;
+        assert_eq!(item, Some(((), "hello".to_owned())));
+        let (server, item) = fibers_global::execute(try_recv(server))?;
+        assert!(item.is_none());
+        assert!(server.is_read_closed());
+        Ok(())
+    }
+
+    #[test]
+    fn half_close_works() -> Result<()> {
+        let (client, mut server) = connect()?;
+        server.shutdown_write()?;
+        assert!(server.is_write_closed());
+        assert!(!server.is_closed());
+
+        let (mut client, item) = fibers_global::execute(try_recv(client))?;
+        assert!(item.is_none());
+        assert!(client.is_read_closed());
+        assert!(!client.is_write_closed());
+
+        client.start_send((), 12)?;
+        let mut client = fibers_global::execute(wait_send(client))?;
+        let (server, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 12);
+
+        client.shutdown_write()?;
+        assert!(client.is_closed());
+        let (server, item) = fibers_global::execute(try_recv(server))?;
+        assert!(item.is_none());
+        assert!(server.is_closed());
+        Ok(())
+    }
+
+    #[test]
+    fn state_changes_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let mut builder = Builder::new();
+        let changes = builder.state_changes();
+        let mut client = fibers_global::execute(builder.connect(server.local_addr()))?;
+        let (server, _, _) = fibers_global::execute(server.accept_one())?;
+        assert_eq!(client.state(), ConnectionState::Connected);
+
+        client.shutdown_write()?;
+        assert_eq!(client.state(), ConnectionState::Draining);
+
+        let (server, item) = fibers_global::execute(try_recv(server))?;
+        assert!(item.is_none());
+        std::mem::drop(server);
+
+        let (client, item) = fibers_global::execute(try_recv(client))?;
+        assert!(item.is_none());
+        assert_eq!(client.state(), ConnectionState::Closed);
+        std::mem::drop(client);
+
+        let states = fibers_global::execute(changes.collect()).expect("never fails");
+        assert_eq!(
+            states,
+            [
+                ConnectionState::Connecting,
+                ConnectionState::Connected,
+                ConnectionState::Draining,
+                ConnectionState::Closed
+            ]
+        );
+
+        // A failed connect attempt.
+        let closed_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("never fails");
+        let mut builder = Builder::new();
+        let changes = builder.state_changes();
+        assert!(fibers_global::execute(builder.connect(closed_addr)).is_err());
+        let states = fibers_global::execute(changes.collect()).expect("never fails");
+        assert_eq!(
+            states,
+            [ConnectionState::Connecting, ConnectionState::Errored]
+        );
+        Ok(())
+    }
+
+    /// A stateful decoder that yields the running sum of the received bytes.
+    #[derive(Debug, Default, Clone)]
+    struct SumDecoder {
+        sum: u8,
+        item: Option<u8>,
+    }
+    impl Decode for SumDecoder {
+        type Item = u8;
+
+        fn decode(&mut self, buf: &[u8], _eos: bytecodec::Eos) -> bytecodec::Result<usize> {
+            if self.item.is_some() || buf.is_empty() {
+                return Ok(0);
+            }
+            self.sum = self.sum.wrapping_add(buf[0]);
+            self.item = Some(self.sum);
+            Ok(1)
+        }
+
+        fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+            let item =
+                track_assert_some!(self.item.take(), bytecodec::ErrorKind::IncompleteDecoding);
+            Ok(item)
+        }
+
+        fn requiring_bytes(&self) -> bytecodec::ByteCount {
+            if self.item.is_some() {
+                bytecodec::ByteCount::Finite(0)
+            } else {
+                bytecodec::ByteCount::Finite(1)
+            }
+        }
+    }
+
+    #[test]
+    fn decoder_state_works() -> Result<()> {
+        type SumServer = TcpListener<DefaultFactory<U8Encoder>, DefaultFactory<SumDecoder>>;
+        let server = fibers_global::execute(SumServer::listen("127.0.0.1:0".parse().unwrap()))?;
+        let server_addr = server.local_addr();
+
+        let mut client = fibers_global::execute(Client::connect(server_addr))?;
+        let (accepted, server) =
+            fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let accepted = accepted.expect("never fails");
+        client.start_send((), 1)?;
+        client.start_send((), 2)?;
+        let _client = fibers_global::execute(wait_send(client))?;
+        let (accepted, _, item) = fibers_global::execute(wait_recv(accepted))?;
+        assert_eq!(item, 1);
+        let (accepted, _, item) = fibers_global::execute(wait_recv(accepted))?;
+        assert_eq!(item, 3);
+        let state = accepted.export_decoder_state();
+
+        // Reconnects, and restores the decoder state on the new connection.
+        let mut client = fibers_global::execute(Client::connect(server_addr))?;
+        let (accepted, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let mut accepted = accepted.expect("never fails");
+        accepted.import_decoder_state(state);
+        client.start_send((), 4)?;
+        let _client = fibers_global::execute(wait_send(client))?;
+        let (_, _, item) = fibers_global::execute(wait_recv(accepted))?;
+        assert_eq!(item, 7);
+        Ok(())
+    }
+
+    /// A buggy encoder that accepts items but never produces any bytes.
+    #[derive(Debug, Default)]
+    struct NoopEncoder;
+    impl Encode for NoopEncoder {
+        type Item = u8;
+
+        fn encode(&mut self, _buf: &mut [u8], _eos: bytecodec::Eos) -> bytecodec::Result<usize> {
+            Ok(0)
+        }
+
+        fn start_encoding(&mut self, _item: Self::Item) -> bytecodec::Result<()> {
+            Ok(())
+        }
+
+        fn requiring_bytes(&self) -> bytecodec::ByteCount {
+            bytecodec::ByteCount::Unknown
+        }
+
+        fn is_idle(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn strict_encode_works() -> Result<()> {
+        type Builder = TcpTransporterBuilder<NoopEncoder, U8Decoder>;
+
+        // The message is silently dropped.
+        let (a, _b) = MemoryStream::pair();
+        let mut a = Builder::new()
+            .eager_flush(false)
+            .strict_encode(false)
+            .finish_with_stream(a);
+        a.start_send((), 1)?;
+        assert_eq!(a.poll_send()?, Async::Ready(()));
+
+        // The dropped message is detected.
+        let (a, _b) = MemoryStream::pair();
+        let mut a = Builder::new()
+            .eager_flush(false)
+            .strict_encode(true)
+            .finish_with_stream(a);
+        a.start_send((), 1)?;
+        assert_eq!(
+            a.poll_send().err().map(|e| *e.kind()),
+            Some(ErrorKind::CodecError)
+        );
+
+        // Messages known to be encoded to zero bytes are not rejected.
+        let (a, _b) = MemoryStream::pair();
+        let mut a = TcpTransporterBuilder::<BytesEncoder<Vec<u8>>, U8Decoder>::new()
+            .eager_flush(false)
+            .strict_encode(true)
+            .finish_with_stream(a);
+        a.start_send((), Vec::new())?;
+        assert_eq!(a.poll_send()?, Async::Ready(()));
+        Ok(())
+    }
+
+    #[test]
+    fn close_with_works() -> Result<()> {
+        let (mut client, server) = connect()?;
+        client.start_send((), 1)?;
+        fibers_global::execute(client.close_with(0xFF))?;
+
+        let (server, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 1);
+        let (server, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 0xFF);
+        let (server, item) = fibers_global::execute(try_recv(server))?;
+        assert!(item.is_none());
+        assert!(server.is_read_closed());
+        Ok(())
+    }
+
+    #[test]
+    fn connection_reset_works() -> Result<()> {
+        let (mut client, server) = connect()?;
+        client.start_send((), 1)?;
+        let mut client = fibers_global::execute(wait_send(client))?;
+
+        // Closing a socket that has unread data makes the kernel send RST to the peer.
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        std::mem::drop(server);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let mut error = None;
+        for i in 0..100 {
+            if let Err(e) = client
+                .start_send((), i)
+                .and_then(|()| client.poll_send().map(|_| ()))
+            {
+                error = Some(e);
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let e = error.expect("The connection should be reset");
+        assert_eq!(*e.kind(), ErrorKind::ConnectionReset);
+        assert!(client.is_closed());
+
+        let e = client.start_send((), 1).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Closed);
+        Ok(())
+    }
+
+    #[test]
+    fn try_start_send_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let builder = TcpTransporterBuilder::<U8Encoder, U8Decoder>::new().max_queue_len(2);
+        let mut client = fibers_global::execute(builder.connect(server.local_addr()))?;
+        let (server, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let mut server = server.expect("never fails");
+
+        client.try_start_send(1).map_err(Error::from)?;
+        client.try_start_send(2).map_err(Error::from)?;
+        let e = client.try_start_send(3).err().unwrap();
+        assert_eq!(*e.error().kind(), ErrorKind::Other);
+        assert_eq!(e.into_item(), 3);
+        assert_eq!(client.message_queue_len(), 2);
+
+        let _client = fibers_global::execute(wait_send(client))?;
+        for i in 1..3 {
+            let (s, _, item) = fibers_global::execute(wait_recv(server))?;
+            assert_eq!(item, i);
+            server = s;
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_buf_size(fd: std::os::unix::io::RawFd, name: libc::c_int, size: libc::c_int) {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                &size as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn track_queue_latency_works() -> Result<()> {
+        use bytecodec::bytes::BytesEncoder;
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        use std::time::Duration;
+
+        // The small socket buffers stall the writes of the client until the server reads.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("never fails");
+        set_buf_size(listener.as_raw_fd(), libc::SO_RCVBUF, 4096);
+        let builder = TcpTransporterBuilder::<BytesEncoder<Vec<u8>>, U8Decoder>::new()
+            .track_queue_latency(true);
+        let client = builder.connect(listener.local_addr().expect("never fails"));
+        let mut client = fibers_global::execute(client)?;
+        let (mut server, _) = listener.accept().expect("never fails");
+        let client_fd = client.stream_ref().with_inner(|s| s.as_raw_fd());
+        set_buf_size(client_fd, libc::SO_SNDBUF, 4096);
+
+        let size = 1024 * 1024;
+        client.start_send((), vec![1; size])?;
+        client.start_send((), vec![2])?;
+        assert_eq!(client.message_queue_len(), 2);
+
+        let reader = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let mut buf = vec![0; size + 1];
+            server.read_exact(&mut buf).expect("never fails");
+        });
+        let client = fibers_global::execute(wait_send(client))?;
+        reader.join().expect("never fails");
+
+        let metrics = client.metrics();
+        assert_eq!(metrics.sent_messages, 2);
+        assert_eq!(metrics.queue_latency_samples, 2);
+        assert!(metrics.total_queue_latency >= Duration::from_millis(50));
+        assert!(metrics.avg_queue_latency().expect("never fails") > Duration::from_millis(0));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn write_buf_threshold_works() -> Result<()> {
+        use bytecodec::bytes::BytesEncoder;
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+
+        // The small socket buffers stall the writes of the client until the server reads.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("never fails");
+        set_buf_size(listener.as_raw_fd(), libc::SO_RCVBUF, 4096);
+        let builder = TcpTransporterBuilder::<BytesEncoder<Vec<u8>>, U8Decoder>::new()
+            .buf_size(8192)
+            .write_buf_threshold(1024);
+        let client = builder.connect(listener.local_addr().expect("never fails"));
+        let mut client = fibers_global::execute(client)?;
+        let (mut server, _) = listener.accept().expect("never fails");
+        let client_fd = client.stream_ref().with_inner(|s| s.as_raw_fd());
+        set_buf_size(client_fd, libc::SO_SNDBUF, 4096);
+
+        let size = 1024 * 1024;
+        client.start_send((), vec![1; size])?;
+        assert_eq!(client.poll_send_ready()?, Async::NotReady);
+        assert_eq!(client.write_buf_high_water(), 8192);
+
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![0; size];
+            server.read_exact(&mut buf).expect("never fails");
+        });
+        let mut client = fibers_global::execute(wait_send(client))?;
+        reader.join().expect("never fails");
+        assert_eq!(client.poll_send_ready()?, Async::Ready(()));
+        assert_eq!(client.write_buf_high_water(), 8192);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn poll_flushed_works() -> Result<()> {
+        use bytecodec::bytes::BytesEncoder;
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+
+        // The small receive buffer of the server keeps the sent bytes in the send buffer of the client.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("never fails");
+        set_buf_size(listener.as_raw_fd(), libc::SO_RCVBUF, 4096);
+        let client = TcpTransporter::<BytesEncoder<Vec<u8>>, U8Decoder>::connect(
+            listener.local_addr().expect("never fails"),
+        );
+        let mut client = fibers_global::execute(client)?;
+        let (mut server, _) = listener.accept().expect("never fails");
+        let client_fd = client.stream_ref().with_inner(|s| s.as_raw_fd());
+        set_buf_size(client_fd, libc::SO_SNDBUF, 1024 * 1024);
+
+        let size = 64 * 1024;
+        client.start_send((), vec![1; size])?;
+        let client = fibers_global::execute(wait_send(client))?;
+
+        let (client, flushed) = fibers_global::execute(futures::lazy(move || {
+            let mut client = client;
+            let flushed = client.poll_flushed();
+            flushed.map(|flushed| (client, flushed.is_ready()))
+        }))?;
+        assert!(!flushed);
+        assert_ne!(sys::tcp_unsent_bytes(client.stream_ref()).unwrap(), 0);
+
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![0; size];
+            server.read_exact(&mut buf).expect("never fails");
+            buf
+        });
+        let (client, ()) = fibers_global::execute(drive(client, |c| c.poll_flushed()))?;
+        assert_eq!(sys::tcp_unsent_bytes(client.stream_ref()).unwrap(), 0);
+        assert_eq!(reader.join().expect("never fails"), vec![1; size]);
+        Ok(())
+    }
+
+    #[test]
+    fn queue_capacity_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let builder = TcpTransporterBuilder::<U8Encoder, U8Decoder>::new()
+            .queue_capacity(100)
+            .eager_flush(false);
+        let mut client = fibers_global::execute(builder.connect(server.local_addr()))?;
+        let (server, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let mut server = server.expect("never fails");
+
+        for i in 0..100 {
+            client.start_send_prioritized((), 0, Priority::Low)?;
+            client.start_send((), i)?;
+            client.start_send_prioritized((), 200, Priority::High)?;
+        }
+        assert_eq!(client.message_queue_len(), 300);
+        let _client = fibers_global::execute(wait_send(client))?;
+
+        let expected = std::iter::repeat_n(200, 100)
+            .chain(0..100)
+            .chain(std::iter::repeat_n(0, 100));
+        for expected in expected {
+            let (s, _, item) = fibers_global::execute(wait_recv(server))?;
+            assert_eq!(item, expected);
+            server = s;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn eager_flush_disabled_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let builder = TcpTransporterBuilder::<U8Encoder, U8Decoder>::new().eager_flush(false);
+        let mut client = fibers_global::execute(builder.connect(server.local_addr()))?;
+        let (server, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let mut server = server.expect("never fails");
+
+        for i in 0..10 {
+            client.start_send((), i)?;
+        }
+        assert_eq!(client.message_queue_len(), 10);
+        assert!(client.inner.encoder.is_idle());
+        assert!(client.inner.stream.write_buf_ref().is_empty());
+
+        let client = fibers_global::execute(wait_send(client))?;
+        assert_eq!(client.message_queue_len(), 0);
+        for i in 0..10 {
+            let (s, _, item) = fibers_global::execute(wait_recv(server))?;
+            assert_eq!(item, i);
+            server = s;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn start_send_prioritized_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let builder = TcpTransporterBuilder::<U8Encoder, U8Decoder>::new().eager_flush(false);
+        let mut client = fibers_global::execute(builder.connect(server.local_addr()))?;
+        let (server, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let server = server.expect("never fails");
+
+        client.start_send_prioritized((), 1, Priority::Low)?;
+        client.start_send((), 2)?;
+        client.start_send_prioritized((), 3, Priority::High)?;
+        let _client = fibers_global::execute(wait_send(client))?;
+
+        let (server, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 3);
+        let (server, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 2);
+        let (_, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 1);
+        Ok(())
+    }
+
+    /// A decoder that consumes no bytes until a whole length-prefixed frame is in the buffer.
+    #[derive(Debug, Default)]
+    struct WholeFrameDecoder(Option<Vec<u8>>);
+    impl Decode for WholeFrameDecoder {
+        type Item = Vec<u8>;
+
+        fn decode(&mut self, buf: &[u8], _eos: bytecodec::Eos) -> bytecodec::Result<usize> {
+            if self.0.is_some() || buf.len() < 2 {
+                return Ok(0);
+            }
+            let len = 2 + ((buf[0] as usize) << 8 | buf[1] as usize);
+            if buf.len() < len {
+                return Ok(0);
+            }
+            self.0 = Some(buf[2..len].to_vec());
+            Ok(len)
+        }
+
+        fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+            let item = track_assert_some!(self.0.take(), bytecodec::ErrorKind::IncompleteDecoding);
+            Ok(item)
+        }
+
+        fn requiring_bytes(&self) -> bytecodec::ByteCount {
+            if self.0.is_some() {
+                bytecodec::ByteCount::Finite(0)
+            } else {
+                bytecodec::ByteCount::Unknown
+            }
+        }
+    }
+
+    #[test]
+    fn read_buf_grow_works() -> Result<()> {
+        let (mut client, server) = connect()?;
+        let builder =
+            TcpTransporterBuilder::with_codec(U8Encoder::new(), WholeFrameDecoder::default())
+                .buf_size(16)
+                .read_buf_grow(true)
+                .max_read_buf_size(64);
+        let server = builder.finish(server.inner.stream.into_stream())?;
+
+        let mut frame = vec![0, 40];
+        frame.extend((0..40).map(|i| i as u8));
+        for b in frame {
+            client.start_send((), b)?;
+        }
+        let mut client = fibers_global::execute(wait_send(client))?;
+        let (server, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, (0..40).map(|i| i as u8).collect::<Vec<_>>());
+        assert_eq!(server.inner.stream.read_buf_ref().capacity(), 64);
+
+        for b in [0, 100] {
+            client.start_send((), b)?;
+        }
+        for _ in 0..100 {
+            client.start_send((), 0)?;
+        }
+        let _client = fibers_global::execute(wait_send(client))?;
+        let e = fibers_global::execute(wait_recv(server)).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn start_send_batch_works() -> Result<()> {
+        let (a, b) = MemoryStream::pair();
+        let writes = a.writes.clone();
+        let mut a = Builder::new()
+            .write_coalesce_bytes(16)
+            .finish_with_stream(a);
+        let mut b = StreamTransporter::<_, U8Encoder, U8Decoder>::new(b);
+
+        // Each item is flushed eagerly.
+        for i in 0..3 {
+            a.start_send((), i)?;
+        }
+        assert_eq!(writes.get(), 3);
+
+        // All of the items are enqueued before being flushed eagerly.
+        a.start_send_batch((), vec![3, 4, 5])?;
+        assert_eq!(writes.get(), 4);
+        for i in 0..6 {
+            assert_eq!(b.poll_recv()?, Async::Ready(Some(((), i))));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn write_coalesce_bytes_works() -> Result<()> {
+        // Returns the number of the `write` calls issued for sending 100 messages.
+        let count_writes = |coalesce_bytes| -> Result<usize> {
+            let (a, b) = MemoryStream::pair();
+            let writes = a.writes.clone();
+            let mut a = Builder::new()
+                .write_coalesce_bytes(coalesce_bytes)
+                .eager_flush(false)
+                .finish_with_stream(a);
+            let mut b = StreamTransporter::<_, U8Encoder, U8Decoder>::new(b);
+            for i in 0..100 {
+                a.start_send((), i)?;
+            }
+            assert_eq!(a.poll_send()?, Async::Ready(()));
+            assert_eq!(a.message_queue_len(), 0);
+            for i in 0..100 {
+                assert_eq!(b.poll_recv()?, Async::Ready(Some(((), i))));
+            }
+            Ok(writes.get())
+        };
+
+        let uncoalesced = count_writes(0)?;
+        let coalesced = count_writes(16)?;
+        assert_eq!(uncoalesced, 100);
+        assert!(coalesced < uncoalesced, "coalesced={}", coalesced);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cork_messages_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let builder = TcpTransporterBuilder::<U8Encoder, U8Decoder>::new().cork_messages(true);
+        let mut client = fibers_global::execute(builder.connect(server.local_addr()))?;
+        let (server, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let mut server = server.expect("never fails");
+
+        for i in 0..3 {
+            client.start_send((), i)?;
+        }
+        let client = fibers_global::execute(wait_send(client))?;
+        assert!(!client.corked);
+
+        for i in 0..3 {
+            let (s, _, item) = fibers_global::execute(wait_recv(server))?;
+            assert_eq!(item, i);
+            server = s;
+        }
+        Ok(())
     }
 }
@@ -1,19 +1,114 @@
-use crate::{Error, Result, TcpTransporter, TcpTransporterBuilder};
+use crate::port_range::{self, FamilyPreference};
+use crate::{
+    Clock, Error, ErrorKind, FibersClock, FibersDelay, Result, TcpTransport, TcpTransporter,
+    TcpTransporterBuilder,
+};
 use bytecodec::{Decode, Encode};
 use factory::Factory;
 use fibers::net::futures::Connected;
 use fibers::net::streams::Incoming;
-use fibers::net::TcpListener as RawTcpListener;
+use fibers::net::TcpListener as FibersTcpListener;
+use fibers::sync::mpsc;
 use futures::{Async, Future, Poll, Stream};
-use std::net::SocketAddr;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type AcceptErrorHook = Box<dyn FnMut(&Error) + Send>;
+type PollAccept = Poll<Option<(Connected, SocketAddr)>, io::Error>;
+
+/// This trait allows for creating a pair of an encoder and a decoder at once.
+///
+/// This is implemented for every [`Factory`] that creates `(encoder, decoder)` pairs
+/// (e.g., `DefaultFactory<(E, D)>`), and is used by [`TcpListenerBuilder::with_codec_factory`].
+///
+/// [`Factory`]: https://docs.rs/factory/0.1/factory/trait.Factory.html
+/// [`TcpListenerBuilder::with_codec_factory`]: ./struct.TcpListenerBuilder.html#method.with_codec_factory
+pub trait CodecFactory {
+    /// Encoder.
+    type Encoder: Encode;
+
+    /// Decoder.
+    type Decoder: Decode;
+
+    /// Creates a pair of an encoder and a decoder.
+    fn create_codec(&self) -> (Self::Encoder, Self::Decoder);
+}
+impl<F, E, D> CodecFactory for F
+where
+    F: Factory<Item = (E, D)>,
+    E: Encode,
+    D: Decode,
+{
+    type Encoder = E;
+    type Decoder = D;
+
+    fn create_codec(&self) -> (Self::Encoder, Self::Decoder) {
+        self.create()
+    }
+}
+
+/// The encoder factory made by [`TcpListenerBuilder::with_codec_factory`].
+///
+/// Each `create` call creates a codec pair, and keeps its decoder for the subsequent
+/// `CodecDecoderFactory::create` call, so that the encoder and decoder of each connection are created together.
+///
+/// [`TcpListenerBuilder::with_codec_factory`]: ./struct.TcpListenerBuilder.html#method.with_codec_factory
+pub struct CodecEncoderFactory<F: CodecFactory>(Arc<SharedCodecFactory<F>>);
+impl<F: CodecFactory> Factory for CodecEncoderFactory<F> {
+    type Item = F::Encoder;
+
+    fn create(&self) -> Self::Item {
+        let (encoder, decoder) = self.0.factory.create_codec();
+        *self.0.decoder.lock().expect("never fails") = Some(decoder);
+        encoder
+    }
+}
+impl<F: CodecFactory> fmt::Debug for CodecEncoderFactory<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CodecEncoderFactory {{ .. }}")
+    }
+}
+
+/// The decoder factory made by [`TcpListenerBuilder::with_codec_factory`].
+///
+/// See [`CodecEncoderFactory`] for more details.
+///
+/// [`TcpListenerBuilder::with_codec_factory`]: ./struct.TcpListenerBuilder.html#method.with_codec_factory
+/// [`CodecEncoderFactory`]: ./struct.CodecEncoderFactory.html
+pub struct CodecDecoderFactory<F: CodecFactory>(Arc<SharedCodecFactory<F>>);
+impl<F: CodecFactory> Factory for CodecDecoderFactory<F> {
+    type Item = F::Decoder;
+
+    fn create(&self) -> Self::Item {
+        let decoder = self.0.decoder.lock().expect("never fails").take();
+        decoder.unwrap_or_else(|| self.0.factory.create_codec().1)
+    }
+}
+impl<F: CodecFactory> fmt::Debug for CodecDecoderFactory<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CodecDecoderFactory {{ .. }}")
+    }
+}
+
+struct SharedCodecFactory<F: CodecFactory> {
+    factory: F,
+    decoder: Mutex<Option<F::Decoder>>,
+}
 
 /// [`TcpListener`] builder.
 ///
 /// [`TcpListener`]: ./struct.TcpListener.html
-#[derive(Debug)]
 pub struct TcpListenerBuilder<E, D> {
     encoder_factory: E,
     decoder_factory: D,
+    accept_error_backoff: Duration,
+    accept_error_hook: Option<AcceptErrorHook>,
+    max_connections: Option<usize>,
+    family_preference: FamilyPreference,
 }
 impl<E, D> TcpListenerBuilder<E, D>
 where
@@ -39,19 +134,98 @@ where
         TcpListenerBuilder {
             encoder_factory,
             decoder_factory,
+            accept_error_backoff: Duration::from_millis(100),
+            accept_error_hook: None,
+            max_connections: None,
+            family_preference: FamilyPreference::default(),
         }
     }
 
-    /// Builds a new `TcpListener` instance from the given `RawTcpListener`.
-    pub fn finish(self, listener: RawTcpListener) -> Result<TcpListener<E, D>> {
+    /// Sets the maximum number of live connections handed out by the listener.
+    ///
+    /// A connection is regarded as live until the `TcpTransporter` created for it is dropped.
+    /// While the number of live connections reaches the limit, the listener stops accepting,
+    /// and the pending connections wait in the backlog of the socket.
+    ///
+    /// By default, the number of connections is unlimited.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Sets the duration for which the listener stops accepting after a recoverable accept error.
+    ///
+    /// Recoverable errors are the ones caused by transient resource exhaustion
+    /// (e.g., `EMFILE` or `ENFILE`) or by connections aborted before being accepted.
+    /// Such errors do not terminate the listener.
+    ///
+    /// The default value is `100ms`.
+    pub fn accept_error_backoff(mut self, backoff: Duration) -> Self {
+        self.accept_error_backoff = backoff;
+        self
+    }
+
+    /// Sets the preference of the address family used by `listen_host`.
+    ///
+    /// The default value is `FamilyPreference::PreferIpv4`.
+    pub fn family_preference(mut self, preference: FamilyPreference) -> Self {
+        self.family_preference = preference;
+        self
+    }
+
+    /// Sets the hook invoked with each recoverable accept error (e.g., for logging).
+    ///
+    /// The hook is also invoked with the error of each accepted connection that failed to be set up
+    /// (see [`TcpListener::failed_connections`]).
+    ///
+    /// By default, recoverable accept errors are silently ignored.
+    ///
+    /// [`TcpListener::failed_connections`]: ./struct.TcpListener.html#method.failed_connections
+    pub fn on_accept_error<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&Error) + Send + 'static,
+    {
+        self.accept_error_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Builds a new `TcpListener` instance from the given `fibers::net::TcpListener`.
+    ///
+    /// Note that [`fibers`] provides no way to convert a `std::net::TcpListener` into
+    /// a `fibers::net::TcpListener`, so listeners created elsewhere cannot be wrapped directly.
+    ///
+    /// [`fibers`]: https://crates.io/crates/fibers
+    pub fn finish(self, listener: FibersTcpListener) -> Result<TcpListener<E, D>> {
         let local_addr = track!(listener.local_addr().map_err(Error::from))?;
-        Ok(TcpListener {
+        let raw = RawTcpListener {
             incoming: listener.incoming(),
             local_addr,
+            client_futures: Vec::new(),
+        };
+        Ok(self.finish_raw(raw))
+    }
+
+    /// Builds a new `TcpListener` instance from the given `RawTcpListener` extracted by `TcpListener::into_raw`.
+    ///
+    /// This is equivalent to `TcpListener::from_raw_with_state(raw, self)`.
+    pub fn finish_raw(self, raw: RawTcpListener) -> TcpListener<E, D> {
+        let (release_tx, release_rx) = mpsc::channel();
+        TcpListener {
+            incoming: raw.incoming,
+            local_addr: raw.local_addr,
             encoder_factory: self.encoder_factory,
             decoder_factory: self.decoder_factory,
-            client_futures: Vec::new(),
-        })
+            client_futures: raw.client_futures,
+            accept_error_backoff: self.accept_error_backoff,
+            accept_error_hook: self.accept_error_hook,
+            backoff: None,
+            max_connections: self.max_connections,
+            live_connections: 0,
+            accepted_connections: 0,
+            failed_connections: 0,
+            release_tx,
+            release_rx,
+        }
     }
 
     /// Builds a new `TcpListener` instance that binds to and listens in the given address.
@@ -59,10 +233,56 @@ where
         self,
         bind_addr: SocketAddr,
     ) -> impl Future<Item = TcpListener<E, D>, Error = Error> {
-        track_err!(RawTcpListener::bind(bind_addr).map_err(Error::from))
+        track_err!(FibersTcpListener::bind(bind_addr).map_err(Error::from))
+            .and_then(move |listener| track!(self.finish(listener)))
+    }
+
+    /// Builds a new `TcpListener` instance that binds to and listens in a port in the given range.
+    ///
+    /// The ports are tried in ascending order, and those already in use are skipped.
+    /// If all of the ports are in use, the resulting future fails with an `ErrorKind::InvalidInput` error.
+    pub fn listen_in_range(
+        self,
+        ip: IpAddr,
+        ports: RangeInclusive<u16>,
+    ) -> impl Future<Item = TcpListener<E, D>, Error = Error> {
+        port_range::bind_in_range(ip, ports, FibersTcpListener::bind)
+            .and_then(move |listener| track!(self.finish(listener)))
+    }
+
+    /// Resolves the given host, and builds a new `TcpListener` instance that binds to and listens in
+    /// one of the resolved addresses.
+    ///
+    /// The resolved addresses are tried in the order specified by `family_preference`
+    /// until one is bound successfully.
+    /// If no address is bound, the resulting future fails with the error of the last attempt.
+    ///
+    /// Note that the host is resolved synchronously (i.e., this blocks the current thread until resolution completes).
+    pub fn listen_host(
+        self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<Item = TcpListener<E, D>, Error = Error> {
+        port_range::bind_host(host, port, self.family_preference, FibersTcpListener::bind)
             .and_then(move |listener| track!(self.finish(listener)))
     }
 }
+impl<F: CodecFactory> TcpListenerBuilder<CodecEncoderFactory<F>, CodecDecoderFactory<F>> {
+    /// Makes a new `TcpListenerBuilder` instance with the given factory that creates
+    /// both the encoder and decoder of each connection.
+    ///
+    /// This is convenient for symmetric protocols (e.g., `DefaultFactory<(MyEncoder, MyDecoder)>`).
+    pub fn with_codec_factory(factory: F) -> Self {
+        let shared = Arc::new(SharedCodecFactory {
+            factory,
+            decoder: Mutex::new(None),
+        });
+        Self::with_codec(
+            CodecEncoderFactory(shared.clone()),
+            CodecDecoderFactory(shared),
+        )
+    }
+}
 impl<E, D> Default for TcpListenerBuilder<E, D>
 where
     E: Factory + Default,
@@ -75,15 +295,47 @@ where
     }
 }
 
+impl<E: fmt::Debug, D: fmt::Debug> fmt::Debug for TcpListenerBuilder<E, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "TcpListenerBuilder {{ encoder_factory: {:?}, decoder_factory: {:?}, \
+             accept_error_backoff: {:?}, max_connections: {:?}, .. }}",
+            self.encoder_factory,
+            self.decoder_factory,
+            self.accept_error_backoff,
+            self.max_connections
+        )
+    }
+}
+
 /// TCP listener.
+///
+/// Recoverable accept errors (see [`TcpListenerBuilder::accept_error_backoff`]) do not terminate the stream.
+/// Instead, the listener reports them to the hook set by [`TcpListenerBuilder::on_accept_error`],
+/// and stops accepting for a while.
+///
+/// Likewise, an accepted connection that fails to be set up (e.g., because it was reset by the peer
+/// before a `TcpTransporter` is built for it) is skipped, and accepting continues.
+///
+/// [`TcpListenerBuilder::accept_error_backoff`]: ./struct.TcpListenerBuilder.html#method.accept_error_backoff
+/// [`TcpListenerBuilder::on_accept_error`]: ./struct.TcpListenerBuilder.html#method.on_accept_error
 #[must_use = "streams do nothing unless polled"]
-#[derive(Debug)]
 pub struct TcpListener<E, D> {
     incoming: Incoming,
     local_addr: SocketAddr,
     encoder_factory: E,
     decoder_factory: D,
     client_futures: Vec<Connected>,
+    accept_error_backoff: Duration,
+    accept_error_hook: Option<AcceptErrorHook>,
+    backoff: Option<FibersDelay>,
+    max_connections: Option<usize>,
+    live_connections: usize,
+    accepted_connections: u64,
+    failed_connections: u64,
+    release_tx: mpsc::Sender<()>,
+    release_rx: mpsc::Receiver<()>,
 }
 impl<E, D> TcpListener<E, D>
 where
@@ -99,10 +351,231 @@ where
         TcpListenerBuilder::new().listen(bind_addr)
     }
 
+    /// Resolves the given host, and makes a new `TcpListener` instance that binds to and listens in
+    /// one of the resolved addresses.
+    ///
+    /// This is equivalent to `TcpListenerBuilder::new().listen_host(host, port)`.
+    pub fn listen_host(host: &str, port: u16) -> impl Future<Item = Self, Error = Error> {
+        TcpListenerBuilder::new().listen_host(host, port)
+    }
+}
+impl<E, D> TcpListener<E, D> {
+    /// Takes ownership of the instance, and returns its listening socket.
+    ///
+    /// The socket keeps listening, so connections arriving in the meantime are not refused
+    /// and can be accepted by the listener rebuilt by [`from_raw_with_state`]
+    /// (e.g., with new codec factories or settings) without downtime.
+    /// The connections being established are carried over too.
+    ///
+    /// Note that the connections handed out by this instance are no longer counted by the rebuilt listener
+    /// (see [`TcpListenerBuilder::max_connections`]).
+    /// Also note that [`fibers`] provides no way to wrap a socket inherited from another process,
+    /// so the socket can only be handed over within the same process.
+    ///
+    /// [`from_raw_with_state`]: ./struct.TcpListener.html#method.from_raw_with_state
+    /// [`TcpListenerBuilder::max_connections`]: ./struct.TcpListenerBuilder.html#method.max_connections
+    /// [`fibers`]: https://crates.io/crates/fibers
+    pub fn into_raw(self) -> RawTcpListener {
+        RawTcpListener {
+            incoming: self.incoming,
+            local_addr: self.local_addr,
+            client_futures: self.client_futures,
+        }
+    }
+
+    /// Returns the number of live connections handed out by the listener.
+    ///
+    /// This is only tracked if the listener was built with [`TcpListenerBuilder::max_connections`].
+    ///
+    /// [`TcpListenerBuilder::max_connections`]: ./struct.TcpListenerBuilder.html#method.max_connections
+    pub fn live_connections(&self) -> usize {
+        self.live_connections
+    }
+
+    /// Returns the number of accepted connections that are still being set up
+    /// (i.e., have not been handed out by the listener yet).
+    pub fn pending_connections(&self) -> usize {
+        self.client_futures.len()
+    }
+
+    /// Returns the total number of connections that have been handed out by the listener.
+    pub fn accepted_connections(&self) -> u64 {
+        self.accepted_connections
+    }
+
+    /// Returns the number of accepted connections that have been skipped because they failed to be set up.
+    pub fn failed_connections(&self) -> u64 {
+        self.failed_connections
+    }
+
     /// Returns the address on which the listener is listening.
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
+
+    /// Returns a future that resolves to the bound address once the listener is accepting connections.
+    ///
+    /// The underlying socket is already listening when `listen` has resolved,
+    /// so the resulting future completes immediately.
+    /// This is convenient for learning the concrete port assigned when binding to port `0`.
+    pub fn ready(&self) -> impl Future<Item = SocketAddr, Error = Error> {
+        futures::future::ok(self.local_addr)
+    }
+
+    fn handle_accept_error(&mut self, e: io::Error) -> Result<()> {
+        let recoverable = is_recoverable_accept_error(&e);
+        let e = track!(Error::from(e));
+        if !recoverable {
+            return Err(e);
+        }
+        if let Some(hook) = self.accept_error_hook.as_mut() {
+            hook(&e);
+        }
+        self.backoff = Some(FibersClock.delay(self.accept_error_backoff));
+        Ok(())
+    }
+
+    fn handle_connection_error(&mut self, e: Error) {
+        self.failed_connections += 1;
+        if let Some(hook) = self.accept_error_hook.as_mut() {
+            hook(&e);
+        }
+    }
+
+    fn is_at_capacity(&self) -> bool {
+        self.max_connections
+            .is_some_and(|max| self.live_connections + self.client_futures.len() >= max)
+    }
+
+    /// Accepts incoming connections by calling `accept` until it would block.
+    ///
+    /// Returns `true` if the listening socket has terminated.
+    fn poll_incoming<A>(&mut self, mut accept: A) -> Result<bool>
+    where
+        A: FnMut(&mut Incoming) -> PollAccept,
+    {
+        while let Ok(Async::Ready(Some(()))) = self.release_rx.poll() {
+            self.live_connections -= 1;
+        }
+        loop {
+            // The backoff timer has to be polled once it is set, so that the task is woken up when it expires.
+            if let Some(mut backoff) = self.backoff.take() {
+                if !track!(backoff.poll())?.is_ready() {
+                    self.backoff = Some(backoff);
+                    return Ok(false);
+                }
+            }
+            if self.is_at_capacity() {
+                return Ok(false);
+            }
+            match accept(&mut self.incoming) {
+                Err(e) => track!(self.handle_accept_error(e))?,
+                Ok(Async::NotReady) => return Ok(false),
+                Ok(Async::Ready(None)) => return Ok(true),
+                Ok(Async::Ready(Some((future, _)))) => self.client_futures.push(future),
+            }
+        }
+    }
+}
+impl<E, D> TcpListener<E, D>
+where
+    E: Factory,
+    D: Factory,
+    E::Item: Encode,
+    D::Item: Decode,
+{
+    /// Makes a new `TcpListener` instance that takes over the given listening socket with the settings of `builder`.
+    ///
+    /// This is equivalent to `builder.finish_raw(raw)`.
+    pub fn from_raw_with_state(raw: RawTcpListener, builder: TcpListenerBuilder<E, D>) -> Self {
+        builder.finish_raw(raw)
+    }
+
+    /// Returns a future that accepts the next connection.
+    ///
+    /// The resulting future resolves to the transporter of the accepted connection,
+    /// the address of its peer, and the listener itself (for accepting more connections).
+    ///
+    /// This is a convenience method over the `Stream` implementation of the listener.
+    ///
+    /// # Errors
+    ///
+    /// If the listener terminates before accepting a connection,
+    /// the future will fail with an `ErrorKind::Closed` error.
+    pub fn accept_one(
+        self,
+    ) -> impl Future<Item = (TcpTransporter<E::Item, D::Item>, SocketAddr, Self), Error = Error>
+    {
+        self.into_future()
+            .map_err(|(e, _)| track!(e))
+            .and_then(|(transporter, listener)| {
+                let transporter = track_assert_some!(
+                    transporter,
+                    ErrorKind::Closed,
+                    "The listener has terminated"
+                );
+                let peer_addr = transporter.peer_addr();
+                Ok((transporter, peer_addr, listener))
+            })
+    }
+
+    /// Polls the listener, accepting connections by `accept` (usually `Incoming::poll`).
+    fn poll_with<A>(&mut self, accept: A) -> Poll<Option<<Self as Stream>::Item>, Error>
+    where
+        A: FnMut(&mut Incoming) -> PollAccept,
+    {
+        if track!(self.poll_incoming(accept))? {
+            return Ok(Async::Ready(None));
+        }
+
+        let mut i = 0;
+        while i < self.client_futures.len() {
+            match self.client_futures[i].poll() {
+                Err(e) => {
+                    self.client_futures.swap_remove(i);
+                    self.handle_connection_error(track!(Error::from(e)));
+                }
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(stream)) => {
+                    self.client_futures.swap_remove(i);
+                    let encoder = self.encoder_factory.create();
+                    let decoder = self.decoder_factory.create();
+                    let builder = TcpTransporterBuilder::with_codec(encoder, decoder);
+                    match track!(builder.finish(stream)) {
+                        Err(e) => self.handle_connection_error(e),
+                        Ok(mut transporter) => {
+                            if self.max_connections.is_some() {
+                                self.live_connections += 1;
+                                let guard = ConnectionGuard(self.release_tx.clone());
+                                transporter.set_connection_guard(guard);
+                            }
+                            self.accepted_connections += 1;
+                            return Ok(Async::Ready(Some(transporter)));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl<E: fmt::Debug, D: fmt::Debug> fmt::Debug for TcpListener<E, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "TcpListener {{ local_addr: {:?}, encoder_factory: {:?}, decoder_factory: {:?}, \
+             client_futures: {}, backoff: {}, live_connections: {}, accepted_connections: {}, \
+             failed_connections: {}, .. }}",
+            self.local_addr,
+            self.encoder_factory,
+            self.decoder_factory,
+            self.client_futures.len(),
+            self.backoff.is_some(),
+            self.live_connections,
+            self.accepted_connections,
+            self.failed_connections
+        )
+    }
 }
 impl<E, D> Stream for TcpListener<E, D>
 where
@@ -115,26 +588,332 @@ where
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        while let Async::Ready(client) = track!(self.incoming.poll().map_err(Error::from))? {
-            if let Some((future, _)) = client {
-                self.client_futures.push(future);
+        track!(self.poll_with(Incoming::poll))
+    }
+}
+
+/// A listening socket extracted from a [`TcpListener`] by [`TcpListener::into_raw`].
+///
+/// [`TcpListener`]: ./struct.TcpListener.html
+/// [`TcpListener::into_raw`]: ./struct.TcpListener.html#method.into_raw
+pub struct RawTcpListener {
+    incoming: Incoming,
+    local_addr: SocketAddr,
+    client_futures: Vec<Connected>,
+}
+impl RawTcpListener {
+    /// Returns the address on which the socket is listening.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+impl fmt::Debug for RawTcpListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "RawTcpListener {{ local_addr: {:?}, client_futures: {}, .. }}",
+            self.local_addr,
+            self.client_futures.len()
+        )
+    }
+}
+
+/// A handle that notifies the listener of the release of a connection when dropped.
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard(mpsc::Sender<()>);
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+fn is_recoverable_accept_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::OutOfMemory => return true,
+        _ => {}
+    }
+    #[cfg(unix)]
+    {
+        if let Some(code) = e.raw_os_error() {
+            return matches!(
+                code,
+                libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM
+            );
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{wait_recv, wait_send, Transport};
+    use bytecodec::fixnum::{U8Decoder, U8Encoder};
+    use factory::DefaultFactory;
+
+    type Server = TcpListener<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>;
+    type Client = TcpTransporter<U8Encoder, U8Decoder>;
+
+    /// Accepts the next connection, making the preceding accept calls fail with the given errors.
+    fn accept_with_errors(
+        server: Server,
+        mut errors: Vec<io::Error>,
+    ) -> Result<(Option<Client>, Server)> {
+        let mut server = Some(server);
+        fibers_global::execute(futures::future::poll_fn(move || {
+            let poll = server
+                .as_mut()
+                .expect("never fails")
+                .poll_with(|incoming| errors.pop().map_or_else(|| incoming.poll(), Err));
+            if let Async::Ready(accepted) = track!(poll)? {
+                Ok(Async::Ready((
+                    accepted,
+                    server.take().expect("never fails"),
+                )))
             } else {
-                return Ok(Async::Ready(None));
+                Ok(Async::NotReady)
             }
+        }))
+    }
+
+    #[test]
+    fn with_codec_factory_works() -> Result<()> {
+        let factory = DefaultFactory::<(U8Encoder, U8Decoder)>::new();
+        let builder = TcpListenerBuilder::with_codec_factory(factory);
+        let server = fibers_global::execute(builder.listen("127.0.0.1:0".parse().unwrap()))?;
+        let mut client = fibers_global::execute(Client::connect(server.local_addr()))?;
+        let (server, _, _) = fibers_global::execute(server.accept_one())?;
+
+        client.start_send((), 7)?;
+        let _client = fibers_global::execute(wait_send(client))?;
+        let (mut server, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 7);
+
+        server.start_send((), 8)?;
+        let _server = fibers_global::execute(wait_send(server))?;
+        Ok(())
+    }
+
+    #[test]
+    fn into_raw_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let addr = server.local_addr();
+        let raw = server.into_raw();
+        assert_eq!(raw.local_addr(), addr);
+
+        // A connection arriving during the handoff is accepted by the rebuilt listener.
+        let mut client = fibers_global::execute(Client::connect(addr))?;
+        let server = Server::from_raw_with_state(raw, TcpListenerBuilder::new());
+        assert_eq!(server.local_addr(), addr);
+        let (server, peer, _) = fibers_global::execute(server.accept_one())?;
+        assert_eq!(peer, client.local_addr());
+
+        client.start_send((), 3)?;
+        let _client = fibers_global::execute(wait_send(client))?;
+        let (_, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, 3);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn accept_error_backoff_works() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let errors = Arc::new(AtomicUsize::new(0));
+        let counter = errors.clone();
+        let builder =
+            TcpListenerBuilder::<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>::new()
+                .accept_error_backoff(Duration::from_millis(10))
+                .on_accept_error(move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+        let server = fibers_global::execute(builder.listen("127.0.0.1:0".parse().unwrap()))?;
+
+        // The listener backs off after a recoverable error, and then resumes accepting by itself.
+        let client = fibers_global::execute(Client::connect(server.local_addr()))?;
+        let accept_errors = vec![io::Error::from_raw_os_error(libc::EMFILE)];
+        let (accepted, server) = accept_with_errors(server, accept_errors)?;
+        assert_eq!(
+            accepted.expect("never fails").peer_addr(),
+            client.local_addr()
+        );
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+
+        // A fatal error terminates the listener.
+        let accept_errors = vec![io::Error::from_raw_os_error(libc::EBADF)];
+        let e = accept_with_errors(server, accept_errors).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::IoError);
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_connection_is_skipped() -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+
+        // Resets the connection before the listener builds a transporter for it,
+        // so that `TcpTransporterBuilder::finish` fails.
+        let reset_client = std::net::TcpStream::connect(server.local_addr()).expect("never fails");
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                reset_client.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                &linger as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::linger>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0);
+        std::mem::drop(reset_client);
+        std::thread::sleep(Duration::from_millis(50));
+
+        let client = fibers_global::execute(Client::connect(server.local_addr()))?;
+        let (accepted, server) =
+            fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        assert_eq!(
+            accepted.expect("never fails").peer_addr(),
+            client.local_addr()
+        );
+        assert_eq!(server.failed_connections(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn max_connections_works() -> Result<()> {
+        let builder =
+            TcpListenerBuilder::<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>::new()
+                .max_connections(1);
+        let server = fibers_global::execute(builder.listen("127.0.0.1:0".parse().unwrap()))?;
+
+        let _client0 = fibers_global::execute(Client::connect(server.local_addr()))?;
+        let (accepted0, server) =
+            fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let accepted0 = accepted0.expect("never fails");
+        assert_eq!(server.live_connections(), 1);
+
+        let client1 = fibers_global::execute(Client::connect(server.local_addr()))?;
+        std::thread::sleep(Duration::from_millis(50));
+        let (server, polled) = fibers_global::execute(futures::lazy(move || {
+            let mut server = server;
+            let polled = server.poll();
+            polled.map(|polled| (server, polled.is_ready()))
+        }))?;
+        assert!(!polled);
+
+        std::mem::drop(accepted0);
+        let (accepted1, server) =
+            fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        let accepted1 = accepted1.expect("never fails");
+        assert_eq!(accepted1.peer_addr(), client1.local_addr());
+        assert_eq!(server.live_connections(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn pending_connections_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(fibers_global::execute(Client::connect(
+                server.local_addr(),
+            ))?);
         }
+        std::thread::sleep(Duration::from_millis(50));
 
-        for i in 0..self.client_futures.len() {
-            if let Async::Ready(stream) =
-                track!(self.client_futures[i].poll().map_err(Error::from))?
-            {
-                self.client_futures.swap_remove(i);
-                let encoder = self.encoder_factory.create();
-                let decoder = self.decoder_factory.create();
-                let transporter =
-                    track!(TcpTransporterBuilder::with_codec(encoder, decoder).finish(stream))?;
-                return Ok(Async::Ready(Some(transporter)));
-            }
+        // The first poll accepts the connections, but their setup has not been completed yet.
+        let (server, polled) = fibers_global::execute(futures::lazy(move || {
+            let mut server = server;
+            let polled = server.poll();
+            polled.map(|polled| (server, polled.is_ready()))
+        }))?;
+        assert!(!polled);
+        assert_eq!(server.pending_connections(), 3);
+        assert_eq!(server.accepted_connections(), 0);
+
+        let mut server = server;
+        for i in 0..3 {
+            let (accepted, s) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+            assert!(accepted.is_some());
+            assert_eq!(s.accepted_connections(), i + 1);
+            server = s;
         }
-        Ok(Async::NotReady)
+        assert_eq!(server.pending_connections(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn listen_in_range_works() -> Result<()> {
+        let holder = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let first = holder.local_addr().port();
+        let ip = "127.0.0.1".parse().unwrap();
+        let ports = first..=first.saturating_add(2);
+
+        // The first port is in use by `holder`, so one of the subsequent ports is chosen.
+        let builder =
+            TcpListenerBuilder::<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>::new();
+        let server = fibers_global::execute(builder.listen_in_range(ip, ports.clone()))?;
+        assert!(ports.contains(&server.local_addr().port()));
+        assert_ne!(server.local_addr().port(), first);
+
+        let builder =
+            TcpListenerBuilder::<DefaultFactory<U8Encoder>, DefaultFactory<U8Decoder>>::new();
+        let result = fibers_global::execute(builder.listen_in_range(ip, first..=first));
+        assert_eq!(
+            result.err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn accept_one_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let mut client = fibers_global::execute(Client::connect(server.local_addr()))?;
+        let (accepted, peer, server) = fibers_global::execute(server.accept_one())?;
+        assert_eq!(peer, client.local_addr());
+
+        client.start_send((), 7)?;
+        let client = fibers_global::execute(wait_send(client))?;
+        let (mut accepted, (), item) = fibers_global::execute(wait_recv(accepted))?;
+        assert_eq!(item, 7);
+
+        accepted.start_send((), 8)?;
+        let _accepted = fibers_global::execute(wait_send(accepted))?;
+        let (_, (), item) = fibers_global::execute(wait_recv(client))?;
+        assert_eq!(item, 8);
+
+        let client = fibers_global::execute(Client::connect(server.local_addr()))?;
+        let (_, peer, _) = fibers_global::execute(server.accept_one())?;
+        assert_eq!(peer, client.local_addr());
+        Ok(())
+    }
+
+    #[test]
+    fn ready_works() -> Result<()> {
+        let server = fibers_global::execute(Server::listen("127.0.0.1:0".parse().unwrap()))?;
+        let addr = fibers_global::execute(server.ready())?;
+        assert_ne!(addr.port(), 0);
+        assert_eq!(addr, server.local_addr());
+
+        let client = fibers_global::execute(Client::connect(addr))?;
+        let (accepted, _) = fibers_global::execute(server.into_future()).map_err(|(e, _)| e)?;
+        assert_eq!(
+            accepted.expect("never fails").peer_addr(),
+            client.local_addr()
+        );
+        Ok(())
     }
 }
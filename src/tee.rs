@@ -0,0 +1,183 @@
+use crate::{PollRecv, PollSend, Result, TcpTransport, Transport, UdpTransport};
+use futures::Async;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// An implementation of [`Transport`] that passes every received item to an inspector.
+///
+/// The inspector `F` is invoked in `poll_recv` for each received item,
+/// and then the item is returned unchanged to the caller.
+/// An inspector for outgoing items can also be set by [`with_send_tee`].
+///
+/// This is useful for debugging and collecting metrics without affecting the normal flow.
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`with_send_tee`]: ./struct.TeeTransporter.html#method.with_send_tee
+pub struct TeeTransporter<T: Transport, F, G = SendTee<T>> {
+    inner: T,
+    recv_tee: F,
+    send_tee: Option<G>,
+}
+
+/// The type of the send tee of `TeeTransporter` instances made without `with_send_tee`.
+type SendTee<T> = fn(&<T as Transport>::PeerAddr, &<T as Transport>::SendItem);
+
+impl<T, F> TeeTransporter<T, F>
+where
+    T: Transport,
+    F: FnMut(&T::PeerAddr, &T::RecvItem),
+{
+    /// Makes a new `TeeTransporter` instance that invokes `recv_tee` for each received item.
+    pub fn new(inner: T, recv_tee: F) -> Self {
+        TeeTransporter {
+            inner,
+            recv_tee,
+            send_tee: None,
+        }
+    }
+}
+impl<T, F, G> TeeTransporter<T, F, G>
+where
+    T: Transport,
+    F: FnMut(&T::PeerAddr, &T::RecvItem),
+    G: FnMut(&T::PeerAddr, &T::SendItem),
+{
+    /// Sets the inspector invoked in `start_send` for each outgoing item.
+    pub fn with_send_tee<G2>(self, send_tee: G2) -> TeeTransporter<T, F, G2>
+    where
+        G2: FnMut(&T::PeerAddr, &T::SendItem),
+    {
+        TeeTransporter {
+            inner: self.inner,
+            recv_tee: self.recv_tee,
+            send_tee: Some(send_tee),
+        }
+    }
+
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Takes ownership of the instance, and returns the inner transporter.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+impl<T, F, G> Transport for TeeTransporter<T, F, G>
+where
+    T: Transport,
+    F: FnMut(&T::PeerAddr, &T::RecvItem),
+    G: FnMut(&T::PeerAddr, &T::SendItem),
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        if let Some(tee) = self.send_tee.as_mut() {
+            tee(&peer, &item);
+        }
+        track!(self.inner.start_send(peer, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        let polled = track!(self.inner.poll_recv())?;
+        if let Async::Ready(Some((ref peer, ref item))) = polled {
+            (self.recv_tee)(peer, item);
+        }
+        Ok(polled)
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+impl<T, F, G> UdpTransport for TeeTransporter<T, F, G>
+where
+    T: UdpTransport,
+    F: FnMut(&SocketAddr, &T::RecvItem),
+    G: FnMut(&SocketAddr, &T::SendItem),
+{
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+impl<T, F, G> TcpTransport for TeeTransporter<T, F, G>
+where
+    T: TcpTransport,
+    F: FnMut(&(), &T::RecvItem),
+    G: FnMut(&(), &T::SendItem),
+{
+    fn peer_addr(&self) -> SocketAddr {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+impl<T: Transport + fmt::Debug, F, G> fmt::Debug for TeeTransporter<T, F, G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TeeTransporter {{ inner: {:?}, .. }}", self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransporter;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn tee_transporter_works() -> Result<()> {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let sent = Rc::new(RefCell::new(Vec::new()));
+
+        let a: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let mut mock = MockTransporter::<SocketAddr, &'static str, &'static str>::new();
+        mock.incoming.push_back((a, "foo"));
+        mock.incoming.push_back((b, "bar"));
+        let recorder = received.clone();
+        let send_recorder = sent.clone();
+        let mut transporter =
+            TeeTransporter::new(mock, move |peer: &SocketAddr, item: &&'static str| {
+                recorder.borrow_mut().push((*peer, *item))
+            })
+            .with_send_tee(move |peer, item| send_recorder.borrow_mut().push((*peer, *item)));
+
+        assert_eq!(transporter.poll_recv()?, Async::Ready(Some((a, "foo"))));
+        assert_eq!(transporter.poll_recv()?, Async::Ready(Some((b, "bar"))));
+        assert_eq!(transporter.poll_recv()?, Async::NotReady);
+        assert_eq!(*received.borrow(), [(a, "foo"), (b, "bar")]);
+
+        transporter.start_send(b, "baz")?;
+        assert_eq!(*sent.borrow(), [(b, "baz")]);
+        assert_eq!(transporter.inner_ref().pending.len(), 1);
+        Ok(())
+    }
+    #[test]
+    fn tee_transporter_is_send() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let mock = MockTransporter::<SocketAddr, &'static str, &'static str>::new();
+        let transporter = TeeTransporter::new(mock, |_: &SocketAddr, _: &&'static str| {});
+        assert_send(&transporter);
+        assert_send(&transporter.with_send_tee(|_, _| {}));
+    }
+}
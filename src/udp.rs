@@ -1,12 +1,25 @@
-use crate::base::Transport;
-use crate::{Error, ErrorKind, PollRecv, PollSend, Result};
+use crate::base::{drive, Transport};
+use crate::buffer_pool::SharedBufferPool;
+use crate::error::CodecErrorMapper;
+use crate::port_range::{self, FamilyPreference};
+use crate::sys;
+use crate::{
+    BufferPool, Clock, Error, ErrorKind, FibersClock, PollRecv, PollSend, Priority, Result,
+    SendError,
+};
 use bytecodec::{Decode, DecodeExt, Encode, EncodeExt};
-use fibers::net::futures::{RecvFrom, SendTo};
+use fibers::net::futures::{RecvFrom, SendTo, UdpSocketBind};
 use fibers::net::UdpSocket;
 use futures::Poll;
 use futures::{Async, Future};
-use std::collections::VecDeque;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, SystemTime};
+use trackable::error::ErrorKindExt;
 
 /// This trait indicates that the implementation implements UDP.
 pub trait UdpTransport: Transport<PeerAddr = SocketAddr> {
@@ -20,6 +33,23 @@ pub trait UdpTransport: Transport<PeerAddr = SocketAddr> {
 #[derive(Debug, Clone)]
 pub struct UdpTransporterBuilder<E, D> {
     buf_size: usize,
+    recv_metadata: bool,
+    dont_fragment: bool,
+    flowinfo: Option<u32>,
+    traffic_class: Option<u8>,
+    allowed_peers: Option<HashSet<IpAddr>>,
+    queue_capacity: usize,
+    max_queue_len: Option<usize>,
+    eager_flush: bool,
+    checksum: Option<ChecksumKind>,
+    recv_batch_size: usize,
+    on_send_full: DropOrBlock,
+    reject_self_send: bool,
+    family_preference: FamilyPreference,
+    buffer_pool: SharedBufferPool,
+    recv_buffer: Option<Vec<u8>>,
+    auto_recv: bool,
+    codec_error_mapper: CodecErrorMapper,
     encoder: E,
     decoder: D,
 }
@@ -38,6 +68,23 @@ impl<E: Encode, D: Decode> UdpTransporterBuilder<E, D> {
     pub fn with_codec(encoder: E, decoder: D) -> Self {
         UdpTransporterBuilder {
             buf_size: 4096,
+            recv_metadata: false,
+            dont_fragment: false,
+            flowinfo: None,
+            traffic_class: None,
+            allowed_peers: None,
+            queue_capacity: 0,
+            max_queue_len: None,
+            eager_flush: true,
+            checksum: None,
+            recv_batch_size: 32,
+            on_send_full: DropOrBlock::Block,
+            reject_self_send: false,
+            family_preference: FamilyPreference::default(),
+            buffer_pool: SharedBufferPool::default(),
+            recv_buffer: None,
+            auto_recv: true,
+            codec_error_mapper: CodecErrorMapper::default(),
             encoder,
             decoder,
         }
@@ -51,18 +98,313 @@ impl<E: Encode, D: Decode> UdpTransporterBuilder<E, D> {
         self
     }
 
+    /// Sets whether the resulting instance captures the metadata of received datagrams.
+    ///
+    /// If `true`, datagrams are received via `recvmsg(2)` with `SO_TIMESTAMPNS` and
+    /// `IP_PKTINFO` (or `IPV6_RECVPKTINFO`) enabled, and the captured metadata
+    /// can be retrieved by [`UdpTransporter::poll_recv_with_meta`].
+    ///
+    /// This is only supported on Linux and has no effect on the other platforms.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`UdpTransporter::poll_recv_with_meta`]: ./struct.UdpTransporter.html#method.poll_recv_with_meta
+    pub fn recv_metadata(mut self, enabled: bool) -> Self {
+        self.recv_metadata = enabled;
+        self
+    }
+
+    /// Sets whether to set the don't-fragment bit on outgoing datagrams.
+    ///
+    /// If `true`, path MTU discovery is enabled on the socket (`IP_MTU_DISCOVER`),
+    /// and sending a datagram that exceeds the path MTU fails with
+    /// an `ErrorKind::MessageTooLarge` error instead of being fragmented.
+    /// This is useful for protocols that perform their own fragmentation.
+    ///
+    /// This is only supported on Linux and has no effect on the other platforms.
+    ///
+    /// The default value is `false`.
+    pub fn dont_fragment(mut self, enabled: bool) -> Self {
+        self.dont_fragment = enabled;
+        self
+    }
+
+    /// Sets the IPv6 flow information (i.e., the flow label) of outgoing datagrams.
+    ///
+    /// The value is set to the `sin6_flowinfo` field of the destination addresses,
+    /// and `IPV6_FLOWINFO_SEND` is enabled on the socket.
+    /// Note that Linux may reject sending datagrams with a flow label
+    /// that has not been registered via the flow label manager.
+    ///
+    /// This is only supported on Linux, and has no effect on IPv4 sockets.
+    ///
+    /// By default, no flow information is set.
+    pub fn flowinfo(mut self, flowinfo: u32) -> Self {
+        self.flowinfo = Some(flowinfo);
+        self
+    }
+
+    /// Sets the IPv6 traffic class (`IPV6_TCLASS`) of outgoing datagrams.
+    ///
+    /// This is only supported on Linux, and has no effect on IPv4 sockets.
+    ///
+    /// By default, the system default traffic class is used.
+    pub fn traffic_class(mut self, traffic_class: u8) -> Self {
+        self.traffic_class = Some(traffic_class);
+        self
+    }
+
+    /// Sets the IP addresses of the peers from which datagrams are accepted.
+    ///
+    /// Datagrams from the other addresses are dropped without being decoded,
+    /// and the number of such datagrams can be retrieved by [`UdpTransporter::rejected_count`].
+    ///
+    /// By default, datagrams from any peers are accepted.
+    ///
+    /// [`UdpTransporter::rejected_count`]: ./struct.UdpTransporter.html#method.rejected_count
+    pub fn allowed_peers<P: Into<HashSet<IpAddr>>>(mut self, peers: P) -> Self {
+        self.allowed_peers = Some(peers.into());
+        self
+    }
+
+    /// Sets the initial capacity of the outgoing message queue for each pair of destination peer and priority.
+    ///
+    /// Pre-allocating the queues avoids reallocations while a burst of messages is being enqueued.
+    /// The queues drained by sending are kept for reuse (shrunk to this capacity), so the allocations survive
+    /// across bursts.
+    ///
+    /// The default value is `0`.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// Sets the maximum number of messages in the outgoing queue (for all peers) of the resulting instance.
+    ///
+    /// If the queue is full, `start_send` will return an `ErrorKind::Other` error,
+    /// and `try_start_send` will hand the rejected message back.
+    ///
+    /// By default, the length of the queue is unlimited.
+    pub fn max_queue_len(mut self, len: usize) -> Self {
+        self.max_queue_len = Some(len);
+        self
+    }
+
+    /// Sets whether `start_send` immediately tries to send the enqueued message.
+    ///
+    /// If `false`, `start_send` only enqueues the message,
+    /// and all of the I/O is left to the explicit calls of `poll_send` (e.g., via `wait_send`).
+    ///
+    /// The default value is `true`.
+    pub fn eager_flush(mut self, enabled: bool) -> Self {
+        self.eager_flush = enabled;
+        self
+    }
+
+    /// Sets the kind of the checksum appended to each outgoing datagram.
+    ///
+    /// If set, the checksum over the encoded payload is appended to each outgoing datagram,
+    /// and is verified (and stripped) before decoding each incoming datagram.
+    /// Incoming datagrams having a mismatched checksum are dropped without being decoded,
+    /// and the number of such datagrams can be retrieved by [`UdpTransporter::checksum_error_count`].
+    ///
+    /// Note that both ends of the communication must use the same setting.
+    ///
+    /// By default, no checksum is appended.
+    ///
+    /// [`UdpTransporter::checksum_error_count`]: ./struct.UdpTransporter.html#method.checksum_error_count
+    pub fn checksum(mut self, kind: ChecksumKind) -> Self {
+        self.checksum = Some(kind);
+        self
+    }
+
+    /// Sets the maximum number of datagrams received at once by [`UdpTransporter::poll_recv_batch`].
+    ///
+    /// On Linux, the datagrams of a batch are received by a single `recvmmsg` system call.
+    /// If `0` is specified, `1` is used instead.
+    ///
+    /// The default value is `32`.
+    ///
+    /// [`UdpTransporter::poll_recv_batch`]: ./struct.UdpTransporter.html#method.poll_recv_batch
+    pub fn recv_batch_size(mut self, size: usize) -> Self {
+        self.recv_batch_size = size;
+        self
+    }
+
+    /// Sets the behavior when the send buffer of the socket is full.
+    ///
+    /// If `DropOrBlock::Drop` is specified, datagrams that cannot be sent immediately are discarded,
+    /// and the number of such datagrams can be retrieved by [`UdpTransporter::dropped_count`].
+    /// This is useful for real-time applications preferring fresh datagrams to delayed ones.
+    ///
+    /// The default value is `DropOrBlock::Block`.
+    ///
+    /// [`UdpTransporter::dropped_count`]: ./struct.UdpTransporter.html#method.dropped_count
+    pub fn on_send_full(mut self, behavior: DropOrBlock) -> Self {
+        self.on_send_full = behavior;
+        self
+    }
+
+    /// Sets whether to reject the items destined for the own local address of the socket.
+    ///
+    /// A datagram sent to the own address loops back and is received by the sender itself,
+    /// which is rarely intended. If `true`, `start_send` (and the other enqueueing methods) will
+    /// return an `ErrorKind::InvalidInput` error for such items.
+    /// If the socket is bound to an unspecified address (e.g., `0.0.0.0`),
+    /// loopback addresses having the same port are regarded as the own address too.
+    ///
+    /// The default value is `false`.
+    pub fn reject_self_send(mut self, enabled: bool) -> Self {
+        self.reject_self_send = enabled;
+        self
+    }
+
+    /// Sets the preference of the address family used by `bind_host`.
+    ///
+    /// The default value is `FamilyPreference::PreferIpv4`.
+    pub fn family_preference(mut self, preference: FamilyPreference) -> Self {
+        self.family_preference = preference;
+        self
+    }
+
+    /// Sets the pool from which the receive buffers are acquired.
+    ///
+    /// Once a received datagram has been decoded, its buffer is released to the pool
+    /// and the buffer for the next datagram is acquired from it.
+    /// The pool is shared by the transporters built from this builder (and its clones).
+    ///
+    /// Note that the buffers used for `recv_metadata` and `poll_recv_batch` are allocated once
+    /// and kept by each transporter, so they are not affected by this setting.
+    ///
+    /// The default value is `FreeListBufferPool::new()`.
+    pub fn buffer_pool<P: BufferPool + 'static>(mut self, pool: P) -> Self {
+        self.buffer_pool = SharedBufferPool::new(pool);
+        self
+    }
+
+    /// Sets the buffer into which the first datagram is received.
+    ///
+    /// This allows for supplying a buffer allocated elsewhere (e.g., taken from a custom pool).
+    /// Its length is used as the maximum size of the datagram instead of `buf_size`,
+    /// and it is released to the buffer pool once the datagram has been decoded.
+    ///
+    /// By default, the buffer is acquired from the buffer pool.
+    pub fn recv_buffer(mut self, buf: Vec<u8>) -> Self {
+        self.recv_buffer = Some(buf);
+        self
+    }
+
+    /// Sets whether the resulting instance starts receiving datagrams immediately.
+    ///
+    /// If `false`, no receive buffer is acquired until [`UdpTransporter::enable_recv`] is called,
+    /// which is useful for send-only transporters.
+    /// Until then, `poll_recv` (and the other receiving methods) return `Ok(Async::NotReady)`.
+    ///
+    /// The default value is `true`.
+    ///
+    /// [`UdpTransporter::enable_recv`]: ./struct.UdpTransporter.html#method.enable_recv
+    pub fn auto_recv(mut self, enabled: bool) -> Self {
+        self.auto_recv = enabled;
+        self
+    }
+
+    /// Sets the function that converts the errors of the encoder and decoder into `Error`.
+    ///
+    /// This is applied to the codec errors that occurred in `poll_send` and the receiving methods,
+    /// and allows for classifying them by custom rules (e.g., regarding some decode errors as `ErrorKind::InvalidInput`).
+    ///
+    /// By default, `Error::from` is used.
+    pub fn codec_error_mapper<F>(mut self, f: F) -> Self
+    where
+        F: Fn(bytecodec::Error) -> Error + Send + Sync + 'static,
+    {
+        self.codec_error_mapper = CodecErrorMapper::new(f);
+        self
+    }
+
     /// Makes a new `UdpTransporter` instance with the given settings.
+    ///
+    /// Note that [`fibers`] provides no way to convert a `std::net::UdpSocket` into
+    /// a `fibers::net::UdpSocket`, so sockets created elsewhere cannot be wrapped directly.
+    /// Socket options (e.g., multicast memberships) can instead be configured on
+    /// the bound socket via `UdpSocket::with_inner` before calling this method.
+    ///
+    /// [`fibers`]: https://crates.io/crates/fibers
     pub fn finish(self, socket: UdpSocket) -> Result<UdpTransporter<E, D>> {
         let local_addr = track!(socket.local_addr().map_err(Error::from))?;
-        let recv_from = socket.clone().recv_from(vec![0; self.buf_size]);
+        let socket_options = SocketOptions {
+            dont_fragment: self.dont_fragment,
+            traffic_class: self.traffic_class,
+            flowinfo: self.flowinfo,
+        };
+        let flowinfo = track!(socket_options.apply(&socket, local_addr))?;
+        #[cfg(target_os = "linux")]
+        let metadata_recv = if self.recv_metadata {
+            track!(sys::enable_recv_metadata(&socket).map_err(Error::from))?;
+            let readiness = track!(sys::Readiness::new(sys::udp_fd(&socket)).map_err(Error::from))?;
+            Some(MetadataRecv {
+                buf: vec![0; self.buf_size],
+                readiness,
+            })
+        } else {
+            None
+        };
+        #[cfg(target_os = "linux")]
+        let recv_buf_size = if metadata_recv.is_some() {
+            0
+        } else {
+            self.buf_size
+        };
+        #[cfg(not(target_os = "linux"))]
+        let recv_buf_size = self.buf_size;
+
+        let buffer_pool = self.buffer_pool;
+        let mut recv_buffer = self.recv_buffer;
+        let recv_from = if self.auto_recv {
+            let buf = recv_buffer
+                .take()
+                .unwrap_or_else(|| buffer_pool.acquire(recv_buf_size));
+            Some(socket.clone().recv_from(buf))
+        } else {
+            None
+        };
+        let recv_batch_size = std::cmp::max(self.recv_batch_size, 1);
         Ok(UdpTransporter {
             socket,
             local_addr,
             encoder: self.encoder,
             decoder: self.decoder,
-            outgoing_queue: VecDeque::new(),
+            outgoing_queue: OutgoingQueue::with_capacity(self.queue_capacity),
+            max_queue_len: self.max_queue_len,
+            flowinfo,
+            socket_options,
+            eager_flush: self.eager_flush,
+            on_send_full: self.on_send_full,
+            reject_self_send: self.reject_self_send,
+            dropped_count: 0,
+            recv_paused: false,
             send_to: None,
             recv_from,
+            recv_buffer,
+            recv_buf_size,
+            rebinding: None,
+            buffer_pool,
+            #[cfg(unix)]
+            raw_readiness: None,
+            stashed: VecDeque::new(),
+            #[cfg(target_os = "linux")]
+            metadata_recv,
+            #[cfg(target_os = "linux")]
+            batch_recv: BatchRecv::new(recv_batch_size, self.buf_size),
+            recv_batch_size,
+            peer_filter: PeerFilter {
+                allowed: self.allowed_peers,
+                rejected_count: 0,
+                checksum: self.checksum,
+                checksum_error_count: 0,
+                decode_error_count: 0,
+                codec_error_mapper: self.codec_error_mapper,
+            },
         })
     }
 
@@ -73,6 +415,37 @@ impl<E: Encode, D: Decode> UdpTransporterBuilder<E, D> {
             .map_err(|e| track!(Error::from(e)))
             .and_then(move |socket| track!(self.finish(socket)))
     }
+
+    /// Starts binding to a port in the given range and will makes
+    /// a new `UdpTransporter` instance if the operation is succeeded.
+    ///
+    /// The ports are tried in ascending order, and those already in use are skipped.
+    /// If all of the ports are in use, the resulting future fails with an `ErrorKind::InvalidInput` error.
+    pub fn bind_in_range(
+        self,
+        ip: IpAddr,
+        ports: RangeInclusive<u16>,
+    ) -> impl Future<Item = UdpTransporter<E, D>, Error = Error> {
+        port_range::bind_in_range(ip, ports, UdpSocket::bind)
+            .and_then(move |socket| track!(self.finish(socket)))
+    }
+
+    /// Resolves the given host, and starts binding to one of the resolved addresses.
+    /// It will make a new `UdpTransporter` instance if the operation is succeeded.
+    ///
+    /// The resolved addresses are tried in the order specified by `family_preference`
+    /// until one is bound successfully.
+    /// If no address is bound, the resulting future fails with the error of the last attempt.
+    ///
+    /// Note that the host is resolved synchronously (i.e., this blocks the current thread until resolution completes).
+    pub fn bind_host(
+        self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<Item = UdpTransporter<E, D>, Error = Error> {
+        port_range::bind_host(host, port, self.family_preference, UdpSocket::bind)
+            .and_then(move |socket| track!(self.finish(socket)))
+    }
 }
 impl<E, D> Default for UdpTransporterBuilder<E, D>
 where
@@ -86,16 +459,55 @@ where
 
 /// An implementation of [`Transport`] that uses UDP as the transport layer.
 ///
+/// # Ordering
+///
+/// Outgoing items are queued per destination peer.
+/// Items destined for the same peer are sent in FIFO order,
+/// and the peers having outstanding items are served in round-robin order,
+/// so that a burst of items to one peer does not delay items to the others.
+/// Items destined for different peers are therefore not necessarily sent in the order they were enqueued.
+///
+/// If sending a datagram fails for a reason specific to its destination
+/// (e.g., `EMSGSIZE` or `EHOSTUNREACH`), the datagram is discarded and the error is returned by
+/// `poll_send`. The items queued for the other peers are kept and sent by the subsequent calls.
+///
+/// Note that this only determines the order in which datagrams are handed to the socket.
+/// All of the peers share the same socket, so if a send blocks (i.e., the socket is not writable),
+/// no items are sent to any peer until the socket becomes writable again
+/// (or the datagram is dropped, see [`UdpTransporterBuilder::on_send_full`]).
+///
 /// [`Transport`]: ./trait.Transport.html
+/// [`UdpTransporterBuilder::on_send_full`]: ./struct.UdpTransporterBuilder.html#method.on_send_full
 #[derive(Debug)]
 pub struct UdpTransporter<E: Encode, D: Decode> {
     socket: UdpSocket,
     local_addr: SocketAddr,
     encoder: E,
     decoder: D,
-    outgoing_queue: VecDeque<(SocketAddr, E::Item)>,
-    send_to: Option<SendTo<Vec<u8>>>,
-    recv_from: RecvFrom<Vec<u8>>,
+    outgoing_queue: OutgoingQueue<E::Item>,
+    max_queue_len: Option<usize>,
+    flowinfo: Option<u32>,
+    socket_options: SocketOptions,
+    eager_flush: bool,
+    on_send_full: DropOrBlock,
+    reject_self_send: bool,
+    dropped_count: u64,
+    recv_paused: bool,
+    send_to: Option<(SocketAddr, SendTo<Vec<u8>>)>,
+    recv_from: Option<RecvFrom<Vec<u8>>>,
+    recv_buffer: Option<Vec<u8>>,
+    recv_buf_size: usize,
+    rebinding: Option<UdpSocketBind>,
+    buffer_pool: SharedBufferPool,
+    #[cfg(unix)]
+    raw_readiness: Option<sys::Readiness>,
+    stashed: VecDeque<(SocketAddr, D::Item)>,
+    #[cfg(target_os = "linux")]
+    metadata_recv: Option<MetadataRecv>,
+    #[cfg(target_os = "linux")]
+    batch_recv: BatchRecv,
+    recv_batch_size: usize,
+    peer_filter: PeerFilter,
 }
 impl<E, D> UdpTransporter<E, D>
 where
@@ -110,6 +522,14 @@ where
         UdpTransporterBuilder::new().bind(addr)
     }
 
+    /// Resolves the given host, and starts binding to one of the resolved addresses.
+    /// It will make a new `UdpTransporter` instance if the operation is succeeded.
+    ///
+    /// This is equivalent to `UdpTransporterBuilder::new().bind_host(host, port)`.
+    pub fn bind_host(host: &str, port: u16) -> impl Future<Item = Self, Error = Error> {
+        UdpTransporterBuilder::new().bind_host(host, port)
+    }
+
     /// Makes a new `UdpTransporter` instance from the given `UdpSocket`.
     ///
     /// This is equivalent to `UdpTransporterBuilder::new().finish(socket)`.
@@ -153,60 +573,1758 @@ impl<E: Encode, D: Decode> UdpTransporter<E, D> {
         &mut self.encoder
     }
 
-    fn poll_send_to(&mut self) -> Poll<(), Error> {
-        match self.send_to.poll() {
-            Err((_, _, e)) => Err(track!(Error::from(e))),
-            Ok(Async::NotReady) => Ok(Async::NotReady),
-            Ok(Async::Ready(None)) => Ok(Async::Ready(())),
-            Ok(Async::Ready(Some((_, buf, written_size)))) => {
-                track_assert_eq!(buf.len(), written_size, ErrorKind::Other);
-                self.send_to = None;
-                Ok(Async::Ready(()))
+    /// Takes ownership of the instance, and returns the encoder and decoder being used by it
+    /// (e.g., for reusing a stateful codec for a new socket).
+    ///
+    /// The underlying socket and the outgoing queue are discarded.
+    ///
+    /// # Errors
+    ///
+    /// If the current encoder is not idle, this will return an `ErrorKind::Other` error.
+    pub fn into_codec(self) -> Result<(E, D)> {
+        track_assert!(
+            self.encoder.is_idle(),
+            ErrorKind::Other,
+            "The encoder is in the middle of encoding an item"
+        );
+        Ok((self.encoder, self.decoder))
+    }
+
+    /// Tries to enqueue the given message destined for `peer`.
+    ///
+    /// Unlike `start_send`, this never issues any I/O (the message is sent by the subsequent `poll_send` calls),
+    /// and hands the message back if the queue is full.
+    pub fn try_start_send(
+        &mut self,
+        peer: SocketAddr,
+        item: E::Item,
+    ) -> std::result::Result<(), SendError<E::Item>> {
+        if let Err(e) = track!(self.check_can_enqueue(peer)) {
+            return Err(SendError::new(e, item));
+        }
+        self.outgoing_queue.push(peer, item);
+        Ok(())
+    }
+
+    /// Enqueues the given message destined for `peer` with the specified priority.
+    ///
+    /// Messages having higher priority are sent ahead of the queued ones having lower priority.
+    /// `start_send` is equivalent to calling this method with `Priority::Normal`.
+    pub fn start_send_prioritized(
+        &mut self,
+        peer: SocketAddr,
+        item: E::Item,
+        priority: Priority,
+    ) -> Result<()> {
+        track!(self.check_can_enqueue(peer))?;
+        self.outgoing_queue.push_with_priority(peer, item, priority);
+        if self.eager_flush && self.rebinding.is_none() {
+            track!(self.poll_send())?;
+        }
+        Ok(())
+    }
+
+    fn check_can_enqueue(&self, peer: SocketAddr) -> Result<()> {
+        if self.reject_self_send {
+            track_assert!(
+                !self.is_self_addr(peer),
+                ErrorKind::InvalidInput,
+                "Sending to the own local address is rejected: peer={}",
+                peer
+            );
+        }
+        if let Some(max) = self.max_queue_len {
+            track_assert!(
+                self.outgoing_queue.len() < max,
+                ErrorKind::Other,
+                "The outgoing queue is full: max_queue_len={}",
+                max
+            );
+        }
+        Ok(())
+    }
+
+    fn is_self_addr(&self, peer: SocketAddr) -> bool {
+        if peer == self.local_addr {
+            return true;
+        }
+        let local_ip = self.local_addr.ip();
+        local_ip.is_unspecified()
+            && peer.ip().is_loopback()
+            && peer.port() == self.local_addr.port()
+            && peer.is_ipv4() == self.local_addr.is_ipv4()
+    }
+
+    /// Replaces the IP addresses of the peers from which datagrams are accepted.
+    ///
+    /// See [`UdpTransporterBuilder::allowed_peers`] for more details.
+    ///
+    /// [`UdpTransporterBuilder::allowed_peers`]: ./struct.UdpTransporterBuilder.html#method.allowed_peers
+    pub fn set_allowed_peers<P: Into<HashSet<IpAddr>>>(&mut self, peers: P) {
+        self.peer_filter.allowed = Some(peers.into());
+    }
+
+    /// Removes the restriction on the peers from which datagrams are accepted.
+    pub fn clear_allowed_peers(&mut self) {
+        self.peer_filter.allowed = None;
+    }
+
+    /// Returns the number of datagrams that have been dropped because they were sent from disallowed peers.
+    pub fn rejected_count(&self) -> u64 {
+        self.peer_filter.rejected_count
+    }
+
+    /// Returns the number of outgoing datagrams that have been discarded because the send buffer was full.
+    ///
+    /// See [`UdpTransporterBuilder::on_send_full`] for more details.
+    ///
+    /// [`UdpTransporterBuilder::on_send_full`]: ./struct.UdpTransporterBuilder.html#method.on_send_full
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped_count
+    }
+
+    /// Returns `true` if the reception of items has been paused by `set_recv_paused`, otherwise `false`.
+    ///
+    /// While paused, `poll_recv_with_meta`, `poll_recv_batch` and `poll_recv_from` also return `Ok(Async::NotReady)`.
+    pub fn is_recv_paused(&self) -> bool {
+        self.recv_paused
+    }
+
+    /// Starts receiving datagrams if the instance was built with [`UdpTransporterBuilder::auto_recv`] disabled.
+    ///
+    /// This has no effect if the reception has already been enabled.
+    ///
+    /// [`UdpTransporterBuilder::auto_recv`]: ./struct.UdpTransporterBuilder.html#method.auto_recv
+    pub fn enable_recv(&mut self) {
+        if self.recv_from.is_none() {
+            let size = self.recv_buf_size;
+            let pool = &self.buffer_pool;
+            let buf = self
+                .recv_buffer
+                .take()
+                .unwrap_or_else(|| pool.acquire(size));
+            self.recv_from = Some(self.socket.clone().recv_from(buf));
+        }
+    }
+
+    /// Returns `true` if the instance is receiving datagrams, otherwise `false`.
+    ///
+    /// See [`UdpTransporterBuilder::auto_recv`] for more details.
+    ///
+    /// [`UdpTransporterBuilder::auto_recv`]: ./struct.UdpTransporterBuilder.html#method.auto_recv
+    pub fn is_recv_enabled(&self) -> bool {
+        self.recv_from.is_some()
+    }
+
+    /// Returns the length of the buffer used for receiving each datagram in byte.
+    ///
+    /// Datagrams larger than this are truncated (see [`UdpTransporterBuilder::buf_size`]).
+    ///
+    /// [`UdpTransporterBuilder::buf_size`]: ./struct.UdpTransporterBuilder.html#method.buf_size
+    pub fn recv_buf_capacity(&self) -> usize {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref r) = self.metadata_recv {
+                return r.buf.len();
             }
         }
+        self.recv_buf_size
     }
-}
-impl<E: Encode, D: Decode> Transport for UdpTransporter<E, D> {
-    type PeerAddr = SocketAddr;
-    type SendItem = E::Item;
-    type RecvItem = D::Item;
 
-    fn start_send(&mut self, peer: Self::PeerAddr, item: E::Item) -> Result<()> {
-        self.outgoing_queue.push_back((peer, item));
-        track!(self.poll_send())?;
+    /// Returns the capacity of the application level send buffer in byte.
+    ///
+    /// `UdpTransporter` has no such buffer (each outgoing item is encoded into a buffer of its own size),
+    /// so this always returns `0`.
+    pub fn send_buf_capacity(&self) -> usize {
+        0
+    }
+
+    /// Returns the number of datagrams that have been dropped because of checksum mismatches.
+    ///
+    /// See [`UdpTransporterBuilder::checksum`] for more details.
+    ///
+    /// [`UdpTransporterBuilder::checksum`]: ./struct.UdpTransporterBuilder.html#method.checksum
+    pub fn checksum_error_count(&self) -> u64 {
+        self.peer_filter.checksum_error_count
+    }
+
+    /// Returns the number of received datagrams that have failed to be decoded.
+    ///
+    /// Each of such failures is reported as an error by the receiving methods,
+    /// but the instance keeps receiving the subsequent datagrams.
+    pub fn decode_error_count(&self) -> u64 {
+        self.peer_filter.decode_error_count
+    }
+
+    /// Migrates the instance to a new socket bound to the given address.
+    ///
+    /// The encoder, decoder and outgoing queue are preserved,
+    /// and the socket options specified by the builder (e.g., `dont_fragment`) are applied to the new socket.
+    ///
+    /// The new socket is bound asynchronously by `poll_send` and the receiving methods (i.e., within a fiber).
+    /// Until it is bound, `poll_send` and the receiving methods return `Ok(Async::NotReady)`,
+    /// and an error of the binding is reported by them.
+    /// Once it is bound, the old socket is closed, and the in-flight `send_to` and `recv_from` futures are reset
+    /// (i.e., a datagram being sent and a datagram being received on the old socket may be lost).
+    pub fn rebind(&mut self, new_addr: SocketAddr) -> Result<()> {
+        self.rebinding = Some(UdpSocket::bind(new_addr));
         Ok(())
     }
 
-    fn poll_send(&mut self) -> PollSend {
-        while track!(self.poll_send_to())?.is_ready() {
-            if let Some((peer, item)) = self.outgoing_queue.pop_front() {
-                // FIXME: optimize
-                let bytes = track!(self.encoder.encode_into_bytes(item))?;
-                self.send_to = Some(self.socket.clone().send_to(bytes, peer));
+    /// Returns a future that waits until the instance receives an item or the given timeout expires.
+    ///
+    /// The resulting future will return the instance along with the received item,
+    /// or `None` if no item has been received within `timeout`.
+    /// Note that the outgoing items are not sent by this future.
+    pub fn recv_timeout_future(
+        self,
+        timeout: Duration,
+    ) -> impl Future<Item = (Self, Option<(SocketAddr, D::Item)>), Error = Error> {
+        let mut delay = FibersClock.delay(timeout);
+        drive(self, move |t| {
+            if let Async::Ready(item) = track!(t.poll_recv())? {
+                return Ok(Async::Ready(item));
+            }
+            if track!(delay.poll())?.is_ready() {
+                return Ok(Async::Ready(None));
+            }
+            Ok(Async::NotReady)
+        })
+    }
+
+    fn poll_recv_ready(&mut self) -> Poll<(), Error> {
+        if self.recv_paused || self.recv_from.is_none() {
+            return Ok(Async::NotReady);
+        }
+        track!(self.poll_rebind())
+    }
+
+    fn poll_rebind(&mut self) -> Poll<(), Error> {
+        let socket = if let Some(ref mut future) = self.rebinding {
+            match future.poll() {
+                Err(e) => {
+                    self.rebinding = None;
+                    return Err(track!(Error::from(e)));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(socket)) => socket,
+            }
+        } else {
+            return Ok(Async::Ready(()));
+        };
+        self.rebinding = None;
+
+        let local_addr = track!(socket.local_addr().map_err(Error::from))?;
+        self.flowinfo = track!(self.socket_options.apply(&socket, local_addr))?;
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref mut r) = self.metadata_recv {
+                track!(sys::enable_recv_metadata(&socket).map_err(Error::from))?;
+                r.readiness =
+                    track!(sys::Readiness::new(sys::udp_fd(&socket)).map_err(Error::from))?;
+            }
+            self.batch_recv.readiness = None;
+        }
+        #[cfg(unix)]
+        {
+            self.raw_readiness = None;
+        }
+        self.send_to = None;
+        if self.recv_from.is_some() {
+            let buf = self.buffer_pool.acquire(self.recv_buf_size);
+            self.recv_from = Some(socket.clone().recv_from(buf));
+        }
+        self.socket = socket;
+        self.local_addr = local_addr;
+        Ok(Async::Ready(()))
+    }
+
+    /// Polls the next received item along with its metadata.
+    ///
+    /// The metadata is only captured if the instance was built with
+    /// [`UdpTransporterBuilder::recv_metadata`] enabled on Linux.
+    /// Otherwise, `RecvMetadata::default()` is returned with each item.
+    ///
+    /// The items stashed by [`poll_recv_from`] are returned first, and they have no metadata.
+    ///
+    /// [`UdpTransporterBuilder::recv_metadata`]: ./struct.UdpTransporterBuilder.html#method.recv_metadata
+    /// [`poll_recv_from`]: #method.poll_recv_from
+    pub fn poll_recv_with_meta(&mut self) -> PollRecv<(SocketAddr, D::Item, RecvMetadata)> {
+        if track!(self.poll_recv_ready())?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        if let Some((peer, item)) = self.stashed.pop_front() {
+            return Ok(Async::Ready(Some((peer, item, RecvMetadata::default()))));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref mut r) = self.metadata_recv {
+                return track!(r.poll_recv(&self.socket, &mut self.decoder, &mut self.peer_filter));
+            }
+        }
+        let polled = track!(self.poll_recv_datagram())?;
+        Ok(polled.map(|x| x.map(|(peer, item)| (peer, item, RecvMetadata::default()))))
+    }
+
+    /// Polls the received items in a batch.
+    ///
+    /// This returns up to [`UdpTransporterBuilder::recv_batch_size`] items at once.
+    /// On Linux, the datagrams are received by a single `recvmmsg` system call for each batch,
+    /// which reduces the number of system calls for high packet-rate traffic.
+    /// On the other platforms, this is equivalent to calling `poll_recv` repeatedly.
+    ///
+    /// If there are items stashed by [`poll_recv_from`], a batch consisting only of them is returned first.
+    ///
+    /// Note that no metadata is captured by this method.
+    ///
+    /// [`UdpTransporterBuilder::recv_batch_size`]: ./struct.UdpTransporterBuilder.html#method.recv_batch_size
+    /// [`poll_recv_from`]: #method.poll_recv_from
+    pub fn poll_recv_batch(&mut self) -> PollRecv<Vec<(SocketAddr, D::Item)>> {
+        if track!(self.poll_recv_ready())?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        if !self.stashed.is_empty() {
+            let n = std::cmp::min(self.stashed.len(), self.recv_batch_size);
+            return Ok(Async::Ready(Some(self.stashed.drain(..n).collect())));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            track!(self.batch_recv.poll_recv(
+                &self.socket,
+                &mut self.decoder,
+                &mut self.peer_filter
+            ))
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let mut items = Vec::new();
+            while items.len() < self.recv_batch_size {
+                match track!(self.poll_recv_datagram())? {
+                    Async::Ready(Some(item)) => items.push(item),
+                    _ => break,
+                }
+            }
+            if items.is_empty() {
+                Ok(Async::NotReady)
             } else {
-                return Ok(Async::Ready(()));
+                Ok(Async::Ready(Some(items)))
             }
         }
-        Ok(Async::NotReady)
     }
 
-    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, D::Item)> {
-        if let Async::Ready((socket, buf, size, peer)) = self
+    /// Polls the next item received from the given peer.
+    ///
+    /// Items received from the other peers in the meantime are stashed in the instance,
+    /// and will be returned by the subsequent receiving calls (e.g., `poll_recv`) in the order of arrival.
+    /// Note that the stash is unbounded, so the items from the other peers should be consumed regularly.
+    pub fn poll_recv_from(&mut self, peer: SocketAddr) -> PollRecv<D::Item> {
+        if track!(self.poll_recv_ready())?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        if let Some(i) = self.stashed.iter().position(|(p, _)| *p == peer) {
+            return Ok(Async::Ready(self.stashed.remove(i).map(|(_, item)| item)));
+        }
+        loop {
+            match track!(self.poll_recv_unstashed())? {
+                Async::Ready(Some((p, item))) if p == peer => return Ok(Async::Ready(Some(item))),
+                Async::Ready(Some(other)) => self.stashed.push_back(other),
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+
+    /// Polls the next datagram, and receives its raw bytes into the given buffer without decoding.
+    ///
+    /// The resulting tuple contains the source address and the size of the datagram.
+    /// As with `recvfrom(2)`, the bytes that do not fit into `buf` are discarded.
+    ///
+    /// The datagrams are read from the socket directly into `buf`, so this does not copy the bytes.
+    /// The allowed peers are applied to the datagrams, but the checksums are not verified
+    /// (i.e., the checksum trailers are included in the received bytes).
+    ///
+    /// This method is only available on Unix platforms.
+    #[cfg(unix)]
+    pub fn poll_recv_into(&mut self, buf: &mut [u8]) -> PollRecv<(SocketAddr, usize)> {
+        if track!(self.poll_recv_ready())?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        loop {
+            match self.socket.with_inner(|s| s.recv_from(buf)) {
+                Ok((_, peer)) if !self.peer_filter.accept(peer) => {}
+                Ok((size, peer)) => return Ok(Async::Ready(Some((peer, size)))),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if self.raw_readiness.is_none() {
+                        let fd = self.socket.with_inner(|s| s.as_raw_fd());
+                        let readiness = sys::Readiness::new(fd);
+                        self.raw_readiness = Some(track!(readiness.map_err(Error::from))?);
+                    }
+                    let readable = self
+                        .raw_readiness
+                        .as_mut()
+                        .expect("never fails")
+                        .poll_readable();
+                    if track!(readable.map_err(Error::from))?.is_not_ready() {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                Err(e) => return Err(track!(Error::from(e))),
+            }
+        }
+    }
+
+    fn poll_recv_unstashed(&mut self) -> PollRecv<(SocketAddr, D::Item)> {
+        #[cfg(target_os = "linux")]
+        {
+            if self.metadata_recv.is_some() {
+                let polled = track!(self.poll_recv_with_meta())?;
+                return Ok(polled.map(|x| x.map(|(peer, item, _)| (peer, item))));
+            }
+        }
+        track!(self.poll_recv_datagram())
+    }
+
+    fn poll_recv_datagram(&mut self) -> PollRecv<(SocketAddr, D::Item)> {
+        while let Async::Ready((socket, buf, size, peer)) = self
             .recv_from
+            .as_mut()
+            .expect("never fails")
             .poll()
             .map_err(|(_, _, e)| track!(Error::from(e)))?
         {
-            let result = track!(self.decoder.decode_from_bytes(&buf[..size]); peer);
-            self.recv_from = socket.recv_from(buf);
-            let item = result?;
-            Ok(Async::Ready(Some((peer, item))))
+            if !self.peer_filter.accept(peer) {
+                self.restart_recv_from(socket, buf);
+                continue;
+            }
+            let payload = if let Some(payload) = self.peer_filter.verify(&buf[..size]) {
+                payload
+            } else {
+                self.restart_recv_from(socket, buf);
+                continue;
+            };
+            let result = track!(self.peer_filter.decode(&mut self.decoder, payload, peer));
+
+            // The next receive is started before propagating a decoding error,
+            // so that a malformed datagram does not stall the instance.
+            self.restart_recv_from(socket, buf);
+            let item = result?;
+            return Ok(Async::Ready(Some((peer, item))));
+        }
+        Ok(Async::NotReady)
+    }
+
+    fn restart_recv_from(&mut self, socket: UdpSocket, buf: Vec<u8>) {
+        let size = buf.len();
+        self.buffer_pool.release(buf);
+        self.recv_from = Some(socket.recv_from(self.buffer_pool.acquire(size)));
+    }
+
+    fn poll_send_to(&mut self) -> Poll<(), Error> {
+        while let Some((peer, mut future)) = self.send_to.take() {
+            match future.poll() {
+                Err((socket, buf, e)) => track!(self.handle_send_error(peer, socket, buf, e))?,
+                Ok(Async::NotReady) if self.on_send_full == DropOrBlock::Drop => {
+                    // `SendTo` only returns `NotReady` if the socket is not writable.
+                    self.dropped_count += 1;
+                }
+                Ok(Async::NotReady) => {
+                    self.send_to = Some((peer, future));
+                    return Ok(Async::NotReady);
+                }
+                Ok(Async::Ready((_, buf, written_size))) => {
+                    track!(check_written_size(buf.len(), written_size); peer)?;
+                }
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+
+    fn start_send_to(&mut self, peer: SocketAddr, bytes: Vec<u8>) -> Result<()> {
+        self.send_to = Some((peer, self.socket.clone().send_to(bytes, peer)));
+        Ok(())
+    }
+
+    fn handle_send_error(
+        &mut self,
+        peer: SocketAddr,
+        socket: UdpSocket,
+        buf: Vec<u8>,
+        e: io::Error,
+    ) -> Result<()> {
+        if is_transient_send_error(&e) {
+            self.send_to = Some((peer, socket.send_to(buf, peer)));
+            Ok(())
         } else {
-            Ok(Async::NotReady)
+            Err(track!(send_error(e); peer))
         }
     }
 }
+impl<E: Encode, D: Decode> Transport for UdpTransporter<E, D> {
+    type PeerAddr = SocketAddr;
+    type SendItem = E::Item;
+    type RecvItem = D::Item;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: E::Item) -> Result<()> {
+        track!(self.start_send_prioritized(peer, item, Priority::Normal))
+    }
+
+    fn start_send_batch(&mut self, peer: Self::PeerAddr, items: Vec<E::Item>) -> Result<()> {
+        for item in items {
+            track!(self.check_can_enqueue(peer))?;
+            self.outgoing_queue.push(peer, item);
+        }
+        if self.eager_flush && self.rebinding.is_none() {
+            track!(self.poll_send())?;
+        }
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        if track!(self.poll_rebind())?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        while track!(self.poll_send_to())?.is_ready() {
+            if let Some((peer, item)) = self.outgoing_queue.pop() {
+                // FIXME: optimize
+                let mapper = &self.peer_filter.codec_error_mapper;
+                let mut bytes = track!(
+                    self.encoder
+                        .encode_into_bytes(item)
+                        .map_err(|e| mapper.map(e)),
+                    "while encoding; peer={}",
+                    peer
+                )?;
+                if let Some(kind) = self.peer_filter.checksum {
+                    kind.append(&mut bytes);
+                }
+                let peer = match (peer, self.flowinfo) {
+                    (SocketAddr::V6(mut peer), Some(flowinfo)) => {
+                        peer.set_flowinfo(flowinfo);
+                        SocketAddr::V6(peer)
+                    }
+                    _ => peer,
+                };
+                track!(self.start_send_to(peer, bytes))?;
+            } else {
+                return Ok(Async::Ready(()));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, D::Item)> {
+        if track!(self.poll_recv_ready())?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        if let Some(item) = self.stashed.pop_front() {
+            return Ok(Async::Ready(Some(item)));
+        }
+        track!(self.poll_recv_unstashed())
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        self.recv_paused = paused;
+        Ok(())
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        Some(self.local_addr)
+    }
+}
 impl<E: Encode, D: Decode> UdpTransport for UdpTransporter<E, D> {
     fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
 }
+#[cfg(unix)]
+impl<E: Encode, D: Decode> AsRawFd for UdpTransporter<E, D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.with_inner(|s| s.as_raw_fd())
+    }
+}
+
+/// Metadata of a datagram received by [`UdpTransporter`].
+///
+/// [`UdpTransporter`]: ./struct.UdpTransporter.html
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecvMetadata {
+    /// The time at which the datagram was received by the kernel (`SO_TIMESTAMPNS`).
+    pub timestamp: Option<SystemTime>,
+
+    /// The destination address of the datagram (`IP_PKTINFO` or `IPV6_PKTINFO`).
+    pub destination: Option<IpAddr>,
+
+    /// The index of the interface on which the datagram was received.
+    pub interface_index: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+struct SocketOptions {
+    dont_fragment: bool,
+    traffic_class: Option<u8>,
+    flowinfo: Option<u32>,
+}
+impl SocketOptions {
+    /// Applies the options to the given socket, and returns the flow information to be set to outgoing datagrams.
+    fn apply(&self, socket: &UdpSocket, local_addr: SocketAddr) -> Result<Option<u32>> {
+        if self.dont_fragment {
+            track!(sys::set_dont_fragment(socket).map_err(Error::from))?;
+        }
+        if !local_addr.is_ipv6() {
+            return Ok(None);
+        }
+        if let Some(traffic_class) = self.traffic_class {
+            track!(sys::set_traffic_class(socket, traffic_class).map_err(Error::from))?;
+        }
+        if self.flowinfo.is_some() {
+            track!(sys::enable_flowinfo_send(socket).map_err(Error::from))?;
+        }
+        Ok(self.flowinfo)
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct MetadataRecv {
+    buf: Vec<u8>,
+    readiness: sys::Readiness,
+}
+#[cfg(target_os = "linux")]
+impl MetadataRecv {
+    fn poll_recv<D: Decode>(
+        &mut self,
+        socket: &UdpSocket,
+        decoder: &mut D,
+        peer_filter: &mut PeerFilter,
+    ) -> PollRecv<(SocketAddr, D::Item, RecvMetadata)> {
+        loop {
+            match sys::recv_with_metadata(socket, &mut self.buf) {
+                Ok((_, peer, _)) if !peer_filter.accept(peer) => {}
+                Ok((size, peer, metadata)) => {
+                    if let Some(payload) = peer_filter.verify(&self.buf[..size]) {
+                        let item = track!(peer_filter.decode(decoder, payload, peer))?;
+                        return Ok(Async::Ready(Some((peer, item, metadata))));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let readable = self.readiness.poll_readable();
+                    if track!(readable.map_err(Error::from))?.is_not_ready() {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                Err(e) => return Err(track!(Error::from(e))),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct BatchRecv {
+    batch_size: usize,
+    buf_size: usize,
+    bufs: Vec<Vec<u8>>,
+    received: VecDeque<(usize, usize, SocketAddr)>,
+    pending_error: Option<Error>,
+    readiness: Option<sys::Readiness>,
+}
+#[cfg(target_os = "linux")]
+impl BatchRecv {
+    fn new(batch_size: usize, buf_size: usize) -> Self {
+        // The buffers are allocated lazily, as most users never receive datagrams in batches.
+        BatchRecv {
+            batch_size,
+            buf_size,
+            bufs: Vec::new(),
+            received: VecDeque::new(),
+            pending_error: None,
+            readiness: None,
+        }
+    }
+
+    fn poll_recv<D: Decode>(
+        &mut self,
+        socket: &UdpSocket,
+        decoder: &mut D,
+        peer_filter: &mut PeerFilter,
+    ) -> PollRecv<Vec<(SocketAddr, D::Item)>> {
+        if let Some(e) = self.pending_error.take() {
+            return Err(track!(e));
+        }
+        if self.bufs.is_empty() {
+            self.bufs = vec![vec![0; self.buf_size]; self.batch_size];
+        }
+        loop {
+            if self.received.is_empty() {
+                match sys::recv_batch(socket, &mut self.bufs) {
+                    Ok(received) => {
+                        let received = received.into_iter().enumerate();
+                        self.received
+                            .extend(received.map(|(i, (size, peer))| (i, size, peer)));
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if self.readiness.is_none() {
+                            let readiness = sys::Readiness::new(sys::udp_fd(socket));
+                            self.readiness = Some(track!(readiness.map_err(Error::from))?);
+                        }
+                        let readable = self
+                            .readiness
+                            .as_mut()
+                            .expect("never fails")
+                            .poll_readable();
+                        if track!(readable.map_err(Error::from))?.is_not_ready() {
+                            return Ok(Async::NotReady);
+                        }
+                        continue;
+                    }
+                    Err(e) => return Err(track!(Error::from(e))),
+                }
+            }
+
+            let mut items = Vec::with_capacity(self.received.len());
+            while let Some((i, size, peer)) = self.received.pop_front() {
+                if !peer_filter.accept(peer) {
+                    continue;
+                }
+                let payload = if let Some(payload) = peer_filter.verify(&self.bufs[i][..size]) {
+                    payload
+                } else {
+                    continue;
+                };
+                match track!(peer_filter.decode(decoder, payload, peer)) {
+                    Ok(item) => items.push((peer, item)),
+                    Err(e) if items.is_empty() => return Err(e),
+                    Err(e) => {
+                        // The error is reported by the next call, so as not to lose the decoded items.
+                        self.pending_error = Some(e);
+                        break;
+                    }
+                }
+            }
+            if !items.is_empty() {
+                return Ok(Async::Ready(Some(items)));
+            }
+        }
+    }
+}
+
+/// The behavior of [`UdpTransporter`] when the send buffer of the socket is full.
+///
+/// See [`UdpTransporterBuilder::on_send_full`] for more details.
+///
+/// [`UdpTransporter`]: ./struct.UdpTransporter.html
+/// [`UdpTransporterBuilder::on_send_full`]: ./struct.UdpTransporterBuilder.html#method.on_send_full
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropOrBlock {
+    /// The datagram is discarded.
+    Drop,
+
+    /// The datagram is kept until the socket becomes writable.
+    #[default]
+    Block,
+}
+
+/// The kind of the checksum appended to datagrams by [`UdpTransporter`].
+///
+/// See [`UdpTransporterBuilder::checksum`] for more details.
+///
+/// [`UdpTransporter`]: ./struct.UdpTransporter.html
+/// [`UdpTransporterBuilder::checksum`]: ./struct.UdpTransporterBuilder.html#method.checksum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChecksumKind {
+    /// CRC-32 (IEEE 802.3), appended in big-endian byte order.
+    Crc32,
+}
+impl ChecksumKind {
+    fn append(self, bytes: &mut Vec<u8>) {
+        match self {
+            ChecksumKind::Crc32 => {
+                let checksum = crc32(bytes);
+                bytes.extend_from_slice(&checksum.to_be_bytes());
+            }
+        }
+    }
+
+    fn verify(self, bytes: &[u8]) -> Option<&[u8]> {
+        match self {
+            ChecksumKind::Crc32 => {
+                if bytes.len() < 4 {
+                    return None;
+                }
+                let (payload, trailer) = bytes.split_at(bytes.len() - 4);
+                if crc32(payload).to_be_bytes() == trailer {
+                    Some(payload)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                (crc >> 1) ^ 0xEDB8_8320
+            };
+        }
+    }
+    !crc
+}
+
+#[derive(Debug)]
+struct PeerFilter {
+    allowed: Option<HashSet<IpAddr>>,
+    rejected_count: u64,
+    checksum: Option<ChecksumKind>,
+    checksum_error_count: u64,
+    decode_error_count: u64,
+    codec_error_mapper: CodecErrorMapper,
+}
+impl PeerFilter {
+    fn accept(&mut self, peer: SocketAddr) -> bool {
+        match self.allowed {
+            Some(ref allowed) if !allowed.contains(&peer.ip()) => {
+                self.rejected_count += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn verify<'a>(&mut self, bytes: &'a [u8]) -> Option<&'a [u8]> {
+        if let Some(kind) = self.checksum {
+            let payload = kind.verify(bytes);
+            if payload.is_none() {
+                self.checksum_error_count += 1;
+            }
+            payload
+        } else {
+            Some(bytes)
+        }
+    }
+
+    fn decode<D: Decode>(
+        &mut self,
+        decoder: &mut D,
+        payload: &[u8],
+        peer: SocketAddr,
+    ) -> Result<D::Item> {
+        let result = track!(
+            decoder
+                .decode_from_bytes(payload)
+                .map_err(|e| self.codec_error_mapper.map(e)),
+            "while decoding; peer={}",
+            peer
+        );
+        if result.is_err() {
+            self.decode_error_count += 1;
+        }
+        Ok(track!(result)?)
+    }
+}
+
+/// Returns `true` if the failed `send_to` operation can be retried immediately.
+fn is_transient_send_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Converts the error of a failed `send_to` operation into an `Error`.
+///
+/// `EMSGSIZE` is reported as `ErrorKind::MessageTooLarge` so that callers can reduce the payload size.
+fn send_error(e: io::Error) -> Error {
+    if is_message_too_large(&e) {
+        ErrorKind::MessageTooLarge.cause(e).into()
+    } else {
+        Error::from(e)
+    }
+}
+
+#[cfg(unix)]
+fn is_message_too_large(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+#[cfg(not(unix))]
+fn is_message_too_large(_e: &io::Error) -> bool {
+    false
+}
+
+fn check_written_size(datagram_size: usize, written_size: usize) -> Result<()> {
+    track_assert_eq!(
+        datagram_size,
+        written_size,
+        ErrorKind::Other,
+        "The datagram was sent only partially"
+    );
+    Ok(())
+}
+
+#[derive(Debug)]
+struct OutgoingQueue<T> {
+    queues: HashMap<(Priority, SocketAddr), VecDeque<T>>,
+    peers: [VecDeque<SocketAddr>; 3],
+    len: usize,
+    capacity: usize,
+    spare_queues: Vec<VecDeque<T>>,
+}
+impl<T> OutgoingQueue<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        OutgoingQueue {
+            queues: HashMap::new(),
+            peers: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            len: 0,
+            capacity,
+            spare_queues: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn push(&mut self, peer: SocketAddr, item: T) {
+        self.push_with_priority(peer, item, Priority::Normal);
+    }
+
+    fn push_with_priority(&mut self, peer: SocketAddr, item: T, priority: Priority) {
+        let capacity = self.capacity;
+        let spare_queues = &mut self.spare_queues;
+        let queue = self.queues.entry((priority, peer)).or_insert_with(|| {
+            spare_queues
+                .pop()
+                .unwrap_or_else(|| VecDeque::with_capacity(capacity))
+        });
+        if queue.is_empty() {
+            self.peers[priority.index()].push_back(peer);
+        }
+        queue.push_back(item);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<(SocketAddr, T)> {
+        for &priority in &Priority::ALL {
+            let peers = &mut self.peers[priority.index()];
+            let peer = if let Some(peer) = peers.pop_front() {
+                peer
+            } else {
+                continue;
+            };
+            let queue = self.queues.get_mut(&(priority, peer)).expect("never fails");
+            let item = queue.pop_front().expect("never fails");
+            if queue.is_empty() {
+                let mut queue = self.queues.remove(&(priority, peer)).expect("never fails");
+                if self.capacity > 0 {
+                    queue.shrink_to(self.capacity);
+                    self.spare_queues.push(queue);
+                }
+            } else {
+                peers.push_back(peer);
+            }
+            self.len -= 1;
+            return Some((peer, item));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{bind, Udp};
+    use crate::{drive, wait_recv, wait_send};
+    use bytecodec::bytes::{Utf8Decoder, Utf8Encoder};
+    use bytecodec::{DecodeExt, EncodeExt};
+
+    #[test]
+    fn outgoing_queue_round_robin_works() {
+        let a = "127.0.0.1:1000".parse().unwrap();
+        let b = "127.0.0.1:2000".parse().unwrap();
+
+        let mut queue = OutgoingQueue::with_capacity(0);
+        queue.push(a, 0);
+        queue.push(a, 1);
+        queue.push(a, 2);
+        queue.push(b, 3);
+        queue.push(b, 4);
+        assert_eq!(queue.len(), 5);
+
+        let order = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(order, [(a, 0), (b, 3), (a, 1), (b, 4), (a, 2)]);
+        assert_eq!(queue.len(), 0);
+        assert!(queue.queues.is_empty());
+    }
+
+    #[test]
+    fn outgoing_queue_priority_works() {
+        let a = "127.0.0.1:1000".parse().unwrap();
+        let b = "127.0.0.1:2000".parse().unwrap();
+
+        let mut queue = OutgoingQueue::with_capacity(0);
+        queue.push_with_priority(a, 0, Priority::Low);
+        queue.push(a, 1);
+        queue.push_with_priority(b, 2, Priority::High);
+        queue.push_with_priority(a, 3, Priority::High);
+        queue.push(b, 4);
+
+        let order = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(order, [(b, 2), (a, 3), (a, 1), (b, 4), (a, 0)]);
+        assert!(queue.queues.is_empty());
+    }
+
+    #[test]
+    fn start_send_prioritized_works() -> Result<()> {
+        let builder = UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new().eager_flush(false);
+        let mut sender = fibers_global::execute(builder.bind("127.0.0.1:0".parse().unwrap()))?;
+        let receiver = bind()?;
+        let peer = receiver.local_addr();
+
+        sender.start_send_prioritized(peer, "bulk".to_owned(), Priority::Low)?;
+        sender.start_send_prioritized(peer, "ack".to_owned(), Priority::High)?;
+        let _sender = fibers_global::execute(wait_send(sender))?;
+
+        let (receiver, _, item) = fibers_global::execute(wait_recv(receiver))?;
+        assert_eq!(item, "ack");
+        let (_, _, item) = fibers_global::execute(wait_recv(receiver))?;
+        assert_eq!(item, "bulk");
+        Ok(())
+    }
+
+    #[test]
+    fn queue_capacity_works() -> Result<()> {
+        let builder = UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new()
+            .queue_capacity(100)
+            .eager_flush(false);
+        let mut sender = fibers_global::execute(builder.bind("127.0.0.1:0".parse().unwrap()))?;
+        let mut receiver = bind()?;
+        let peer = receiver.local_addr();
+
+        for i in 0..30 {
+            sender.start_send_prioritized(peer, format!("low-{}", i), Priority::Low)?;
+            sender.start_send(peer, i.to_string())?;
+            sender.start_send_prioritized(peer, format!("high-{}", i), Priority::High)?;
+        }
+        assert_eq!(sender.message_queue_len(), 90);
+        for &priority in &Priority::ALL {
+            assert!(sender.outgoing_queue.queues[&(priority, peer)].capacity() >= 100);
+        }
+        let _sender = fibers_global::execute(wait_send(sender))?;
+
+        let expected = (0..30)
+            .map(|i| format!("high-{}", i))
+            .chain((0..30).map(|i| i.to_string()))
+            .chain((0..30).map(|i| format!("low-{}", i)));
+        for expected in expected {
+            let (r, _, item) = fibers_global::execute(wait_recv(receiver))?;
+            assert_eq!(item, expected);
+            receiver = r;
+        }
+
+        // The drained queue is reused by the next burst.
+        let mut queue = OutgoingQueue::with_capacity(100);
+        for _ in 0..2 {
+            for i in 0..100 {
+                queue.push(peer, i);
+            }
+            assert!(queue.queues[&(Priority::Normal, peer)].capacity() >= 100);
+            assert!(queue.spare_queues.is_empty());
+            while queue.pop().is_some() {}
+            assert!(queue.queues.is_empty());
+            assert!(queue.spare_queues[0].capacity() >= 100);
+        }
+        assert_eq!(queue.spare_queues.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn traffic_class_works() -> Result<()> {
+        let builder = UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new().traffic_class(0x28);
+        let mut sender = fibers_global::execute(builder.bind("[::1]:0".parse().unwrap()))?;
+        let receiver = fibers_global::execute(Udp::bind("[::1]:0".parse().unwrap()))?;
+
+        sender.start_send(receiver.local_addr(), "foo".to_owned())?;
+        let sender = fibers_global::execute(wait_send(sender))?;
+        let (_, peer, item) = fibers_global::execute(wait_recv(receiver))?;
+        assert_eq!(peer, sender.local_addr());
+        assert_eq!(item, "foo");
+        Ok(())
+    }
+
+    #[test]
+    fn send_error_classification_works() {
+        assert!(is_transient_send_error(&io::Error::new(
+            io::ErrorKind::Interrupted,
+            "interrupted"
+        )));
+        assert!(is_transient_send_error(&io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "would block"
+        )));
+        assert!(!is_transient_send_error(&io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "denied"
+        )));
+
+        assert!(check_written_size(10, 10).is_ok());
+        let e = check_written_size(10, 4).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn interrupted_send_retry_works() -> Result<()> {
+        let mut sender = bind()?;
+        let receiver = bind()?;
+
+        // An interrupted send is retried, and the datagram is delivered.
+        let socket = sender.socket.clone();
+        let e = io::Error::new(io::ErrorKind::Interrupted, "interrupted");
+        sender.handle_send_error(receiver.local_addr(), socket, b"foo".to_vec(), e)?;
+        let mut sender = fibers_global::execute(wait_send(sender))?;
+
+        let (_, peer, item) = fibers_global::execute(wait_recv(receiver))?;
+        assert_eq!(peer, sender.local_addr());
+        assert_eq!(item, "foo");
+
+        // Other errors fail the send (an IPv4 socket cannot send to IPv6 addresses).
+        let e = sender
+            .start_send("[::1]:1234".parse().unwrap(), "bar".to_owned())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::IoError);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn recv_metadata_works() -> Result<()> {
+        let mut sender = bind()?;
+        let receiver = fibers_global::execute(
+            UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new()
+                .recv_metadata(true)
+                .bind("127.0.0.1:0".parse().unwrap()),
+        )?;
+
+        sender.start_send(receiver.local_addr(), "foo".to_owned())?;
+        let _ = fibers_global::execute(wait_send(sender))?;
+
+        let mut receiver = Some(receiver);
+        let (peer, item, metadata) = fibers_global::execute(futures::future::poll_fn(move || {
+            let polled = track!(receiver
+                .as_mut()
+                .expect("never fails")
+                .poll_recv_with_meta())?;
+            Ok::<_, Error>(polled.map(|x| x.expect("never fails")))
+        }))?;
+        assert_eq!(peer.ip(), IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(item, "foo");
+        assert!(metadata.timestamp.expect("no timestamp") > std::time::UNIX_EPOCH);
+        assert_eq!(metadata.destination, Some(IpAddr::from([127, 0, 0, 1])));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dont_fragment_works() -> Result<()> {
+        let mut sender = fibers_global::execute(
+            UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new()
+                .dont_fragment(true)
+                .bind("127.0.0.1:0".parse().unwrap()),
+        )?;
+        let receiver = bind()?;
+
+        let oversized = "a".repeat(70_000);
+        let result = sender.start_send(receiver.local_addr(), oversized);
+        let e = result
+            .and_then(|()| fibers_global::execute(wait_send(sender)).map(|_| ()))
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::MessageTooLarge);
+        Ok(())
+    }
+
+    #[test]
+    fn allowed_peers_works() -> Result<()> {
+        let receiver = fibers_global::execute(
+            UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new()
+                .allowed_peers([IpAddr::from([127, 0, 0, 1])])
+                .bind("127.0.0.1:0".parse().unwrap()),
+        )?;
+        let mut disallowed = fibers_global::execute(Udp::bind("127.0.0.2:0".parse().unwrap()))?;
+        let mut allowed = bind()?;
+
+        disallowed.start_send(receiver.local_addr(), "foo".to_owned())?;
+        let _ = fibers_global::execute(wait_send(disallowed))?;
+        allowed.start_send(receiver.local_addr(), "bar".to_owned())?;
+        let allowed = fibers_global::execute(wait_send(allowed))?;
+
+        let (receiver, peer, item) = fibers_global::execute(wait_recv(receiver))?;
+        assert_eq!(peer, allowed.local_addr());
+        assert_eq!(item, "bar");
+        assert_eq!(receiver.rejected_count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_self_send_works() -> Result<()> {
+        let builder = UdpTransporterBuilder::new().reject_self_send(true);
+        let mut socket: Udp = fibers_global::execute(builder.bind("0.0.0.0:0".parse().unwrap()))?;
+        let port = socket.local_addr().port();
+
+        let result = socket.start_send(socket.local_addr(), "foo".to_owned());
+        assert_eq!(
+            result.err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        let peer = SocketAddr::from(([127, 0, 0, 1], port));
+        let result = socket.start_send(peer, "foo".to_owned());
+        assert_eq!(
+            result.err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        assert_eq!(socket.message_queue_len(), 0);
+
+        // The self-send is accepted by default.
+        let mut socket = bind()?;
+        socket.start_send(socket.local_addr(), "foo".to_owned())?;
+        let socket = fibers_global::execute(wait_send(socket))?;
+        let (_, _, item) = fibers_global::execute(wait_recv(socket))?;
+        assert_eq!(item, "foo");
+        Ok(())
+    }
+
+    #[test]
+    fn set_recv_paused_works() -> Result<()> {
+        let mut client = bind()?;
+        let mut server = bind()?;
+        client.start_send(server.local_addr(), "foo".to_owned())?;
+        let _client = fibers_global::execute(wait_send(client))?;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        server.set_recv_paused(true)?;
+        assert!(server.is_recv_paused());
+        assert_eq!(server.poll_recv()?, Async::NotReady);
+        assert_eq!(server.poll_recv_batch()?, Async::NotReady);
+
+        // The datagram is left in the socket.
+        server.set_recv_paused(false)?;
+        let (_, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, "foo");
+        Ok(())
+    }
+
+    #[test]
+    fn buffer_pool_works() -> Result<()> {
+        use crate::FreeListBufferPool;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Default)]
+        struct CountingPool {
+            inner: FreeListBufferPool,
+            acquired: AtomicUsize,
+            allocated: AtomicUsize,
+        }
+        impl BufferPool for CountingPool {
+            fn acquire(&self, size: usize) -> Vec<u8> {
+                self.acquired.fetch_add(1, Ordering::SeqCst);
+                if self.inner.free_buffers() == 0 {
+                    self.allocated.fetch_add(1, Ordering::SeqCst);
+                }
+                self.inner.acquire(size)
+            }
+
+            fn release(&self, buf: Vec<u8>) {
+                self.inner.release(buf);
+            }
+        }
+
+        let pool = Arc::new(CountingPool::default());
+        let mut client = bind()?;
+        let builder =
+            UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new().buffer_pool(pool.clone());
+        let mut server = fibers_global::execute(builder.bind("127.0.0.1:0".parse().unwrap()))?;
+        for i in 0..100 {
+            client.start_send(server.local_addr(), i.to_string())?;
+        }
+        let _client = fibers_global::execute(wait_send(client))?;
+        for i in 0..100 {
+            let (s, _, item) = fibers_global::execute(wait_recv(server))?;
+            assert_eq!(item, i.to_string());
+            server = s;
+        }
+        assert_eq!(pool.acquired.load(Ordering::SeqCst), 101);
+        assert_eq!(pool.allocated.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn auto_recv_works() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Default)]
+        struct CountingPool(AtomicUsize);
+        impl BufferPool for CountingPool {
+            fn acquire(&self, size: usize) -> Vec<u8> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                vec![0; size]
+            }
+
+            fn release(&self, _buf: Vec<u8>) {}
+        }
+
+        let pool = Arc::new(CountingPool::default());
+        let builder = UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new()
+            .buffer_pool(pool.clone())
+            .auto_recv(false);
+        let mut sender = fibers_global::execute(builder.bind("127.0.0.1:0".parse().unwrap()))?;
+        assert!(!sender.is_recv_enabled());
+        assert_eq!(pool.0.load(Ordering::SeqCst), 0);
+
+        // Send-only transporters can send items as usual.
+        let receiver = bind()?;
+        sender.start_send(receiver.local_addr(), "foo".to_owned())?;
+        let mut sender = fibers_global::execute(wait_send(sender))?;
+        let (mut receiver, peer, _) = fibers_global::execute(wait_recv(receiver))?;
+        assert_eq!(peer, sender.local_addr());
+        assert_eq!(sender.poll_recv()?, Async::NotReady);
+        assert_eq!(pool.0.load(Ordering::SeqCst), 0);
+
+        sender.enable_recv();
+        assert!(sender.is_recv_enabled());
+        assert_eq!(pool.0.load(Ordering::SeqCst), 1);
+        receiver.start_send(peer, "bar".to_owned())?;
+        fibers_global::execute(wait_send(receiver))?;
+        let (_, _, item) = fibers_global::execute(wait_recv(sender))?;
+        assert_eq!(item, "bar");
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn recv_batch_works() -> Result<()> {
+        let mut client = bind()?;
+        let builder = UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new().recv_batch_size(8);
+        let server = fibers_global::execute(builder.bind("127.0.0.1:0".parse().unwrap()))?;
+        for i in 0..100 {
+            client.start_send(server.local_addr(), i.to_string())?;
+        }
+        let client = fibers_global::execute(wait_send(client))?;
+
+        let mut batches = Vec::new();
+        let mut received = 0;
+        let (_, batches) = fibers_global::execute(drive(server, move |server| {
+            while let Async::Ready(Some(batch)) = track!(server.poll_recv_batch())? {
+                received += batch.len();
+                batches.push(batch);
+            }
+            if received < 100 {
+                Ok(Async::NotReady)
+            } else {
+                Ok(Async::Ready(std::mem::take(&mut batches)))
+            }
+        }))?;
+        assert!(batches.iter().all(|b| !b.is_empty() && b.len() <= 8));
+        assert!(batches.len() < 100);
+
+        let items = batches.into_iter().flatten().collect::<Vec<_>>();
+        assert!(items.iter().all(|(peer, _)| *peer == client.local_addr()));
+        let items = items.into_iter().map(|(_, item)| item).collect::<Vec<_>>();
+        let expected = (0..100).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(items, expected);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn as_raw_fd_works() -> Result<()> {
+        let socket = bind()?;
+        let fd = socket.as_raw_fd();
+        assert_eq!(fd, socket.socket_ref().with_inner(|s| s.as_raw_fd()));
+        assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn on_send_full_works() -> Result<()> {
+        fn bind_with(behavior: DropOrBlock) -> Result<Udp> {
+            let builder = UdpTransporterBuilder::new().on_send_full(behavior);
+            let socket = fibers_global::execute(builder.bind("0.0.0.0:0".parse().unwrap()))?;
+            let fd = socket.as_raw_fd();
+            let size: libc::c_int = 4096;
+            let ret = unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_SNDBUF,
+                    &size as *const _ as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            assert_eq!(ret, 0);
+            Ok(socket)
+        }
+
+        // Datagrams to the loopback interface never fill the send buffer,
+        // so a non-local address (TEST-NET-2) is used as the destination.
+        let peer: SocketAddr = "198.51.100.1:9".parse().unwrap();
+        let probe = std::net::UdpSocket::bind("0.0.0.0:0").expect("never fails");
+        if probe.send_to(b"probe", peer).is_err() {
+            // There is no route to the destination in this environment.
+            return Ok(());
+        }
+
+        let mut dropping = bind_with(DropOrBlock::Drop)?;
+        let mut blocking = bind_with(DropOrBlock::Block)?;
+        let item = "x".repeat(1000);
+        for _ in 0..1000 {
+            dropping.start_send(peer, item.clone())?;
+            blocking.start_send(peer, item.clone())?;
+        }
+        assert_eq!(dropping.message_queue_len(), 0);
+        assert!(dropping.dropped_count() > 0);
+        assert!(blocking.message_queue_len() > 0);
+        assert_eq!(blocking.dropped_count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn bind_in_range_works() -> Result<()> {
+        let holder = bind()?;
+        let first = holder.local_addr().port();
+        let ip = "127.0.0.1".parse().unwrap();
+        let ports = first..=first.saturating_add(2);
+
+        // The first port is in use by `holder`, so one of the subsequent ports is chosen.
+        let builder = UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new();
+        let udp = fibers_global::execute(builder.bind_in_range(ip, ports.clone()))?;
+        assert!(ports.contains(&udp.local_addr().port()));
+        assert_ne!(udp.local_addr().port(), first);
+
+        let builder = UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new();
+        let result = fibers_global::execute(builder.bind_in_range(ip, first..=first));
+        assert_eq!(
+            result.err().map(|e| *e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bind_host_works() -> Result<()> {
+        let mut udp = fibers_global::execute(Udp::bind_host("localhost", 0))?;
+        let addr = udp.local_addr();
+        assert!(addr.ip().is_loopback());
+
+        // The transporter works on whatever family "localhost" resolves to.
+        udp.start_send(addr, "hello".to_owned())?;
+        let udp = fibers_global::execute(wait_send(udp))?;
+        let (_, peer, item) = fibers_global::execute(wait_recv(udp))?;
+        assert_eq!(peer, addr);
+        assert_eq!(item, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn buf_capacity_works() -> Result<()> {
+        let builder = UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new().buf_size(100);
+        let udp = fibers_global::execute(builder.bind("127.0.0.1:0".parse().unwrap()))?;
+        assert_eq!(udp.recv_buf_capacity(), 100);
+        assert_eq!(udp.send_buf_capacity(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn rebind_works() -> Result<()> {
+        let mut sender = bind()?;
+        let receiver = bind()?;
+        let old_addr = sender.local_addr();
+
+        sender.rebind("127.0.0.1:0".parse().unwrap())?;
+        sender.start_send(receiver.local_addr(), "hello".to_owned())?;
+        let sender = fibers_global::execute(wait_send(sender))?;
+        let new_addr = sender.local_addr();
+        assert_ne!(new_addr, old_addr);
+
+        let (_, peer, item) = fibers_global::execute(wait_recv(receiver))?;
+        assert_eq!(peer, new_addr);
+        assert_eq!(item, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn recv_timeout_future_works() -> Result<()> {
+        let receiver = bind()?;
+        let addr = receiver.local_addr();
+        let future = receiver.recv_timeout_future(Duration::from_millis(10));
+        let (receiver, item) = fibers_global::execute(future)?;
+        assert_eq!(receiver.local_addr(), addr);
+        assert_eq!(item, None);
+        Ok(())
+    }
+
+    #[test]
+    fn poll_recv_into_works() -> Result<()> {
+        let builder = UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new();
+        let receiver = fibers_global::execute(
+            builder
+                .recv_buffer(vec![0; 64])
+                .bind("127.0.0.1:0".parse().unwrap()),
+        )?;
+        let mut sender = bind()?;
+        sender.start_send(receiver.local_addr(), "hello".to_owned())?;
+        let sender = fibers_global::execute(wait_send(sender))?;
+
+        let mut buf = [0; 16];
+        let (_, received) = fibers_global::execute(drive(receiver, move |t| {
+            let polled = track!(t.poll_recv_into(&mut buf))?;
+            Ok(polled.map(|x| x.map(|(peer, size)| (peer, buf[..size].to_vec()))))
+        }))?;
+        let (peer, bytes) = received.expect("never fails");
+        assert_eq!(peer, sender.local_addr());
+        assert_eq!(
+            crate::decode_item(&mut Utf8Decoder::new(), &bytes)?,
+            "hello"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn poll_recv_from_works() -> Result<()> {
+        let server = bind()?;
+        let mut alice = bind()?;
+        let mut bob = bind()?;
+        let bob_addr = bob.local_addr();
+
+        alice.start_send(server.local_addr(), "from alice".to_owned())?;
+        let _alice = fibers_global::execute(wait_send(alice))?;
+        bob.start_send(server.local_addr(), "from bob".to_owned())?;
+        let _bob = fibers_global::execute(wait_send(bob))?;
+
+        let (server, item) =
+            fibers_global::execute(drive(server, move |s| track!(s.poll_recv_from(bob_addr))))?;
+        assert_eq!(item.as_deref(), Some("from bob"));
+
+        // The item from `alice` has been stashed.
+        let (_, _, item) = fibers_global::execute(wait_recv(server))?;
+        assert_eq!(item, "from alice");
+        Ok(())
+    }
+
+    #[test]
+    fn stashed_items_are_returned_by_all_recv_methods() -> Result<()> {
+        let server = bind()?;
+        let mut alice = bind()?;
+        let mut bob = bind()?;
+        let alice_addr = alice.local_addr();
+        let bob_addr = bob.local_addr();
+
+        alice.start_send(server.local_addr(), "foo".to_owned())?;
+        alice.start_send(server.local_addr(), "bar".to_owned())?;
+        let _alice = fibers_global::execute(wait_send(alice))?;
+        bob.start_send(server.local_addr(), "baz".to_owned())?;
+        let _bob = fibers_global::execute(wait_send(bob))?;
+
+        let (server, item) =
+            fibers_global::execute(drive(server, move |s| track!(s.poll_recv_from(bob_addr))))?;
+        assert_eq!(item.as_deref(), Some("baz"));
+
+        // The items from `alice` have been stashed.
+        let (server, item) =
+            fibers_global::execute(drive(server, |s| track!(s.poll_recv_with_meta())))?;
+        let (peer, item, meta) = item.unwrap();
+        assert_eq!(peer, alice_addr);
+        assert_eq!(item, "foo");
+        assert_eq!(meta, RecvMetadata::default());
+
+        let (_, items) = fibers_global::execute(drive(server, |s| track!(s.poll_recv_batch())))?;
+        assert_eq!(items, Some(vec![(alice_addr, "bar".to_owned())]));
+        Ok(())
+    }
+
+    #[test]
+    fn crc32_works() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn checksum_works() -> Result<()> {
+        use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+
+        let builder =
+            UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new().checksum(ChecksumKind::Crc32);
+        let receiver = fibers_global::execute(builder.bind("127.0.0.1:0".parse().unwrap()))?;
+        let receiver_addr = receiver.local_addr();
+
+        let mut valid = b"foo".to_vec();
+        ChecksumKind::Crc32.append(&mut valid);
+        let mut corrupted = valid.clone();
+        corrupted[1] ^= 0xFF;
+
+        let mut sender = fibers_global::execute(UdpTransporter::<
+            BytesEncoder<Vec<u8>>,
+            RemainingBytesDecoder,
+        >::bind("127.0.0.1:0".parse().unwrap()))?;
+        sender.start_send(receiver_addr, corrupted)?;
+        sender.start_send(receiver_addr, valid)?;
+        fibers_global::execute(wait_send(sender))?;
+
+        let (receiver, _, item) = fibers_global::execute(wait_recv(receiver))?;
+        assert_eq!(item, "foo");
+        assert_eq!(receiver.checksum_error_count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_error_works() -> Result<()> {
+        use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+
+        let receiver = bind()?;
+        let receiver_addr = receiver.local_addr();
+
+        let mut sender = fibers_global::execute(UdpTransporter::<
+            BytesEncoder<Vec<u8>>,
+            RemainingBytesDecoder,
+        >::bind("127.0.0.1:0".parse().unwrap()))?;
+        let sender_port = sender.local_addr().port();
+        sender.start_send(receiver_addr, vec![0xFF, 0xFE])?;
+        sender.start_send(receiver_addr, b"foo".to_vec())?;
+        fibers_global::execute(wait_send(sender))?;
+
+        // The malformed datagram is reported as an error.
+        let (receiver, result) = fibers_global::execute(drive(receiver, |t| {
+            Ok(match t.poll_recv() {
+                Ok(Async::NotReady) => Async::NotReady,
+                result => Async::Ready(result.is_err()),
+            })
+        }))?;
+        assert!(result);
+        assert_eq!(receiver.decode_error_count(), 1);
+
+        // The subsequent valid datagram is still received.
+        let (_, peer, item) = fibers_global::execute(wait_recv(receiver))?;
+        assert_eq!(peer.port(), sender_port);
+        assert_eq!(item, "foo");
+        Ok(())
+    }
+
+    #[test]
+    fn executor_stopped_works() -> Result<()> {
+        use fibers::{Executor, InPlaceExecutor, Spawn};
+
+        let mut executor = track!(InPlaceExecutor::new().map_err(Error::from))?;
+        let monitor = executor.spawn_monitor(Udp::bind("127.0.0.1:0".parse().unwrap()));
+        let transporter = executor
+            .run_fiber(monitor)
+            .expect("never fails")
+            .map_err(|e| e.unwrap_or_else(|| ErrorKind::Other.into()))?;
+        std::mem::drop(executor);
+
+        let e = fibers_global::execute(wait_recv(transporter))
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ExecutorStopped);
+        Ok(())
+    }
+
+    #[test]
+    fn codec_error_context_works() -> Result<()> {
+        let encoder = Utf8Encoder::new().try_map_from(|_: String| -> bytecodec::Result<String> {
+            track_panic!(bytecodec::ErrorKind::InvalidInput)
+        });
+        let decoder = Utf8Decoder::new().try_map(|_| -> bytecodec::Result<String> {
+            track_panic!(bytecodec::ErrorKind::InvalidInput)
+        });
+        let mut failing = fibers_global::execute(
+            UdpTransporterBuilder::with_codec(encoder, decoder)
+                .bind("127.0.0.1:0".parse().unwrap()),
+        )?;
+        let mut peer = bind()?;
+
+        let e = failing
+            .start_send(peer.local_addr(), "foo".to_owned())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::CodecError);
+        assert!(e.to_string().contains("while encoding"));
+
+        peer.start_send(failing.local_addr(), "bar".to_owned())?;
+        let _ = fibers_global::execute(wait_send(peer))?;
+        let e = fibers_global::execute(wait_recv(failing)).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::CodecError);
+        assert!(e.to_string().contains("while decoding"));
+        Ok(())
+    }
+
+    #[test]
+    fn send_to_multiple_peers_works() -> Result<()> {
+        let mut sender = bind()?;
+        let peer0 = bind()?;
+        let peer1 = bind()?;
+
+        for i in 0..10 {
+            sender.start_send(peer0.local_addr(), format!("foo{}", i))?;
+        }
+        sender.start_send(peer1.local_addr(), "bar".to_owned())?;
+        let _ = fibers_global::execute(wait_send(sender))?;
+
+        let (_, _, item) = fibers_global::execute(wait_recv(peer1))?;
+        assert_eq!(item, "bar");
+
+        let mut peer0 = peer0;
+        for i in 0..10 {
+            let (p, _, item) = fibers_global::execute(wait_recv(peer0))?;
+            assert_eq!(item, format!("foo{}", i));
+            peer0 = p;
+        }
+        Ok(())
+    }
+    #[test]
+    fn send_failure_to_one_peer_does_not_block_others() -> Result<()> {
+        let builder = UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new().eager_flush(false);
+        let mut sender = fibers_global::execute(builder.bind("127.0.0.1:0".parse().unwrap()))?;
+        let receiver = bind()?;
+
+        // An IPv4 socket cannot send to IPv6 addresses.
+        let unreachable: SocketAddr = "[::1]:1234".parse().unwrap();
+        for i in 0..2 {
+            sender.start_send(unreachable, format!("foo{}", i))?;
+            sender.start_send(receiver.local_addr(), format!("bar{}", i))?;
+        }
+
+        let mut errors = 0;
+        loop {
+            match sender.poll_send() {
+                Err(e) => {
+                    assert_eq!(*e.kind(), ErrorKind::IoError);
+                    errors += 1;
+                }
+                Ok(Async::Ready(())) => break,
+                Ok(Async::NotReady) => {}
+            }
+        }
+        assert_eq!(errors, 2);
+        assert_eq!(sender.message_queue_len(), 0);
+
+        let mut receiver = receiver;
+        for i in 0..2 {
+            let (r, _, item) = fibers_global::execute(wait_recv(receiver))?;
+            assert_eq!(item, format!("bar{}", i));
+            receiver = r;
+        }
+        Ok(())
+    }
+}
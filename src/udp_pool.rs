@@ -0,0 +1,247 @@
+use crate::{
+    Error, ErrorKind, PollRecv, PollSend, Result, Transport, UdpTransport, UdpTransporter,
+    UdpTransporterBuilder,
+};
+use bytecodec::{Decode, Encode};
+use fibers::net::futures::UdpSocketBind;
+use fibers::net::UdpSocket;
+use futures::{Async, Future};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+/// A pool of UDP transporters keyed by their local ports.
+///
+/// This allows for choosing the source port of each outgoing datagram (e.g., for NAT traversal)
+/// via [`send_from`].
+/// The transporter bound to the requested port is created with a builder made by
+/// the function given to [`new`] if it does not exist yet.
+///
+/// [`send_from`]: ./struct.UdpTransporterPool.html#method.send_from
+/// [`new`]: ./struct.UdpTransporterPool.html#method.new
+pub struct UdpTransporterPool<E: Encode, D: Decode, F> {
+    builder: F,
+    ip: IpAddr,
+    transporters: BTreeMap<u16, UdpTransporter<E, D>>,
+    binding: BTreeMap<u16, Binding<E::Item>>,
+    next_recv: u16,
+}
+impl<E, D, F> UdpTransporterPool<E, D, F>
+where
+    E: Encode,
+    D: Decode,
+    F: FnMut() -> UdpTransporterBuilder<E, D>,
+{
+    /// Makes a new `UdpTransporterPool` instance.
+    ///
+    /// The transporters in the pool are bound to the given IP address,
+    /// and built with the builders made by `builder` (e.g., `|| UdpTransporterBuilder::new().buf_size(1500)`).
+    pub fn new(ip: IpAddr, builder: F) -> Self {
+        UdpTransporterPool {
+            builder,
+            ip,
+            transporters: BTreeMap::new(),
+            binding: BTreeMap::new(),
+            next_recv: 0,
+        }
+    }
+
+    /// Returns the local ports of the transporters in the pool.
+    ///
+    /// The ports still being bound are not included.
+    pub fn local_ports(&self) -> impl Iterator<Item = u16> + '_ {
+        self.transporters.keys().cloned()
+    }
+
+    /// Returns a reference to the transporter bound to the given local port.
+    pub fn transporter_ref(&self, local_port: u16) -> Option<&UdpTransporter<E, D>> {
+        self.transporters.get(&local_port)
+    }
+
+    /// Returns a mutable reference to the transporter bound to the given local port.
+    pub fn transporter_mut(&mut self, local_port: u16) -> Option<&mut UdpTransporter<E, D>> {
+        self.transporters.get_mut(&local_port)
+    }
+
+    /// Enqueues the given item destined for `peer` to be sent from the given local port.
+    ///
+    /// If there is no transporter bound to the port, a new one is created.
+    /// Because binding is asynchronous, the item is kept in the pool until the binding is completed
+    /// by `poll_send` (or `poll_recv_tagged`), and an error of the binding is reported by them.
+    /// If the binding fails, the items waiting for it are discarded.
+    ///
+    /// # Errors
+    ///
+    /// If `local_port` is `0`, this will return an `ErrorKind::InvalidInput` error.
+    pub fn send_from(&mut self, local_port: u16, peer: SocketAddr, item: E::Item) -> Result<()> {
+        track_assert_ne!(
+            local_port,
+            0,
+            ErrorKind::InvalidInput,
+            "The source port must be specified explicitly"
+        );
+        if let Some(t) = self.transporters.get_mut(&local_port) {
+            return track!(t.start_send(peer, item));
+        }
+        let ip = self.ip;
+        self.binding
+            .entry(local_port)
+            .or_insert_with(|| Binding {
+                future: UdpSocket::bind(SocketAddr::new(ip, local_port)),
+                pending: Vec::new(),
+            })
+            .pending
+            .push((peer, item));
+        Ok(())
+    }
+
+    /// Sends the enqueued items.
+    ///
+    /// This returns `Ok(Async::Ready(()))` once all of the bindings have been completed
+    /// and all of the enqueued items have been sent.
+    pub fn poll_send(&mut self) -> PollSend {
+        track!(self.poll_binding())?;
+        let mut ready = self.binding.is_empty();
+        for t in self.transporters.values_mut() {
+            ready &= track!(t.poll_send())?.is_ready();
+        }
+        if ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Polls reception of an item from a peer.
+    ///
+    /// The resulting tuple contains the local address on which the item was received
+    /// (i.e., `(local_addr, peer, item)`).
+    /// The transporters are polled in round-robin order.
+    pub fn poll_recv_tagged(&mut self) -> PollRecv<(SocketAddr, SocketAddr, D::Item)> {
+        track!(self.poll_binding())?;
+        let ports = self
+            .transporters
+            .range(self.next_recv..)
+            .chain(self.transporters.range(..self.next_recv))
+            .map(|(port, _)| *port)
+            .collect::<Vec<_>>();
+        for port in ports {
+            let t = self.transporters.get_mut(&port).expect("never fails");
+            if let Async::Ready(Some((peer, item))) = track!(t.poll_recv())? {
+                self.next_recv = port.wrapping_add(1);
+                return Ok(Async::Ready(Some((t.local_addr(), peer, item))));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+
+    fn poll_binding(&mut self) -> Result<()> {
+        let mut bound = Vec::new();
+        let mut failed = None;
+        for (port, binding) in &mut self.binding {
+            match binding.future.poll() {
+                Err(e) => {
+                    failed = Some((*port, e));
+                    break;
+                }
+                Ok(Async::NotReady) => {}
+                Ok(Async::Ready(socket)) => bound.push((*port, socket)),
+            }
+        }
+        if let Some((port, e)) = failed {
+            // The failed `UdpSocketBind` must not be polled again.
+            self.binding.remove(&port);
+            return Err(track!(Error::from(e); port));
+        }
+        for (port, socket) in bound {
+            let binding = self.binding.remove(&port).expect("never fails");
+            let mut t = track!((self.builder)().finish(socket))?;
+            for (peer, item) in binding.pending {
+                track!(t.start_send(peer, item))?;
+            }
+            self.transporters.insert(port, t);
+        }
+        Ok(())
+    }
+}
+impl<E: Encode, D: Decode, F> fmt::Debug for UdpTransporterPool<E, D, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "UdpTransporterPool {{ ip: {}, local_ports: {:?}, .. }}",
+            self.ip,
+            self.transporters.keys().collect::<Vec<_>>()
+        )
+    }
+}
+
+struct Binding<T> {
+    future: UdpSocketBind,
+    pending: Vec<(SocketAddr, T)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{drive, wait_recv};
+    use bytecodec::bytes::{Utf8Decoder, Utf8Encoder};
+
+    type Udp = UdpTransporter<Utf8Encoder, Utf8Decoder>;
+
+    fn free_port() -> u16 {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn send_from_works() -> Result<()> {
+        let receiver = fibers_global::execute(Udp::bind("127.0.0.1:0".parse().unwrap()))?;
+        let receiver_addr = receiver.local_addr();
+        let ports = [free_port(), free_port()];
+
+        let mut pool = UdpTransporterPool::new("127.0.0.1".parse().unwrap(), || {
+            UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new()
+        });
+        pool.send_from(ports[0], receiver_addr, "foo".to_owned())?;
+        pool.send_from(ports[1], receiver_addr, "bar".to_owned())?;
+        let (pool, ()) = fibers_global::execute(drive(pool, |t| t.poll_send()))?;
+        let mut local_ports = pool.local_ports().collect::<Vec<_>>();
+        local_ports.sort();
+        let mut expected = ports.to_vec();
+        expected.sort();
+        assert_eq!(local_ports, expected);
+
+        let mut received = Vec::new();
+        let (receiver, peer, item) = fibers_global::execute(wait_recv(receiver))?;
+        received.push((peer.port(), item));
+        let (_, peer, item) = fibers_global::execute(wait_recv(receiver))?;
+        received.push((peer.port(), item));
+        received.sort();
+
+        let mut expected = vec![(ports[0], "foo".to_owned()), (ports[1], "bar".to_owned())];
+        expected.sort();
+        assert_eq!(received, expected);
+        Ok(())
+    }
+    #[test]
+    fn send_from_bind_failure_works() -> Result<()> {
+        let occupied = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = occupied.local_addr().unwrap().port();
+
+        let mut pool = UdpTransporterPool::new("127.0.0.1".parse().unwrap(), || {
+            UdpTransporterBuilder::<Utf8Encoder, Utf8Decoder>::new()
+        });
+        pool.send_from(port, "127.0.0.1:1".parse().unwrap(), "foo".to_owned())?;
+        let (first, second, pool) = fibers_global::execute(futures::future::lazy(move || {
+            let first = pool.poll_send();
+            let second = pool.poll_send();
+            Ok::<_, Error>((first, second, pool))
+        }))?;
+
+        // The failed binding is reported once, and then discarded with its items.
+        assert_eq!(*first.err().unwrap().kind(), ErrorKind::IoError);
+        assert_eq!(second?, Async::Ready(()));
+        assert_eq!(pool.local_ports().count(), 0);
+        Ok(())
+    }
+}
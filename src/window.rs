@@ -0,0 +1,187 @@
+use crate::{ErrorKind, PollRecv, PollSend, Result, Transport};
+use futures::Async;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// An implementation of [`Transport`] that limits the number of in-flight unacknowledged items.
+///
+/// This is useful for credit-based flow control.
+/// Each item handed to the inner transporter is regarded as unacknowledged
+/// until `poll_recv` yields an item for which the acknowledgement predicate `F` returns `true`.
+///
+/// While the number of unacknowledged items reaches the window size,
+/// the subsequent items are kept in the queue of the instance and
+/// `poll_send` returns `Ok(Async::NotReady)` until some acknowledgements are received.
+/// Note that the acknowledgement items are also returned to the caller of `poll_recv` as usual.
+///
+/// [`Transport`]: ./trait.Transport.html
+pub struct WindowedTransporter<T: Transport, F> {
+    inner: T,
+    is_ack: F,
+    window_size: usize,
+    unacked: usize,
+    pending: VecDeque<(T::PeerAddr, T::SendItem)>,
+}
+impl<T, F> WindowedTransporter<T, F>
+where
+    T: Transport,
+    F: FnMut(&T::PeerAddr, &T::RecvItem) -> bool,
+{
+    /// Makes a new `WindowedTransporter` instance.
+    ///
+    /// # Errors
+    ///
+    /// If `window_size` is `0`, this will return an `ErrorKind::InvalidInput` error.
+    pub fn new(inner: T, window_size: usize, is_ack: F) -> Result<Self> {
+        track_assert_ne!(
+            window_size,
+            0,
+            ErrorKind::InvalidInput,
+            "The window size must be a positive number"
+        );
+        Ok(WindowedTransporter {
+            inner,
+            is_ack,
+            window_size,
+            unacked: 0,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Returns the window size of the instance.
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    /// Returns the number of the items that have been sent but not yet acknowledged.
+    pub fn unacked_len(&self) -> usize {
+        self.unacked
+    }
+
+    /// Returns the number of the items waiting for the window to open.
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Takes ownership of the instance, and returns the inner transporter.
+    ///
+    /// The items waiting for the window to open are discarded.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn start_pending_sends(&mut self) -> Result<()> {
+        while self.unacked < self.window_size {
+            if let Some((peer, item)) = self.pending.pop_front() {
+                track!(self.inner.start_send(peer, item))?;
+                self.unacked += 1;
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+impl<T, F> Transport for WindowedTransporter<T, F>
+where
+    T: Transport,
+    F: FnMut(&T::PeerAddr, &T::RecvItem) -> bool,
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = T::SendItem;
+    type RecvItem = T::RecvItem;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        self.pending.push_back((peer, item));
+        track!(self.start_pending_sends())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.start_pending_sends())?;
+        let ready = track!(self.inner.poll_send())?.is_ready();
+        if ready && self.pending.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        let polled = track!(self.inner.poll_recv())?;
+        if let Async::Ready(Some((ref peer, ref item))) = polled {
+            if (self.is_ack)(peer, item) {
+                self.unacked = self.unacked.saturating_sub(1);
+            }
+        }
+        Ok(polled)
+    }
+
+    fn set_recv_paused(&mut self, paused: bool) -> Result<()> {
+        track!(self.inner.set_recv_paused(paused))
+    }
+
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_socket_addr()
+    }
+}
+impl<T: Transport + fmt::Debug, F> fmt::Debug for WindowedTransporter<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "WindowedTransporter {{ inner: {:?}, window_size: {}, unacked: {}, pending: {}, .. }}",
+            self.inner,
+            self.window_size,
+            self.unacked,
+            self.pending.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransporter;
+
+    #[test]
+    fn windowed_transporter_works() -> Result<()> {
+        let peer: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let mock = MockTransporter::<SocketAddr, &'static str, &'static str>::new();
+        let mut transporter =
+            WindowedTransporter::new(mock, 2, |_: &SocketAddr, item: &&str| *item == "ack")?;
+
+        transporter.start_send(peer, "foo")?;
+        transporter.start_send(peer, "bar")?;
+        transporter.start_send(peer, "baz")?;
+        assert_eq!(transporter.unacked_len(), 2);
+        assert_eq!(transporter.pending_len(), 1);
+        assert_eq!(transporter.poll_send()?, Async::NotReady);
+        assert_eq!(transporter.inner_ref().sent, [(peer, "foo"), (peer, "bar")]);
+
+        transporter.inner_mut().incoming.push_back((peer, "hello"));
+        transporter.inner_mut().incoming.push_back((peer, "ack"));
+        assert_eq!(
+            transporter.poll_recv()?,
+            Async::Ready(Some((peer, "hello")))
+        );
+        assert_eq!(transporter.unacked_len(), 2);
+        assert_eq!(transporter.poll_recv()?, Async::Ready(Some((peer, "ack"))));
+        assert_eq!(transporter.unacked_len(), 1);
+
+        assert_eq!(transporter.poll_send()?, Async::Ready(()));
+        assert_eq!(transporter.unacked_len(), 2);
+        assert_eq!(transporter.pending_len(), 0);
+        assert_eq!(transporter.inner_ref().sent.last(), Some(&(peer, "baz")));
+        Ok(())
+    }
+}